@@ -129,7 +129,7 @@ while [ "$#" -gt 0 ]; do
             fi
 
             if [ "$PROVIDER_NAME" = "all" ] || [ "$PROVIDER_NAME" = "cargo-check" ]; then
-                FEATURES="--features=all-providers,all-authenticators"
+                FEATURES="--features=all-providers,all-authenticators,tcp-listener,vsock-listener,sqlite-manager,mappings-watcher"
                 TEST_FEATURES="--features=all-providers"
             else
                 FEATURES="--features=$1-provider,direct-authenticator"
@@ -206,6 +206,8 @@ if [ "$PROVIDER_NAME" = "coverage" ]; then
 
         cp -r /tmp/mappings/ .
         cp -r $(pwd)/e2e_tests/fake_mappings/* mappings
+        cargo +1.51.0 run --release --features="$provider-provider,direct-authenticator" -- \
+            -c $CONFIG_PATH migrate-mappings
         if [ "$PROVIDER_NAME" = "mbed-crypto" ]; then
             cp /tmp/*.psa_its .
         fi
@@ -282,7 +284,20 @@ if [ "$PROVIDER_NAME" = "cargo-check" ]; then
     RUST_BACKTRACE=1 cargo check --features="direct-authenticator"
     RUST_BACKTRACE=1 cargo check --features="unix-peer-credentials-authenticator"
     RUST_BACKTRACE=1 cargo check --features="jwt-svid-authenticator"
+    RUST_BACKTRACE=1 cargo check --features="client-certificate-authenticator"
+    RUST_BACKTRACE=1 cargo check --features="vsock-cid-authenticator"
     RUST_BACKTRACE=1 cargo check --features="all-authenticators"
+    RUST_BACKTRACE=1 cargo check --features="tcp-listener"
+    RUST_BACKTRACE=1 cargo check --features="vsock-listener"
+    RUST_BACKTRACE=1 cargo check --features="sqlite-manager"
+    RUST_BACKTRACE=1 cargo check --features="mappings-watcher"
+
+    # Clippy runs on the optional features alone here, the "all" job covering them together.
+    if rustup component list | grep -q clippy; then
+        for feature in tcp-listener vsock-listener sqlite-manager mappings-watcher; do
+            cargo clippy --all-targets --features="$feature" -- -D clippy::all -D clippy::cargo
+        done
+    fi
 
     exit 0
 fi
@@ -313,6 +328,9 @@ cp -r /tmp/mappings/ .
 # those keys have successfully been deleted.
 # TODO: add fake mappings for the Trusted Service and CryptoAuthLib providers.
 cp -r $(pwd)/e2e_tests/fake_mappings/* mappings
+# Those mappings were written before the authenticator type was recorded, they are moved to the
+# namespace of the configured authenticator.
+RUST_LOG=info cargo run --release $FEATURES -- --config $CONFIG_PATH migrate-mappings
 # As Mbed Crypto saves its keys on the current directory we need to move them
 # as well.
 if [ "$PROVIDER_NAME" = "mbed-crypto" ]; then
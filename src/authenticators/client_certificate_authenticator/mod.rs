@@ -0,0 +1,289 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Client certificate authenticator
+//!
+//! The `ClientCertificateAuthenticator` identifies the applications connecting through a listener
+//! using mutual TLS by the certificate they presented, which the listener verified during the TLS
+//! handshake. The application name is read from a field of the certificate: the Common Name of
+//! the subject, a URI in the Subject Alternative Name such as a SPIFFE ID, or another attribute
+//! of the subject. The authentication field of the requests is not used.
+//!
+//! The wire protocol does not define an authentication type for client certificates yet, so the
+//! clients select this authenticator with the `Direct` one, the name they declare being ignored.
+//! The names it gives are in their own namespace, distinct from the one of the direct
+//! authenticator. Both can not be used on the same listener.
+
+use super::{Admin, AdminList, Application, Authenticate, AuthenticatorType};
+use crate::front::listener::ConnectionMetadata;
+use log::error;
+use parsec_interface::operations::list_authenticators;
+use parsec_interface::requests::request::RequestAuth;
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use std::io::{Error, ErrorKind};
+use x509_parser::prelude::{parse_x509_certificate, GeneralName, X509Certificate};
+
+/// Field of the client certificate giving the application name
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertificateIdentity {
+    /// Common Name of the subject
+    SubjectCommonName,
+    /// First URI in the Subject Alternative Name, such as a SPIFFE ID
+    SanUri,
+    /// Attribute of the subject, identified by its OID in dotted notation
+    SubjectAttribute(String),
+}
+
+impl CertificateIdentity {
+    /// Parse the identity from its name in the configuration: "SubjectCommonName", "SanUri" or
+    /// the OID of an attribute of the subject, such as "2.5.4.10" for the Organization.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the name is not valid.
+    pub fn from_name(name: &str) -> std::io::Result<CertificateIdentity> {
+        match name {
+            "SubjectCommonName" => Ok(CertificateIdentity::SubjectCommonName),
+            "SanUri" => Ok(CertificateIdentity::SanUri),
+            oid if is_oid(oid) => Ok(CertificateIdentity::SubjectAttribute(oid.to_string())),
+            _ => {
+                error!(
+                    "Invalid identity \"{}\" for the client certificate authenticator.",
+                    name
+                );
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid certificate identity",
+                ))
+            }
+        }
+    }
+
+    /// Read the identity from the certificate, if present.
+    fn read(&self, certificate: &X509Certificate) -> Option<String> {
+        let subject = &certificate.tbs_certificate.subject;
+        match self {
+            CertificateIdentity::SubjectCommonName => subject
+                .iter_common_name()
+                .next()
+                .and_then(|attribute| attribute.as_str().ok())
+                .map(String::from),
+            CertificateIdentity::SanUri => certificate
+                .tbs_certificate
+                .subject_alternative_name()
+                .and_then(|(_, san)| {
+                    san.general_names.iter().find_map(|name| match name {
+                        GeneralName::URI(uri) => Some(uri.to_string()),
+                        _ => None,
+                    })
+                }),
+            CertificateIdentity::SubjectAttribute(oid) => subject
+                .rdn_seq
+                .iter()
+                .flat_map(|rdn| rdn.set.iter())
+                .find(|attribute| attribute.attr_type.to_id_string() == *oid)
+                .and_then(|attribute| attribute.as_str().ok())
+                .map(String::from),
+        }
+    }
+}
+
+/// Checks if the name is an OID in dotted notation.
+fn is_oid(name: &str) -> bool {
+    name.split('.').count() > 1
+        && name
+            .split('.')
+            .all(|arc| !arc.is_empty() && arc.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Client certificate authenticator implementation
+#[derive(Clone, Debug)]
+pub struct ClientCertificateAuthenticator {
+    identity: CertificateIdentity,
+    admins: AdminList,
+}
+
+impl ClientCertificateAuthenticator {
+    /// Create a new client certificate authenticator, reading the application names from the
+    /// `identity` field of the certificates, or the Common Name of their subject if absent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the identity is not valid.
+    pub fn new(identity: Option<&str>, admins: Vec<Admin>) -> std::io::Result<Self> {
+        Ok(ClientCertificateAuthenticator {
+            identity: identity
+                .map(CertificateIdentity::from_name)
+                .transpose()?
+                .unwrap_or(CertificateIdentity::SubjectCommonName),
+            admins: admins.into(),
+        })
+    }
+}
+
+impl Authenticate for ClientCertificateAuthenticator {
+    fn describe(&self) -> Result<list_authenticators::AuthenticatorInfo> {
+        Ok(list_authenticators::AuthenticatorInfo {
+            description: String::from(
+                "Uses the identity in the certificate presented by the client, verified by a \
+                listener using mutual TLS, as the application identity. The authentication field \
+                is not used.",
+            ),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::Direct,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        _: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<Application> {
+        let chain = match meta {
+            Some(ConnectionMetadata::TlsPeerCertificates { chain }) => chain,
+            _ => {
+                error!(
+                    "The client certificate authenticator did not receive the certificate of the \
+                    client; the connection must use TLS."
+                );
+                return Err(ResponseStatus::AuthenticationError);
+            }
+        };
+        let certificate = chain.first().ok_or_else(|| {
+            error!("The certificate chain of the client is empty.");
+            ResponseStatus::AuthenticationError
+        })?;
+        let (_, certificate) = parse_x509_certificate(certificate).map_err(|e| {
+            error!("Failed to parse the client certificate ({}).", e);
+            ResponseStatus::AuthenticationError
+        })?;
+
+        let app_name = self.identity.read(&certificate).ok_or_else(|| {
+            error!(
+                "The client certificate does not contain the identity {:?}.",
+                self.identity
+            );
+            ResponseStatus::AuthenticationError
+        })?;
+        let is_admin = self.admins.is_admin(&app_name);
+        Ok(Application::new(
+            app_name,
+            AuthenticatorType::ClientCertificate,
+            is_admin,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Authenticate;
+    use super::{CertificateIdentity, ClientCertificateAuthenticator};
+    use crate::authenticators::{ApplicationName, AuthenticatorType};
+    use crate::front::listener::ConnectionMetadata;
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::{AuthType, ResponseStatus};
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+
+    fn client_certificate() -> Vec<u8> {
+        let mut params = CertificateParams::default();
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, "client");
+        name.push(DnType::OrganizationName, "Parsec");
+        params.distinguished_name = name;
+        params.subject_alt_names = vec![
+            SanType::DnsName(String::from("client.example.org")),
+            SanType::URI(String::from("spiffe://example.org/client")),
+        ];
+        Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap()
+    }
+
+    fn certificate_metadata() -> Option<ConnectionMetadata> {
+        Some(ConnectionMetadata::TlsPeerCertificates {
+            chain: vec![client_certificate()],
+        })
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(
+            CertificateIdentity::from_name("2.5.4.10").unwrap(),
+            CertificateIdentity::SubjectAttribute(String::from("2.5.4.10"))
+        );
+        for name in ["CommonName", "2.5..10", "2", "2.5.4.x"].iter() {
+            let _ = CertificateIdentity::from_name(name).unwrap_err();
+        }
+
+        let req_auth = RequestAuth::new(b"declared name".to_vec());
+        for (identity, app_name) in [
+            (None, "client"),
+            (Some("SubjectCommonName"), "client"),
+            (Some("SanUri"), "spiffe://example.org/client"),
+            (Some("2.5.4.10"), "Parsec"),
+        ]
+        .iter()
+        {
+            let authenticator =
+                ClientCertificateAuthenticator::new(*identity, Default::default()).unwrap();
+            let app = authenticator
+                .authenticate(&req_auth, certificate_metadata())
+                .expect("Failed to authenticate");
+            assert_eq!(
+                app.get_name(),
+                &ApplicationName::new(app_name.to_string(), AuthenticatorType::ClientCertificate)
+            );
+            assert_ne!(
+                app.get_name(),
+                &ApplicationName::new(app_name.to_string(), AuthType::Direct)
+            );
+            assert!(!app.is_admin);
+        }
+
+        // Organizational Unit
+        let authenticator =
+            ClientCertificateAuthenticator::new(Some("2.5.4.11"), Default::default()).unwrap();
+        let status = authenticator
+            .authenticate(&req_auth, certificate_metadata())
+            .unwrap_err();
+        assert_eq!(status, ResponseStatus::AuthenticationError);
+    }
+
+    #[test]
+    fn wrong_metadata() {
+        let authenticator = ClientCertificateAuthenticator::new(None, Default::default()).unwrap();
+        let req_auth = RequestAuth::new(b"client".to_vec());
+
+        for conn_metadata in vec![
+            None,
+            Some(ConnectionMetadata::UnixPeerCredentials {
+                uid: 0,
+                gid: 0,
+                pid: None,
+            }),
+            Some(ConnectionMetadata::TlsPeerCertificates { chain: Vec::new() }),
+            Some(ConnectionMetadata::TlsPeerCertificates {
+                chain: vec![vec![0x30, 0x00]],
+            }),
+        ] {
+            let status = authenticator
+                .authenticate(&req_auth, conn_metadata)
+                .unwrap_err();
+            assert_eq!(status, ResponseStatus::AuthenticationError);
+        }
+    }
+
+    #[test]
+    fn admin_check() {
+        let admin = toml::from_str("name = 'spiffe://example.org/client'").unwrap();
+        let authenticator =
+            ClientCertificateAuthenticator::new(Some("SanUri"), vec![admin]).unwrap();
+
+        let app = authenticator
+            .authenticate(&RequestAuth::new(Vec::new()), certificate_metadata())
+            .expect("Failed to authenticate");
+        assert!(app.is_admin);
+    }
+}
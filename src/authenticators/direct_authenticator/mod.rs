@@ -61,7 +61,7 @@ impl Authenticate for DirectAuthenticator {
                 Ok(str) => {
                     let app_name = String::from(str);
                     let is_admin = self.admins.is_admin(&app_name);
-                    Ok(Application::new(app_name, is_admin))
+                    Ok(Application::new(app_name, AuthType::Direct, is_admin))
                 }
                 Err(_) => {
                     error!("Error parsing the authentication value as a UTF-8 string.");
@@ -78,7 +78,7 @@ mod test {
     use super::DirectAuthenticator;
     use crate::authenticators::ApplicationName;
     use parsec_interface::requests::request::RequestAuth;
-    use parsec_interface::requests::ResponseStatus;
+    use parsec_interface::requests::{AuthType, ResponseStatus};
 
     #[test]
     fn successful_authentication() {
@@ -94,7 +94,10 @@ mod test {
             .authenticate(&req_auth, conn_metadata)
             .expect("Failed to authenticate");
 
-        assert_eq!(app.get_name(), &ApplicationName::from_name(app_name));
+        assert_eq!(
+            app.get_name(),
+            &ApplicationName::new(app_name, AuthType::Direct)
+        );
         assert!(!app.is_admin);
     }
 
@@ -140,17 +143,21 @@ mod test {
             .authenticate(&req_auth, conn_metadata)
             .expect("Failed to authenticate");
 
-        assert_eq!(auth_name.get_name(), &ApplicationName::from_name(app_name));
+        assert_eq!(
+            auth_name.get_name(),
+            &ApplicationName::new(app_name, AuthType::Direct)
+        );
         assert!(!auth_name.is_admin);
 
         let req_auth = RequestAuth::new(admin_name.clone().into_bytes());
+        let conn_metadata = None;
         let auth_name = authenticator
             .authenticate(&req_auth, conn_metadata)
             .expect("Failed to authenticate");
 
         assert_eq!(
             auth_name.get_name(),
-            &ApplicationName::from_name(admin_name)
+            &ApplicationName::new(admin_name, AuthType::Direct)
         );
         assert!(auth_name.is_admin);
     }
@@ -72,6 +72,6 @@ impl Authenticate for JwtSvidAuthenticator {
             })?;
         let app_name = spiffe_id.to_string();
         let is_admin = self.admins.is_admin(&app_name);
-        Ok(Application::new(app_name, is_admin))
+        Ok(Application::new(app_name, AuthType::JwtSvid, is_admin))
     }
 }
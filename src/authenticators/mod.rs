@@ -13,6 +13,8 @@
     feature = "direct-authenticator",
     feature = "unix-peer-credentials-authenticator",
     feature = "jwt-svid-authenticator",
+    feature = "client-certificate-authenticator",
+    feature = "vsock-cid-authenticator",
 )))]
 compile_error!("Please provide in at least one authenticator");
 
@@ -25,17 +27,93 @@ pub mod unix_peer_credentials_authenticator;
 #[cfg(feature = "jwt-svid-authenticator")]
 pub mod jwt_svid_authenticator;
 
+#[cfg(feature = "client-certificate-authenticator")]
+pub mod client_certificate_authenticator;
+
+#[cfg(feature = "vsock-cid-authenticator")]
+pub mod vsock_cid_authenticator;
+
 use crate::front::listener::ConnectionMetadata;
 use crate::utils::config::Admin;
 use parsec_interface::operations::list_authenticators;
 use parsec_interface::requests::request::RequestAuth;
-use parsec_interface::requests::Result;
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use std::convert::TryFrom;
 use std::ops::Deref;
 
+/// Identifier of the first authenticator type not defined by the wire protocol
+///
+/// The identifiers of these authenticator types start high enough not to collide with the
+/// authenticator types that the wire protocol will define.
+const FIRST_LOCAL_AUTHENTICATOR_ID: u8 = 128;
+/// Identifier of the vsock CID authenticator type
+const VSOCK_CID_AUTHENTICATOR_ID: u8 = FIRST_LOCAL_AUTHENTICATOR_ID + 1;
+
+/// Type of an authenticator
+///
+/// Each type of authenticator gives names in its own namespace. The types defined by the wire
+/// protocol are identified by their `AuthType`. The other ones are local to the service: the
+/// clients select them with the `AuthType` of another authenticator, which they can not be
+/// combined with on the same listener.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum AuthenticatorType {
+    /// Authenticator type defined by the wire protocol
+    Wire(AuthType),
+    /// Client certificate authenticator, selected with the `Direct` authentication type
+    ClientCertificate,
+    /// vsock CID authenticator, selected with the `Direct` authentication type
+    VsockCid,
+}
+
+impl AuthenticatorType {
+    /// Get the authentication type the clients select this type of authenticator with
+    pub fn auth_type(self) -> AuthType {
+        match self {
+            AuthenticatorType::Wire(auth_type) => auth_type,
+            AuthenticatorType::ClientCertificate | AuthenticatorType::VsockCid => AuthType::Direct,
+        }
+    }
+}
+
+impl From<AuthType> for AuthenticatorType {
+    fn from(auth_type: AuthType) -> Self {
+        AuthenticatorType::Wire(auth_type)
+    }
+}
+
+impl From<AuthenticatorType> for u8 {
+    fn from(authenticator_type: AuthenticatorType) -> Self {
+        match authenticator_type {
+            AuthenticatorType::Wire(auth_type) => auth_type as u8,
+            AuthenticatorType::ClientCertificate => FIRST_LOCAL_AUTHENTICATOR_ID,
+            AuthenticatorType::VsockCid => VSOCK_CID_AUTHENTICATOR_ID,
+        }
+    }
+}
+
+impl TryFrom<u8> for AuthenticatorType {
+    type Error = ResponseStatus;
+
+    fn try_from(id: u8) -> Result<Self> {
+        match id {
+            FIRST_LOCAL_AUTHENTICATOR_ID => Ok(AuthenticatorType::ClientCertificate),
+            VSOCK_CID_AUTHENTICATOR_ID => Ok(AuthenticatorType::VsockCid),
+            id if id < FIRST_LOCAL_AUTHENTICATOR_ID => AuthType::try_from(id)
+                .map(From::from)
+                .map_err(|_| ResponseStatus::AuthenticatorDoesNotExist),
+            _ => Err(ResponseStatus::AuthenticatorDoesNotExist),
+        }
+    }
+}
+
 /// String wrapper for app names
+///
+/// The name is qualified by the type of the authenticator which authenticated the application:
+/// the same name given by two different authenticators identifies two different applications.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct ApplicationName {
     name: String,
+    authenticator_type: AuthenticatorType,
 }
 
 impl Deref for ApplicationName {
@@ -79,17 +157,29 @@ pub trait Authenticate {
 }
 
 impl ApplicationName {
-    /// Create ApplicationName from name string only
-    pub fn from_name(name: String) -> ApplicationName {
-        ApplicationName { name }
+    /// Create ApplicationName from the name string and the type of the authenticator which gave it
+    pub fn new(name: String, authenticator_type: impl Into<AuthenticatorType>) -> ApplicationName {
+        ApplicationName {
+            name,
+            authenticator_type: authenticator_type.into(),
+        }
+    }
+
+    /// Get the type of the authenticator which gave the name
+    pub fn authenticator_type(&self) -> AuthenticatorType {
+        self.authenticator_type
     }
 }
 
 impl Application {
     /// Create a new Application structure
-    pub fn new(name: String, is_admin: bool) -> Application {
+    pub fn new(
+        name: String,
+        authenticator_type: impl Into<AuthenticatorType>,
+        is_admin: bool,
+    ) -> Application {
         Application {
-            name: ApplicationName::from_name(name),
+            name: ApplicationName::new(name, authenticator_type),
             is_admin,
         }
     }
@@ -75,7 +75,6 @@ impl Authenticate for UnixPeerCredentialsAuthenticator {
             ResponseStatus::AuthenticationError
         })?;
 
-        #[allow(unreachable_patterns)]
         let (uid, _gid, _pid) = match meta {
             ConnectionMetadata::UnixPeerCredentials { uid, gid, pid } => (uid, gid, pid),
             _ => {
@@ -89,7 +88,11 @@ impl Authenticate for UnixPeerCredentialsAuthenticator {
         if uid == expected_uid {
             let app_name = uid.to_string();
             let is_admin = self.admins.is_admin(&app_name);
-            Ok(Application::new(app_name, is_admin))
+            Ok(Application::new(
+                app_name,
+                AuthType::UnixPeerCredentials,
+                is_admin,
+            ))
         } else {
             error!("Declared UID in authentication request does not match the process's UID.");
             Err(ResponseStatus::AuthenticationError)
@@ -105,7 +108,7 @@ mod test {
     use crate::front::domain_socket::peer_credentials;
     use crate::front::listener::ConnectionMetadata;
     use parsec_interface::requests::request::RequestAuth;
-    use parsec_interface::requests::ResponseStatus;
+    use parsec_interface::requests::{AuthType, ResponseStatus};
     use rand::Rng;
     use std::os::unix::net::UnixStream;
     use users::get_current_uid;
@@ -140,7 +143,7 @@ mod test {
 
         assert_eq!(
             auth_name.get_name(),
-            &ApplicationName::from_name(get_current_uid().to_string())
+            &ApplicationName::new(get_current_uid().to_string(), AuthType::UnixPeerCredentials)
         );
         assert!(!auth_name.is_admin);
     }
@@ -247,15 +250,26 @@ mod test {
 
         assert_eq!(
             auth_name.get_name(),
-            &ApplicationName::from_name(get_current_uid().to_string())
+            &ApplicationName::new(get_current_uid().to_string(), AuthType::UnixPeerCredentials)
         );
         assert!(auth_name.is_admin);
     }
 
     #[test]
     fn unsuccessful_authentication_wrong_metadata() {
-        // TODO(new_metadata_variant): this test needs implementing when we have more than one
-        // metadata type. At the moment, the compiler just complains with an 'unreachable branch'
-        // message.
+        let authenticator = UnixPeerCredentialsAuthenticator {
+            admins: Default::default(),
+        };
+
+        let req_auth_data = get_current_uid().to_le_bytes().to_vec();
+        let req_auth = RequestAuth::new(req_auth_data);
+        let conn_metadata = Some(ConnectionMetadata::TlsPeerCertificates {
+            chain: vec![vec![0x30, 0x00]],
+        });
+
+        let auth_result = authenticator
+            .authenticate(&req_auth, conn_metadata)
+            .unwrap_err();
+        assert_eq!(auth_result, ResponseStatus::AuthenticationError);
     }
 }
@@ -0,0 +1,172 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! vsock CID authenticator
+//!
+//! The `VsockCidAuthenticator` identifies the virtual machines connecting through a vsock listener
+//! by their Context Identifier (CID), which the host assigns to each of them. The application name
+//! of each CID is given in the configuration; the virtual machines with another CID are refused.
+//! The authentication field of the requests is not used.
+//!
+//! The wire protocol does not define an authentication type for vsock CIDs, so the clients select
+//! this authenticator with the `Direct` one, the name they declare being ignored. The names it
+//! gives are in their own namespace, distinct from the one of the direct authenticator. Both can
+//! not be used on the same listener.
+
+use super::{Admin, AdminList, Application, Authenticate, AuthenticatorType};
+use crate::front::listener::ConnectionMetadata;
+use crate::utils::config::VsockApplication;
+use log::error;
+use parsec_interface::operations::list_authenticators;
+use parsec_interface::requests::request::RequestAuth;
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+/// vsock CID authenticator
+#[derive(Clone, Debug)]
+pub struct VsockCidAuthenticator {
+    app_names: HashMap<u32, String>,
+    admins: AdminList,
+}
+
+impl VsockCidAuthenticator {
+    /// Create a new authenticator giving the application names of the CIDs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a CID is given twice.
+    pub fn new(
+        applications: &[VsockApplication],
+        admins: Vec<Admin>,
+    ) -> std::io::Result<VsockCidAuthenticator> {
+        let mut app_names = HashMap::new();
+        for application in applications {
+            if app_names
+                .insert(application.cid(), application.name().to_string())
+                .is_some()
+            {
+                error!(
+                    "The CID {} is given twice to the vsock CID authenticator.",
+                    application.cid()
+                );
+                return Err(Error::new(ErrorKind::InvalidData, "CID given twice"));
+            }
+        }
+
+        Ok(VsockCidAuthenticator {
+            app_names,
+            admins: admins.into(),
+        })
+    }
+}
+
+impl Authenticate for VsockCidAuthenticator {
+    fn describe(&self) -> Result<list_authenticators::AuthenticatorInfo> {
+        Ok(list_authenticators::AuthenticatorInfo {
+            description: String::from(
+                "Uses the name configured for the Context Identifier of the virtual machine \
+                connecting through a vsock listener as the application identity. The \
+                authentication field is not used.",
+            ),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::Direct,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        _: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<Application> {
+        let cid = match meta {
+            Some(ConnectionMetadata::VsockPeer { cid }) => cid,
+            _ => {
+                error!(
+                    "The vsock CID authenticator did not receive the CID of the client; the \
+                    connection must use vsock."
+                );
+                return Err(ResponseStatus::AuthenticationError);
+            }
+        };
+
+        let app_name = self.app_names.get(&cid).ok_or_else(|| {
+            error!("No application name is configured for the CID {}.", cid);
+            ResponseStatus::AuthenticationError
+        })?;
+        let is_admin = self.admins.is_admin(app_name);
+        Ok(Application::new(
+            app_name.clone(),
+            AuthenticatorType::VsockCid,
+            is_admin,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Authenticate;
+    use super::VsockCidAuthenticator;
+    use crate::authenticators::{ApplicationName, AuthenticatorType};
+    use crate::front::listener::ConnectionMetadata;
+    use crate::utils::config::{Admin, VsockApplication};
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::ResponseStatus;
+
+    fn applications() -> Vec<VsockApplication> {
+        vec![
+            toml::from_str("cid = 3\nname = 'vm-3'").unwrap(),
+            toml::from_str("cid = 4\nname = 'vm-4'").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn known_cids() {
+        let admin: Admin = toml::from_str("name = 'vm-4'").unwrap();
+        let authenticator = VsockCidAuthenticator::new(&applications(), vec![admin]).unwrap();
+        let req_auth = RequestAuth::new(b"declared name".to_vec());
+
+        let app = authenticator
+            .authenticate(&req_auth, Some(ConnectionMetadata::VsockPeer { cid: 3 }))
+            .expect("Failed to authenticate");
+        assert_eq!(
+            app.get_name(),
+            &ApplicationName::new(String::from("vm-3"), AuthenticatorType::VsockCid)
+        );
+        assert!(!app.is_admin());
+
+        let app = authenticator
+            .authenticate(&req_auth, Some(ConnectionMetadata::VsockPeer { cid: 4 }))
+            .expect("Failed to authenticate");
+        assert!(app.is_admin());
+    }
+
+    #[test]
+    fn unknown_cid() {
+        let authenticator = VsockCidAuthenticator::new(&applications(), Vec::new()).unwrap();
+        let req_auth = RequestAuth::new(b"vm-3".to_vec());
+
+        for conn_metadata in vec![
+            None,
+            Some(ConnectionMetadata::VsockPeer { cid: 5 }),
+            Some(ConnectionMetadata::UnixPeerCredentials {
+                uid: 3,
+                gid: 3,
+                pid: None,
+            }),
+        ] {
+            let status = authenticator
+                .authenticate(&req_auth, conn_metadata)
+                .unwrap_err();
+            assert_eq!(status, ResponseStatus::AuthenticationError);
+        }
+    }
+
+    #[test]
+    fn cid_given_twice() {
+        let mut applications = applications();
+        applications.push(applications[0].clone());
+        let _ = VsockCidAuthenticator::new(&applications, Vec::new()).unwrap_err();
+    }
+}
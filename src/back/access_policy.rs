@@ -0,0 +1,564 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Role-based access control of the operations
+//!
+//! Without an access policy, authenticated applications can perform all the operations, except the
+//! admin ones which are reserved to the admins. The access policy, defined in the TOML file
+//! referenced by the configuration of the service, restricts each application to the operations
+//! allowed by the roles bound to it. A role allows some opcodes, on some providers, with keys
+//! whose names start with some prefixes. Roles are bound to applications by name or by pattern,
+//! where `*` matches any sequence of characters, of all the authenticators or of the ones selected
+//! by the binding: applications of different authenticators can have the same name.
+//!
+//! Requests of applications not allowed by any of their roles are refused with
+//! `PsaErrorNotPermitted` before reaching the provider. Requests without an application, when
+//! authentication is not required, are not restricted.
+//!
+//! The admin commands, run with the service stopped, bypass the roles. The access policy refuses
+//! the ones it does not list in its `admin_commands`, so that a policy written for the requests
+//! does not let the keys be modified outside of them.
+use crate::authenticators::{ApplicationName, AuthenticatorType};
+use crate::utils::config::{opcode_from_name, provider_id_from_name, AuthenticatorConfig};
+use log::{error, warn};
+use parsec_interface::requests::{Opcode, ProviderId, ResponseStatus};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// Admin commands of the service binary which the access policy can allow
+const ADMIN_COMMANDS: [&str; 6] = [
+    "key-metadata",
+    "list-key-metadata",
+    "transfer-client",
+    "set-key-expiry",
+    "grant-key-access",
+    "list-key-grants",
+];
+
+/// Access policy file
+#[derive(Deserialize, Debug)]
+struct AccessPolicyFile {
+    admin_commands: Option<Vec<String>>,
+    role: Option<Vec<RoleConfig>>,
+    binding: Option<Vec<BindingConfig>>,
+}
+
+/// Role, as defined in the access policy file
+#[derive(Deserialize, Debug)]
+struct RoleConfig {
+    name: String,
+    opcodes: Option<Vec<String>>,
+    providers: Option<Vec<String>>,
+    key_name_prefixes: Option<Vec<String>>,
+}
+
+/// Binding of roles to applications, as defined in the access policy file
+#[derive(Deserialize, Debug)]
+struct BindingConfig {
+    applications: Vec<String>,
+    roles: Vec<String>,
+    authenticators: Option<Vec<String>>,
+}
+
+/// Operations allowed by a role. Each restriction is lifted when absent.
+#[derive(Debug)]
+struct Role {
+    opcodes: Option<HashSet<Opcode>>,
+    providers: Option<HashSet<ProviderId>>,
+    key_name_prefixes: Option<Vec<String>>,
+}
+
+impl Role {
+    /// Checks if the role allows an operation. The key name prefixes only restrict the operations
+    /// using a key.
+    fn allows(&self, opcode: Opcode, provider_id: ProviderId, key_name: Option<&str>) -> bool {
+        self.opcodes
+            .as_ref()
+            .map_or(true, |opcodes| opcodes.contains(&opcode))
+            && self
+                .providers
+                .as_ref()
+                .map_or(true, |providers| providers.contains(&provider_id))
+            && match (&self.key_name_prefixes, key_name) {
+                (Some(prefixes), Some(key_name)) => prefixes
+                    .iter()
+                    .any(|prefix| key_name.starts_with(prefix.as_str())),
+                _ => true,
+            }
+    }
+}
+
+/// Roles bound to the applications whose name matches a pattern
+#[derive(Debug)]
+struct Binding {
+    pattern: String,
+    roles: Vec<String>,
+    /// Authenticators of the applications, all of them when absent
+    authenticator_types: Option<Vec<AuthenticatorType>>,
+}
+
+impl Binding {
+    /// Checks if the roles are bound to the application.
+    fn binds(&self, app_name: &ApplicationName) -> bool {
+        self.authenticator_types
+            .as_ref()
+            .map_or(true, |authenticator_types| {
+                authenticator_types.contains(&app_name.authenticator_type())
+            })
+            && matches_pattern(&self.pattern, app_name.as_str())
+    }
+}
+
+/// Access policy of the service
+#[derive(Debug)]
+pub struct AccessPolicy {
+    admin_commands: HashSet<String>,
+    roles: HashMap<String, Role>,
+    bindings: Vec<Binding>,
+}
+
+impl AccessPolicy {
+    /// Load the access policy defined in a TOML file, whose bindings can select some of the
+    /// authenticators configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the file can not be read, or if the policy is
+    /// invalid.
+    pub fn from_file(
+        policy_path: &Path,
+        authenticators: &[AuthenticatorConfig],
+    ) -> std::io::Result<AccessPolicy> {
+        let policy = read_to_string(policy_path).map_err(|e| {
+            error!(
+                "Failed to read the access policy file {}: {}.",
+                policy_path.display(),
+                e
+            );
+            Error::new(ErrorKind::InvalidData, "access policy file can not be read")
+        })?;
+
+        AccessPolicy::from_toml(&policy, authenticators)
+    }
+
+    /// Create the access policy defined in TOML, whose bindings can select some of the
+    /// authenticators configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the policy can not be parsed, names an unknown
+    /// admin command, opcode or provider, defines a role twice, binds a role which is not defined
+    /// or selects an authenticator which is not configured.
+    pub fn from_toml(
+        policy: &str,
+        authenticators: &[AuthenticatorConfig],
+    ) -> std::io::Result<AccessPolicy> {
+        let policy: AccessPolicyFile = toml::from_str(policy).map_err(|e| {
+            error!("Failed to parse the access policy: {}.", e);
+            Error::new(ErrorKind::InvalidData, "access policy can not be parsed")
+        })?;
+
+        let admin_commands = policy.admin_commands.unwrap_or_default();
+        if let Some(command) = admin_commands
+            .iter()
+            .find(|command| !ADMIN_COMMANDS.contains(&command.as_str()))
+        {
+            return Err(invalid_policy(format!(
+                "the unknown admin command \"{}\" is allowed",
+                command
+            )));
+        }
+
+        let mut roles = HashMap::new();
+        for role in policy.role.unwrap_or_default() {
+            let RoleConfig {
+                name,
+                opcodes,
+                providers,
+                key_name_prefixes,
+            } = role;
+            let opcodes = opcodes
+                .map(|opcodes| {
+                    opcodes
+                        .iter()
+                        .map(|opcode| {
+                            opcode_from_name(opcode).ok_or_else(|| {
+                                invalid_policy(format!(
+                                    "role \"{}\" allows the unknown opcode \"{}\"",
+                                    name, opcode
+                                ))
+                            })
+                        })
+                        .collect::<std::io::Result<HashSet<Opcode>>>()
+                })
+                .transpose()?;
+            let providers = providers
+                .map(|providers| {
+                    providers
+                        .iter()
+                        .map(|provider| {
+                            provider_id_from_name(provider).ok_or_else(|| {
+                                invalid_policy(format!(
+                                    "role \"{}\" allows the unknown provider \"{}\"",
+                                    name, provider
+                                ))
+                            })
+                        })
+                        .collect::<std::io::Result<HashSet<ProviderId>>>()
+                })
+                .transpose()?;
+
+            if roles
+                .insert(
+                    name.clone(),
+                    Role {
+                        opcodes,
+                        providers,
+                        key_name_prefixes,
+                    },
+                )
+                .is_some()
+            {
+                return Err(invalid_policy(format!(
+                    "role \"{}\" is defined twice",
+                    name
+                )));
+            }
+        }
+
+        let mut bindings = Vec::new();
+        for binding in policy.binding.unwrap_or_default() {
+            if let Some(role) = binding.roles.iter().find(|role| !roles.contains_key(*role)) {
+                return Err(invalid_policy(format!(
+                    "the role \"{}\" is bound but not defined",
+                    role
+                )));
+            }
+            let authenticator_types = binding
+                .authenticators
+                .map(|names| {
+                    names
+                        .iter()
+                        .map(|name| {
+                            authenticators
+                                .iter()
+                                .find(|authenticator| authenticator.name() == name.as_str())
+                                .map(AuthenticatorConfig::authenticator_type)
+                                .ok_or_else(|| {
+                                    invalid_policy(format!(
+                                        "the authenticator \"{}\" is selected but not configured",
+                                        name
+                                    ))
+                                })
+                        })
+                        .collect::<std::io::Result<Vec<AuthenticatorType>>>()
+                })
+                .transpose()?;
+            for pattern in binding.applications {
+                bindings.push(Binding {
+                    pattern,
+                    roles: binding.roles.clone(),
+                    authenticator_types: authenticator_types.clone(),
+                });
+            }
+        }
+
+        Ok(AccessPolicy {
+            admin_commands: admin_commands.into_iter().collect(),
+            roles,
+            bindings,
+        })
+    }
+
+    /// Check that the admin command is allowed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the policy does not list the command in its
+    /// `admin_commands`.
+    pub fn check_admin_command(&self, command: &str) -> parsec_interface::requests::Result<()> {
+        if self.admin_commands.contains(command) {
+            Ok(())
+        } else {
+            warn!(
+                "The access policy does not allow the admin command \"{}\".",
+                command
+            );
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        }
+    }
+
+    /// Check that the application is allowed to perform an operation on the provider, using the
+    /// key `key_name` if the operation uses a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if none of the roles bound to the application allows the
+    /// operation.
+    pub fn check(
+        &self,
+        app_name: &ApplicationName,
+        opcode: Opcode,
+        provider_id: ProviderId,
+        key_name: Option<&str>,
+    ) -> parsec_interface::requests::Result<()> {
+        let allowed = self
+            .bindings
+            .iter()
+            .filter(|binding| binding.binds(app_name))
+            .flat_map(|binding| binding.roles.iter())
+            .filter_map(|role| self.roles.get(role))
+            .any(|role| role.allows(opcode, provider_id, key_name));
+
+        if allowed {
+            Ok(())
+        } else {
+            warn!(
+                "The access policy does not allow application \"{}\" to perform {:?} on the {} \
+                provider.",
+                app_name, opcode, provider_id
+            );
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        }
+    }
+}
+
+/// Logs the reason why the access policy is invalid and returns the corresponding error.
+fn invalid_policy(reason: String) -> Error {
+    error!("Invalid access policy: {}.", reason);
+    Error::new(ErrorKind::InvalidData, "invalid access policy")
+}
+
+/// Checks if the name matches the pattern, where `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There is always a first part, possibly empty.
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No wildcard in the pattern
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches_pattern, AccessPolicy};
+    use crate::authenticators::{ApplicationName, AuthenticatorType};
+    use crate::utils::config::AuthenticatorConfig;
+    use parsec_interface::requests::{AuthType, Opcode, ProviderId, ResponseStatus};
+
+    const POLICY: &str = r#"
+        [[role]]
+        name = "discovery"
+        opcodes = ["Ping", "ListProviders", "ListOpcodes", "ListAuthenticators"]
+        providers = ["Core"]
+
+        [[role]]
+        name = "tpm-signer"
+        opcodes = ["PsaSignHash"]
+        providers = ["Tpm"]
+        key_name_prefixes = ["signing/"]
+
+        [[role]]
+        name = "all"
+
+        [[binding]]
+        applications = ["signer", "tenant-*"]
+        roles = ["discovery", "tpm-signer"]
+
+        [[binding]]
+        applications = ["admin"]
+        roles = ["all"]
+    "#;
+
+    fn app_name(name: &str) -> ApplicationName {
+        ApplicationName::new(name.to_string(), AuthType::UnixPeerCredentials)
+    }
+
+    fn authenticators() -> Vec<AuthenticatorConfig> {
+        vec![
+            toml::from_str("auth_type = 'UnixPeerCredentials'").unwrap(),
+            toml::from_str("auth_type = 'ClientCertificate'").unwrap(),
+        ]
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("app", "app"));
+        assert!(!matches_pattern("app", "app2"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("tenant-*", "tenant-1"));
+        assert!(!matches_pattern("tenant-*", "other-1"));
+        assert!(matches_pattern("*-signer", "ci-signer"));
+        assert!(matches_pattern("a*b*c", "a-b-b-c"));
+        assert!(!matches_pattern("a*b*c", "a-c-b"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn roles_restrict_operations() {
+        let policy = AccessPolicy::from_toml(POLICY, &authenticators()).unwrap();
+
+        for name in ["signer", "tenant-1"].iter() {
+            let app_name = app_name(name);
+            policy
+                .check(
+                    &app_name,
+                    Opcode::PsaSignHash,
+                    ProviderId::Tpm,
+                    Some("signing/key"),
+                )
+                .unwrap();
+            policy
+                .check(&app_name, Opcode::ListProviders, ProviderId::Core, None)
+                .unwrap();
+            for (opcode, provider_id, key_name) in [
+                (Opcode::PsaSignHash, ProviderId::MbedCrypto, "signing/key"),
+                (Opcode::PsaSignHash, ProviderId::Tpm, "other key"),
+                (Opcode::PsaExportKey, ProviderId::Tpm, "signing/key"),
+            ]
+            .iter()
+            {
+                assert_eq!(
+                    policy
+                        .check(&app_name, *opcode, *provider_id, Some(*key_name))
+                        .unwrap_err(),
+                    ResponseStatus::PsaErrorNotPermitted
+                );
+            }
+        }
+
+        policy
+            .check(
+                &app_name("admin"),
+                Opcode::DeleteClient,
+                ProviderId::Core,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            policy
+                .check(&app_name("unbound"), Opcode::Ping, ProviderId::Core, None)
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn bindings_select_authenticators() {
+        let policy = r#"
+            [[role]]
+            name = "all"
+
+            [[binding]]
+            applications = ["1000"]
+            roles = ["all"]
+            authenticators = ["UnixPeerCredentials"]
+        "#;
+        let policy = AccessPolicy::from_toml(policy, &authenticators()).unwrap();
+
+        policy
+            .check(&app_name("1000"), Opcode::Ping, ProviderId::Core, None)
+            .unwrap();
+        // An application of another authenticator with the same name is not bound to the role.
+        let client_certificate_app_name =
+            ApplicationName::new(String::from("1000"), AuthenticatorType::ClientCertificate);
+        assert_eq!(
+            policy
+                .check(
+                    &client_certificate_app_name,
+                    Opcode::Ping,
+                    ProviderId::Core,
+                    None
+                )
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn admin_commands_are_refused_by_default() {
+        let policy = AccessPolicy::from_toml(POLICY, &authenticators()).unwrap();
+        assert_eq!(
+            policy.check_admin_command("key-metadata").unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+
+        let policy = AccessPolicy::from_toml(
+            &format!("admin_commands = [\"key-metadata\"]\n{}", POLICY),
+            &authenticators(),
+        )
+        .unwrap();
+        policy.check_admin_command("key-metadata").unwrap();
+        assert_eq!(
+            policy.check_admin_command("transfer-client").unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn invalid_policies() {
+        let unknown_admin_command = r#"
+            admin_commands = ["delete-everything"]
+        "#;
+        let unknown_opcode = r#"
+            [[role]]
+            name = "role"
+            opcodes = ["PsaSignEverything"]
+        "#;
+        let unknown_provider = r#"
+            [[role]]
+            name = "role"
+            providers = ["Hsm"]
+        "#;
+        let undefined_role = r#"
+            [[binding]]
+            applications = ["app"]
+            roles = ["role"]
+        "#;
+        let role_defined_twice = r#"
+            [[role]]
+            name = "role"
+
+            [[role]]
+            name = "role"
+        "#;
+        let unconfigured_authenticator = r#"
+            [[role]]
+            name = "role"
+
+            [[binding]]
+            applications = ["app"]
+            roles = ["role"]
+            authenticators = ["JwtSvid"]
+        "#;
+        for policy in [
+            unknown_admin_command,
+            unknown_opcode,
+            unknown_provider,
+            undefined_role,
+            role_defined_twice,
+            unconfigured_authenticator,
+        ]
+        .iter()
+        {
+            let _ = AccessPolicy::from_toml(policy, &authenticators()).unwrap_err();
+        }
+    }
+}
@@ -5,7 +5,13 @@
 //! The backend handler embodies the last processing step from external request
 //! to internal function call - parsing of the request body and conversion to a
 //! native operation which is then passed to the provider.
-use crate::authenticators::Application;
+use super::access_policy::AccessPolicy;
+use super::crypto_policy::CryptoPolicy;
+use super::key_quota::{KeyQuota, KeyReservation};
+use crate::authenticators::{Application, ApplicationName, AuthenticatorType};
+use crate::key_info_managers::acl::KeyPermission;
+use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
+use crate::providers::opcode_filter::OpcodeFilter;
 use crate::providers::Provide;
 use derivative::Derivative;
 use log::{error, trace, warn};
@@ -14,7 +20,7 @@ use parsec_interface::operations::{NativeOperation, NativeResult};
 use parsec_interface::requests::{
     request::RequestHeader, Request, Response, ResponseStatus, Result,
 };
-use parsec_interface::requests::{BodyType, ProviderId};
+use parsec_interface::requests::{BodyType, Opcode, ProviderId};
 use std::io::{Error, ErrorKind};
 use std::sync::Arc;
 
@@ -36,6 +42,11 @@ pub struct BackEndHandler {
     provider_id: ProviderId,
     content_type: BodyType,
     accept_type: BodyType,
+    opcode_filter: OpcodeFilter,
+    key_info_manager_client: Option<KeyInfoManagerClient>,
+    key_quota: Option<KeyQuota>,
+    access_policy: Option<Arc<AccessPolicy>>,
+    crypto_policy: Option<Arc<CryptoPolicy>>,
 }
 
 impl BackEndHandler {
@@ -49,6 +60,68 @@ impl BackEndHandler {
         response
     }
 
+    /// Reserve the storage of a new key of the application in the provider, if its number of
+    /// keys is limited.
+    fn reserve_key(&self, app_name: &ApplicationName) -> Result<Option<KeyReservation<'_>>> {
+        self.key_quota
+            .as_ref()
+            .map(|key_quota| key_quota.reserve(app_name))
+            .transpose()
+    }
+
+    /// Returns the application on behalf of which the operation uses its key: the application
+    /// itself if it owns the key, or the owner of the key if it is shared with the application for
+    /// this operation. The key used must not be expired, but destroying an expired key is
+    /// permitted.
+    fn key_user(
+        &self,
+        app: Application,
+        opcode: Opcode,
+        operation: &NativeOperation,
+    ) -> Result<Application> {
+        let (key_info_manager_client, key_name) =
+            match (&self.key_info_manager_client, used_key_name(operation)) {
+                (Some(key_info_manager_client), Some(key_name)) => {
+                    (key_info_manager_client, key_name)
+                }
+                _ => return Ok(app),
+            };
+
+        let key_triple =
+            key_info_manager_client.get_key_triple(app.get_name().clone(), key_name.to_string());
+        match key_info_manager_client.does_not_exist(&key_triple) {
+            Err(ResponseStatus::PsaErrorAlreadyExists) => {
+                check_key_not_expired(key_info_manager_client, &key_triple, app.get_name())?;
+                return Ok(app);
+            }
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        }
+
+        let permission = match KeyPermission::required_by(opcode) {
+            Some(permission) => permission,
+            None => return Ok(app),
+        };
+        match key_info_manager_client.find_shared_key(app.get_name(), key_name, permission)? {
+            Some(shared_key_triple) => {
+                check_key_not_expired(key_info_manager_client, &shared_key_triple, app.get_name())?;
+                trace!(
+                    "Application \"{}\" uses the key \"{}\" shared by application \"{}\".",
+                    app.get_name(),
+                    key_name,
+                    shared_key_triple.app_name()
+                );
+                let owner = shared_key_triple.app_name();
+                Ok(Application::new(
+                    owner.to_string(),
+                    owner.authenticator_type(),
+                    false,
+                ))
+            }
+            None => Ok(app),
+        }
+    }
+
     /// Assess whether the backend handler-provider pair is capable of handling
     /// the request.
     ///
@@ -56,6 +129,8 @@ impl BackEndHandler {
     /// - if the provider ID can not perform the type of operation, returns
     /// `ResponseStatus::PsaErrorNotSupported`
     /// - if the provider ID does not match, returns `ResponseStatus::WrongProviderId`
+    /// - if the operation is turned off on the provider, returns
+    /// `ResponseStatus::PsaErrorNotSupported`
     /// - if the content type does not match, returns `ResponseStatus::ContentTypeNotSupported`
     /// - if the accept type does not match, returns `ResponseStatus::AcceptTypeNotSupported`
     pub fn is_capable(&self, request: &Request) -> Result<()> {
@@ -68,6 +143,8 @@ impl BackEndHandler {
 
         if header.provider != self.provider_id {
             Err(ResponseStatus::WrongProviderId)
+        } else if !self.opcode_filter.allows(header.opcode) {
+            Err(ResponseStatus::PsaErrorNotSupported)
         } else if header.content_type != self.content_type {
             Err(ResponseStatus::ContentTypeNotSupported)
         } else if header.accept_type != self.accept_type {
@@ -81,8 +158,14 @@ impl BackEndHandler {
     /// the result back.
     ///
     /// If any of the steps fails, a response containing an appropriate status code is
-    /// returned.
-    pub fn execute_request(&self, request: Request, app: Option<Application>) -> Response {
+    /// returned. The `authenticator_types` are the ones of the listener which received the
+    /// request.
+    pub fn execute_request(
+        &self,
+        request: Request,
+        app: Option<Application>,
+        authenticator_types: &[AuthenticatorType],
+    ) -> Response {
         trace!("execute_request ingress");
         let opcode = request.header.opcode;
         let header = request.header;
@@ -110,7 +193,27 @@ impl BackEndHandler {
             }
         }
 
-        match unwrap_or_else_return!(self.converter.body_to_operation(request.body, opcode)) {
+        let operation =
+            unwrap_or_else_return!(self.converter.body_to_operation(request.body, opcode));
+        if let (Some(access_policy), Some(app)) = (&self.access_policy, &app) {
+            unwrap_or_else_return!(access_policy.check(
+                app.get_name(),
+                opcode,
+                self.provider_id,
+                key_name(&operation)
+            ));
+        }
+        if let Some(crypto_policy) = &self.crypto_policy {
+            unwrap_or_else_return!(crypto_policy.check(&operation));
+        }
+        let app = match app {
+            Some(app) => Some(unwrap_or_else_return!(
+                self.key_user(app, opcode, &operation)
+            )),
+            None => None,
+        };
+
+        match operation {
             NativeOperation::ListProviders(op_list_providers) => {
                 let result =
                     unwrap_or_else_return!(self.provider.list_providers(op_list_providers));
@@ -129,6 +232,7 @@ impl BackEndHandler {
             }
             NativeOperation::PsaGenerateKey(op_generate_key) => {
                 let app = unwrap_or_else_return!(app.ok_or(ResponseStatus::NotAuthenticated));
+                let _reservation = unwrap_or_else_return!(self.reserve_key(app.get_name()));
                 let result = unwrap_or_else_return!(self
                     .provider
                     .psa_generate_key(app.into(), op_generate_key));
@@ -137,6 +241,7 @@ impl BackEndHandler {
             }
             NativeOperation::PsaImportKey(op_import_key) => {
                 let app = unwrap_or_else_return!(app.ok_or(ResponseStatus::NotAuthenticated));
+                let _reservation = unwrap_or_else_return!(self.reserve_key(app.get_name()));
                 let result =
                     unwrap_or_else_return!(self.provider.psa_import_key(app.into(), op_import_key));
                 trace!("psa_import_key egress");
@@ -215,7 +320,7 @@ impl BackEndHandler {
             NativeOperation::ListAuthenticators(op_list_authenticators) => {
                 let result = unwrap_or_else_return!(self
                     .provider
-                    .list_authenticators(op_list_authenticators));
+                    .list_authenticators(authenticator_types, op_list_authenticators));
                 trace!("list_authenticators egress");
                 self.result_to_response(NativeResult::ListAuthenticators(result), header)
             }
@@ -232,7 +337,10 @@ impl BackEndHandler {
                 self.result_to_response(NativeResult::ListClients(result), header)
             }
             NativeOperation::DeleteClient(op_delete_client) => {
-                let result = unwrap_or_else_return!(self.provider.delete_client(op_delete_client));
+                let app = unwrap_or_else_return!(app.ok_or(ResponseStatus::NotAuthenticated));
+                let result = unwrap_or_else_return!(self
+                    .provider
+                    .delete_client(app.into(), op_delete_client));
                 trace!("delete_client egress");
                 self.result_to_response(NativeResult::DeleteClient(result), header)
             }
@@ -293,6 +401,11 @@ pub struct BackEndHandlerBuilder {
     provider_id: Option<ProviderId>,
     content_type: Option<BodyType>,
     accept_type: Option<BodyType>,
+    opcode_filter: Option<OpcodeFilter>,
+    key_info_manager_client: Option<KeyInfoManagerClient>,
+    key_quota: Option<KeyQuota>,
+    access_policy: Option<Arc<AccessPolicy>>,
+    crypto_policy: Option<Arc<CryptoPolicy>>,
 }
 
 impl BackEndHandlerBuilder {
@@ -304,6 +417,11 @@ impl BackEndHandlerBuilder {
             provider_id: None,
             content_type: None,
             accept_type: None,
+            opcode_filter: None,
+            key_info_manager_client: None,
+            key_quota: None,
+            access_policy: None,
+            crypto_policy: None,
         }
     }
 
@@ -337,6 +455,41 @@ impl BackEndHandlerBuilder {
         self
     }
 
+    /// Restrict the operations of the provider to the ones allowed by the filter
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> Self {
+        self.opcode_filter = Some(opcode_filter);
+        self
+    }
+
+    /// Add the Key Info Manager client of the provider, used to check the keys before the
+    /// operations reach the provider
+    pub fn with_key_info_manager_client(
+        mut self,
+        key_info_manager_client: KeyInfoManagerClient,
+    ) -> Self {
+        self.key_info_manager_client = Some(key_info_manager_client);
+        self
+    }
+
+    /// Limit the number of keys stored in the provider
+    pub fn with_key_quota(mut self, key_quota: KeyQuota) -> Self {
+        self.key_quota = Some(key_quota);
+        self
+    }
+
+    /// Restrict the operations of the applications to the ones allowed by the access policy
+    pub fn with_access_policy(mut self, access_policy: Arc<AccessPolicy>) -> Self {
+        self.access_policy = Some(access_policy);
+        self
+    }
+
+    /// Restrict the keys and algorithms used by the operations to the ones allowed by the
+    /// cryptographic policy
+    pub fn with_crypto_policy(mut self, crypto_policy: Arc<CryptoPolicy>) -> Self {
+        self.crypto_policy = Some(crypto_policy);
+        self
+    }
+
     /// Build into a BackEndHandler
     pub fn build(self) -> std::io::Result<BackEndHandler> {
         Ok(BackEndHandler {
@@ -355,6 +508,308 @@ impl BackEndHandlerBuilder {
             accept_type: self
                 .accept_type
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "accept_type is missing"))?,
+            opcode_filter: self.opcode_filter.unwrap_or_default(),
+            key_info_manager_client: self.key_info_manager_client,
+            key_quota: self.key_quota,
+            access_policy: self.access_policy,
+            crypto_policy: self.crypto_policy,
         })
     }
 }
+
+/// Checks that the key represented by a key triple, used by the application `app_name`, is not
+/// expired.
+fn check_key_not_expired(
+    key_info_manager_client: &KeyInfoManagerClient,
+    key_triple: &KeyTriple,
+    app_name: &ApplicationName,
+) -> Result<()> {
+    key_info_manager_client
+        .check_not_expired(key_triple)
+        .map_err(|status| {
+            if status == ResponseStatus::PsaErrorNotPermitted {
+                warn!(
+                    "Application \"{}\" tried to use the expired key \"{}\".",
+                    app_name,
+                    key_triple.key_name()
+                );
+            }
+            status
+        })
+}
+
+/// Returns the name of the key used by an operation, if any. The key destroyed by a
+/// PsaDestroyKey operation and the keys created by PsaGenerateKey and PsaImportKey are not
+/// considered as used: the key must exist to be used.
+fn used_key_name(operation: &NativeOperation) -> Option<&str> {
+    match operation {
+        NativeOperation::PsaDestroyKey(_)
+        | NativeOperation::PsaGenerateKey(_)
+        | NativeOperation::PsaImportKey(_) => None,
+        _ => key_name(operation),
+    }
+}
+
+/// Returns the name of the key an operation is about, if any: the key used, created or destroyed.
+fn key_name(operation: &NativeOperation) -> Option<&str> {
+    match operation {
+        NativeOperation::PsaGenerateKey(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaImportKey(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaDestroyKey(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaExportPublicKey(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaExportKey(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaSignHash(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaVerifyHash(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaAsymmetricEncrypt(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaAsymmetricDecrypt(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaAeadEncrypt(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaAeadDecrypt(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaRawKeyAgreement(op) => Some(op.private_key_name.as_str()),
+        NativeOperation::PsaSignMessage(op) => Some(op.key_name.as_str()),
+        NativeOperation::PsaVerifyMessage(op) => Some(op.key_name.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BackEndHandler, BackEndHandlerBuilder};
+    use crate::authenticators::{Application, ApplicationName};
+    use crate::key_info_managers::expiry::KeyLifetimes;
+    use crate::key_info_managers::{KeyInfoManagerClient, KeyInfoManagerFactory};
+    use crate::providers::Provide;
+    use crate::utils::config::{KeyInfoManagerConfig, KeyInfoManagerType};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, EccFamily, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::operations::{
+        list_clients, list_keys, list_providers, psa_destroy_key, psa_generate_key, psa_sign_hash,
+        Convert, NativeOperation,
+    };
+    use parsec_interface::operations_protobuf::ProtobufConverter;
+    use parsec_interface::requests::{
+        AuthType, BodyType, Opcode, ProviderId, Request, ResponseStatus, Result,
+    };
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    /// Provider keeping its keys in the Key Info Manager only
+    struct KeyInfoProvider {
+        client: KeyInfoManagerClient,
+    }
+
+    impl Provide for KeyInfoProvider {
+        fn describe(&self) -> Result<(list_providers::ProviderInfo, HashSet<Opcode>)> {
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+
+        fn list_keys(
+            &self,
+            app_name: ApplicationName,
+            _op: list_keys::Operation,
+        ) -> Result<list_keys::Result> {
+            Ok(list_keys::Result {
+                keys: self.client.list_keys(&app_name)?,
+            })
+        }
+
+        fn list_clients(&self, _op: list_clients::Operation) -> Result<list_clients::Result> {
+            Ok(list_clients::Result {
+                clients: self
+                    .client
+                    .list_clients()?
+                    .iter()
+                    .map(ApplicationName::to_string)
+                    .collect(),
+            })
+        }
+
+        fn psa_generate_key(
+            &self,
+            app_name: ApplicationName,
+            op: psa_generate_key::Operation,
+        ) -> Result<psa_generate_key::Result> {
+            let key_triple = self.client.get_key_triple(app_name, op.key_name);
+            self.client.does_not_exist(&key_triple)?;
+            self.client
+                .insert_key_info(key_triple, &0_u32, op.attributes)?;
+            Ok(psa_generate_key::Result {})
+        }
+
+        fn psa_destroy_key(
+            &self,
+            app_name: ApplicationName,
+            op: psa_destroy_key::Operation,
+        ) -> Result<psa_destroy_key::Result> {
+            let key_triple = self.client.get_key_triple(app_name, op.key_name);
+            self.client.remove_key_info(&key_triple)?;
+            Ok(psa_destroy_key::Result {})
+        }
+
+        fn psa_sign_hash(
+            &self,
+            app_name: ApplicationName,
+            op: psa_sign_hash::Operation,
+        ) -> Result<psa_sign_hash::Result> {
+            let key_triple = self.client.get_key_triple(app_name, op.key_name);
+            let _: u32 = self.client.get_key_id(&key_triple)?;
+            Ok(psa_sign_hash::Result {
+                signature: op.hash.to_vec().into(),
+            })
+        }
+    }
+
+    /// Returns a backend handler of a provider keeping its keys in a volatile Key Info Manager,
+    /// giving the keys the lifetimes given.
+    fn backend_handler(key_lifetimes: KeyLifetimes) -> BackEndHandler {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile-manager"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        let mut factory = KeyInfoManagerFactory::new(&config, None).unwrap();
+        factory.set_key_lifetimes(key_lifetimes);
+        let provider = KeyInfoProvider {
+            client: factory.build_client(ProviderId::MbedCrypto),
+        };
+
+        BackEndHandlerBuilder::new()
+            .with_provider(Arc::new(provider))
+            .with_converter(Box::from(ProtobufConverter {}))
+            .with_provider_id(ProviderId::MbedCrypto)
+            .with_content_type(BodyType::Protobuf)
+            .with_accept_type(BodyType::Protobuf)
+            .with_key_info_manager_client(factory.build_client(ProviderId::MbedCrypto))
+            .build()
+            .unwrap()
+    }
+
+    /// Executes an operation for the application and returns the status of the response.
+    fn execute(
+        backend_handler: &BackEndHandler,
+        app: &Application,
+        opcode: Opcode,
+        operation: NativeOperation,
+    ) -> ResponseStatus {
+        let mut request = Request::new();
+        request.header.provider = ProviderId::MbedCrypto;
+        request.header.opcode = opcode;
+        request.header.content_type = BodyType::Protobuf;
+        request.header.accept_type = BodyType::Protobuf;
+        request.header.auth_type = AuthType::Direct;
+        request.body = ProtobufConverter {}.operation_to_body(operation).unwrap();
+
+        backend_handler
+            .execute_request(request, Some(app.clone()), &[AuthType::Direct.into()])
+            .header
+            .status
+    }
+
+    fn generate_key(key_name: &str) -> NativeOperation {
+        NativeOperation::PsaGenerateKey(psa_generate_key::Operation {
+            key_name: String::from(key_name),
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::EccKeyPair {
+                    curve_family: EccFamily::SecpR1,
+                },
+                bits: 256,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: true,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::Ecdsa {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+        })
+    }
+
+    fn sign_hash(key_name: &str) -> NativeOperation {
+        NativeOperation::PsaSignHash(psa_sign_hash::Operation {
+            key_name: String::from(key_name),
+            alg: AsymmetricSignature::Ecdsa {
+                hash_alg: SignHash::Specific(Hash::Sha256),
+            },
+            hash: vec![0xAB; 32].into(),
+        })
+    }
+
+    fn destroy_key(key_name: &str) -> NativeOperation {
+        NativeOperation::PsaDestroyKey(psa_destroy_key::Operation {
+            key_name: String::from(key_name),
+        })
+    }
+
+    #[test]
+    fn expired_key_is_not_created_again() {
+        // The keys expire as soon as they are created.
+        let backend_handler = backend_handler(KeyLifetimes::new(Some(0), HashMap::new()));
+        let app = Application::new(String::from("app"), AuthType::Direct, false);
+
+        assert_eq!(
+            execute(
+                &backend_handler,
+                &app,
+                Opcode::PsaGenerateKey,
+                generate_key("key")
+            ),
+            ResponseStatus::Success
+        );
+        assert_eq!(
+            execute(
+                &backend_handler,
+                &app,
+                Opcode::PsaSignHash,
+                sign_hash("key")
+            ),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        // The expiry is only checked for the keys used, the key exists.
+        assert_eq!(
+            execute(
+                &backend_handler,
+                &app,
+                Opcode::PsaGenerateKey,
+                generate_key("key")
+            ),
+            ResponseStatus::PsaErrorAlreadyExists
+        );
+        assert_eq!(
+            execute(
+                &backend_handler,
+                &app,
+                Opcode::PsaDestroyKey,
+                destroy_key("key")
+            ),
+            ResponseStatus::Success
+        );
+        assert_eq!(
+            execute(
+                &backend_handler,
+                &app,
+                Opcode::PsaGenerateKey,
+                generate_key("key")
+            ),
+            ResponseStatus::Success
+        );
+    }
+}
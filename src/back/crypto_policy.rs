@@ -0,0 +1,432 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Cryptographic policy of the service
+//!
+//! The cryptographic policy enforces the cryptographic profile of the organisation running the
+//! service, whichever provider the requests are sent to: forbidden hash and asymmetric algorithms,
+//! minimum sizes of the RSA and ECC keys and allowed elliptic curve families. The attributes of the
+//! keys generated or imported, including their permitted algorithm, and the algorithm of each
+//! operation are checked before the operation reaches the provider. Violations are refused with
+//! `PsaErrorNotPermitted`.
+//!
+//! The size of the keys imported must be given in their attributes when a minimum size applies to
+//! them, it is not deduced from the key data.
+use crate::utils::config::CryptoPolicyConfig;
+use log::{error, warn};
+use parsec_interface::operations::psa_algorithm::{
+    Algorithm, AsymmetricEncryption, AsymmetricSignature, Hash, KeyAgreement, RawKeyAgreement,
+    SignHash,
+};
+use parsec_interface::operations::psa_key_attributes::{Attributes, EccFamily, Type};
+use parsec_interface::operations::NativeOperation;
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::io::{Error, ErrorKind};
+
+/// Hash algorithms, to find them by name
+#[allow(deprecated)]
+const HASHES: [Hash; 15] = [
+    Hash::Md2,
+    Hash::Md4,
+    Hash::Md5,
+    Hash::Ripemd160,
+    Hash::Sha1,
+    Hash::Sha224,
+    Hash::Sha256,
+    Hash::Sha384,
+    Hash::Sha512,
+    Hash::Sha512_224,
+    Hash::Sha512_256,
+    Hash::Sha3_224,
+    Hash::Sha3_256,
+    Hash::Sha3_384,
+    Hash::Sha3_512,
+];
+
+/// Elliptic curve families, to find them by name
+#[allow(deprecated)]
+const ECC_FAMILIES: [EccFamily; 9] = [
+    EccFamily::SecpK1,
+    EccFamily::SecpR1,
+    EccFamily::SecpR2,
+    EccFamily::SectK1,
+    EccFamily::SectR1,
+    EccFamily::SectR2,
+    EccFamily::BrainpoolPR1,
+    EccFamily::Frp,
+    EccFamily::Montgomery,
+];
+
+/// Names of the algorithms which can be forbidden
+const ALGORITHMS: [&str; 10] = [
+    "RsaPkcs1v15Sign",
+    "RsaPkcs1v15SignRaw",
+    "RsaPss",
+    "Ecdsa",
+    "EcdsaAny",
+    "DeterministicEcdsa",
+    "RsaPkcs1v15Crypt",
+    "RsaOaep",
+    "Ecdh",
+    "Ffdh",
+];
+
+/// Cryptographic policy of the service
+#[derive(Debug)]
+pub struct CryptoPolicy {
+    forbidden_hashes: Vec<Hash>,
+    forbidden_algorithms: Vec<&'static str>,
+    min_rsa_key_bits: Option<usize>,
+    allowed_ecc_families: Option<Vec<EccFamily>>,
+    min_ecc_key_bits: Option<usize>,
+}
+
+impl CryptoPolicy {
+    /// Create the cryptographic policy from its configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the configuration names an unknown hash,
+    /// algorithm or elliptic curve family.
+    pub fn new(config: &CryptoPolicyConfig) -> std::io::Result<CryptoPolicy> {
+        let forbidden_hashes = config
+            .forbidden_hashes
+            .iter()
+            .flatten()
+            .map(|name| {
+                HASHES
+                    .iter()
+                    .copied()
+                    .find(|hash| format!("{:?}", hash) == *name)
+                    .ok_or_else(|| unknown_name("hash", name))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let forbidden_algorithms = config
+            .forbidden_algorithms
+            .iter()
+            .flatten()
+            .map(|name| {
+                ALGORITHMS
+                    .iter()
+                    .copied()
+                    .find(|algorithm| *algorithm == name.as_str())
+                    .ok_or_else(|| unknown_name("algorithm", name))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let allowed_ecc_families = config
+            .allowed_ecc_families
+            .as_ref()
+            .map(|families| {
+                families
+                    .iter()
+                    .map(|name| {
+                        ECC_FAMILIES
+                            .iter()
+                            .copied()
+                            .find(|family| format!("{:?}", family) == *name)
+                            .ok_or_else(|| unknown_name("elliptic curve family", name))
+                    })
+                    .collect::<std::io::Result<_>>()
+            })
+            .transpose()?;
+
+        Ok(CryptoPolicy {
+            forbidden_hashes,
+            forbidden_algorithms,
+            min_rsa_key_bits: config.min_rsa_key_bits,
+            allowed_ecc_families,
+            min_ecc_key_bits: config.min_ecc_key_bits,
+        })
+    }
+
+    /// Check that the policy allows the operation: the attributes of the key it creates, or the
+    /// algorithm it uses.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the policy does not allow the operation.
+    pub fn check(&self, operation: &NativeOperation) -> Result<()> {
+        let checked = match operation {
+            NativeOperation::PsaGenerateKey(op) => self.check_attributes(&op.attributes),
+            NativeOperation::PsaImportKey(op) => self.check_attributes(&op.attributes),
+            NativeOperation::PsaSignHash(op) => {
+                self.check_algorithm(Algorithm::AsymmetricSignature(op.alg))
+            }
+            NativeOperation::PsaVerifyHash(op) => {
+                self.check_algorithm(Algorithm::AsymmetricSignature(op.alg))
+            }
+            NativeOperation::PsaSignMessage(op) => {
+                self.check_algorithm(Algorithm::AsymmetricSignature(op.alg))
+            }
+            NativeOperation::PsaVerifyMessage(op) => {
+                self.check_algorithm(Algorithm::AsymmetricSignature(op.alg))
+            }
+            NativeOperation::PsaAsymmetricEncrypt(op) => {
+                self.check_algorithm(Algorithm::AsymmetricEncryption(op.alg))
+            }
+            NativeOperation::PsaAsymmetricDecrypt(op) => {
+                self.check_algorithm(Algorithm::AsymmetricEncryption(op.alg))
+            }
+            NativeOperation::PsaRawKeyAgreement(op) => {
+                self.check_algorithm(Algorithm::KeyAgreement(KeyAgreement::Raw(op.alg)))
+            }
+            NativeOperation::PsaHashCompute(op) => self.check_algorithm(Algorithm::Hash(op.alg)),
+            NativeOperation::PsaHashCompare(op) => self.check_algorithm(Algorithm::Hash(op.alg)),
+            _ => Ok(()),
+        };
+
+        checked.map_err(|violation| {
+            warn!("The cryptographic policy does not allow {}.", violation);
+            ResponseStatus::PsaErrorNotPermitted
+        })
+    }
+
+    /// Check the attributes of a key created. Returns the violation of the policy, if any.
+    fn check_attributes(&self, attributes: &Attributes) -> std::result::Result<(), String> {
+        match attributes.key_type {
+            Type::RsaKeyPair | Type::RsaPublicKey => {
+                check_key_bits("RSA", attributes.bits, self.min_rsa_key_bits)?
+            }
+            Type::EccKeyPair { curve_family } | Type::EccPublicKey { curve_family } => {
+                if let Some(allowed_ecc_families) = &self.allowed_ecc_families {
+                    if !allowed_ecc_families.contains(&curve_family) {
+                        return Err(format!("ECC keys of the {:?} family", curve_family));
+                    }
+                }
+                check_key_bits("ECC", attributes.bits, self.min_ecc_key_bits)?
+            }
+            _ => (),
+        }
+
+        self.check_algorithm(attributes.policy.permitted_algorithms)
+    }
+
+    /// Check an algorithm, and the hash algorithm within it. Returns the violation of the
+    /// policy, if any.
+    fn check_algorithm(&self, algorithm: Algorithm) -> std::result::Result<(), String> {
+        let (name, hash) = match algorithm {
+            Algorithm::Hash(hash) => (None, Some(hash)),
+            Algorithm::AsymmetricSignature(alg) => (Some(signature_name(alg)), signature_hash(alg)),
+            Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaPkcs1v15Crypt) => {
+                (Some("RsaPkcs1v15Crypt"), None)
+            }
+            Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaOaep { hash_alg }) => {
+                (Some("RsaOaep"), Some(hash_alg))
+            }
+            Algorithm::KeyAgreement(KeyAgreement::Raw(alg))
+            | Algorithm::KeyAgreement(KeyAgreement::WithKeyDerivation { ka_alg: alg, .. }) => {
+                (Some(key_agreement_name(alg)), None)
+            }
+            _ => (None, None),
+        };
+
+        if let Some(name) = name {
+            if self.forbidden_algorithms.contains(&name) {
+                return Err(format!("the {} algorithm", name));
+            }
+        }
+        if let Some(hash) = hash {
+            if self.forbidden_hashes.contains(&hash) {
+                return Err(format!("the {:?} hash algorithm", hash));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the size of a key against the minimum size of its type. Returns the violation of the
+/// policy, if any.
+fn check_key_bits(
+    key_type: &str,
+    bits: usize,
+    min_bits: Option<usize>,
+) -> std::result::Result<(), String> {
+    match min_bits {
+        Some(min_bits) if bits == 0 => Err(format!(
+            "{} keys of unspecified size, as they must have at least {} bits",
+            key_type, min_bits
+        )),
+        Some(min_bits) if bits < min_bits => Err(format!(
+            "{}-bit {} keys, as they must have at least {} bits",
+            bits, key_type, min_bits
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the name of an asymmetric signature algorithm.
+fn signature_name(alg: AsymmetricSignature) -> &'static str {
+    match alg {
+        AsymmetricSignature::RsaPkcs1v15Sign { .. } => "RsaPkcs1v15Sign",
+        AsymmetricSignature::RsaPkcs1v15SignRaw => "RsaPkcs1v15SignRaw",
+        AsymmetricSignature::RsaPss { .. } => "RsaPss",
+        AsymmetricSignature::Ecdsa { .. } => "Ecdsa",
+        AsymmetricSignature::EcdsaAny => "EcdsaAny",
+        AsymmetricSignature::DeterministicEcdsa { .. } => "DeterministicEcdsa",
+    }
+}
+
+/// Returns the hash algorithm of an asymmetric signature algorithm, if it is specified.
+fn signature_hash(alg: AsymmetricSignature) -> Option<Hash> {
+    match alg {
+        AsymmetricSignature::RsaPkcs1v15Sign { hash_alg }
+        | AsymmetricSignature::RsaPss { hash_alg }
+        | AsymmetricSignature::Ecdsa { hash_alg }
+        | AsymmetricSignature::DeterministicEcdsa { hash_alg } => match hash_alg {
+            SignHash::Specific(hash) => Some(hash),
+            SignHash::Any => None,
+        },
+        AsymmetricSignature::RsaPkcs1v15SignRaw | AsymmetricSignature::EcdsaAny => None,
+    }
+}
+
+/// Returns the name of a key agreement algorithm.
+fn key_agreement_name(alg: RawKeyAgreement) -> &'static str {
+    match alg {
+        RawKeyAgreement::Ecdh => "Ecdh",
+        RawKeyAgreement::Ffdh => "Ffdh",
+    }
+}
+
+/// Logs the unknown name in the configuration and returns the corresponding error.
+fn unknown_name(kind: &str, name: &str) -> Error {
+    error!(
+        "The cryptographic policy names the unknown {} \"{}\".",
+        kind, name
+    );
+    Error::new(ErrorKind::InvalidData, "invalid cryptographic policy")
+}
+
+#[cfg(test)]
+mod test {
+    use super::CryptoPolicy;
+    use crate::utils::config::CryptoPolicyConfig;
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricEncryption, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, EccFamily, Lifetime, Policy, Type, UsageFlags,
+    };
+
+    fn policy() -> CryptoPolicy {
+        CryptoPolicy::new(&CryptoPolicyConfig {
+            forbidden_hashes: Some(vec![String::from("Sha1")]),
+            forbidden_algorithms: Some(vec![String::from("RsaPkcs1v15Crypt")]),
+            min_rsa_key_bits: Some(2048),
+            allowed_ecc_families: Some(vec![String::from("SecpR1")]),
+            min_ecc_key_bits: Some(256),
+        })
+        .unwrap()
+    }
+
+    fn attributes(key_type: Type, bits: usize, permitted_algorithms: Algorithm) -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type,
+            bits,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: true,
+                    verify_hash: true,
+                    sign_message: true,
+                    verify_message: true,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms,
+            },
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn algorithms() {
+        let policy = policy();
+        let rsa_sign = |hash| {
+            Algorithm::AsymmetricSignature(AsymmetricSignature::RsaPkcs1v15Sign {
+                hash_alg: SignHash::Specific(hash),
+            })
+        };
+
+        policy.check_algorithm(rsa_sign(Hash::Sha256)).unwrap();
+        let _ = policy.check_algorithm(rsa_sign(Hash::Sha1)).unwrap_err();
+        let _ = policy
+            .check_algorithm(Algorithm::AsymmetricEncryption(
+                AsymmetricEncryption::RsaOaep {
+                    hash_alg: Hash::Sha1,
+                },
+            ))
+            .unwrap_err();
+        let _ = policy
+            .check_algorithm(Algorithm::AsymmetricEncryption(
+                AsymmetricEncryption::RsaPkcs1v15Crypt,
+            ))
+            .unwrap_err();
+        let _ = policy
+            .check_algorithm(Algorithm::Hash(Hash::Sha1))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn key_attributes() {
+        let policy = policy();
+        let rsa_sign = Algorithm::AsymmetricSignature(AsymmetricSignature::RsaPss {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        });
+        let ecdsa = Algorithm::AsymmetricSignature(AsymmetricSignature::Ecdsa {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        });
+
+        policy
+            .check_attributes(&attributes(Type::RsaKeyPair, 2048, rsa_sign))
+            .unwrap();
+        let _ = policy
+            .check_attributes(&attributes(Type::RsaKeyPair, 1024, rsa_sign))
+            .unwrap_err();
+        let _ = policy
+            .check_attributes(&attributes(Type::RsaPublicKey, 0, rsa_sign))
+            .unwrap_err();
+        let _ = policy
+            .check_attributes(&attributes(
+                Type::RsaKeyPair,
+                2048,
+                Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaPkcs1v15Crypt),
+            ))
+            .unwrap_err();
+
+        let secp_r1 = Type::EccKeyPair {
+            curve_family: EccFamily::SecpR1,
+        };
+        policy
+            .check_attributes(&attributes(secp_r1, 256, ecdsa))
+            .unwrap();
+        let _ = policy
+            .check_attributes(&attributes(secp_r1, 192, ecdsa))
+            .unwrap_err();
+        let _ = policy
+            .check_attributes(&attributes(
+                Type::EccPublicKey {
+                    curve_family: EccFamily::SecpK1,
+                },
+                256,
+                ecdsa,
+            ))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn unknown_names() {
+        let config = CryptoPolicyConfig {
+            forbidden_hashes: Some(vec![String::from("SHA-1")]),
+            forbidden_algorithms: None,
+            min_rsa_key_bits: None,
+            allowed_ecc_families: None,
+            min_ecc_key_bits: None,
+        };
+        let _ = CryptoPolicy::new(&config).unwrap_err();
+    }
+}
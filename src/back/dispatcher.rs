@@ -5,7 +5,8 @@
 //! The dispatcher's role is to direct requests to the provider they specify, if
 //! said provider is available on the system, thus acting as a multiplexer.
 use super::backend_handler::BackEndHandler;
-use crate::authenticators::Application;
+use super::key_reaper::KeyReaper;
+use crate::authenticators::{Application, AuthenticatorType};
 use log::trace;
 use parsec_interface::requests::request::Request;
 use parsec_interface::requests::ProviderId;
@@ -23,6 +24,8 @@ use std::io::{Error, ErrorKind, Result};
 #[derive(Debug)]
 pub struct Dispatcher {
     backends: HashMap<ProviderId, BackEndHandler>,
+    // Destroys the expired keys of the backends while the dispatcher exists.
+    _key_reaper: Option<KeyReaper>,
 }
 
 impl Dispatcher {
@@ -32,14 +35,21 @@ impl Dispatcher {
     /// Returns either the response coming from the backend handler, or a response
     /// containing a status code consistent with the error encountered during
     /// processing.
-    pub fn dispatch_request(&self, request: Request, app: Option<Application>) -> Response {
+    ///
+    /// The `authenticator_types` are the ones of the listener which received the request.
+    pub fn dispatch_request(
+        &self,
+        request: Request,
+        app: Option<Application>,
+        authenticator_types: &[AuthenticatorType],
+    ) -> Response {
         trace!("dispatch_request ingress");
         if let Some(backend) = self.backends.get(&request.header.provider) {
             if let Err(status) = backend.is_capable(&request) {
                 Response::from_request_header(request.header, status)
             } else {
                 {
-                    let response = backend.execute_request(request, app);
+                    let response = backend.execute_request(request, app, authenticator_types);
                     trace!("execute_request egress");
                     response
                 }
@@ -54,12 +64,16 @@ impl Dispatcher {
 #[derive(Debug, Default)]
 pub struct DispatcherBuilder {
     backends: Option<HashMap<ProviderId, BackEndHandler>>,
+    key_reaper: Option<KeyReaper>,
 }
 
 impl DispatcherBuilder {
     /// Create a new Dispatcher builder
     pub fn new() -> Self {
-        DispatcherBuilder { backends: None }
+        DispatcherBuilder {
+            backends: None,
+            key_reaper: None,
+        }
     }
 
     /// Add a BackEndHandler with a specific Provider ID to the dispatcher
@@ -84,12 +98,20 @@ impl DispatcherBuilder {
         self
     }
 
+    /// Add the reaper destroying the expired keys of the backends
+    pub fn with_key_reaper(mut self, key_reaper: KeyReaper) -> Self {
+        self.key_reaper = Some(key_reaper);
+
+        self
+    }
+
     /// Build the builder into a dispatcher
     pub fn build(self) -> Result<Dispatcher> {
         Ok(Dispatcher {
             backends: self
                 .backends
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "backends is missing"))?,
+            _key_reaper: self.key_reaper,
         })
     }
 }
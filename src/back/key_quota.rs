@@ -0,0 +1,276 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Limits on the number of keys stored in a provider
+//!
+//! The storage of keys in some providers is scarce (PKCS 11 tokens, ATECC slots, key IDs of the
+//! Mbed Crypto provider) and shared by all the clients. The key quota of a provider limits the
+//! number of keys each application can have in it, and the number of keys all the applications
+//! together can have in it. Some applications can be given their own limit, or none.
+//!
+//! The keys being created are counted with the keys already stored so that concurrent requests
+//! of an application can not go over its limit. They are counted before the stored keys are, so
+//! that the stored keys are not counted while holding a lock.
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyInfoManagerClient;
+use crate::utils::config::KeyQuotaConfig;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Key quota of a provider
+#[derive(Debug)]
+pub struct KeyQuota {
+    key_info_manager_client: KeyInfoManagerClient,
+    max_keys_per_application: Option<usize>,
+    max_keys: Option<usize>,
+    // Limits of the applications having their own.
+    overrides: HashMap<ApplicationName, Option<usize>>,
+    // Number of keys being created by each application.
+    pending_keys: Mutex<HashMap<ApplicationName, usize>>,
+    // Number of keys being created by all the applications.
+    total_pending_keys: AtomicUsize,
+}
+
+/// Reservation of the storage of a key being created. It is released when dropped, once the key
+/// is stored or its creation failed.
+#[derive(Debug)]
+pub struct KeyReservation<'a> {
+    key_quota: &'a KeyQuota,
+    app_name: ApplicationName,
+}
+
+impl KeyQuota {
+    /// Create the key quota of the provider of the Key Info Manager client, `overrides` giving
+    /// the limits of the applications having their own. Returns `None` if the configuration does
+    /// not limit the number of keys of the provider.
+    pub fn new(
+        key_info_manager_client: KeyInfoManagerClient,
+        config: KeyQuotaConfig,
+        overrides: HashMap<ApplicationName, Option<usize>>,
+    ) -> Option<KeyQuota> {
+        if config.max_keys_per_application.is_none() && config.max_keys.is_none() {
+            return None;
+        }
+
+        Some(KeyQuota {
+            key_info_manager_client,
+            max_keys_per_application: config.max_keys_per_application,
+            max_keys: config.max_keys,
+            overrides,
+            pending_keys: Mutex::new(HashMap::new()),
+            total_pending_keys: AtomicUsize::new(0),
+        })
+    }
+
+    /// Reserve the storage of a new key of the application, for the duration of its creation.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientStorage` if the application, or all the applications
+    /// together, already have the maximum number of keys in the provider. Returns
+    /// `KeyInfoManagerError` if the keys could not be counted.
+    pub fn reserve(&self, app_name: &ApplicationName) -> Result<KeyReservation<'_>> {
+        let provider_id = self.key_info_manager_client.provider_id();
+
+        // The key is counted as pending first: the concurrent requests count it with the stored
+        // keys. If it goes over a limit, the reservation is released when dropped.
+        let app_pending_keys = {
+            let mut pending_keys = self
+                .pending_keys
+                .lock()
+                .expect("Pending keys lock poisoned");
+            let app_pending_keys = pending_keys.entry(app_name.clone()).or_default();
+            *app_pending_keys += 1;
+            *app_pending_keys - 1
+        };
+        let total_pending_keys = self.total_pending_keys.fetch_add(1, Ordering::SeqCst);
+        let reservation = KeyReservation {
+            key_quota: self,
+            app_name: app_name.clone(),
+        };
+
+        let max_keys_per_application = match self.overrides.get(app_name) {
+            Some(max_keys_per_application) => *max_keys_per_application,
+            None => self.max_keys_per_application,
+        };
+        if let Some(max_keys_per_application) = max_keys_per_application {
+            let keys = self.key_info_manager_client.count_keys(app_name)? + app_pending_keys;
+            if keys >= max_keys_per_application {
+                error!(
+                    "Application \"{}\" reached its quota of {} keys in the {} provider.",
+                    app_name, max_keys_per_application, provider_id
+                );
+                return Err(ResponseStatus::PsaErrorInsufficientStorage);
+            }
+        }
+
+        if let Some(max_keys) = self.max_keys {
+            let keys = self.key_info_manager_client.get_all()?.len() + total_pending_keys;
+            if keys >= max_keys {
+                error!(
+                    "The {} provider reached its quota of {} keys, \"{}\" can not create a new one.",
+                    provider_id, max_keys, app_name
+                );
+                return Err(ResponseStatus::PsaErrorInsufficientStorage);
+            }
+        }
+
+        Ok(reservation)
+    }
+}
+
+impl Drop for KeyReservation<'_> {
+    fn drop(&mut self) {
+        let _ = self
+            .key_quota
+            .total_pending_keys
+            .fetch_sub(1, Ordering::SeqCst);
+        let mut pending_keys = self
+            .key_quota
+            .pending_keys
+            .lock()
+            .expect("Pending keys lock poisoned");
+        if let Some(pending) = pending_keys.get_mut(&self.app_name) {
+            *pending -= 1;
+            if *pending == 0 {
+                let _ = pending_keys.remove(&self.app_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyQuota;
+    use crate::authenticators::{ApplicationName, AuthenticatorType};
+    use crate::key_info_managers::{KeyInfoManagerClient, KeyInfoManagerFactory};
+    use crate::utils::config::{KeyInfoManagerConfig, KeyInfoManagerType, KeyQuotaConfig};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{AuthType, ProviderId, ResponseStatus};
+    use std::collections::HashMap;
+
+    fn new_factory() -> KeyInfoManagerFactory {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile-manager"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        KeyInfoManagerFactory::new(&config, None).unwrap()
+    }
+
+    fn insert_key(client: &KeyInfoManagerClient, app_name: &ApplicationName, key_name: &str) {
+        let attributes = Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::Aes,
+            bits: 128,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: true,
+                    decrypt: true,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+            },
+        };
+        let key_triple = client.get_key_triple(app_name.clone(), key_name.to_string());
+        client
+            .insert_key_info(key_triple, &[0x11_u8, 0x22, 0x33], attributes)
+            .unwrap();
+    }
+
+    #[test]
+    fn no_limit_no_quota() {
+        let client = new_factory().build_client(ProviderId::MbedCrypto);
+        assert!(KeyQuota::new(client, KeyQuotaConfig::default(), HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn application_limit() {
+        let factory = new_factory();
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let app_name = ApplicationName::new(String::from("app"), AuthType::Direct);
+        let other_app_name = ApplicationName::new(String::from("other app"), AuthType::Direct);
+        insert_key(&client, &app_name, "key one");
+        let key_quota = KeyQuota::new(
+            factory.build_client(ProviderId::MbedCrypto),
+            KeyQuotaConfig {
+                max_keys_per_application: Some(2),
+                max_keys: None,
+            },
+            HashMap::new(),
+        )
+        .unwrap();
+
+        let reservation = key_quota.reserve(&app_name).unwrap();
+        // The key being created counts as well.
+        assert_eq!(
+            key_quota.reserve(&app_name).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        drop(key_quota.reserve(&other_app_name).unwrap());
+        drop(reservation);
+        drop(key_quota.reserve(&app_name).unwrap());
+
+        insert_key(&client, &app_name, "key two");
+        assert_eq!(
+            key_quota.reserve(&app_name).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+    }
+
+    #[test]
+    fn provider_limit_and_overrides() {
+        let factory = new_factory();
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let app_name = ApplicationName::new(String::from("app"), AuthType::Direct);
+        let admin_name = ApplicationName::new(String::from("admin"), AuthType::Direct);
+        // Application with the same name as the admin, authenticated by another authenticator.
+        let other_admin_name =
+            ApplicationName::new(String::from("admin"), AuthenticatorType::ClientCertificate);
+        let mut overrides = HashMap::new();
+        let _ = overrides.insert(admin_name.clone(), None);
+        let key_quota = KeyQuota::new(
+            factory.build_client(ProviderId::MbedCrypto),
+            KeyQuotaConfig {
+                max_keys_per_application: Some(1),
+                max_keys: Some(3),
+            },
+            overrides,
+        )
+        .unwrap();
+
+        insert_key(&client, &other_admin_name, "key one");
+        assert_eq!(
+            key_quota.reserve(&other_admin_name).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        client
+            .remove_key_info(&client.get_key_triple(other_admin_name, String::from("key one")))
+            .unwrap();
+
+        insert_key(&client, &admin_name, "key one");
+        insert_key(&client, &admin_name, "key two");
+        let reservation = key_quota.reserve(&admin_name).unwrap();
+        assert_eq!(
+            key_quota.reserve(&app_name).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        drop(reservation);
+        drop(key_quota.reserve(&app_name).unwrap());
+    }
+}
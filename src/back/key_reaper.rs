@@ -0,0 +1,102 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Destruction of the expired keys
+//!
+//! The key reaper runs in the background while the service is running. It periodically looks for
+//! the expired keys of the providers and destroys them through the provider owning them, as if
+//! their application had destroyed them.
+use crate::key_info_managers::KeyInfoManagerClient;
+use crate::providers::Provide;
+use log::{error, info, trace};
+use parsec_interface::operations::psa_destroy_key;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type Provider = Arc<dyn Provide + Send + Sync>;
+
+/// Background destruction of the expired keys
+///
+/// The reaper stops when it is dropped.
+#[derive(Debug)]
+pub struct KeyReaper {
+    stop_sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeyReaper {
+    /// Start destroying the expired keys of the providers every `interval`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread of the reaper could not be started.
+    pub fn start(
+        providers: Vec<(Provider, KeyInfoManagerClient)>,
+        interval: Duration,
+    ) -> std::io::Result<KeyReaper> {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("key-reaper"))
+            .spawn(move || loop {
+                match stop_receiver.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => reap(&providers),
+                    _ => break,
+                }
+            })?;
+
+        Ok(KeyReaper {
+            stop_sender: Some(stop_sender),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for KeyReaper {
+    fn drop(&mut self) {
+        // Dropping the sender wakes up the thread which then stops.
+        drop(self.stop_sender.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The key reaper thread panicked.");
+            }
+        }
+    }
+}
+
+/// Destroys the expired keys of the providers.
+fn reap(providers: &[(Provider, KeyInfoManagerClient)]) {
+    trace!("reap ingress");
+    for (provider, key_info_manager_client) in providers {
+        let provider_id = key_info_manager_client.provider_id();
+        let expired = match key_info_manager_client.get_expired() {
+            Ok(expired) => expired,
+            Err(e) => {
+                format_error!(
+                    &format!(
+                        "Failed to list the expired keys of the {} provider",
+                        provider_id
+                    ),
+                    e
+                );
+                continue;
+            }
+        };
+
+        for key_triple in expired {
+            let op = psa_destroy_key::Operation {
+                key_name: key_triple.key_name().to_string(),
+            };
+            match provider.psa_destroy_key(key_triple.app_name().clone(), op) {
+                Ok(_) => info!("Destroyed an expired key of the {} provider.", provider_id),
+                Err(e) => format_error!(
+                    &format!(
+                        "Failed to destroy an expired key of the {} provider",
+                        provider_id
+                    ),
+                    e
+                ),
+            }
+        }
+    }
+}
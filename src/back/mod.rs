@@ -1,5 +1,9 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 //! Routing and parsing requests for processing by providers
+pub mod access_policy;
 pub mod backend_handler;
+pub mod crypto_policy;
 pub mod dispatcher;
+pub mod key_quota;
+pub mod key_reaper;
@@ -41,10 +41,17 @@
 
 use anyhow::Result;
 use log::{info, trace};
-use parsec_service::utils::cli::Opts;
+use parsec_interface::requests::ProviderId;
+use parsec_service::authenticators::ApplicationName;
+use parsec_service::key_info_managers::{
+    self, acl::KeyPermission, archive::KeyInfoArchive, metadata::KeyMetadata, KeyTriple,
+};
+use parsec_service::utils::cli::{Command, Opts};
 use parsec_service::utils::{config::ServiceConfig, ServiceBuilder};
 use signal_hook::{consts::SIGHUP, consts::SIGINT, consts::SIGTERM, flag};
+use std::convert::TryFrom;
 use std::io::{Error, ErrorKind};
+use std::path::Path;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
@@ -94,14 +101,110 @@ fn main() -> Result<()> {
 
     log_setup(&config);
 
+    match opts.command {
+        Some(Command::MigrateMappings { dry_run }) => return migrate_mappings(&config, dry_run),
+        Some(Command::BackupMappings {
+            output,
+            key_manager,
+        }) => return backup_mappings(&config, key_manager, &output),
+        Some(Command::RestoreMappings { input, key_manager }) => {
+            return restore_mappings(&config, key_manager, &input)
+        }
+        Some(Command::Reconcile) => return reconcile(&config),
+        Some(Command::ReconciliationReport) => {
+            print!("{}", ServiceBuilder::saved_reconciliation_reports(&config)?);
+            return Ok(());
+        }
+        Some(Command::KeyMetadata {
+            provider,
+            application,
+            authenticator,
+            key_name,
+        }) => {
+            return key_metadata(
+                &config,
+                KeyTriple::new(
+                    application_name(&config, application, authenticator)?,
+                    provider_id(provider)?,
+                    key_name,
+                ),
+            )
+        }
+        Some(Command::ListKeyMetadata { provider }) => return list_key_metadata(&config, provider),
+        Some(Command::TransferClient {
+            from,
+            from_authenticator,
+            to,
+            to_authenticator,
+        }) => {
+            return transfer_client(
+                &config,
+                application_name(&config, from, from_authenticator)?,
+                application_name(&config, to, to_authenticator)?,
+            )
+        }
+        Some(Command::SetKeyExpiry {
+            provider,
+            application,
+            authenticator,
+            key_name,
+            expires_at,
+        }) => {
+            return set_key_expiry(
+                &config,
+                KeyTriple::new(
+                    application_name(&config, application, authenticator)?,
+                    provider_id(provider)?,
+                    key_name,
+                ),
+                expires_at,
+            )
+        }
+        Some(Command::GrantKeyAccess {
+            provider,
+            application,
+            authenticator,
+            key_name,
+            grantee,
+            grantee_authenticator,
+            permissions,
+        }) => {
+            return grant_key_access(
+                &config,
+                KeyTriple::new(
+                    application_name(&config, application, authenticator)?,
+                    provider_id(provider)?,
+                    key_name,
+                ),
+                application_name(&config, grantee, grantee_authenticator)?,
+                &permissions,
+            )
+        }
+        Some(Command::ListKeyGrants {
+            provider,
+            application,
+            authenticator,
+            key_name,
+        }) => {
+            return list_key_grants(
+                &config,
+                KeyTriple::new(
+                    application_name(&config, application, authenticator)?,
+                    provider_id(provider)?,
+                    key_name,
+                ),
+            )
+        }
+        None => (),
+    }
+
     info!("Parsec started. Configuring the service...");
 
-    let front_end_handler = ServiceBuilder::build_service(&config)?;
-    // Multiple threads can not just have a reference of the front end handler because they could
+    // Multiple threads can not just have a reference of the front end handlers because they could
     // outlive the run function. It is needed to give them all ownership of the front end handler
-    // through an Arc.
-    let mut front_end_handler = Arc::from(front_end_handler);
-    let mut listener = ServiceBuilder::start_listener(config.listener)?;
+    // of their listener through an Arc.
+    let mut listeners =
+        ServiceBuilder::start_listeners(&config, &ServiceBuilder::build_service(&config)?)?;
     let mut threadpool = ServiceBuilder::build_threadpool(config.core_settings.thread_pool_size);
 
     // Notify systemd that the daemon is ready, the start command will block until this point.
@@ -119,8 +222,7 @@ fn main() -> Result<()> {
             // Explicitely call drop now because otherwise Rust will drop these variables only
             // after they have been overwritten, in which case some values/libraries might be
             // initialized twice.
-            drop(front_end_handler);
-            drop(listener);
+            drop(listeners);
             drop(threadpool);
 
             config_file = ::std::fs::read_to_string(opts.config.clone()).map_err(|e| {
@@ -135,21 +237,26 @@ fn main() -> Result<()> {
                     format!("Failed to parse service configuration ({})", e),
                 )
             })?;
-            front_end_handler = Arc::from(ServiceBuilder::build_service(&config)?);
-            listener = ServiceBuilder::start_listener(config.listener)?;
+            listeners =
+                ServiceBuilder::start_listeners(&config, &ServiceBuilder::build_service(&config)?)?;
             threadpool = ServiceBuilder::build_threadpool(config.core_settings.thread_pool_size);
 
             let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
             info!("Parsec configuration reloaded.");
         }
 
-        if let Some(connection) = listener.accept() {
-            let front_end_handler = front_end_handler.clone();
-            threadpool.execute(move || {
-                front_end_handler.handle_request(connection);
-                trace!("handle_request egress");
-            });
-        } else {
+        let mut idle = true;
+        for (listener, front_end_handler) in listeners.iter() {
+            if let Some(connection) = listener.accept() {
+                idle = false;
+                let front_end_handler = front_end_handler.clone();
+                threadpool.execute(move || {
+                    front_end_handler.handle_request(connection);
+                    trace!("handle_request egress");
+                });
+            }
+        }
+        if idle {
             ::std::thread::sleep(Duration::from_millis(
                 config
                     .core_settings
@@ -167,6 +274,320 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn migrate_mappings(config: &ServiceConfig, dry_run: bool) -> Result<()> {
+    let mut failed = 0;
+    for key_manager in config.key_manager.iter().flatten() {
+        let report = key_info_managers::migrate_mappings(
+            key_manager,
+            // Mappings written before the authenticator type was recorded belong to the default
+            // authenticator.
+            config.authenticator[0].authenticator_type(),
+            dry_run,
+        )?;
+        println!(
+            "Key manager \"{}\": {} mappings {}, {} already up to date, {} could not be read.",
+            key_manager.name,
+            report.upgraded,
+            if dry_run { "to upgrade" } else { "upgraded" },
+            report.up_to_date,
+            report.failed.len()
+        );
+        for (mapping, reason) in report.failed.iter() {
+            eprintln!("  {}: {}", mapping, reason);
+        }
+        failed += report.failed.len();
+    }
+
+    if failed > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} mappings could not be read and were not upgraded",
+                failed
+            ),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Returns the name of the key manager given or of the first one of the configuration.
+fn key_manager_name(config: &ServiceConfig, key_manager: Option<String>) -> Result<String> {
+    match key_manager {
+        Some(key_manager) => Ok(key_manager),
+        None => config
+            .key_manager
+            .iter()
+            .flatten()
+            .next()
+            .map(|key_manager| key_manager.name.clone())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "no key manager in the configuration",
+                )
+                .into()
+            }),
+    }
+}
+
+fn backup_mappings(
+    config: &ServiceConfig,
+    key_manager: Option<String>,
+    output: &str,
+) -> Result<()> {
+    let key_manager = key_manager_name(config, key_manager)?;
+    let archive = ServiceBuilder::backup_mappings(config, &key_manager)?;
+    archive.write(Path::new(output))?;
+    println!(
+        "{} mappings of the key manager \"{}\" saved to {}.",
+        archive.len(),
+        key_manager,
+        output
+    );
+
+    Ok(())
+}
+
+fn restore_mappings(
+    config: &ServiceConfig,
+    key_manager: Option<String>,
+    input: &str,
+) -> Result<()> {
+    let key_manager = key_manager_name(config, key_manager)?;
+    // Archives written before the authenticator type was recorded are restored in the namespace
+    // of the default authenticator.
+    let archive = KeyInfoArchive::read(
+        Path::new(input),
+        config.authenticator[0].authenticator_type(),
+    )?;
+    let report = ServiceBuilder::restore_mappings(config, &key_manager, &archive)?;
+    println!(
+        "{} mappings restored in the key manager \"{}\".",
+        report.restored, key_manager
+    );
+    for provider_id in report.unchecked_providers.iter() {
+        println!(
+            "The keys of the {} provider could not be checked as it does not use this key manager.",
+            provider_id
+        );
+    }
+    if !report.missing_keys.is_empty() {
+        eprintln!(
+            "{} restored mappings were quarantined as their key does not exist in the provider:",
+            report.missing_keys.len()
+        );
+        for key_triple in report.missing_keys.iter() {
+            eprintln!("  {}", key_triple);
+        }
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "some keys of the archive do not exist in the providers",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn reconcile(config: &ServiceConfig) -> Result<()> {
+    let reports = ServiceBuilder::reconcile(config)?;
+    for report in reports.iter() {
+        print!("{}", report);
+    }
+
+    if reports.iter().any(|report| !report.is_consistent()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the mappings are not consistent with the keys of the providers",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Returns the ID of the provider given by its number.
+fn provider_id(provider: u8) -> Result<ProviderId> {
+    ProviderId::try_from(provider).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not the ID of a provider", provider),
+        )
+        .into()
+    })
+}
+
+/// Returns the name of the application authenticated by the authenticator named, or by the first
+/// one of the configuration.
+fn application_name(
+    config: &ServiceConfig,
+    application: String,
+    authenticator: Option<String>,
+) -> Result<ApplicationName> {
+    let authenticator_config = match authenticator {
+        Some(authenticator) => config
+            .authenticator
+            .iter()
+            .find(|authenticator_config| authenticator_config.name() == authenticator)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("the authenticator \"{}\" is not configured", authenticator),
+                )
+            })?,
+        None => &config.authenticator[0],
+    };
+
+    Ok(ApplicationName::new(
+        application,
+        authenticator_config.authenticator_type(),
+    ))
+}
+
+fn print_key_metadata(key_triple: &KeyTriple, metadata: &KeyMetadata) {
+    let or_unknown = |value: Option<String>| value.unwrap_or_else(|| String::from("unknown"));
+    println!("{}", key_triple);
+    println!(
+        "  Created at: {}",
+        or_unknown(metadata.created_at().map(|time| time.to_string()))
+    );
+    println!(
+        "  Created with the authenticator: {}",
+        or_unknown(
+            metadata
+                .creator_authenticator_type()
+                .map(|authenticator_type| format!("{:?}", authenticator_type))
+        )
+    );
+    println!(
+        "  Created by the service version: {}",
+        or_unknown(metadata.provider_version().map(String::from))
+    );
+    println!(
+        "  Last used at: {}",
+        metadata
+            .last_used()
+            .map(|time| time.to_string())
+            .unwrap_or_else(|| String::from("never"))
+    );
+    println!("  Use count: {}", metadata.use_count());
+}
+
+fn key_metadata(config: &ServiceConfig, key_triple: KeyTriple) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "key-metadata")?;
+    let metadata = core_provider.key_metadata(
+        key_triple.provider_id(),
+        key_triple.app_name().clone(),
+        key_triple.key_name().to_string(),
+    )?;
+    print_key_metadata(&key_triple, &metadata);
+
+    Ok(())
+}
+
+fn list_key_metadata(config: &ServiceConfig, provider: u8) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "list-key-metadata")?;
+    let key_metadata = core_provider.list_key_metadata(provider_id(provider)?)?;
+    for (key_triple, metadata) in key_metadata.iter() {
+        print_key_metadata(key_triple, metadata);
+    }
+    println!("{} keys.", key_metadata.len());
+
+    Ok(())
+}
+
+fn transfer_client(
+    config: &ServiceConfig,
+    from: ApplicationName,
+    to: ApplicationName,
+) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "transfer-client")?;
+    let transferred = core_provider.transfer_client(from.clone(), to.clone())?;
+    println!(
+        "{} keys moved from the application \"{}\" ({:?}) to the application \"{}\" ({:?}).",
+        transferred,
+        from,
+        from.authenticator_type(),
+        to,
+        to.authenticator_type()
+    );
+
+    Ok(())
+}
+
+fn set_key_expiry(
+    config: &ServiceConfig,
+    key_triple: KeyTriple,
+    expires_at: Option<u64>,
+) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "set-key-expiry")?;
+    core_provider.set_key_expiry(
+        key_triple.provider_id(),
+        key_triple.app_name().clone(),
+        key_triple.key_name().to_string(),
+        expires_at,
+    )?;
+    match expires_at {
+        Some(expires_at) => println!("{}\n  Expires at: {}", key_triple, expires_at),
+        None => println!("{}\n  Does not expire", key_triple),
+    }
+
+    Ok(())
+}
+
+fn grant_key_access(
+    config: &ServiceConfig,
+    key_triple: KeyTriple,
+    grantee: ApplicationName,
+    permissions: &[KeyPermission],
+) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "grant-key-access")?;
+    core_provider.grant_key_access(
+        key_triple.provider_id(),
+        key_triple.app_name().clone(),
+        key_triple.key_name().to_string(),
+        grantee.clone(),
+        permissions,
+    )?;
+    println!(
+        "{}\n  Granted to the application \"{}\" ({:?}): {:?}",
+        key_triple,
+        grantee,
+        grantee.authenticator_type(),
+        permissions
+    );
+
+    Ok(())
+}
+
+fn list_key_grants(config: &ServiceConfig, key_triple: KeyTriple) -> Result<()> {
+    let core_provider = ServiceBuilder::build_admin_provider(config, "list-key-grants")?;
+    let grants = core_provider.key_grants(
+        key_triple.provider_id(),
+        key_triple.app_name().clone(),
+        key_triple.key_name().to_string(),
+    )?;
+    println!("{}", key_triple);
+    for grant in grants.iter() {
+        match grant.app_name() {
+            Some(grantee) => println!(
+                "  Granted to the application \"{}\" ({:?}): {:?}",
+                grantee,
+                grantee.authenticator_type(),
+                grant.permissions()
+            ),
+            None => println!(
+                "  Granted to an application of an unsupported authenticator: {:?}",
+                grant.permissions()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
 fn log_setup(config: &ServiceConfig) {
     let mut env_log_builder = env_logger::builder();
 
@@ -21,6 +21,8 @@ use std::path::PathBuf;
 use std::time::Duration;
 
 static DEFAULT_SOCKET_PATH: &str = "/run/parsec/parsec.sock";
+/// Permissions allowing clients of different users to connect
+const DEFAULT_SOCKET_PERMISSIONS: u32 = 0o666;
 
 /// Unix Domain Socket IPC manager
 ///
@@ -34,8 +36,8 @@ pub struct DomainSocketListener {
 }
 
 impl DomainSocketListener {
-    /// Initialise the connection to the Unix socket.
-    pub fn new(timeout: Duration, socket_path: PathBuf) -> Result<Self> {
+    /// Initialise the connection to the Unix socket, created with the given permissions.
+    pub fn new(timeout: Duration, socket_path: PathBuf, socket_permissions: u32) -> Result<Self> {
         // If Parsec was service activated or not started under systemd, this
         // will return `0`. `1` will be returned in case Parsec is socket activated.
         let listener = match sd_notify::listen_fds()? {
@@ -62,9 +64,9 @@ impl DomainSocketListener {
                 })?;
                 listener.set_nonblocking(true)?;
 
-                // Set the socket's permission to 666 to allow clients of different user to
-                // connect.
-                let permissions = Permissions::from_mode(0o666);
+                // The socket's permissions are 666 by default to allow clients of different
+                // users to connect.
+                let permissions = Permissions::from_mode(socket_permissions);
                 fs::set_permissions(socket_path, permissions)?;
 
                 listener
@@ -130,6 +132,7 @@ impl Listen for DomainSocketListener {
                             gid: ucred.gid,
                             pid: ucred.pid,
                         }),
+                        deferred_metadata: None,
                     })
                 }
             }
@@ -150,6 +153,7 @@ impl Listen for DomainSocketListener {
 pub struct DomainSocketListenerBuilder {
     timeout: Option<Duration>,
     socket_path: Option<PathBuf>,
+    socket_permissions: Option<u32>,
 }
 
 impl DomainSocketListenerBuilder {
@@ -158,6 +162,7 @@ impl DomainSocketListenerBuilder {
         DomainSocketListenerBuilder {
             timeout: None,
             socket_path: None,
+            socket_permissions: None,
         }
     }
 
@@ -173,6 +178,12 @@ impl DomainSocketListenerBuilder {
         self
     }
 
+    /// Specify the permissions of the Unix Domain Socket file
+    pub fn with_socket_permissions(mut self, socket_permissions: Option<u32>) -> Self {
+        self.socket_permissions = socket_permissions;
+        self
+    }
+
     /// Build the builder into the listener
     pub fn build(self) -> Result<DomainSocketListener> {
         DomainSocketListener::new(
@@ -182,6 +193,8 @@ impl DomainSocketListenerBuilder {
             })?,
             self.socket_path
                 .unwrap_or_else(|| DEFAULT_SOCKET_PATH.into()),
+            self.socket_permissions
+                .unwrap_or(DEFAULT_SOCKET_PERMISSIONS),
         )
     }
 }
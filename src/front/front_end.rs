@@ -4,16 +4,16 @@
 //!
 //! The front end handler accepts streams of data that it can use to read requests,
 //! pass them to the rest of the service and write the responses back.
-use crate::authenticators::Authenticate;
+use crate::authenticators::{Authenticate, AuthenticatorType};
 use crate::back::dispatcher::Dispatcher;
 use crate::front::listener::Connection;
 use derivative::Derivative;
-use log::{info, trace};
+use log::{error, info, trace};
 use parsec_interface::requests::AuthType;
 use parsec_interface::requests::ResponseStatus;
 use parsec_interface::requests::{Request, Response};
-use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
 
 /// Read and verify request from IPC stream
 ///
@@ -24,15 +24,66 @@ use std::io::{Error, ErrorKind, Result};
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct FrontEndHandler {
-    dispatcher: Dispatcher,
-    // Send and Sync are required for Arc<FrontEndHandler> to be Send.
+    // Shared by the front end handlers of all the listeners.
+    dispatcher: Arc<Dispatcher>,
+    // Send and Sync are required for Arc<FrontEndHandler> to be Send. The first one is the
+    // default authenticator.
     #[derivative(Debug = "ignore")]
-    authenticators: HashMap<AuthType, Box<dyn Authenticate + Send + Sync>>,
+    authenticators: Vec<(AuthenticatorType, Arc<dyn Authenticate + Send + Sync>)>,
     /// Value used to limit the size of the request body to be that can be accepted by the service.
     body_len_limit: usize,
 }
 
 impl FrontEndHandler {
+    /// Create the front end handler of a listener, sharing the dispatcher and the authenticators
+    /// of this one. Only the authenticators of the `authenticator_types` are kept, or all of them
+    /// if absent. The limit on the request body size is replaced if `body_len_limit` is given.
+    ///
+    /// # Errors
+    ///
+    /// If two of the authenticators kept are selected with the same authentication type, an
+    /// error of kind `InvalidInput` is returned.
+    pub fn restrict(
+        &self,
+        authenticator_types: Option<&[AuthenticatorType]>,
+        body_len_limit: Option<usize>,
+    ) -> Result<FrontEndHandler> {
+        let authenticators: Vec<_> = self
+            .authenticators
+            .iter()
+            .filter(|(authenticator_type, _)| {
+                authenticator_types.map_or(true, |authenticator_types| {
+                    authenticator_types.contains(authenticator_type)
+                })
+            })
+            .cloned()
+            .collect();
+        for (index, (authenticator_type, _)) in authenticators.iter().enumerate() {
+            if let Some((other_authenticator_type, _)) = authenticators[..index]
+                .iter()
+                .find(|(other, _)| other.auth_type() == authenticator_type.auth_type())
+            {
+                error!(
+                    "The authenticators {:?} and {:?} are both selected with the {:?} \
+                    authentication type, they can not be used on the same listener.",
+                    other_authenticator_type,
+                    authenticator_type,
+                    authenticator_type.auth_type()
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "authentication type used twice on a listener",
+                ));
+            }
+        }
+
+        Ok(FrontEndHandler {
+            dispatcher: self.dispatcher.clone(),
+            authenticators,
+            body_len_limit: body_len_limit.unwrap_or(self.body_len_limit),
+        })
+    }
+
     /// Handle new connections on the underlying IPC mechanism.
     ///
     /// Unmarshalls a request from the stream, passes it to the dispatcher and marshalls
@@ -60,13 +111,23 @@ impl FrontEndHandler {
             }
         };
 
+        // The deferred metadata is known now that the request has been read.
+        let metadata = match connection.deferred_metadata {
+            Some(read_metadata) => read_metadata(),
+            None => connection.metadata,
+        };
+
         // Check if the request was sent without authentication
         let (app, err_response) = if AuthType::NoAuth == request.header.auth_type {
             (None, None)
         // Otherwise find an authenticator that is capable to authenticate the request
-        } else if let Some(authenticator) = self.authenticators.get(&request.header.auth_type) {
+        } else if let Some((_, authenticator)) =
+            self.authenticators.iter().find(|(authenticator_type, _)| {
+                authenticator_type.auth_type() == request.header.auth_type
+            })
+        {
             // Authenticate the request
-            match authenticator.authenticate(&request.auth, connection.metadata) {
+            match authenticator.authenticate(&request.auth, metadata) {
                 // Send the request to the dispatcher
                 // Get a response back
                 Ok(app) => (Some(app), None),
@@ -98,7 +159,14 @@ impl FrontEndHandler {
                     info!("New request received without authentication")
                 }
             };
-            let response = self.dispatcher.dispatch_request(request, app.clone());
+            let authenticator_types: Vec<AuthenticatorType> = self
+                .authenticators
+                .iter()
+                .map(|(authenticator_type, _)| *authenticator_type)
+                .collect();
+            let response =
+                self.dispatcher
+                    .dispatch_request(request, app.clone(), &authenticator_types);
             trace!("dispatch_request egress");
             response
         };
@@ -129,7 +197,7 @@ impl FrontEndHandler {
 pub struct FrontEndHandlerBuilder {
     dispatcher: Option<Dispatcher>,
     #[derivative(Debug = "ignore")]
-    authenticators: Option<HashMap<AuthType, Box<dyn Authenticate + Send + Sync>>>,
+    authenticators: Option<Vec<(AuthenticatorType, Arc<dyn Authenticate + Send + Sync>)>>,
     body_len_limit: Option<usize>,
 }
 
@@ -149,22 +217,16 @@ impl FrontEndHandlerBuilder {
         self
     }
 
-    /// Add an authenticator to the builder
+    /// Add an authenticator to the builder, after the ones already added
     pub fn with_authenticator(
         mut self,
-        auth_type: AuthType,
+        authenticator_type: AuthenticatorType,
         authenticator: Box<dyn Authenticate + Send + Sync>,
     ) -> Self {
-        match &mut self.authenticators {
-            Some(authenticators) => {
-                let _ = authenticators.insert(auth_type, authenticator);
-            }
-            None => {
-                let mut map = HashMap::new();
-                let _ = map.insert(auth_type, authenticator);
-                self.authenticators = Some(map);
-            }
-        };
+        let authenticator: Arc<dyn Authenticate + Send + Sync> = Arc::from(authenticator);
+        self.authenticators
+            .get_or_insert_with(Vec::new)
+            .push((authenticator_type, authenticator));
 
         self
     }
@@ -178,9 +240,10 @@ impl FrontEndHandlerBuilder {
     /// Build into a FrontEndHandler
     pub fn build(self) -> Result<FrontEndHandler> {
         Ok(FrontEndHandler {
-            dispatcher: self
-                .dispatcher
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "dispatcher is missing"))?,
+            dispatcher: Arc::new(
+                self.dispatcher
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "dispatcher is missing"))?,
+            ),
             authenticators: self
                 .authenticators
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "authenticators is missing"))?,
@@ -15,7 +15,7 @@ pub trait ReadWrite: std::io::Read + std::io::Write {}
 impl<T: std::io::Read + std::io::Write> ReadWrite for T {}
 
 /// Specifies metadata associated with a connection, if any.
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum ConnectionMetadata {
     /// Unix peer credentials metadata for Unix domain sockets.
     UnixPeerCredentials {
@@ -27,9 +27,16 @@ pub enum ConnectionMetadata {
         /// platforms support retrieving PID via a domain socket.
         pid: Option<i32>,
     },
-    // NOTE: there is currently only _one_ variant of the ConnectionMetadata enum. When a second
-    //       variant is added, you will need to update some tests!
-    //       You should grep the tests for `TODO(new_metadata_variant)` and update them accordingly.
+    /// Certificate chain of the peer, verified during the TLS handshake.
+    TlsPeerCertificates {
+        /// DER-encoded certificates, starting with the certificate of the peer.
+        chain: Vec<Vec<u8>>,
+    },
+    /// Address of the peer of a vsock connection.
+    VsockPeer {
+        /// The Context Identifier of the connecting virtual machine.
+        cid: u32,
+    },
 }
 
 /// Represents a connection to a single client
@@ -41,6 +48,10 @@ pub struct Connection {
     pub stream: Box<dyn ReadWrite + Send>,
     /// Metadata associated with the connection that might be useful elsewhere (i.e. authentication, etc)
     pub metadata: Option<ConnectionMetadata>,
+    /// Reads the metadata which is only known once data has been read from the stream, such as
+    /// the certificates of a TLS peer. If given, it replaces `metadata`.
+    #[derivative(Debug = "ignore")]
+    pub deferred_metadata: Option<Box<dyn FnOnce() -> Option<ConnectionMetadata> + Send>>,
 }
 
 /// IPC front manager interface
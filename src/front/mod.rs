@@ -4,3 +4,7 @@
 pub mod domain_socket;
 pub mod front_end;
 pub mod listener;
+#[cfg(feature = "tcp-listener")]
+pub mod tcp;
+#[cfg(feature = "vsock-listener")]
+pub mod vsock;
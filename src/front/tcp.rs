@@ -0,0 +1,508 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Service front using TCP with mutual TLS
+//!
+//! Expose Parsec functionality over TCP, for clients which can not reach the Unix Domain Socket,
+//! such as workloads in sibling virtual machines or network namespaces. All the connections are
+//! secured with TLS and clients must present a certificate issued by one of the configured CAs.
+//! The verified certificate chain of the client is given as metadata of the connection.
+use super::listener;
+use anyhow::{Context, Result};
+use derivative::Derivative;
+use listener::Listen;
+use listener::{Connection, ConnectionMetadata};
+use log::error;
+use once_cell::sync::OnceCell;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    ServerSession, Session, StreamOwned,
+};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// TCP IPC manager
+///
+/// Listener implementation for TCP, with mutual TLS authentication, as the underlying IPC
+/// mechanism.
+///
+/// The TLS handshake is performed by the thread handling the connection, when the request is
+/// read, within the timeout of the listener.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct TcpTlsListener {
+    listener: TcpListener,
+    timeout: Duration,
+    #[derivative(Debug = "ignore")]
+    tls_config: Arc<ServerConfig>,
+}
+
+impl TcpTlsListener {
+    /// Bind to the address and load the TLS configuration from the PEM files.
+    pub fn new(
+        timeout: Duration,
+        address: SocketAddr,
+        certificate_path: &Path,
+        private_key_path: &Path,
+        client_ca_path: &Path,
+    ) -> Result<Self> {
+        let mut client_roots = RootCertStore::empty();
+        let (valid, _) = client_roots
+            .add_pem_file(&mut open_pem_file(client_ca_path)?)
+            .map_err(|_| invalid_pem_file(client_ca_path))?;
+        if valid == 0 {
+            error!(
+                "No valid CA certificate found in {}.",
+                client_ca_path.display()
+            );
+            return Err(Error::new(ErrorKind::InvalidData, "no client CA certificate").into());
+        }
+
+        let certificate_chain = load_certificates(certificate_path)?;
+        let private_key = load_private_key(private_key_path)?;
+
+        let mut tls_config = ServerConfig::new(AllowAnyAuthenticatedClient::new(client_roots));
+        tls_config
+            .set_single_cert(certificate_chain, private_key)
+            .with_context(|| "Failed to set the certificate of the TCP listener")?;
+
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("Failed to bind to TCP address {}", address))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            timeout,
+            tls_config: Arc::new(tls_config),
+        })
+    }
+
+    /// Get the address the listener is bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Listen for TcpTlsListener {
+    fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+
+    fn accept(&self) -> Option<Connection> {
+        let stream_result = self.listener.accept();
+        match stream_result {
+            Ok((stream, _)) => {
+                if let Err(err) = stream.set_read_timeout(Some(self.timeout)) {
+                    format_error!("Failed to set read timeout", err);
+                    None
+                } else if let Err(err) = stream.set_write_timeout(Some(self.timeout)) {
+                    format_error!("Failed to set write timeout", err);
+                    None
+                } else if let Err(err) = stream.set_nonblocking(false) {
+                    format_error!("Failed to set stream as blocking", err);
+                    None
+                } else {
+                    let peer_certificates = Arc::new(OnceCell::new());
+                    let handshake_certificates = peer_certificates.clone();
+                    Some(Connection {
+                        stream: Box::new(TlsStream {
+                            stream: StreamOwned::new(ServerSession::new(&self.tls_config), stream),
+                            peer_certificates,
+                        }),
+                        metadata: None,
+                        // The handshake can not succeed without a client certificate.
+                        deferred_metadata: Some(Box::new(move || {
+                            handshake_certificates.get().map(|chain| {
+                                ConnectionMetadata::TlsPeerCertificates {
+                                    chain: chain.clone(),
+                                }
+                            })
+                        })),
+                    })
+                }
+            }
+            Err(err) => {
+                // Check if the error is because no connections are currently present.
+                if err.kind() != ErrorKind::WouldBlock {
+                    // Only log the real errors.
+                    format_error!("Failed to connect with a TcpStream", err);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// TLS stream of an accepted connection
+///
+/// The handshake is completed by the first read or write, so that a client stalling it only holds
+/// the thread handling its connection. The certificate chain of the client is kept once the
+/// handshake is complete.
+struct TlsStream {
+    stream: StreamOwned<ServerSession, TcpStream>,
+    peer_certificates: Arc<OnceCell<Vec<Vec<u8>>>>,
+}
+
+impl TlsStream {
+    fn keep_peer_certificates(&self) {
+        if self.peer_certificates.get().is_some() || self.stream.sess.is_handshaking() {
+            return;
+        }
+        if let Some(certificates) = self.stream.sess.get_peer_certificates() {
+            let _ = self.peer_certificates.set(
+                certificates
+                    .into_iter()
+                    .map(|certificate| certificate.0)
+                    .collect(),
+            );
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.keep_peer_certificates();
+        Ok(read)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.keep_peer_certificates();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn open_pem_file(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open the PEM file {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+fn invalid_pem_file(path: &Path) -> Error {
+    error!("Failed to parse the PEM file {}.", path.display());
+    Error::new(ErrorKind::InvalidData, "invalid PEM file")
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let certificates = certs(&mut open_pem_file(path)?).map_err(|_| invalid_pem_file(path))?;
+    if certificates.is_empty() {
+        error!("No certificate found in {}.", path.display());
+        return Err(Error::new(ErrorKind::InvalidData, "no certificate").into());
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut keys =
+        pkcs8_private_keys(&mut open_pem_file(path)?).map_err(|_| invalid_pem_file(path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open_pem_file(path)?).map_err(|_| invalid_pem_file(path))?;
+    }
+    if keys.len() != 1 {
+        error!(
+            "Expected one private key in {}, found {}.",
+            path.display(),
+            keys.len()
+        );
+        return Err(Error::new(ErrorKind::InvalidData, "expected one private key").into());
+    }
+
+    Ok(keys.remove(0))
+}
+
+/// Builder for `TcpTlsListener`
+#[derive(Clone, Debug, Default)]
+pub struct TcpTlsListenerBuilder {
+    timeout: Option<Duration>,
+    address: Option<String>,
+    certificate_path: Option<PathBuf>,
+    private_key_path: Option<PathBuf>,
+    client_ca_path: Option<PathBuf>,
+}
+
+impl TcpTlsListenerBuilder {
+    /// Create a new TcpTlsListener builder
+    pub fn new() -> Self {
+        TcpTlsListenerBuilder {
+            timeout: None,
+            address: None,
+            certificate_path: None,
+            private_key_path: None,
+            client_ca_path: None,
+        }
+    }
+
+    /// Add a timeout on the TCP connections
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Specify the address to bind to, as a `host:port` string
+    pub fn with_address(mut self, address: Option<String>) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Specify the PEM file containing the certificate chain of the listener
+    pub fn with_certificate_path(mut self, certificate_path: Option<PathBuf>) -> Self {
+        self.certificate_path = certificate_path;
+        self
+    }
+
+    /// Specify the PEM file containing the private key of the listener
+    pub fn with_private_key_path(mut self, private_key_path: Option<PathBuf>) -> Self {
+        self.private_key_path = private_key_path;
+        self
+    }
+
+    /// Specify the PEM file containing the CA certificates trusted to issue client certificates
+    pub fn with_client_ca_path(mut self, client_ca_path: Option<PathBuf>) -> Self {
+        self.client_ca_path = client_ca_path;
+        self
+    }
+
+    /// Build the builder into the listener
+    pub fn build(self) -> Result<TcpTlsListener> {
+        let address = self.address.ok_or_else(|| {
+            error!("The address of the TCP listener was not set.");
+            Error::new(ErrorKind::InvalidInput, "listener address missing")
+        })?;
+        let address = address
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve the TCP address {}", address))?
+            .next()
+            .ok_or_else(|| {
+                error!("The address {} does not resolve to any address.", address);
+                Error::new(ErrorKind::InvalidInput, "listener address invalid")
+            })?;
+
+        TcpTlsListener::new(
+            self.timeout.ok_or_else(|| {
+                error!("The listener timeout was not set.");
+                Error::new(ErrorKind::InvalidInput, "listener timeout missing")
+            })?,
+            address,
+            &self.certificate_path.ok_or_else(|| {
+                error!("The TLS certificate of the TCP listener was not set.");
+                Error::new(ErrorKind::InvalidInput, "listener certificate missing")
+            })?,
+            &self.private_key_path.ok_or_else(|| {
+                error!("The TLS private key of the TCP listener was not set.");
+                Error::new(ErrorKind::InvalidInput, "listener private key missing")
+            })?,
+            &self.client_ca_path.ok_or_else(|| {
+                error!("The client CA certificates of the TCP listener were not set.");
+                Error::new(ErrorKind::InvalidInput, "listener client CA missing")
+            })?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpTlsListener, TcpTlsListenerBuilder};
+    use crate::front::listener::{Connection, ConnectionMetadata, Listen};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa,
+    };
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+    use rustls::{ClientConfig, ClientSession, StreamOwned};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_millis(1000);
+
+    fn certificate(common_name: &str, is_ca: bool) -> Certificate {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, common_name);
+        params.distinguished_name = name;
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // Creates a listener on the loopback interface which trusts the client certificates issued by
+    // `ca`. Its files are written in `dir`.
+    fn listener(dir: &Path, ca: &Certificate) -> TcpTlsListener {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let server = certificate("localhost", false);
+
+        let mut listener = TcpTlsListenerBuilder::new()
+            .with_timeout(TIMEOUT)
+            .with_address(Some("127.0.0.1:0".to_string()))
+            .with_certificate_path(Some(write_file(
+                dir,
+                "server.pem",
+                &server.serialize_pem_with_signer(ca).unwrap(),
+            )))
+            .with_private_key_path(Some(write_file(
+                dir,
+                "server-key.pem",
+                &server.serialize_private_key_pem(),
+            )))
+            .with_client_ca_path(Some(write_file(
+                dir,
+                "ca.pem",
+                &ca.serialize_pem().unwrap(),
+            )))
+            .build()
+            .unwrap();
+        listener.set_timeout(TIMEOUT);
+        listener
+    }
+
+    fn accept(listener: &TcpTlsListener) -> Connection {
+        loop {
+            if let Some(connection) = listener.accept() {
+                break connection;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Connects to the listener in another thread and sends a message. The receiver is notified
+    // when the client is done.
+    fn connect(
+        address: SocketAddr,
+        server_ca_pem: String,
+        client_pem: String,
+        client_key_pem: String,
+    ) -> mpsc::Receiver<()> {
+        let (done_sender, done_receiver) = mpsc::channel();
+        let _ = thread::spawn(move || {
+            let mut config = ClientConfig::new();
+            let _ = config
+                .root_store
+                .add_pem_file(&mut server_ca_pem.as_bytes())
+                .unwrap();
+            config
+                .set_single_client_cert(
+                    certs(&mut client_pem.as_bytes()).unwrap(),
+                    pkcs8_private_keys(&mut client_key_pem.as_bytes())
+                        .unwrap()
+                        .remove(0),
+                )
+                .unwrap();
+            let session = ClientSession::new(
+                &Arc::new(config),
+                webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+            );
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+            let mut stream = StreamOwned::new(session, stream);
+            // Fails if the client certificate is refused.
+            let _ = stream.write_all(b"ping");
+            let _ = stream.flush();
+            done_sender.send(()).unwrap();
+        });
+        done_receiver
+    }
+
+    #[test]
+    fn mutual_tls_over_loopback() {
+        let dir = PathBuf::from(env!("OUT_DIR").to_owned() + "/mutual_tls_over_loopback");
+        let ca = certificate("Parsec test CA", true);
+        let other_ca = certificate("Other test CA", true);
+        let client = certificate("client", false);
+        let ca_pem = ca.serialize_pem().unwrap();
+        let listener = listener(&dir, &ca);
+        let address = listener.local_addr().unwrap();
+
+        // Client certificate issued by the trusted CA
+        let client_pem = client.serialize_pem_with_signer(&ca).unwrap();
+        let done = connect(
+            address,
+            ca_pem.clone(),
+            client_pem.clone(),
+            client.serialize_private_key_pem(),
+        );
+        let mut connection = accept(&listener);
+        let mut message = [0; 4];
+        connection.stream.read_exact(&mut message).unwrap();
+        assert_eq!(&message, b"ping");
+        match connection.deferred_metadata.unwrap()() {
+            Some(ConnectionMetadata::TlsPeerCertificates { chain }) => {
+                assert_eq!(chain.len(), 1);
+                assert_eq!(chain[0], certs(&mut client_pem.as_bytes()).unwrap()[0].0);
+            }
+            _ => panic!("Expected the certificate chain of the client"),
+        }
+        done.recv().unwrap();
+
+        // Client certificate issued by another CA
+        let other_client = certificate("other client", false);
+        let done = connect(
+            address,
+            ca_pem,
+            other_client.serialize_pem_with_signer(&other_ca).unwrap(),
+            other_client.serialize_private_key_pem(),
+        );
+        let mut connection = accept(&listener);
+        assert!(connection.stream.read_exact(&mut message).is_err());
+        assert!(connection.deferred_metadata.unwrap()().is_none());
+        done.recv().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stalled_handshake_does_not_block_accept() {
+        let dir = PathBuf::from(env!("OUT_DIR").to_owned() + "/stalled_handshake");
+        let ca = certificate("Parsec test CA", true);
+        let client = certificate("client", false);
+        let listener = listener(&dir, &ca);
+        let address = listener.local_addr().unwrap();
+
+        // The stalled client connects but never starts the handshake.
+        let _stalled_client = TcpStream::connect(address).unwrap();
+        let start = Instant::now();
+        let mut stalled_connection = accept(&listener);
+        assert!(start.elapsed() < TIMEOUT);
+
+        // The clients connecting after it are still accepted.
+        let done = connect(
+            address,
+            ca.serialize_pem().unwrap(),
+            client.serialize_pem_with_signer(&ca).unwrap(),
+            client.serialize_private_key_pem(),
+        );
+        let mut connection = accept(&listener);
+        let mut message = [0; 4];
+        connection.stream.read_exact(&mut message).unwrap();
+        assert_eq!(&message, b"ping");
+        done.recv().unwrap();
+
+        // The handshake of the stalled client times out in the thread reading its connection.
+        assert!(stalled_connection.stream.read_exact(&mut message).is_err());
+        assert!(stalled_connection.deferred_metadata.unwrap()().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Service front using vsock
+//!
+//! Expose Parsec functionality over vsock, for virtual machines which need to reach the Parsec
+//! service of their host without sharing a filesystem with it. The Context Identifier (CID) of the
+//! connecting virtual machine is given as metadata of the connection.
+use super::listener;
+use anyhow::{Context, Result};
+use listener::Listen;
+use listener::{Connection, ConnectionMetadata};
+use log::error;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use vsock::SockAddr;
+
+/// CID to listen on all the CIDs of the host
+const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// vsock IPC manager
+///
+/// Listener implementation for vsock as the underlying IPC mechanism.
+#[derive(Debug)]
+pub struct VsockListener {
+    listener: vsock::VsockListener,
+    timeout: Duration,
+}
+
+impl VsockListener {
+    /// Bind to the port on the CID.
+    pub fn new(timeout: Duration, cid: u32, port: u32) -> Result<Self> {
+        let listener = vsock::VsockListener::bind_with_cid_port(cid, port)
+            .with_context(|| format!("Failed to bind to vsock CID {} port {}", cid, port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self { listener, timeout })
+    }
+}
+
+impl Listen for VsockListener {
+    fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+
+    fn accept(&self) -> Option<Connection> {
+        let stream_result = self.listener.accept();
+        match stream_result {
+            Ok((stream, address)) => {
+                if let Err(err) = stream.set_read_timeout(Some(self.timeout)) {
+                    format_error!("Failed to set read timeout", err);
+                    None
+                } else if let Err(err) = stream.set_write_timeout(Some(self.timeout)) {
+                    format_error!("Failed to set write timeout", err);
+                    None
+                } else if let Err(err) = stream.set_nonblocking(false) {
+                    format_error!("Failed to set stream as blocking", err);
+                    None
+                } else {
+                    let cid = match address {
+                        SockAddr::Vsock(address) => address.cid(),
+                        address => {
+                            error!("Unexpected address {} of a vsock peer.", address);
+                            return None;
+                        }
+                    };
+                    Some(Connection {
+                        stream: Box::new(stream),
+                        metadata: Some(ConnectionMetadata::VsockPeer { cid }),
+                        deferred_metadata: None,
+                    })
+                }
+            }
+            Err(err) => {
+                // Check if the error is because no connections are currently present.
+                if err.kind() != ErrorKind::WouldBlock {
+                    // Only log the real errors.
+                    format_error!("Failed to connect with a VsockStream", err);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Builder for `VsockListener`
+#[derive(Clone, Debug, Default)]
+pub struct VsockListenerBuilder {
+    timeout: Option<Duration>,
+    cid: Option<u32>,
+    port: Option<u32>,
+}
+
+impl VsockListenerBuilder {
+    /// Create a new VsockListener builder
+    pub fn new() -> Self {
+        VsockListenerBuilder {
+            timeout: None,
+            cid: None,
+            port: None,
+        }
+    }
+
+    /// Add a timeout on the vsock connections
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Specify the CID to listen on, all the CIDs of the host if absent
+    pub fn with_cid(mut self, cid: Option<u32>) -> Self {
+        self.cid = cid;
+        self
+    }
+
+    /// Specify the port to listen on
+    pub fn with_port(mut self, port: Option<u32>) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Build the builder into the listener
+    pub fn build(self) -> Result<VsockListener> {
+        VsockListener::new(
+            self.timeout.ok_or_else(|| {
+                error!("The listener timeout was not set.");
+                Error::new(ErrorKind::InvalidInput, "listener timeout missing")
+            })?,
+            self.cid.unwrap_or(VMADDR_CID_ANY),
+            self.port.ok_or_else(|| {
+                error!("The port of the vsock listener was not set.");
+                Error::new(ErrorKind::InvalidInput, "listener port missing")
+            })?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VsockListenerBuilder;
+    use crate::front::listener::{ConnectionMetadata, Listen};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+    use vsock::VsockStream;
+
+    /// CID of the local host, reachable with the vsock_loopback transport
+    const VMADDR_CID_LOCAL: u32 = 1;
+    const PORT: u32 = 4242;
+
+    // Binding to the local CID needs the vsock_loopback transport, which is not available on all
+    // the hosts. Run with `cargo test --features vsock-listener -- --ignored` where it is.
+    #[test]
+    #[ignore]
+    fn vsock_over_loopback() {
+        let listener = VsockListenerBuilder::new()
+            .with_timeout(Duration::from_millis(1000))
+            .with_cid(Some(VMADDR_CID_LOCAL))
+            .with_port(Some(PORT))
+            .build()
+            .expect("Failed to bind the vsock listener, is vsock_loopback loaded?");
+
+        let client_thread = thread::spawn(|| {
+            let mut stream = VsockStream::connect_with_cid_port(VMADDR_CID_LOCAL, PORT).unwrap();
+            stream.write_all(b"ping").unwrap();
+        });
+        let mut connection = loop {
+            if let Some(connection) = listener.accept() {
+                break connection;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        match connection.metadata {
+            Some(ConnectionMetadata::VsockPeer { cid }) => assert_eq!(cid, VMADDR_CID_LOCAL),
+            _ => panic!("Expected the CID of the peer"),
+        }
+        let mut message = [0; 4];
+        connection.stream.read_exact(&mut message).unwrap();
+        assert_eq!(&message, b"ping");
+        client_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Sharing of keys between applications
+//!
+//! Keys belong to the application which created them. Their owner can grant other applications
+//! the permission to use them for some operations: signing, verifying, encrypting and exporting
+//! the public key. Destroying a key or exporting it can not be granted. The grants are stored with
+//! the key info.
+//!
+//! An application refers to a key shared with it by its name, as if it was one of its own keys.
+//! Its own keys take precedence over the keys shared with it.
+//!
+//! The keys shared with each application are also kept in memory, so that the keys shared with an
+//! application are found without reading all the mappings. The mappings of a provider are only
+//! read to index them the first time a key shared in it is looked for, and again once they were
+//! changed outside of the Key Info Manager.
+use super::KeyTriple;
+use crate::authenticators::{ApplicationName, AuthenticatorType};
+use parsec_interface::requests::{Opcode, ProviderId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use zeroize::Zeroize;
+
+/// Operations on a key which can be granted to other applications
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyPermission {
+    /// Sign hashes and messages
+    Sign,
+    /// Verify signatures of hashes and messages
+    Verify,
+    /// Encrypt with asymmetric encryption or AEAD
+    Encrypt,
+    /// Export the public key
+    ExportPublic,
+}
+
+const KEY_PERMISSIONS: [KeyPermission; 4] = [
+    KeyPermission::Sign,
+    KeyPermission::Verify,
+    KeyPermission::Encrypt,
+    KeyPermission::ExportPublic,
+];
+
+impl KeyPermission {
+    /// Returns the permission needed to perform an operation on a key shared with the
+    /// application, or `None` if the operation can not be granted.
+    pub fn required_by(opcode: Opcode) -> Option<KeyPermission> {
+        match opcode {
+            Opcode::PsaSignHash | Opcode::PsaSignMessage => Some(KeyPermission::Sign),
+            Opcode::PsaVerifyHash | Opcode::PsaVerifyMessage => Some(KeyPermission::Verify),
+            Opcode::PsaAsymmetricEncrypt | Opcode::PsaAeadEncrypt => Some(KeyPermission::Encrypt),
+            Opcode::PsaExportPublicKey => Some(KeyPermission::ExportPublic),
+            _ => None,
+        }
+    }
+
+    /// Bit of the permission in the stored grants
+    fn bit(self) -> u8 {
+        match self {
+            KeyPermission::Sign => 1,
+            KeyPermission::Verify => 1 << 1,
+            KeyPermission::Encrypt => 1 << 2,
+            KeyPermission::ExportPublic => 1 << 3,
+        }
+    }
+}
+
+impl FromStr for KeyPermission {
+    type Err = String;
+
+    fn from_str(permission: &str) -> Result<Self, Self::Err> {
+        match permission {
+            "Sign" => Ok(KeyPermission::Sign),
+            "Verify" => Ok(KeyPermission::Verify),
+            "Encrypt" => Ok(KeyPermission::Encrypt),
+            "ExportPublic" => Ok(KeyPermission::ExportPublic),
+            _ => Err(format!("unknown key permission \"{}\"", permission)),
+        }
+    }
+}
+
+/// Permissions on a key granted to an application
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Zeroize)]
+pub struct KeyGrant {
+    app_name: String,
+    auth_type: u8,
+    permissions: u8,
+}
+
+impl KeyGrant {
+    /// Creates a grant of the permissions to the application.
+    pub(super) fn new(app_name: &ApplicationName, permissions: &[KeyPermission]) -> KeyGrant {
+        KeyGrant {
+            app_name: app_name.to_string(),
+            auth_type: u8::from(app_name.authenticator_type()),
+            permissions: permissions
+                .iter()
+                .fold(0, |bits, permission| bits | permission.bit()),
+        }
+    }
+
+    /// Get the application the permissions are granted to
+    ///
+    /// Returns `None` if its authenticator is not supported by this version of the service.
+    pub fn app_name(&self) -> Option<ApplicationName> {
+        AuthenticatorType::try_from(self.auth_type)
+            .ok()
+            .map(|authenticator_type| {
+                ApplicationName::new(self.app_name.clone(), authenticator_type)
+            })
+    }
+
+    /// Get the permissions granted
+    pub fn permissions(&self) -> Vec<KeyPermission> {
+        KEY_PERMISSIONS
+            .iter()
+            .copied()
+            .filter(|permission| self.permissions & permission.bit() != 0)
+            .collect()
+    }
+
+    /// Checks if the grant is for the application.
+    pub(super) fn is_for(&self, app_name: &ApplicationName) -> bool {
+        self.app_name == app_name.as_str()
+            && self.auth_type == u8::from(app_name.authenticator_type())
+    }
+
+    /// Checks if the grant gives the permission to the application.
+    pub(super) fn allows(&self, app_name: &ApplicationName, permission: KeyPermission) -> bool {
+        self.is_for(app_name) && self.permissions & permission.bit() != 0
+    }
+}
+
+/// Keys shared with other applications, by provider
+#[derive(Debug, Default)]
+pub(super) struct GrantIndex {
+    providers: HashMap<ProviderId, ProviderGrants>,
+}
+
+/// Keys of a provider shared with other applications
+#[derive(Debug)]
+struct ProviderGrants {
+    /// External changes of the Key Info Manager when the provider was indexed
+    external_changes: u64,
+    /// Keys shared with each application
+    shared_keys: HashMap<ApplicationName, HashSet<KeyTriple>>,
+}
+
+impl GrantIndex {
+    /// Checks if the provider was indexed since the given number of external changes of the Key
+    /// Info Manager.
+    pub(super) fn is_indexed(&self, provider_id: ProviderId, external_changes: u64) -> bool {
+        self.providers.get(&provider_id).map_or(false, |provider| {
+            provider.external_changes == external_changes
+        })
+    }
+
+    /// Replaces the keys of a provider shared with other applications, read after the given
+    /// number of external changes of the Key Info Manager.
+    pub(super) fn index(
+        &mut self,
+        provider_id: ProviderId,
+        external_changes: u64,
+        shared_keys: HashMap<ApplicationName, HashSet<KeyTriple>>,
+    ) {
+        let _ = self.providers.insert(
+            provider_id,
+            ProviderGrants {
+                external_changes,
+                shared_keys,
+            },
+        );
+    }
+
+    /// Sets whether a key is shared with the application. The keys of the providers not indexed
+    /// yet are left to be read when they are.
+    pub(super) fn set(&mut self, key_triple: &KeyTriple, app_name: &ApplicationName, shared: bool) {
+        if let Some(provider) = self.providers.get_mut(&key_triple.provider_id) {
+            if shared {
+                let _ = provider
+                    .shared_keys
+                    .entry(app_name.clone())
+                    .or_default()
+                    .insert(key_triple.clone());
+            } else if let Some(keys) = provider.shared_keys.get_mut(app_name) {
+                let _ = keys.remove(key_triple);
+            }
+        }
+    }
+
+    /// Forgets the applications a key is shared with, once it is removed.
+    pub(super) fn remove(&mut self, key_triple: &KeyTriple) {
+        if let Some(provider) = self.providers.get_mut(&key_triple.provider_id) {
+            for keys in provider.shared_keys.values_mut() {
+                let _ = keys.remove(key_triple);
+            }
+        }
+    }
+
+    /// Moves the keys of the application `from` in a provider to the application `to`.
+    pub(super) fn transfer(
+        &mut self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) {
+        if let Some(provider) = self.providers.get_mut(&provider_id) {
+            for keys in provider.shared_keys.values_mut() {
+                let moved: Vec<KeyTriple> = keys
+                    .iter()
+                    .filter(|key_triple| key_triple.app_name() == from)
+                    .cloned()
+                    .collect();
+                for key_triple in moved.iter() {
+                    let _ = keys.remove(key_triple);
+                    let _ = keys.insert(key_triple.with_app_name(to.clone()));
+                }
+            }
+        }
+    }
+
+    /// Returns the keys of a provider named `key_name` shared with the application.
+    pub(super) fn shared_keys(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+        key_name: &str,
+    ) -> Vec<KeyTriple> {
+        self.providers
+            .get(&provider_id)
+            .and_then(|provider| provider.shared_keys.get(app_name))
+            .map(|keys| {
+                keys.iter()
+                    .filter(|key_triple| key_triple.key_name() == key_name)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forgets the keys shared in all the providers, which are indexed again when needed.
+    pub(super) fn clear(&mut self) {
+        self.providers.clear();
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Portable archive of the mappings of a Key Info Manager
+//!
+//! An archive is a single file containing all the key triple to key info mappings of a Key Info
+//! Manager, grouped in one section per provider. It does not depend on the way the Key Info
+//! Manager stores the mappings and can be restored into any of them.
+//!
+//! The file starts with the `ARCHIVE_MAGIC` bytes and the archive format version on one byte,
+//! followed by the sections serialised with bincode and by the SHA-256 checksum of all the
+//! preceding bytes. The key info are stored in the same versioned format as in the Key Info
+//! Managers, but never encrypted: the archive contains the same secrets as the mappings (for
+//! example the authentication values of the TPM keys) and must be protected as such. It is
+//! created readable by its owner only.
+//!
+//! Archives of the first format version do not record the authenticator type of the
+//! applications: their mappings are restored in the namespace of the authenticator type given
+//! when they are read.
+use super::format::{deserialize_key_info, serialize_key_info};
+use super::{KeyInfo, KeyTriple};
+use crate::authenticators::{ApplicationName, AuthenticatorType};
+use anyhow::{Context, Result};
+use derivative::Derivative;
+use parsec_interface::requests::ProviderId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zeroize::Zeroize;
+
+/// Bytes starting an archive file
+const ARCHIVE_MAGIC: &[u8; 4] = b"PKIA";
+/// Current version of the archive format
+const ARCHIVE_FORMAT_VERSION: u8 = 2;
+/// Version of the archive format not recording the authenticator type of the applications
+const LEGACY_ARCHIVE_FORMAT_VERSION: u8 = 1;
+/// Size of the SHA-256 checksum, in bytes
+const CHECKSUM_SIZE: usize = 32;
+
+/// Mapping of the archive, the provider being the one of its section
+#[derive(Serialize, Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct ArchivedMapping {
+    app_name: String,
+    auth_type: u8,
+    key_name: String,
+    /// Key info in the stored format, not encrypted
+    key_info: Vec<u8>,
+}
+
+/// Mappings of one provider
+#[derive(Serialize, Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct ProviderSection {
+    provider_id: u8,
+    mappings: Vec<ArchivedMapping>,
+}
+
+/// Mapping of an archive of the legacy format version
+#[derive(Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct LegacyArchivedMapping {
+    app_name: String,
+    key_name: String,
+    key_info: Vec<u8>,
+}
+
+/// Mappings of one provider in an archive of the legacy format version
+#[derive(Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct LegacyProviderSection {
+    provider_id: u8,
+    mappings: Vec<LegacyArchivedMapping>,
+}
+
+impl LegacyProviderSection {
+    /// Converts the section to the current format version, the applications being authenticated
+    /// by the given authenticator type.
+    fn upgrade(&self, authenticator_type: AuthenticatorType) -> ProviderSection {
+        ProviderSection {
+            provider_id: self.provider_id,
+            mappings: self
+                .mappings
+                .iter()
+                .map(|mapping| ArchivedMapping {
+                    app_name: mapping.app_name.clone(),
+                    auth_type: u8::from(authenticator_type),
+                    key_name: mapping.key_name.clone(),
+                    key_info: mapping.key_info.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Converts the authenticator type of an archived mapping.
+fn archived_authenticator_type(mapping: &ArchivedMapping) -> Result<AuthenticatorType, String> {
+    AuthenticatorType::try_from(mapping.auth_type).map_err(|e| e.to_string())
+}
+
+/// Result of the restoration of an archive
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Number of mappings restored in the Key Info Manager
+    pub restored: usize,
+    /// Restored mappings which were quarantined because their key does not exist in the provider
+    pub missing_keys: Vec<KeyTriple>,
+    /// Providers having mappings in the archive but which are not configured to use the Key Info
+    /// Manager: their keys could not be checked
+    pub unchecked_providers: Vec<ProviderId>,
+}
+
+/// Portable archive of the mappings of a Key Info Manager
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub struct KeyInfoArchive {
+    #[derivative(Debug = "ignore")]
+    sections: Vec<ProviderSection>,
+}
+
+impl KeyInfoArchive {
+    /// Adds a mapping to the section of its provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the key info could not be serialised.
+    pub(super) fn add(&mut self, key_triple: &KeyTriple, key_info: &KeyInfo) -> Result<(), String> {
+        let provider_id = key_triple.provider_id as u8;
+        let mapping = ArchivedMapping {
+            app_name: key_triple.app_name.to_string(),
+            auth_type: u8::from(key_triple.authenticator_type()),
+            key_name: key_triple.key_name.clone(),
+            key_info: serialize_key_info(key_triple, key_info, None)?,
+        };
+
+        match self
+            .sections
+            .iter_mut()
+            .find(|section| section.provider_id == provider_id)
+        {
+            Some(section) => section.mappings.push(mapping),
+            None => self.sections.push(ProviderSection {
+                provider_id,
+                mappings: vec![mapping],
+            }),
+        }
+
+        Ok(())
+    }
+
+    /// Returns all the mappings of the archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if a section has an invalid provider, if a mapping has an
+    /// invalid authenticator type or if a key info could not be deserialised.
+    pub(super) fn mappings(&self) -> Result<Vec<(KeyTriple, KeyInfo)>, String> {
+        let mut mappings = Vec::new();
+        for section in self.sections.iter() {
+            let provider_id =
+                ProviderId::try_from(section.provider_id).map_err(|e| e.to_string())?;
+            for mapping in section.mappings.iter() {
+                let key_triple = KeyTriple::new(
+                    ApplicationName::new(
+                        mapping.app_name.clone(),
+                        archived_authenticator_type(mapping)?,
+                    ),
+                    provider_id,
+                    mapping.key_name.clone(),
+                );
+                let key_info = deserialize_key_info(&key_triple, &mapping.key_info, None)?;
+                mappings.push((key_triple, key_info));
+            }
+        }
+
+        Ok(mappings)
+    }
+
+    /// Returns the key triples of all the mappings of the archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a section has an invalid provider or if a mapping has an invalid
+    /// authenticator type.
+    pub fn key_triples(&self) -> Result<Vec<KeyTriple>> {
+        let mut key_triples = Vec::new();
+        for section in self.sections.iter() {
+            let provider_id = ProviderId::try_from(section.provider_id)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            for mapping in section.mappings.iter() {
+                let authenticator_type = archived_authenticator_type(mapping)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                key_triples.push(KeyTriple::new(
+                    ApplicationName::new(mapping.app_name.clone(), authenticator_type),
+                    provider_id,
+                    mapping.key_name.clone(),
+                ));
+            }
+        }
+
+        Ok(key_triples)
+    }
+
+    /// Returns the number of mappings in the archive.
+    pub fn len(&self) -> usize {
+        self.sections
+            .iter()
+            .map(|section| section.mappings.len())
+            .sum()
+    }
+
+    /// Checks if the archive does not contain any mapping.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the archive to a new file at the given path, readable by its owner only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the archive could not be serialised or written.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut data = ARCHIVE_MAGIC.to_vec();
+        data.push(ARCHIVE_FORMAT_VERSION);
+        data.extend_from_slice(&bincode::serialize(&self.sections)?);
+        let checksum = Sha256::digest(&data);
+        data.extend_from_slice(&checksum);
+
+        // The archive is written under another name first so that a crash can not leave a
+        // partial archive behind.
+        let temp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .with_context(|| format!("Failed to create the archive at {:?}", temp_path))?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        data.zeroize();
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to create the archive at {:?}", path))?;
+
+        Ok(())
+    }
+
+    /// Reads an archive from the file at the given path and verifies its checksum. The mappings
+    /// of an archive of the legacy format version are given the `legacy_authenticator_type`
+    /// authenticator type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be read, if it is not an archive in a supported
+    /// version or if its checksum does not match.
+    pub fn read(
+        path: &Path,
+        legacy_authenticator_type: AuthenticatorType,
+    ) -> Result<KeyInfoArchive> {
+        let mut data =
+            fs::read(path).with_context(|| format!("Failed to read the archive at {:?}", path))?;
+        let header_size = ARCHIVE_MAGIC.len() + 1;
+        if data.len() < header_size + CHECKSUM_SIZE || !data.starts_with(ARCHIVE_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, "not a key info archive").into());
+        }
+        let version = data[ARCHIVE_MAGIC.len()];
+        if version != ARCHIVE_FORMAT_VERSION && version != LEGACY_ARCHIVE_FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported key info archive version {}", version),
+            )
+            .into());
+        }
+
+        let (content, checksum) = data.split_at(data.len() - CHECKSUM_SIZE);
+        if Sha256::digest(content).as_slice() != checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the checksum of the key info archive does not match, it is corrupted",
+            )
+            .into());
+        }
+        let sections = if version == LEGACY_ARCHIVE_FORMAT_VERSION {
+            let legacy_sections: Vec<LegacyProviderSection> =
+                bincode::deserialize(&content[header_size..])?;
+            legacy_sections
+                .iter()
+                .map(|section| section.upgrade(legacy_authenticator_type))
+                .collect()
+        } else {
+            bincode::deserialize(&content[header_size..])?
+        };
+        data.zeroize();
+
+        Ok(KeyInfoArchive { sections })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{KeyInfo, KeyInfoManagerFactory, KeyTriple};
+    use super::{KeyInfoArchive, ARCHIVE_MAGIC, LEGACY_ARCHIVE_FORMAT_VERSION};
+    use crate::authenticators::ApplicationName;
+    use crate::utils::config::{KeyInfoManagerConfig, KeyInfoManagerType};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{AuthType, ProviderId};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn test_key_info(id: u8) -> KeyInfo {
+        KeyInfo {
+            id: vec![id, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::Aes,
+                bits: 128,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: true,
+                        decrypt: true,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+                },
+            },
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        }
+    }
+
+    fn test_archive() -> (KeyInfoArchive, Vec<(KeyTriple, KeyInfo)>) {
+        let mappings = vec![
+            (
+                KeyTriple::new(
+                    ApplicationName::new("app 😎".to_string(), AuthType::Direct),
+                    ProviderId::MbedCrypto,
+                    "key one".to_string(),
+                ),
+                test_key_info(1),
+            ),
+            (
+                KeyTriple::new(
+                    ApplicationName::new("app 😎".to_string(), AuthType::Direct),
+                    ProviderId::Tpm,
+                    "key two".to_string(),
+                ),
+                test_key_info(2),
+            ),
+            (
+                KeyTriple::new(
+                    ApplicationName::new("other app".to_string(), AuthType::Direct),
+                    ProviderId::MbedCrypto,
+                    "key three".to_string(),
+                ),
+                test_key_info(3),
+            ),
+        ];
+        let mut archive = KeyInfoArchive::default();
+        for (key_triple, key_info) in mappings.iter() {
+            archive.add(key_triple, key_info).unwrap();
+        }
+
+        (archive, mappings)
+    }
+
+    #[test]
+    fn write_read_archive() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/write_read_archive.pkia");
+        let (archive, mappings) = test_archive();
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.sections.len(), 2);
+
+        archive.write(&path).unwrap();
+        let archive = KeyInfoArchive::read(&path, AuthType::Direct.into()).unwrap();
+        let read_mappings = archive.mappings().unwrap();
+        assert_eq!(read_mappings.len(), mappings.len());
+        for mapping in mappings.iter() {
+            assert!(read_mappings.contains(mapping));
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn backup_restore_key_info_manager() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/backup_restore_mappings");
+        let new_factory = |name: &str| {
+            KeyInfoManagerFactory::new(
+                &KeyInfoManagerConfig {
+                    name: name.to_string(),
+                    manager_type: KeyInfoManagerType::OnDisk,
+                    store_path: Some(path.join(name).to_str().unwrap().to_string()),
+                    encryption: None,
+                    shared: None,
+                    watch: None,
+                },
+                None,
+            )
+            .unwrap()
+        };
+        let (archive, mappings) = test_archive();
+
+        let source = new_factory("source");
+        assert_eq!(source.restore(&archive).unwrap(), 3);
+        let backup = source.backup().unwrap();
+        assert_eq!(backup.len(), 3);
+
+        let destination = new_factory("destination");
+        assert_eq!(destination.restore(&backup).unwrap(), 3);
+        let restored_mappings = destination.backup().unwrap().mappings().unwrap();
+        for mapping in mappings.iter() {
+            assert!(restored_mappings.contains(mapping));
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_archive_is_rejected() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/corrupted_archive.pkia");
+        let (archive, _) = test_archive();
+        archive.write(&path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        data[10] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+        let _ = KeyInfoArchive::read(&path, AuthType::Direct.into()).unwrap_err();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_archive_is_read() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/legacy_archive.pkia");
+        let (archive, mappings) = test_archive();
+
+        // Archive written before the authenticator type was recorded.
+        let legacy_sections: Vec<(u8, Vec<(String, String, Vec<u8>)>)> = archive
+            .sections
+            .iter()
+            .map(|section| {
+                let legacy_mappings = section
+                    .mappings
+                    .iter()
+                    .map(|mapping| {
+                        (
+                            mapping.app_name.clone(),
+                            mapping.key_name.clone(),
+                            mapping.key_info.clone(),
+                        )
+                    })
+                    .collect();
+                (section.provider_id, legacy_mappings)
+            })
+            .collect();
+        let mut data = ARCHIVE_MAGIC.to_vec();
+        data.push(LEGACY_ARCHIVE_FORMAT_VERSION);
+        data.extend_from_slice(&bincode::serialize(&legacy_sections).unwrap());
+        let checksum = Sha256::digest(&data);
+        data.extend_from_slice(&checksum);
+        fs::write(&path, &data).unwrap();
+
+        let archive = KeyInfoArchive::read(&path, AuthType::Direct.into()).unwrap();
+        let read_mappings = archive.mappings().unwrap();
+        assert_eq!(read_mappings.len(), mappings.len());
+        for mapping in mappings.iter() {
+            assert!(read_mappings.contains(mapping));
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
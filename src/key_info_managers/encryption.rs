@@ -0,0 +1,284 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! At-rest encryption of the key info mappings
+//!
+//! When enabled, the key info stored by a Key Info Manager is encrypted and authenticated with
+//! AES-256-GCM under a storage key. The key triple of the mapping is used as additional
+//! authenticated data so that the content of two mappings can not be swapped.
+//!
+//! The storage key is randomly generated the first time and is only written to disk after having
+//! been sealed by a provider implementing the `SealStorageKey` trait. Without access to that
+//! provider (for example the TPM of the machine), a copy of the mappings can not be decrypted.
+use super::KeyTriple;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use anyhow::{Context, Result};
+use derivative::Derivative;
+use log::info;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Bytes prefixing an encrypted key info. A plain bincode-serialised key info starts with the
+/// length of the key ID as a 64 bits integer, which would have to be unrealistically large to
+/// start with those bytes.
+const ENCRYPTED_KEY_INFO_MAGIC: &[u8; 4] = b"PKE1";
+/// Size of the storage key, in bytes
+const STORAGE_KEY_SIZE: usize = 32;
+/// Size of the AES-GCM nonce, in bytes
+const NONCE_SIZE: usize = 12;
+
+/// Sealing of the storage key
+///
+/// Interface implemented by the providers which can protect the storage key of the mappings
+/// with a key that they hold.
+pub trait SealStorageKey {
+    /// Seals the storage key so that it can only be recovered with this provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the provider failed to seal the key.
+    fn seal(&self, storage_key: &[u8]) -> Result<Vec<u8>>;
+
+    /// Recovers a storage key sealed with the `seal` method.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the provider failed to unseal the key, for example if it was sealed on
+    /// another machine.
+    fn unseal(&self, sealed_storage_key: &[u8]) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+/// Encrypts and decrypts the key info stored by a Key Info Manager
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(super) struct MappingCipher {
+    #[derivative(Debug = "ignore")]
+    storage_key: Zeroizing<Vec<u8>>,
+}
+
+impl MappingCipher {
+    /// Unseals the storage key stored at `sealed_storage_key_path` or, if that file does not
+    /// exist, generates a new storage key and stores it there once sealed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sealed storage key could not be read, unsealed or created.
+    pub(super) fn load_or_create(
+        sealed_storage_key_path: &Path,
+        sealer: &dyn SealStorageKey,
+    ) -> Result<MappingCipher> {
+        let storage_key = if sealed_storage_key_path.exists() {
+            let sealed_storage_key = fs::read(sealed_storage_key_path).with_context(|| {
+                format!(
+                    "Failed to read the sealed storage key at {:?}",
+                    sealed_storage_key_path
+                )
+            })?;
+            let storage_key = sealer
+                .unseal(&sealed_storage_key)
+                .context("Failed to unseal the storage key of the mappings")?;
+            if storage_key.len() != STORAGE_KEY_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "invalid storage key size").into());
+            }
+            storage_key
+        } else {
+            info!(
+                "Generating a new storage key for the mappings, sealed at {:?}.",
+                sealed_storage_key_path
+            );
+            let mut storage_key = Zeroizing::new(vec![0; STORAGE_KEY_SIZE]);
+            getrandom::getrandom(&mut storage_key).map_err(|e| {
+                format_error!("Failed to generate the storage key", e);
+                Error::new(ErrorKind::Other, "failed to generate the storage key")
+            })?;
+            let sealed_storage_key = sealer
+                .seal(&storage_key)
+                .context("Failed to seal the storage key of the mappings")?;
+
+            if let Some(parent) = sealed_storage_key_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // The sealed key is written under another name first so that a crash can not leave
+            // a partial file behind.
+            let temp_path = sealed_storage_key_path.with_extension("tmp");
+            fs::write(&temp_path, &sealed_storage_key)?;
+            fs::File::open(&temp_path)?.sync_all()?;
+            fs::rename(&temp_path, sealed_storage_key_path).with_context(|| {
+                format!(
+                    "Failed to write the sealed storage key at {:?}",
+                    sealed_storage_key_path
+                )
+            })?;
+            storage_key
+        };
+
+        Ok(MappingCipher { storage_key })
+    }
+
+    /// Checks if the stored data is an encrypted key info.
+    pub(super) fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(ENCRYPTED_KEY_INFO_MAGIC)
+    }
+
+    /// Encrypts a serialised key info, bound to its key triple.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the encryption failed.
+    pub(super) fn encrypt(
+        &self,
+        key_triple: &KeyTriple,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let mut nonce = [0; NONCE_SIZE];
+        getrandom::getrandom(&mut nonce).map_err(|e| e.to_string())?;
+        let aad = additional_data(key_triple)?;
+
+        let ciphertext = Aes256Gcm::new(GenericArray::from_slice(&self.storage_key))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| String::from("Failed to encrypt the key info"))?;
+
+        let mut data =
+            Vec::with_capacity(ENCRYPTED_KEY_INFO_MAGIC.len() + NONCE_SIZE + ciphertext.len());
+        data.extend_from_slice(ENCRYPTED_KEY_INFO_MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+
+        Ok(data)
+    }
+
+    /// Decrypts an encrypted key info and checks that it belongs to the given key triple.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the data is malformed or if its authentication failed.
+    pub(super) fn decrypt(
+        &self,
+        key_triple: &KeyTriple,
+        data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, String> {
+        if !MappingCipher::is_encrypted(data)
+            || data.len() < ENCRYPTED_KEY_INFO_MAGIC.len() + NONCE_SIZE
+        {
+            return Err(String::from("The key info is not encrypted"));
+        }
+        let (nonce, ciphertext) = data[ENCRYPTED_KEY_INFO_MAGIC.len()..].split_at(NONCE_SIZE);
+        let aad = additional_data(key_triple)?;
+
+        Aes256Gcm::new(GenericArray::from_slice(&self.storage_key))
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| String::from("Failed to authenticate and decrypt the key info"))
+    }
+}
+
+/// Additional authenticated data binding an encrypted key info to its key triple. The elements
+/// are length-prefixed so that two different triples can not give the same data.
+///
+/// The type of the authenticator is not part of the data: it was added to the key triples after
+/// the mappings could be encrypted, and the mappings stored before are moved to the namespace of
+/// an authenticator without being decrypted.
+fn additional_data(key_triple: &KeyTriple) -> Result<Vec<u8>, String> {
+    bincode::serialize(&(
+        key_triple.app_name.as_str(),
+        key_triple.provider_id as u8,
+        key_triple.key_name.as_str(),
+    ))
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::KeyTriple;
+    use super::{MappingCipher, SealStorageKey};
+    use crate::authenticators::ApplicationName;
+    use anyhow::Result;
+    use parsec_interface::requests::{AuthType, ProviderId};
+    use std::fs;
+    use std::path::PathBuf;
+    use zeroize::Zeroizing;
+
+    /// Not a real sealing: only used to check that the sealer is called.
+    struct XorSealer;
+
+    impl SealStorageKey for XorSealer {
+        fn seal(&self, storage_key: &[u8]) -> Result<Vec<u8>> {
+            Ok(storage_key.iter().map(|byte| byte ^ 0xff).collect())
+        }
+
+        fn unseal(&self, sealed_storage_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+            Ok(Zeroizing::new(
+                sealed_storage_key.iter().map(|byte| byte ^ 0xff).collect(),
+            ))
+        }
+    }
+
+    fn new_key_triple(key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct),
+            ProviderId::Tpm,
+            key_name.to_string(),
+        )
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/encrypt_decrypt/sealed_key");
+        let cipher = MappingCipher::load_or_create(&path, &XorSealer).unwrap();
+        let key_triple = new_key_triple("encrypt_decrypt");
+
+        let data = cipher.encrypt(&key_triple, &[0x11, 0x22, 0x33]).unwrap();
+        assert!(MappingCipher::is_encrypted(&data));
+        assert!(!data.windows(3).any(|window| window == [0x11, 0x22, 0x33]));
+        assert_eq!(
+            *cipher.decrypt(&key_triple, &data).unwrap(),
+            vec![0x11, 0x22, 0x33]
+        );
+
+        // The same storage key is unsealed the second time.
+        let cipher = MappingCipher::load_or_create(&path, &XorSealer).unwrap();
+        assert_eq!(
+            *cipher.decrypt(&key_triple, &data).unwrap(),
+            vec![0x11, 0x22, 0x33]
+        );
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn encrypted_data_is_bound_to_key_triple() {
+        let path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/encrypted_data_is_bound/sealed_key");
+        let cipher = MappingCipher::load_or_create(&path, &XorSealer).unwrap();
+
+        let data = cipher
+            .encrypt(&new_key_triple("first key"), &[0x11, 0x22, 0x33])
+            .unwrap();
+        let _ = cipher
+            .decrypt(&new_key_triple("second key"), &data)
+            .unwrap_err();
+
+        let mut tampered_data = data.clone();
+        *tampered_data.last_mut().unwrap() ^= 0x01;
+        let _ = cipher
+            .decrypt(&new_key_triple("first key"), &tampered_data)
+            .unwrap_err();
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
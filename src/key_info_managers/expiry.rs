@@ -0,0 +1,158 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Expiry of the keys
+//!
+//! Keys can be given a lifetime when they are created, from the configuration of the service, or
+//! an expiry time set by an admin afterwards. The expiry time is stored with the key info. Once it
+//! is passed, the operations using the key are refused and the key is destroyed by the key reaper
+//! of the service. Keys created before the expiry was introduced do not expire.
+//!
+//! The expiry times of the keys which expire are also kept in memory, so that the key reaper finds
+//! the expired keys without reading all the mappings. The mappings of a provider are only read to
+//! index them the first time its expired keys are looked for, and again once they were changed
+//! outside of the Key Info Manager.
+use super::metadata::now;
+use super::KeyTriple;
+use crate::authenticators::ApplicationName;
+use parsec_interface::requests::ProviderId;
+use std::collections::HashMap;
+
+/// Lifetimes given to the keys when they are created
+#[derive(Debug, Default, Clone)]
+pub struct KeyLifetimes {
+    default_lifetime: Option<u64>,
+    // Lifetimes of the applications having their own.
+    application_lifetimes: HashMap<ApplicationName, Option<u64>>,
+}
+
+impl KeyLifetimes {
+    /// Create the key lifetimes giving the default lifetime of the keys, in seconds, and the
+    /// lifetimes of the applications having their own. The keys do not expire if the lifetime is
+    /// `None`.
+    pub fn new(
+        default_lifetime: Option<u64>,
+        application_lifetimes: HashMap<ApplicationName, Option<u64>>,
+    ) -> KeyLifetimes {
+        KeyLifetimes {
+            default_lifetime,
+            application_lifetimes,
+        }
+    }
+
+    /// Returns the expiry time of a key of the application created at `created_at`, in seconds
+    /// since the Unix epoch, or `None` if it does not expire.
+    pub(super) fn expiry(&self, app_name: &ApplicationName, created_at: u64) -> Option<u64> {
+        let lifetime = match self.application_lifetimes.get(app_name) {
+            Some(lifetime) => *lifetime,
+            None => self.default_lifetime,
+        };
+
+        lifetime.map(|lifetime| created_at.saturating_add(lifetime))
+    }
+}
+
+/// Checks if a key with the given expiry time is expired.
+pub(super) fn is_expired(expires_at: Option<u64>) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= now())
+}
+
+/// Expiry times of the keys which expire, by provider
+#[derive(Debug, Default)]
+pub(super) struct ExpiryIndex {
+    providers: HashMap<ProviderId, ProviderExpiries>,
+}
+
+/// Expiry times of the keys of a provider
+#[derive(Debug)]
+struct ProviderExpiries {
+    /// External changes of the Key Info Manager when the provider was indexed
+    external_changes: u64,
+    expiries: HashMap<KeyTriple, u64>,
+}
+
+impl ExpiryIndex {
+    /// Checks if the provider was indexed since the given number of external changes of the Key
+    /// Info Manager.
+    pub(super) fn is_indexed(&self, provider_id: ProviderId, external_changes: u64) -> bool {
+        self.providers.get(&provider_id).map_or(false, |provider| {
+            provider.external_changes == external_changes
+        })
+    }
+
+    /// Replaces the expiry times of the keys of a provider, read after the given number of
+    /// external changes of the Key Info Manager.
+    pub(super) fn index(
+        &mut self,
+        provider_id: ProviderId,
+        external_changes: u64,
+        expiries: HashMap<KeyTriple, u64>,
+    ) {
+        let _ = self.providers.insert(
+            provider_id,
+            ProviderExpiries {
+                external_changes,
+                expiries,
+            },
+        );
+    }
+
+    /// Sets the expiry time of a key, which does not expire if `expires_at` is `None`. The keys of
+    /// the providers not indexed yet are left to be read when they are.
+    pub(super) fn set(&mut self, key_triple: &KeyTriple, expires_at: Option<u64>) {
+        if let Some(provider) = self.providers.get_mut(&key_triple.provider_id) {
+            match expires_at {
+                Some(expires_at) => {
+                    let _ = provider.expiries.insert(key_triple.clone(), expires_at);
+                }
+                None => {
+                    let _ = provider.expiries.remove(key_triple);
+                }
+            }
+        }
+    }
+
+    /// Moves the expiry times of the keys of the application `from` in a provider to the
+    /// application `to`.
+    pub(super) fn transfer(
+        &mut self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) {
+        if let Some(provider) = self.providers.get_mut(&provider_id) {
+            let moved: Vec<KeyTriple> = provider
+                .expiries
+                .keys()
+                .filter(|key_triple| key_triple.app_name() == from)
+                .cloned()
+                .collect();
+            for key_triple in moved.iter() {
+                if let Some(expires_at) = provider.expiries.remove(key_triple) {
+                    let _ = provider
+                        .expiries
+                        .insert(key_triple.with_app_name(to.clone()), expires_at);
+                }
+            }
+        }
+    }
+
+    /// Returns the key triples of the expired keys of a provider.
+    pub(super) fn expired(&self, provider_id: ProviderId) -> Vec<KeyTriple> {
+        self.providers
+            .get(&provider_id)
+            .map(|provider| {
+                provider
+                    .expiries
+                    .iter()
+                    .filter(|(_, expires_at)| is_expired(Some(**expires_at)))
+                    .map(|(key_triple, _)| key_triple.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forgets the expiry times of all the providers, which are indexed again when needed.
+    pub(super) fn clear(&mut self) {
+        self.providers.clear();
+    }
+}
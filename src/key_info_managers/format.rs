@@ -0,0 +1,184 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Format of the key info stored by the Key Info Managers
+//!
+//! A stored key info is wrapped in a versioned envelope: the `KEY_INFO_ENVELOPE_MAGIC` bytes,
+//! followed by the format version on one byte and by the payload. The payload is the key info
+//! serialised with bincode, followed by its metadata, its expiry time and the permissions granted
+//! on the key to other applications, also serialised with bincode, encrypted if encryption of the
+//! mappings is configured.
+//!
+//! The metadata, the expiry time and the grants are optional: payloads written before one of them
+//! was introduced end before it and are read with an empty metadata, no expiry or no grants.
+//! Services not knowing about them ignore them.
+//!
+//! Key info stored before the envelope was introduced are considered to be in version 0 and are
+//! still read. When the layout of the payload changes, the format version is increased and the
+//! decoding of the previous versions is kept here, so that the `parsec migrate-mappings` command
+//! can upgrade existing stores before support for the old versions is removed.
+//! A key info in a version newer than the one supported is never read nor modified, to protect
+//! the stores from service downgrades.
+use super::encryption::MappingCipher;
+use super::{KeyInfo, KeyTriple};
+
+/// Bytes prefixing a versioned key info. Key info stored in version 0 start either with the
+/// length of the key ID, as a 64 bits integer, or with the encryption magic bytes.
+const KEY_INFO_ENVELOPE_MAGIC: &[u8; 4] = b"PKIV";
+
+/// Current version of the stored key info format
+pub const KEY_INFO_FORMAT_VERSION: u8 = 1;
+
+/// Wraps a payload in an envelope of the current format version.
+fn wrap_in_envelope(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(KEY_INFO_ENVELOPE_MAGIC.len() + 1 + payload.len());
+    data.extend_from_slice(KEY_INFO_ENVELOPE_MAGIC);
+    data.push(KEY_INFO_FORMAT_VERSION);
+    data.extend_from_slice(payload);
+
+    data
+}
+
+/// Returns the format version and the payload of a stored key info.
+///
+/// # Errors
+///
+/// Returns an error as a String if the envelope is malformed or if its version is not supported.
+fn unwrap_envelope(data: &[u8]) -> Result<(u8, &[u8]), String> {
+    if !data.starts_with(KEY_INFO_ENVELOPE_MAGIC) {
+        return Ok((0, data));
+    }
+
+    match data.get(KEY_INFO_ENVELOPE_MAGIC.len()) {
+        Some(&version) if version > KEY_INFO_FORMAT_VERSION => Err(format!(
+            "The key info format version {} is newer than the latest supported version {}",
+            version, KEY_INFO_FORMAT_VERSION
+        )),
+        Some(&version) if version > 0 => Ok((version, &data[KEY_INFO_ENVELOPE_MAGIC.len() + 1..])),
+        _ => Err(String::from("Invalid key info envelope")),
+    }
+}
+
+/// Serialises a key info to be stored by a Key Info Manager, in the current format version. If a
+/// cipher is given, the serialised key info is encrypted and bound to its key triple.
+///
+/// # Errors
+///
+/// Returns an error as a String if the serialisation or the encryption failed.
+pub(super) fn serialize_key_info(
+    key_triple: &KeyTriple,
+    key_info: &KeyInfo,
+    cipher: Option<&MappingCipher>,
+) -> Result<Vec<u8>, String> {
+    let mut key_info_data =
+        bincode::serialize(key_info).map_err(|e| format!("Error serializing key info ({})", e))?;
+    bincode::serialize_into(&mut key_info_data, &key_info.metadata)
+        .map_err(|e| format!("Error serializing key metadata ({})", e))?;
+    bincode::serialize_into(&mut key_info_data, &key_info.expires_at)
+        .map_err(|e| format!("Error serializing key expiry ({})", e))?;
+    bincode::serialize_into(&mut key_info_data, &key_info.grants)
+        .map_err(|e| format!("Error serializing key grants ({})", e))?;
+    let payload = match cipher {
+        Some(cipher) => cipher.encrypt(key_triple, &key_info_data)?,
+        None => key_info_data,
+    };
+
+    Ok(wrap_in_envelope(&payload))
+}
+
+/// Deserialises a key info stored by a Key Info Manager, decrypting it first if it was stored
+/// encrypted. Key info stored in plaintext are still accepted when a cipher is given so that
+/// existing mappings can be migrated.
+///
+/// # Errors
+///
+/// Returns an error as a String if the format version is not supported, if the key info is
+/// encrypted and no cipher is given, if the decryption failed or if the deserialisation failed.
+pub(super) fn deserialize_key_info(
+    key_triple: &KeyTriple,
+    data: &[u8],
+    cipher: Option<&MappingCipher>,
+) -> Result<KeyInfo, String> {
+    // The payload of the versions 0 and 1 are identical.
+    let (_version, payload) = unwrap_envelope(data)?;
+    if MappingCipher::is_encrypted(payload) {
+        let cipher = cipher
+            .ok_or("The key info is encrypted but no encryption is configured for the mappings")?;
+        deserialize_payload(&cipher.decrypt(key_triple, payload)?)
+    } else {
+        deserialize_payload(payload)
+    }
+}
+
+/// Deserialises a plaintext payload: the key info, followed by its metadata, its expiry time and
+/// its grants if they were stored.
+///
+/// # Errors
+///
+/// Returns an error as a String if the deserialisation failed.
+fn deserialize_payload(mut payload: &[u8]) -> Result<KeyInfo, String> {
+    let mut key_info: KeyInfo = bincode::deserialize_from(&mut payload)
+        .map_err(|e| format!("Error deserializing key info ({})", e))?;
+    if !payload.is_empty() {
+        key_info.metadata = bincode::deserialize_from(&mut payload)
+            .map_err(|e| format!("Error deserializing key metadata ({})", e))?;
+    }
+    if !payload.is_empty() {
+        key_info.expires_at = bincode::deserialize_from(&mut payload)
+            .map_err(|e| format!("Error deserializing key expiry ({})", e))?;
+    }
+    if !payload.is_empty() {
+        key_info.grants = bincode::deserialize(payload)
+            .map_err(|e| format!("Error deserializing key grants ({})", e))?;
+    }
+
+    Ok(key_info)
+}
+
+/// Checks if the stored key info is encrypted.
+pub(super) fn is_encrypted(data: &[u8]) -> bool {
+    unwrap_envelope(data)
+        .map(|(_version, payload)| MappingCipher::is_encrypted(payload))
+        .unwrap_or(false)
+}
+
+/// Returns the reason why a stored key info can not be read by this service while not being
+/// corrupted: it was written by a newer version of the service or it is encrypted and no cipher
+/// is given. Such key info should be kept as they are.
+pub(super) fn unreadable_reason(data: &[u8], cipher: Option<&MappingCipher>) -> Option<String> {
+    if data.starts_with(KEY_INFO_ENVELOPE_MAGIC) {
+        if let Some(&version) = data.get(KEY_INFO_ENVELOPE_MAGIC.len()) {
+            if version > KEY_INFO_FORMAT_VERSION {
+                return unwrap_envelope(data).err();
+            }
+        }
+    }
+    if cipher.is_none() && is_encrypted(data) {
+        return Some(String::from(
+            "The key info is encrypted but no encryption is configured for the mappings",
+        ));
+    }
+
+    None
+}
+
+/// Upgrades a stored key info to the current format version. Encrypted key info are upgraded
+/// without being decrypted. Returns `None` if the key info is already in the current version.
+///
+/// # Errors
+///
+/// Returns an error as a String if the key info can not be read.
+pub(super) fn upgrade_key_info(data: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let (version, payload) = unwrap_envelope(data)?;
+    if version == KEY_INFO_FORMAT_VERSION {
+        return Ok(None);
+    }
+
+    // Version 0 only differs from version 1 by the absence of envelope. The plaintext payloads
+    // are still checked to not wrap corrupted data.
+    if !MappingCipher::is_encrypted(payload) {
+        let _: KeyInfo = bincode::deserialize(payload)
+            .map_err(|e| format!("Error deserializing key info ({})", e))?;
+    }
+
+    Ok(Some(wrap_in_envelope(payload)))
+}
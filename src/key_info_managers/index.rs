@@ -0,0 +1,218 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! In-memory index of the mappings
+//!
+//! The mappings are indexed by provider and then by application so that the operations on the
+//! keys of one client, such as listing them, only go through the keys of that client, and listing
+//! the clients of a provider only goes through its clients.
+//!
+//! Each provider has its own lock: modifying the mappings of a provider does not block the
+//! operations on the other providers. The index of the providers is only locked for writing the
+//! first time a provider stores a mapping.
+use super::{KeyInfo, KeyTriple};
+use crate::authenticators::ApplicationName;
+use parsec_interface::requests::ProviderId;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Mappings of the applications of a provider
+type ProviderMappings = HashMap<ApplicationName, HashMap<KeyTriple, KeyInfo>>;
+
+/// Key triple to key info mappings indexed by provider and application
+#[derive(Debug, Default)]
+pub(super) struct KeyInfoIndex {
+    providers: RwLock<HashMap<ProviderId, RwLock<ProviderMappings>>>,
+}
+
+impl KeyInfoIndex {
+    /// Returns a copy of the key info of a key triple, if it exists.
+    pub fn get(&self, key_triple: &KeyTriple) -> Option<KeyInfo> {
+        self.with_provider(key_triple.provider_id, |applications| {
+            applications
+                .get(&key_triple.app_name)?
+                .get(key_triple)
+                .cloned()
+        })
+        .flatten()
+    }
+
+    /// Returns the key triples of a provider.
+    pub fn get_all(&self, provider_id: ProviderId) -> Vec<KeyTriple> {
+        self.with_provider(provider_id, |applications| {
+            applications
+                .values()
+                .flat_map(HashMap::keys)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns the key triples of an application in a provider.
+    pub fn get_all_for_app(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Vec<KeyTriple> {
+        self.with_provider(provider_id, |applications| {
+            applications
+                .get(app_name)
+                .map(|mappings| mappings.keys().cloned().collect())
+        })
+        .flatten()
+        .unwrap_or_default()
+    }
+
+    /// Returns the applications having keys in a provider.
+    pub fn get_clients(&self, provider_id: ProviderId) -> Vec<ApplicationName> {
+        self.with_provider(provider_id, |applications| {
+            applications.keys().cloned().collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Inserts a mapping, returning the key info it replaces if any.
+    pub fn insert(&self, key_triple: KeyTriple, key_info: KeyInfo) -> Option<KeyInfo> {
+        self.with_provider_mut(key_triple.provider_id, |applications| {
+            applications
+                .entry(key_triple.app_name.clone())
+                .or_default()
+                .insert(key_triple, key_info)
+        })
+    }
+
+    /// Updates the key info of a mapping in place. Returns `false` if the mapping does not exist.
+    pub fn update(&self, key_triple: &KeyTriple, update: &mut dyn FnMut(&mut KeyInfo)) -> bool {
+        self.with_provider_mut(key_triple.provider_id, |applications| {
+            match applications
+                .get_mut(&key_triple.app_name)
+                .and_then(|mappings| mappings.get_mut(key_triple))
+            {
+                Some(key_info) => {
+                    update(key_info);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Removes a mapping and returns its key info, if it exists. Applications left without keys
+    /// are removed from the index.
+    pub fn remove(&self, key_triple: &KeyTriple) -> Option<KeyInfo> {
+        self.with_provider_mut(key_triple.provider_id, |applications| {
+            let mappings = applications.get_mut(&key_triple.app_name)?;
+            let key_info = mappings.remove(key_triple);
+            if mappings.is_empty() {
+                let _ = applications.remove(&key_triple.app_name);
+            }
+
+            key_info
+        })
+    }
+
+    /// Moves the mappings of an application in a provider to another application and returns
+    /// their number. Returns `None`, without moving any mapping, if the other application
+    /// already has a key with the same name as one of the moved keys.
+    pub fn transfer(
+        &self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> Option<usize> {
+        self.with_provider_mut(provider_id, |applications| {
+            let key_triples = match applications.get(from) {
+                Some(mappings) => mappings
+                    .keys()
+                    .map(|key_triple| key_triple.with_app_name(to.clone()))
+                    .collect::<Vec<KeyTriple>>(),
+                None => return Some(0),
+            };
+            if let Some(mappings) = applications.get(to) {
+                if key_triples
+                    .iter()
+                    .any(|key_triple| mappings.contains_key(key_triple))
+                {
+                    return None;
+                }
+            }
+
+            let mappings = applications.remove(from).unwrap_or_default();
+            let transferred = mappings.len();
+            applications.entry(to.clone()).or_default().extend(
+                mappings
+                    .into_iter()
+                    .map(|(key_triple, key_info)| (key_triple.with_app_name(to.clone()), key_info)),
+            );
+
+            Some(transferred)
+        })
+    }
+
+    /// Checks if a mapping exists.
+    pub fn contains(&self, key_triple: &KeyTriple) -> bool {
+        self.with_provider(key_triple.provider_id, |applications| {
+            applications
+                .get(&key_triple.app_name)
+                .map_or(false, |mappings| mappings.contains_key(key_triple))
+        })
+        .unwrap_or(false)
+    }
+
+    /// Returns the number of mappings.
+    pub fn count(&self) -> usize {
+        self.providers
+            .read()
+            .expect("Key index lock poisoned")
+            .values()
+            .map(|applications| {
+                applications
+                    .read()
+                    .expect("Provider mappings lock poisoned")
+                    .values()
+                    .map(HashMap::len)
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Runs a function on the mappings of a provider, holding its read lock. Returns `None` if
+    /// the provider has no mappings.
+    fn with_provider<T>(
+        &self,
+        provider_id: ProviderId,
+        f: impl FnOnce(&ProviderMappings) -> T,
+    ) -> Option<T> {
+        let providers = self.providers.read().expect("Key index lock poisoned");
+        let applications = providers
+            .get(&provider_id)?
+            .read()
+            .expect("Provider mappings lock poisoned");
+
+        Some(f(&applications))
+    }
+
+    /// Runs a function on the mappings of a provider, holding its write lock. The provider is
+    /// added to the index if it has no mappings yet.
+    fn with_provider_mut<T>(
+        &self,
+        provider_id: ProviderId,
+        f: impl FnOnce(&mut ProviderMappings) -> T,
+    ) -> T {
+        {
+            let providers = self.providers.read().expect("Key index lock poisoned");
+            if let Some(applications) = providers.get(&provider_id) {
+                return f(&mut applications
+                    .write()
+                    .expect("Provider mappings lock poisoned"));
+            }
+        }
+
+        let mut providers = self.providers.write().expect("Key index lock poisoned");
+        f(&mut providers
+            .entry(provider_id)
+            .or_default()
+            .get_mut()
+            .expect("Provider mappings lock poisoned"))
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Metadata stored alongside the key info
+//!
+//! The metadata records when and how a key was created and how it has been used since. It is
+//! meant for auditing and for finding stale keys, and is never used to decide if an operation is
+//! permitted.
+//!
+//! Mappings stored before the metadata was introduced have an empty one: their creation is
+//! unknown and their usage is only tracked from the first use after the upgrade.
+use crate::authenticators::AuthenticatorType;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+use version::version;
+use zeroize::Zeroize;
+
+/// Metadata of a key, kept by the Key Info Manager
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, Zeroize)]
+pub struct KeyMetadata {
+    created_at: Option<u64>,
+    creator_auth_type: Option<u8>,
+    provider_version: Option<String>,
+    last_used: Option<u64>,
+    use_count: u64,
+}
+
+impl KeyMetadata {
+    /// Creates the metadata of a key being created now, by a client authenticated by an
+    /// authenticator of type `creator_authenticator_type` if it is known.
+    pub(super) fn new(creator_authenticator_type: Option<AuthenticatorType>) -> KeyMetadata {
+        KeyMetadata {
+            created_at: Some(now()),
+            creator_auth_type: creator_authenticator_type.map(u8::from),
+            provider_version: Some(version!().to_string()),
+            last_used: None,
+            use_count: 0,
+        }
+    }
+
+    /// Records `count` uses of the key, the last one at `time`.
+    pub(super) fn record_uses(&mut self, count: u64, time: u64) {
+        self.use_count = self.use_count.saturating_add(count);
+        self.last_used = Some(time);
+    }
+
+    /// Get the creation time of the key, in seconds since the Unix epoch
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// Get the authenticator used by the client which created the key
+    ///
+    /// Returns `None` if it is unknown or if it is not supported by this version of the service.
+    pub fn creator_authenticator_type(&self) -> Option<AuthenticatorType> {
+        self.creator_auth_type
+            .and_then(|id| AuthenticatorType::try_from(id).ok())
+    }
+
+    /// Get the version of the service, and so of the provider implementation, which created the
+    /// key
+    pub fn provider_version(&self) -> Option<&str> {
+        self.provider_version.as_deref()
+    }
+
+    /// Get the time of the last use of the key, in seconds since the Unix epoch
+    pub fn last_used(&self) -> Option<u64> {
+        self.last_used
+    }
+
+    /// Get the number of uses of the key
+    pub fn use_count(&self) -> u64 {
+        self.use_count
+    }
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -7,22 +7,47 @@
 //! information of the keys they manage. Different implementors might store this mapping using different
 //! means but it has to be persistent.
 
-use crate::authenticators::ApplicationName;
+use crate::authenticators::{ApplicationName, AuthenticatorType};
 use crate::utils::config::{KeyInfoManagerConfig, KeyInfoManagerType};
+use acl::{GrantIndex, KeyGrant, KeyPermission};
 use anyhow::Result;
+use archive::KeyInfoArchive;
 use derivative::Derivative;
+use encryption::{MappingCipher, SealStorageKey};
+use expiry::{ExpiryIndex, KeyLifetimes};
+use log::warn;
+use metadata::KeyMetadata;
 use parsec_interface::operations::psa_key_attributes::Attributes;
 use parsec_interface::requests::{ProviderId, ResponseStatus};
+use reconciliation::{QuarantinedMapping, ReconciliationReport};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use zeroize::Zeroize;
 
+pub mod acl;
+pub mod archive;
+pub mod encryption;
+pub mod expiry;
+mod format;
+mod index;
+pub mod metadata;
 pub mod on_disk_manager;
+pub mod reconciliation;
+#[cfg(feature = "sqlite-manager")]
+pub mod sqlite_manager;
+mod store_lock;
+pub mod volatile_manager;
 
 /// This structure corresponds to a unique identifier of the key. It is used internally by the Key
 /// ID manager to refer to a key.
+///
+/// The application name is qualified by the type of its authenticator, so that the keys of
+/// applications authenticated by different authenticators never share a namespace.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct KeyTriple {
     app_name: ApplicationName,
@@ -34,8 +59,11 @@ impl fmt::Display for KeyTriple {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Application Name: \"{}\", Provider ID: {}, Key Name: \"{}\"",
-            self.app_name, self.provider_id, self.key_name
+            "Application Name: \"{}\", Authenticator: {:?}, Provider ID: {}, Key Name: \"{}\"",
+            self.app_name,
+            self.app_name.authenticator_type(),
+            self.provider_id,
+            self.key_name
         )
     }
 }
@@ -48,6 +76,15 @@ struct KeyInfo {
     id: Vec<u8>,
     /// Attributes of a key
     attributes: Attributes,
+    /// Metadata of a key. It is stored after the key info, see the `format` module.
+    #[serde(skip)]
+    metadata: KeyMetadata,
+    /// Expiry time of a key, in seconds since the Unix epoch. It is stored after the metadata.
+    #[serde(skip)]
+    expires_at: Option<u64>,
+    /// Permissions on a key granted to other applications. They are stored after the expiry time.
+    #[serde(skip)]
+    grants: Vec<KeyGrant>,
 }
 
 impl KeyTriple {
@@ -65,6 +102,11 @@ impl KeyTriple {
         self.provider_id == provider_id
     }
 
+    /// Get the provider ID
+    pub fn provider_id(&self) -> ProviderId {
+        self.provider_id
+    }
+
     /// Get the key name
     pub fn key_name(&self) -> &str {
         &self.key_name
@@ -74,6 +116,17 @@ impl KeyTriple {
     pub fn app_name(&self) -> &ApplicationName {
         &self.app_name
     }
+
+    /// Get the type of the authenticator of the application
+    pub fn authenticator_type(&self) -> AuthenticatorType {
+        self.app_name.authenticator_type()
+    }
+
+    /// Get the key triple of the key with the same name in the same provider, belonging to
+    /// another application
+    pub fn with_app_name(&self, app_name: ApplicationName) -> KeyTriple {
+        KeyTriple::new(app_name, self.provider_id, self.key_name.clone())
+    }
 }
 
 /// Converts the error string returned by the ManageKeyInfo methods to
@@ -89,21 +142,43 @@ pub fn to_response_status(error_string: String) -> ResponseStatus {
 /// Management interface for key name to key info mapping
 ///
 /// Interface to be implemented for persistent storage of key name -> key info mappings.
+///
+/// Implementations are shared between all the providers and synchronise internally, per provider
+/// and application: operations on the keys of an application in a provider must not wait for
+/// the mappings of other applications or providers to be written.
 trait ManageKeyInfo {
-    /// Returns a reference to the key info corresponding to this key triple or `None` if it does not
+    /// Returns a copy of the key info corresponding to this key triple or `None` if it does not
     /// exist.
     ///
     /// # Errors
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
-    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String>;
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
 
-    /// Returns a Vec of reference to the key triples corresponding to this provider.
+    /// Returns a Vec of the key triples corresponding to this provider.
     ///
     /// # Errors
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
-    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<&KeyTriple>, String>;
+    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<KeyTriple>, String>;
+
+    /// Returns a Vec of the key triples corresponding to this application in this provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn get_all_for_app(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Result<Vec<KeyTriple>, String>;
+
+    /// Returns a Vec of the applications having keys in this provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn get_clients(&self, provider_id: ProviderId) -> Result<Vec<ApplicationName>, String>;
 
     /// Inserts a new mapping between the key triple and the key info. If the triple already exists,
     /// overwrite the existing mapping and returns the old `KeyInfo`. Otherwise returns `None`.
@@ -111,11 +186,20 @@ trait ManageKeyInfo {
     /// # Errors
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
-    fn insert(
-        &mut self,
-        key_triple: KeyTriple,
-        key_info: KeyInfo,
-    ) -> Result<Option<KeyInfo>, String>;
+    fn insert(&self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<Option<KeyInfo>, String>;
+
+    /// Updates the key info of a key triple in place and returns `true`. Does nothing and returns
+    /// `false` if the mapping does not exist. The key info can not be modified concurrently
+    /// during the update.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn update(
+        &self,
+        key_triple: &KeyTriple,
+        update: &mut dyn FnMut(&mut KeyInfo),
+    ) -> Result<bool, String>;
 
     /// Removes a key triple mapping and returns it. Does nothing and returns `None` if the mapping
     /// does not exist.
@@ -123,7 +207,7 @@ trait ManageKeyInfo {
     /// # Errors
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
-    fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
+    fn remove(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
 
     /// Check if a key triple mapping exists.
     ///
@@ -131,8 +215,80 @@ trait ManageKeyInfo {
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
     fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String>;
+
+    /// Moves a key triple mapping to the quarantine area of the Key Info Manager, where it is
+    /// kept but not used anymore, and returns it. Does nothing and returns `None` if the mapping
+    /// does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn quarantine(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
+
+    /// Moves all the mappings of the application `from` in this provider to the application `to`,
+    /// keeping their key names and key info, and returns their number. Either all the mappings
+    /// are moved or none of them: returns `None`, without moving any mapping, if `to` already has
+    /// a key with the same name as one of the keys of `from`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn transfer(
+        &self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> Result<Option<usize>, String>;
+
+    /// Checks if the mappings were modified by another instance of the service sharing the store
+    /// since they were read, in which case they need to be reloaded. Stores which can not be
+    /// shared are never stale.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn is_stale(&self) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Discards the mappings read so far so that they are read again from the store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn reload(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns the number of times the mappings were read again or changed outside of this
+    /// manager, by another instance of the service sharing the store or by an operator, so that
+    /// what is derived from the mappings is derived again once it changes. Stores which can only
+    /// be changed through the manager never change.
+    fn external_changes(&self) -> u64 {
+        0
+    }
+}
+
+type ReconciliationReports = Arc<RwLock<HashMap<ProviderId, ReconciliationReport>>>;
+
+/// Minimum time between two writes of the usage of a key in the Key Info Manager, in seconds. Uses
+/// in between are only counted in memory so that using a key does not always imply a write.
+const KEY_USAGE_WRITE_INTERVAL: u64 = 60;
+
+/// Uses of a key not written yet in the Key Info Manager
+#[derive(Debug, Default, Copy, Clone)]
+struct KeyUsage {
+    pending_uses: u64,
+    last_used: u64,
+    last_written: u64,
 }
 
+type KeyUsages = Arc<Mutex<HashMap<KeyTriple, KeyUsage>>>;
+
+type KeyExpiries = Arc<Mutex<ExpiryIndex>>;
+
+type KeyGrants = Arc<Mutex<GrantIndex>>;
+
 /// KeyInfoManager client structure that bridges between the KIM and the providers that need
 /// to use it.
 #[derive(Derivative)]
@@ -140,19 +296,34 @@ trait ManageKeyInfo {
 pub struct KeyInfoManagerClient {
     provider_id: ProviderId,
     #[derivative(Debug = "ignore")]
-    key_info_manager_impl: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+    key_info_manager_impl: Arc<dyn ManageKeyInfo + Send + Sync>,
+    #[derivative(Debug = "ignore")]
+    reconciliation_reports: ReconciliationReports,
+    #[derivative(Debug = "ignore")]
+    key_usages: KeyUsages,
+    #[derivative(Debug = "ignore")]
+    key_expiries: KeyExpiries,
+    #[derivative(Debug = "ignore")]
+    key_grants: KeyGrants,
+    key_lifetimes: Arc<KeyLifetimes>,
 }
 
 impl KeyInfoManagerClient {
+    /// Get the ID of the provider using this client
+    pub fn provider_id(&self) -> ProviderId {
+        self.provider_id
+    }
+
     /// Get the KeyTriple representing a key.
     pub fn get_key_triple(&self, app_name: ApplicationName, key_name: String) -> KeyTriple {
         KeyTriple::new(app_name, self.provider_id, key_name)
     }
 
-    /// Get the key ID for a given key triple
+    /// Get the key ID for a given key triple, to use the key
     ///
     /// The ID does not have to be a specific type. Rather, it must implement the `serde::Deserialize`
     /// trait. Before returning, an instance of that type is created from the bytes stored by the KIM.
+    /// The use of the key is recorded in its metadata.
     ///
     /// # Errors
     ///
@@ -163,20 +334,49 @@ impl KeyInfoManagerClient {
         &self,
         key_triple: &KeyTriple,
     ) -> parsec_interface::requests::Result<T> {
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
-        let key_info = match key_info_manager_impl.get(key_triple) {
+        let key_id = self.peek_key_id(key_triple)?;
+        self.record_key_use(key_triple);
+
+        Ok(key_id)
+    }
+
+    /// Get the key ID for a given key triple without recording a use of the key
+    ///
+    /// This is meant for checks of the mappings which do not use the key, such as the
+    /// reconciliation of the mappings with the key store.
+    ///
+    /// # Errors
+    ///
+    /// Same as `get_key_id`.
+    pub fn peek_key_id<T: DeserializeOwned>(
+        &self,
+        key_triple: &KeyTriple,
+    ) -> parsec_interface::requests::Result<T> {
+        self.reload_if_stale()?;
+        let key_info = match self.key_info_manager_impl.get(key_triple) {
             Ok(Some(key_info)) => key_info,
             Ok(None) => return Err(ResponseStatus::PsaErrorDoesNotExist),
             Err(string) => return Err(to_response_status(string)),
         };
-        // The `deserialize` call below creates a new instance of T decoupled from the
-        // scope of the lock acquired above.
         Ok(bincode::deserialize(&key_info.id)?)
     }
 
+    /// Reload the mappings if another instance of the service sharing the store modified them or
+    /// if the watched store changed.
+    fn reload_if_stale(&self) -> parsec_interface::requests::Result<()> {
+        if self
+            .key_info_manager_impl
+            .is_stale()
+            .map_err(to_response_status)?
+        {
+            self.key_info_manager_impl
+                .reload()
+                .map_err(to_response_status)?;
+        }
+
+        Ok(())
+    }
+
     /// Get the `Attributes` for a given key triple
     ///
     /// # Errors
@@ -187,11 +387,8 @@ impl KeyInfoManagerClient {
         &self,
         key_triple: &KeyTriple,
     ) -> parsec_interface::requests::Result<Attributes> {
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
-        let key_info = match key_info_manager_impl.get(key_triple) {
+        self.reload_if_stale()?;
+        let key_info = match self.key_info_manager_impl.get(key_triple) {
             Ok(Some(key_info)) => key_info,
             Ok(None) => return Err(ResponseStatus::PsaErrorDoesNotExist),
             Err(string) => return Err(to_response_status(string)),
@@ -199,16 +396,84 @@ impl KeyInfoManagerClient {
         Ok(key_info.attributes)
     }
 
+    /// Get the metadata of the key represented by a key triple, including the uses not written
+    /// yet in the Key Info Manager.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn get_key_metadata(
+        &self,
+        key_triple: &KeyTriple,
+    ) -> parsec_interface::requests::Result<KeyMetadata> {
+        self.reload_if_stale()?;
+        // The usages are not locked while the key info is read, which does not block the uses of
+        // the other keys. Uses written meanwhile might be counted twice.
+        let usage = self
+            .key_usages
+            .lock()
+            .expect("Key usages lock poisoned")
+            .get(key_triple)
+            .copied();
+        let mut metadata = match self.key_info_manager_impl.get(key_triple) {
+            Ok(Some(key_info)) => key_info.metadata,
+            Ok(None) => return Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => return Err(to_response_status(string)),
+        };
+        if let Some(usage) = usage {
+            if usage.pending_uses > 0 {
+                metadata.record_uses(usage.pending_uses, usage.last_used);
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Record a use of the key represented by a key triple. The use is written in the Key Info
+    /// Manager if the last write of the usage of the key is older than `KEY_USAGE_WRITE_INTERVAL`,
+    /// otherwise it is only counted in memory.
+    ///
+    /// Failing to record a use does not fail the operation using the key: a warning is logged.
+    fn record_key_use(&self, key_triple: &KeyTriple) {
+        let now = metadata::now();
+        let (pending_uses, last_used) = {
+            let mut key_usages = self.key_usages.lock().expect("Key usages lock poisoned");
+            let usage = key_usages.entry(key_triple.clone()).or_default();
+            usage.pending_uses += 1;
+            usage.last_used = now;
+            if now < usage.last_written.saturating_add(KEY_USAGE_WRITE_INTERVAL) {
+                return;
+            }
+            // The pending uses are taken so that other threads do not write them again while
+            // they are written. The usages are not locked during the write, which does not
+            // block the other keys.
+            usage.last_written = now;
+            (std::mem::take(&mut usage.pending_uses), usage.last_used)
+        };
+
+        let result = self
+            .key_info_manager_impl
+            .update(key_triple, &mut |key_info: &mut KeyInfo| {
+                key_info.metadata.record_uses(pending_uses, last_used)
+            });
+        if let Err(string) = result {
+            format_error!("Failed to record the use of a key", string);
+            // The uses are written with the next ones. Do not retry at each use if the Key Info
+            // Manager keeps failing.
+            let mut key_usages = self.key_usages.lock().expect("Key usages lock poisoned");
+            if let Some(usage) = key_usages.get_mut(key_triple) {
+                usage.pending_uses += pending_uses;
+            }
+        }
+    }
+
     /// Get all the key triples for the current provider
     pub fn get_all(&self) -> parsec_interface::requests::Result<Vec<KeyTriple>> {
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
+        self.reload_if_stale()?;
 
-        key_info_manager_impl
+        self.key_info_manager_impl
             .get_all(self.provider_id)
-            .map(|vec| vec.into_iter().cloned().collect())
             .map_err(to_response_status)
     }
 
@@ -222,18 +487,125 @@ impl KeyInfoManagerClient {
         &self,
         key_triple: &KeyTriple,
     ) -> parsec_interface::requests::Result<()> {
-        let mut key_info_manager_impl = self
-            .key_info_manager_impl
-            .write()
-            .expect("Key Info Manager lock poisoned");
-        match key_info_manager_impl.remove(key_triple) {
+        let _ = self
+            .key_usages
+            .lock()
+            .expect("Key usages lock poisoned")
+            .remove(key_triple);
+        self.key_expiries
+            .lock()
+            .expect("Key expiries lock poisoned")
+            .set(key_triple, None);
+        self.key_grants
+            .lock()
+            .expect("Key grants lock poisoned")
+            .remove(key_triple);
+        match self.key_info_manager_impl.remove(key_triple) {
             Ok(Some(_key_info)) => Ok(()),
             Ok(None) => Err(ResponseStatus::PsaErrorDoesNotExist),
             Err(string) => Err(to_response_status(string)),
         }
     }
 
-    /// Insert key info for a given triple.
+    /// Move the mapping of the key represented by a key triple to the quarantine area of the Key
+    /// Info Manager and record it in the reconciliation report.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn quarantine_key_info(
+        &self,
+        key_triple: &KeyTriple,
+        reason: &str,
+        report: &mut ReconciliationReport,
+    ) -> parsec_interface::requests::Result<()> {
+        match self.key_info_manager_impl.quarantine(key_triple) {
+            Ok(Some(_key_info)) => {
+                self.key_expiries
+                    .lock()
+                    .expect("Key expiries lock poisoned")
+                    .set(key_triple, None);
+                self.key_grants
+                    .lock()
+                    .expect("Key grants lock poisoned")
+                    .remove(key_triple);
+                report.quarantined.push(QuarantinedMapping {
+                    key_triple: key_triple.clone(),
+                    reason: reason.to_string(),
+                });
+                Ok(())
+            }
+            Ok(None) => Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
+    /// Move all the keys of the application `from` in the provider to the application `to` and
+    /// return their number. Either all the keys are moved or none of them. The uses of the keys
+    /// not written yet are moved with them.
+    ///
+    /// # Errors
+    ///
+    /// If `from` and `to` are the same application, PsaErrorInvalidArgument is returned. If `to`
+    /// already has a key with the same name as one of the keys of `from`, PsaErrorAlreadyExists
+    /// is returned. If any other error occurs, KeyInfoManagerError is returned.
+    pub fn transfer_keys(
+        &self,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> parsec_interface::requests::Result<usize> {
+        if from == to {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        self.reload_if_stale()?;
+
+        let transferred = match self
+            .key_info_manager_impl
+            .transfer(self.provider_id, from, to)
+        {
+            Ok(Some(transferred)) => transferred,
+            Ok(None) => return Err(ResponseStatus::PsaErrorAlreadyExists),
+            Err(string) => return Err(to_response_status(string)),
+        };
+        self.key_expiries
+            .lock()
+            .expect("Key expiries lock poisoned")
+            .transfer(self.provider_id, from, to);
+        self.key_grants
+            .lock()
+            .expect("Key grants lock poisoned")
+            .transfer(self.provider_id, from, to);
+        // The usages are only locked to move them, not while the mappings are transferred.
+        let mut key_usages = self.key_usages.lock().expect("Key usages lock poisoned");
+        let moved_usages: Vec<KeyTriple> = key_usages
+            .keys()
+            .filter(|key_triple| {
+                key_triple.belongs_to_provider(self.provider_id) && key_triple.app_name() == from
+            })
+            .cloned()
+            .collect();
+        for key_triple in moved_usages.iter() {
+            if let Some(usage) = key_usages.remove(key_triple) {
+                let _ = key_usages.insert(key_triple.with_app_name(to.clone()), usage);
+            }
+        }
+
+        Ok(transferred)
+    }
+
+    /// Log the report of the reconciliation of the provider's mappings with its key store and
+    /// keep it so that it can be retrieved with `KeyInfoManagerFactory::reconciliation_reports`.
+    pub fn set_reconciliation_report(&self, report: ReconciliationReport) {
+        report.log();
+        let _ = self
+            .reconciliation_reports
+            .write()
+            .expect("Reconciliation reports lock poisoned")
+            .insert(self.provider_id, report);
+    }
+
+    /// Insert key info for a given triple. The metadata of the key records that it is created now.
     ///
     /// # Errors
     ///
@@ -245,16 +617,36 @@ impl KeyInfoManagerClient {
         key_id: &T,
         attributes: Attributes,
     ) -> parsec_interface::requests::Result<()> {
-        let mut key_info_manager_impl = self
-            .key_info_manager_impl
-            .write()
-            .expect("Key Info Manager lock poisoned");
+        let metadata = KeyMetadata::new(Some(key_triple.authenticator_type()));
+        let expires_at = self.key_lifetimes.expiry(
+            key_triple.app_name(),
+            metadata.created_at().unwrap_or_else(metadata::now),
+        );
         let key_info = KeyInfo {
             id: bincode::serialize(key_id)?,
             attributes,
+            metadata,
+            expires_at,
+            grants: Vec::new(),
         };
 
-        match key_info_manager_impl.insert(key_triple, key_info) {
+        let result = self
+            .key_info_manager_impl
+            .insert(key_triple.clone(), key_info);
+        if let Ok(previous) = &result {
+            self.key_expiries
+                .lock()
+                .expect("Key expiries lock poisoned")
+                .set(&key_triple, expires_at);
+            // The key info replaced had its own grants.
+            if previous.is_some() {
+                self.key_grants
+                    .lock()
+                    .expect("Key grants lock poisoned")
+                    .remove(&key_triple);
+            }
+        }
+        match result {
             Ok(None) => Ok(()),
             Ok(Some(_)) => Err(ResponseStatus::PsaErrorAlreadyExists),
             Err(string) => Err(to_response_status(string)),
@@ -267,22 +659,11 @@ impl KeyInfoManagerClient {
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
     pub fn list_clients(&self) -> parsec_interface::requests::Result<Vec<ApplicationName>> {
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
-        let key_triples = key_info_manager_impl
-            .get_all(self.provider_id)
-            .map_err(to_response_status)?;
-        let mut clients = Vec::new();
-
-        for key_triple in key_triples {
-            if !clients.contains(key_triple.app_name()) {
-                let _ = clients.push(key_triple.app_name().clone());
-            }
-        }
+        self.reload_if_stale()?;
 
-        Ok(clients)
+        self.key_info_manager_impl
+            .get_clients(self.provider_id)
+            .map_err(to_response_status)
     }
 
     /// Returns a Vec of the KeyInfo objects corresponding to the given application name and
@@ -297,23 +678,18 @@ impl KeyInfoManagerClient {
     ) -> parsec_interface::requests::Result<Vec<parsec_interface::operations::list_keys::KeyInfo>>
     {
         use parsec_interface::operations::list_keys::KeyInfo;
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
+        self.reload_if_stale()?;
 
         let mut keys: Vec<KeyInfo> = Vec::new();
-        let key_triples = key_info_manager_impl
-            .get_all(self.provider_id)
+        let key_triples = self
+            .key_info_manager_impl
+            .get_all_for_app(self.provider_id, app_name)
             .map_err(to_response_status)?;
 
         for key_triple in key_triples {
-            if key_triple.app_name() != app_name {
-                continue;
-            }
-
-            let key_info = key_info_manager_impl
-                .get(key_triple)
+            let key_info = self
+                .key_info_manager_impl
+                .get(&key_triple)
                 .map_err(to_response_status)?;
             let key_info = match key_info {
                 Some(key_info) => key_info,
@@ -330,6 +706,254 @@ impl KeyInfoManagerClient {
         Ok(keys)
     }
 
+    /// Check that the key represented by a key triple is not expired. Keys which do not exist
+    /// are not expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns PsaErrorNotPermitted if the key is expired or KeyInfoManagerError for another
+    /// error.
+    pub fn check_not_expired(&self, key_triple: &KeyTriple) -> Result<(), ResponseStatus> {
+        self.reload_if_stale()?;
+
+        match self.key_info_manager_impl.get(key_triple) {
+            Ok(Some(key_info)) if expiry::is_expired(key_info.expires_at) => {
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+            Ok(_) => Ok(()),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
+    /// Get the key triples of the expired keys of the provider.
+    ///
+    /// The expiry times are indexed in memory: the mappings of the provider are only read the
+    /// first time and once they were changed outside of the Key Info Manager. The index is locked
+    /// while they are read, so that the expiry times set meanwhile are not lost.
+    ///
+    /// # Errors
+    ///
+    /// Returns KeyInfoManagerError if there was a problem accessing the Key Info Manager.
+    pub fn get_expired(&self) -> parsec_interface::requests::Result<Vec<KeyTriple>> {
+        self.reload_if_stale()?;
+
+        let external_changes = self.key_info_manager_impl.external_changes();
+        let mut key_expiries = self
+            .key_expiries
+            .lock()
+            .expect("Key expiries lock poisoned");
+        if !key_expiries.is_indexed(self.provider_id, external_changes) {
+            let expiries = self
+                .get_all_key_info()?
+                .into_iter()
+                .filter_map(|(key_triple, key_info)| Some((key_triple, key_info.expires_at?)))
+                .collect();
+            key_expiries.index(self.provider_id, external_changes, expiries);
+        }
+
+        Ok(key_expiries.expired(self.provider_id))
+    }
+
+    /// Set the expiry time of the key represented by a key triple, in seconds since the Unix
+    /// epoch. The key does not expire anymore if `expires_at` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn set_key_expiry(
+        &self,
+        key_triple: &KeyTriple,
+        expires_at: Option<u64>,
+    ) -> parsec_interface::requests::Result<()> {
+        self.reload_if_stale()?;
+
+        match self
+            .key_info_manager_impl
+            .update(key_triple, &mut |key_info: &mut KeyInfo| {
+                key_info.expires_at = expires_at
+            }) {
+            Ok(true) => {
+                self.key_expiries
+                    .lock()
+                    .expect("Key expiries lock poisoned")
+                    .set(key_triple, expires_at);
+                Ok(())
+            }
+            Ok(false) => Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
+    /// Grant permissions on the key represented by a key triple to another application, replacing
+    /// the permissions granted to it before. The permissions of the application are revoked if
+    /// `permissions` is empty.
+    ///
+    /// # Errors
+    ///
+    /// If the application is the owner of the key, PsaErrorInvalidArgument is returned. If the key
+    /// does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn grant_key_access(
+        &self,
+        key_triple: &KeyTriple,
+        app_name: &ApplicationName,
+        permissions: &[KeyPermission],
+    ) -> parsec_interface::requests::Result<()> {
+        if key_triple.app_name() == app_name {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        self.reload_if_stale()?;
+
+        match self
+            .key_info_manager_impl
+            .update(key_triple, &mut |key_info: &mut KeyInfo| {
+                key_info.grants.retain(|grant| !grant.is_for(app_name));
+                if !permissions.is_empty() {
+                    key_info.grants.push(KeyGrant::new(app_name, permissions));
+                }
+            }) {
+            Ok(true) => {
+                self.key_grants
+                    .lock()
+                    .expect("Key grants lock poisoned")
+                    .set(key_triple, app_name, !permissions.is_empty());
+                Ok(())
+            }
+            Ok(false) => Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
+    /// Get the permissions on the key represented by a key triple granted to other applications.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn get_key_grants(
+        &self,
+        key_triple: &KeyTriple,
+    ) -> parsec_interface::requests::Result<Vec<KeyGrant>> {
+        self.reload_if_stale()?;
+
+        match self.key_info_manager_impl.get(key_triple) {
+            Ok(Some(key_info)) => Ok(key_info.grants.clone()),
+            Ok(None) => Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
+    /// Find the key named `key_name` of another application in the provider which is shared with
+    /// the application `app_name` with the given permission, and return its key triple.
+    ///
+    /// The keys shared with each application are indexed in memory: the mappings of the provider
+    /// are only read the first time and once they were changed outside of the Key Info Manager.
+    ///
+    /// # Errors
+    ///
+    /// If several applications share a key with that name with the application,
+    /// PsaErrorNotPermitted is returned as the key to use can not be chosen. If any other error
+    /// occurs, KeyInfoManagerError is returned.
+    pub fn find_shared_key(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+        permission: KeyPermission,
+    ) -> parsec_interface::requests::Result<Option<KeyTriple>> {
+        self.reload_if_stale()?;
+
+        let external_changes = self.key_info_manager_impl.external_changes();
+        let candidates = {
+            let mut key_grants = self.key_grants.lock().expect("Key grants lock poisoned");
+            if !key_grants.is_indexed(self.provider_id, external_changes) {
+                let mut shared_keys: HashMap<ApplicationName, HashSet<KeyTriple>> = HashMap::new();
+                for (key_triple, key_info) in self.get_all_key_info()? {
+                    for grantee in key_info.grants.iter().filter_map(KeyGrant::app_name) {
+                        let _ = shared_keys
+                            .entry(grantee)
+                            .or_default()
+                            .insert(key_triple.clone());
+                    }
+                }
+                key_grants.index(self.provider_id, external_changes, shared_keys);
+            }
+            key_grants.shared_keys(self.provider_id, app_name, key_name)
+        };
+
+        // Only the permissions of the keys shared with the application are read.
+        let mut shared_keys = Vec::new();
+        for key_triple in candidates {
+            if let Some(key_info) = self
+                .key_info_manager_impl
+                .get(&key_triple)
+                .map_err(to_response_status)?
+            {
+                if key_info
+                    .grants
+                    .iter()
+                    .any(|grant| grant.allows(app_name, permission))
+                {
+                    shared_keys.push(key_triple);
+                }
+            }
+        }
+
+        if shared_keys.len() > 1 {
+            warn!(
+                "{} applications share a key named \"{}\" with application \"{}\", none of \
+                 them is used.",
+                shared_keys.len(),
+                key_name,
+                app_name
+            );
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+
+        Ok(shared_keys.pop())
+    }
+
+    /// Reads the key info of all the keys of the provider, to index them.
+    ///
+    /// # Errors
+    ///
+    /// Returns KeyInfoManagerError if there was a problem accessing the Key Info Manager.
+    fn get_all_key_info(&self) -> parsec_interface::requests::Result<Vec<(KeyTriple, KeyInfo)>> {
+        let mut key_infos = Vec::new();
+        for key_triple in self
+            .key_info_manager_impl
+            .get_all(self.provider_id)
+            .map_err(to_response_status)?
+        {
+            if let Some(key_info) = self
+                .key_info_manager_impl
+                .get(&key_triple)
+                .map_err(to_response_status)?
+            {
+                key_infos.push((key_triple, key_info));
+            }
+        }
+
+        Ok(key_infos)
+    }
+
+    /// Returns the number of keys of the given application in the provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns KeyInfoManagerError if there was a problem accessing the Key Info Manager.
+    pub fn count_keys(
+        &self,
+        app_name: &ApplicationName,
+    ) -> parsec_interface::requests::Result<usize> {
+        self.reload_if_stale()?;
+
+        self.key_info_manager_impl
+            .get_all_for_app(self.provider_id, app_name)
+            .map(|key_triples| key_triples.len())
+            .map_err(to_response_status)
+    }
+
     /// Check if a key triple exists in the Key Info Manager and return a ResponseStatus
     ///
     /// # Errors
@@ -337,12 +961,10 @@ impl KeyInfoManagerClient {
     /// Returns PsaErrorAlreadyExists if the key triple already exists or KeyInfoManagerError for
     /// another error.
     pub fn does_not_exist(&self, key_triple: &KeyTriple) -> Result<(), ResponseStatus> {
-        let key_info_manager_impl = self
-            .key_info_manager_impl
-            .read()
-            .expect("Key Info Manager lock poisoned");
+        self.reload_if_stale()?;
 
-        if key_info_manager_impl
+        if self
+            .key_info_manager_impl
             .exists(key_triple)
             .map_err(to_response_status)?
         {
@@ -358,32 +980,282 @@ impl KeyInfoManagerClient {
 #[derivative(Debug)]
 pub struct KeyInfoManagerFactory {
     #[derivative(Debug = "ignore")]
-    key_info_manager_impl: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+    key_info_manager_impl: Arc<dyn ManageKeyInfo + Send + Sync>,
+    #[derivative(Debug = "ignore")]
+    reconciliation_reports: ReconciliationReports,
+    #[derivative(Debug = "ignore")]
+    key_usages: KeyUsages,
+    #[derivative(Debug = "ignore")]
+    key_expiries: KeyExpiries,
+    #[derivative(Debug = "ignore")]
+    key_grants: KeyGrants,
+    key_lifetimes: Arc<KeyLifetimes>,
 }
 
 impl KeyInfoManagerFactory {
     /// Create a KeyInfoManagerFactory
-    pub fn new(config: &KeyInfoManagerConfig) -> Result<Self> {
-        let manager = match config.manager_type {
+    ///
+    /// If encryption of the mappings is configured, `sealer` is used to seal and unseal their
+    /// storage key and must be given.
+    ///
+    /// The mappings stored before the authenticator type was recorded are refused: they must be
+    /// upgraded with `parsec migrate-mappings` first.
+    pub fn new(config: &KeyInfoManagerConfig, sealer: Option<&dyn SealStorageKey>) -> Result<Self> {
+        let key_info_manager_impl: Arc<dyn ManageKeyInfo + Send + Sync> = match config.manager_type
+        {
             KeyInfoManagerType::OnDisk => {
-                let mut builder = on_disk_manager::OnDiskKeyInfoManagerBuilder::new();
-                if let Some(store_path) = &config.store_path {
-                    builder = builder.with_mappings_dir_path(store_path.into());
+                let mappings_dir_path = store_path(config, on_disk_manager::DEFAULT_MAPPINGS_PATH);
+                let default_sealed_key_path =
+                    mappings_dir_path.join(on_disk_manager::SEALED_STORAGE_KEY_FILE_NAME);
+                let mut builder = on_disk_manager::OnDiskKeyInfoManagerBuilder::new()
+                    .with_mappings_dir_path(mappings_dir_path)
+                    .with_shared(config.shared.unwrap_or(false))
+                    .with_watch(config.watch.unwrap_or(false));
+                if let Some(cipher) = load_cipher(config, sealer, &default_sealed_key_path)? {
+                    builder = builder.with_cipher(cipher);
                 }
                 builder.build()?
             }
+            #[cfg(feature = "sqlite-manager")]
+            KeyInfoManagerType::SQLite => {
+                if config.shared == Some(true) || config.watch == Some(true) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "the store of the \"{}\" key info manager can not be shared nor \
+                             watched, only the OnDisk manager supports it",
+                            config.name
+                        ),
+                    )
+                    .into());
+                }
+                let database_path = store_path(config, sqlite_manager::DEFAULT_DB_PATH);
+                let default_sealed_key_path = database_path.with_extension("storage_key");
+                let mut builder = sqlite_manager::SQLiteKeyInfoManagerBuilder::new()
+                    .with_database_path(database_path);
+                if let Some(cipher) = load_cipher(config, sealer, &default_sealed_key_path)? {
+                    builder = builder.with_cipher(cipher);
+                }
+                Arc::new(builder.build()?)
+            }
+            #[cfg(not(feature = "sqlite-manager"))]
+            KeyInfoManagerType::SQLite => return Err(sqlite_manager_not_compiled(config)),
+            KeyInfoManagerType::Volatile => {
+                if config.store_path.is_some()
+                    || config.encryption.is_some()
+                    || config.shared.is_some()
+                    || config.watch.is_some()
+                {
+                    warn!(
+                        "The storage options of the \"{}\" key info manager are ignored as it \
+                             does not store the mappings.",
+                        config.name
+                    );
+                }
+                Arc::new(volatile_manager::VolatileKeyInfoManagerBuilder::new().build()?)
+            }
         };
 
         Ok(KeyInfoManagerFactory {
-            key_info_manager_impl: Arc::new(RwLock::new(manager)),
+            key_info_manager_impl,
+            reconciliation_reports: Default::default(),
+            key_usages: Default::default(),
+            key_expiries: Default::default(),
+            key_grants: Default::default(),
+            key_lifetimes: Default::default(),
         })
     }
 
+    /// Set the lifetimes given to the keys created through the clients built afterwards
+    pub fn set_key_lifetimes(&mut self, key_lifetimes: KeyLifetimes) {
+        self.key_lifetimes = Arc::new(key_lifetimes);
+    }
+
     /// Build a KeyInfoManagerClient
     pub fn build_client(&self, provider: ProviderId) -> KeyInfoManagerClient {
         KeyInfoManagerClient {
             key_info_manager_impl: self.key_info_manager_impl.clone(),
             provider_id: provider,
+            reconciliation_reports: self.reconciliation_reports.clone(),
+            key_usages: self.key_usages.clone(),
+            key_expiries: self.key_expiries.clone(),
+            key_grants: self.key_grants.clone(),
+            key_lifetimes: self.key_lifetimes.clone(),
         }
     }
+
+    /// Returns the reports of the reconciliations made by the providers using this Key Info
+    /// Manager, ordered by provider ID.
+    pub fn reconciliation_reports(&self) -> Vec<ReconciliationReport> {
+        let mut reports: Vec<ReconciliationReport> = self
+            .reconciliation_reports
+            .read()
+            .expect("Reconciliation reports lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+        reports.sort_by_key(|report| report.provider_id as u8);
+
+        reports
+    }
+
+    /// Snapshot all the mappings of the Key Info Manager into an archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mappings could not be read.
+    pub fn backup(&self) -> Result<KeyInfoArchive> {
+        let key_info_manager_impl = &self.key_info_manager_impl;
+        let mut archive = KeyInfoArchive::default();
+
+        for provider_id in (0..=u8::MAX).filter_map(|id| ProviderId::try_from(id).ok()) {
+            for key_triple in key_info_manager_impl
+                .get_all(provider_id)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            {
+                if let Some(key_info) = key_info_manager_impl
+                    .get(&key_triple)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+                {
+                    archive
+                        .add(&key_triple, &key_info)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                }
+            }
+        }
+
+        Ok(archive)
+    }
+
+    /// Restores all the mappings of an archive in the Key Info Manager. Existing mappings with
+    /// the same key triple are overwritten, the other ones are kept. Returns the number of mappings
+    /// restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the archive could not be read or if a mapping could not be stored.
+    pub fn restore(&self, archive: &KeyInfoArchive) -> Result<usize> {
+        let mappings = archive
+            .mappings()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let restored = mappings.len();
+        // The restored expiry times and grants are indexed again when needed.
+        self.key_expiries
+            .lock()
+            .expect("Key expiries lock poisoned")
+            .clear();
+        self.key_grants
+            .lock()
+            .expect("Key grants lock poisoned")
+            .clear();
+
+        for (key_triple, key_info) in mappings {
+            let _ = self
+                .key_info_manager_impl
+                .insert(key_triple, key_info)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
+        Ok(restored)
+    }
+}
+
+/// Result of the upgrade of the stored mappings to the current format version
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Number of mappings already in the current format version
+    pub up_to_date: usize,
+    /// Number of mappings upgraded, or which would be upgraded in dry-run mode
+    pub upgraded: usize,
+    /// Mappings which could not be read, with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+/// Upgrades the mappings stored by the Key Info Manager described by the configuration to the
+/// current format version, without starting it. In dry-run mode, the mappings are checked but
+/// not modified.
+///
+/// The mappings stored before the authenticator type was recorded are moved to the namespace of
+/// the given authenticator type, which should be the one the service is configured with.
+///
+/// The service must not be running while the mappings are upgraded, the stores are locked to
+/// check it.
+///
+/// # Errors
+///
+/// Returns an error if the store could not be accessed or if it is used by the service. Mappings
+/// which can not be read are reported in the `MigrationReport`.
+pub fn migrate_mappings(
+    config: &KeyInfoManagerConfig,
+    authenticator_type: AuthenticatorType,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    match config.manager_type {
+        KeyInfoManagerType::OnDisk => on_disk_manager::migrate_mappings(
+            &store_path(config, on_disk_manager::DEFAULT_MAPPINGS_PATH),
+            authenticator_type,
+            dry_run,
+        ),
+        #[cfg(feature = "sqlite-manager")]
+        KeyInfoManagerType::SQLite => sqlite_manager::migrate_mappings(
+            &store_path(config, sqlite_manager::DEFAULT_DB_PATH),
+            authenticator_type,
+            dry_run,
+        ),
+        #[cfg(not(feature = "sqlite-manager"))]
+        KeyInfoManagerType::SQLite => Err(sqlite_manager_not_compiled(config)),
+        // There is nothing stored to upgrade.
+        KeyInfoManagerType::Volatile => Ok(MigrationReport::default()),
+    }
+}
+
+/// Logs and returns the error of a SQLite Key Info Manager configured while it was not compiled.
+#[cfg(not(feature = "sqlite-manager"))]
+fn sqlite_manager_not_compiled(config: &KeyInfoManagerConfig) -> anyhow::Error {
+    log::error!(
+        "The SQLite manager of the \"{}\" key info manager was not compiled in Parsec binary.",
+        config.name
+    );
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "key info manager not compiled",
+    )
+    .into()
+}
+
+/// Returns the path where the Key Info Manager stores the mappings, or the given default one.
+fn store_path(config: &KeyInfoManagerConfig, default_store_path: &str) -> PathBuf {
+    config
+        .store_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(default_store_path))
+}
+
+/// Loads the cipher of the mappings if their encryption is configured.
+fn load_cipher(
+    config: &KeyInfoManagerConfig,
+    sealer: Option<&dyn SealStorageKey>,
+    default_sealed_key_path: &Path,
+) -> Result<Option<MappingCipher>> {
+    let encryption_config = match &config.encryption {
+        Some(encryption_config) => encryption_config,
+        None => return Ok(None),
+    };
+    let sealer = sealer.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "encryption of the mappings is configured but no storage key sealer was given",
+        )
+    })?;
+    let sealed_key_path = encryption_config
+        .sealed_key_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_sealed_key_path.to_path_buf());
+
+    Ok(Some(MappingCipher::load_or_create(
+        &sealed_key_path,
+        sealer,
+    )?))
 }
@@ -2,21 +2,56 @@
 // SPDX-License-Identifier: Apache-2.0
 //! A key info manager storing key triple to key info mapping on files on disk
 //!
-//! The path where the mappings should be stored is configurable. Because of possible data races,
-//! there should not be two instances of this manager pointing to the same mapping folder at a time.
+//! The path where the mappings should be stored is configurable. The manager locks the mappings
+//! directory for its lifetime, so that a second instance of the service pointing to the same
+//! directory refuses to start instead of corrupting the mappings. Instances configured to share
+//! the directory lock it for each modification instead, and bump a generation counter stored in the
+//! directory so that the other instances know when to read the mappings again. Shared instances
+//! only read the mapping files they do not modify: leftover temporary files, plaintext mapping
+//! files and mapping files which can not be read are left as they are.
 //! Methods modifying the mapping will also block until the modifications are done on disk to be
 //! ensured to not lose mappings.
 //! Because application and key names can contain any UTF-8 characters, those strings are converted
-//! to base64 strings so that they can be used as filenames. Because of filenames limitations, some
-//! very long UTF-8 names might not be able to be represented as a filename and will fail. For
-//! example, for operating systems having a limit of 255 characters for filenames (Unix systems),
-//! names will be limited to 188 bytes of UTF-8 characters.
+//! to base64 strings so that they can be used as filenames. Names whose base64 encoding does not
+//! fit in a filename (more than 188 bytes of UTF-8 characters for key names and 186 bytes for
+//! application names on Unix systems) are replaced by their SHA-256 hash instead, and the full
+//! names are then stored inside the mapping file, along with the key info. Names short enough keep
+//! their base64 filenames so that existing mapping directories are read unchanged.
+//! Application directories are prefixed with the type of the authenticator of the application,
+//! for example `1.dGVzdA==` for the application "test" authenticated directly. Directories written
+//! before the authenticator type was recorded are moved to the namespace of the default
+//! authenticator when the manager starts, or with `parsec migrate-mappings`.
 //! For security reasons, only the PARSEC service should have the ability to modify these files.
-use super::{KeyInfo, KeyTriple, ManageKeyInfo};
-use crate::authenticators::ApplicationName;
+//! Mapping files are written to a temporary file first which is then renamed over the final one,
+//! so that a mapping file always contains either the old or the new key info, even after a power
+//! loss. Mapping files which can not be read are moved to a quarantine directory instead of
+//! preventing the service from using the other ones. Mapping files quarantined by the providers,
+//! because they are inconsistent with their key store, are moved there as well.
+//! If encryption of the mappings is configured, the content of the mapping files is encrypted;
+//! mapping files previously written in plaintext are encrypted when they are read.
+//! The mappings directory can optionally be watched with inotify, if the `mappings-watcher`
+//! feature is compiled, so that mapping files added, replaced or removed by an operator are taken
+//! into account without restarting the service.
+//! The changed mapping files are merged into the mappings as soon as they are noticed and are
+//! validated as at startup; mapping files which can not be read are ignored, not quarantined, as
+//! they might still be being written.
+//! When the manager starts, only the application and provider directories are indexed. The
+//! mapping files of an application in a provider are read the first time one of its mappings is
+//! needed, so that the startup time and the memory used depend on the applications using the
+//! service rather than on the total number of keys.
+//! Each application directory of a provider has its own locks: writing a mapping file only blocks
+//! the operations on the keys of the same application in the same provider.
+use super::encryption::MappingCipher;
+use super::format::{self, deserialize_key_info, serialize_key_info, upgrade_key_info};
+use super::store_lock::{FileLock, LockMode};
+use super::{KeyInfo, KeyTriple, ManageKeyInfo, MigrationReport};
+use crate::authenticators::{ApplicationName, AuthenticatorType};
 use anyhow::{Context, Result};
 use log::{error, info, warn};
+use once_cell::sync::OnceCell;
 use parsec_interface::requests::ProviderId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::OsStr;
@@ -24,31 +59,219 @@ use std::fs;
 use std::fs::{DirEntry, File};
 use std::io::{Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "mappings-watcher")]
+mod watcher;
 
 /// Default path where the mapping files will be stored on disk
 pub const DEFAULT_MAPPINGS_PATH: &str = "/var/lib/parsec/mappings";
 
+/// Name of the directory, inside the mappings directory, where the mapping files that could not be
+/// read are moved to. As the dot is not part of the URL-safe base64 alphabet, it can not be
+/// mistaken for an application directory.
+pub const QUARANTINE_DIR_NAME: &str = ".quarantine";
+
+/// Prefix of the temporary files used, inside a provider directory, to atomically write a mapping
+/// file. Each temporary file has a unique name, so that instances sharing the directory do not
+/// write to the same one. As the dot is not part of the URL-safe base64 alphabet, it can not be
+/// mistaken for a key name file.
+const TEMP_FILE_PREFIX: &str = ".mapping.tmp";
+
+/// Name of the file, inside the mappings directory, where the sealed storage key is stored by
+/// default when encryption of the mappings is configured.
+pub const SEALED_STORAGE_KEY_FILE_NAME: &str = ".storage_key";
+
+/// Name of the file, inside the mappings directory, locked by the instances of the service using
+/// it: exclusively by default, or shared by the instances configured to share the directory.
+const INSTANCE_LOCK_FILE_NAME: &str = ".lock";
+
+/// Name of the file, inside the mappings directory, locked exclusively by the instances sharing
+/// the directory while they modify it.
+const WRITE_LOCK_FILE_NAME: &str = ".write_lock";
+
+/// Name of the file, inside the mappings directory, storing the number of modifications made by
+/// the instances sharing the directory.
+const GENERATION_FILE_NAME: &str = ".generation";
+
+/// Prefix of the filenames made of the hash of a name too long to be encoded in base64. As the
+/// plus sign is not part of the URL-safe base64 alphabet, it can not be mistaken for a base64
+/// encoded name.
+const HASHED_NAME_PREFIX: &str = "sha256+";
+
+/// Maximum length of a filename on most Unix filesystems
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Separator between the authenticator type and the application name in the name of an
+/// application directory. The dot is neither part of the URL-safe base64 alphabet nor of the
+/// hashed names.
+const AUTH_TYPE_SEPARATOR: char = '.';
+
+/// Maximum length of the authenticator type prefix of an application directory name
+const AUTH_TYPE_PREFIX_MAX_LEN: usize = 4;
+
+/// Content of a mapping file whose path contains a hashed name. The full names can not be
+/// recovered from the path and are stored next to the key info.
+#[derive(Serialize, Deserialize)]
+struct NamedMapping {
+    app_name: String,
+    key_name: String,
+    key_info: Vec<u8>,
+}
+
+/// Mappings of an application in a provider, read from the disk the first time one of them is
+/// needed.
+#[derive(Debug)]
+struct ProviderDirectory {
+    /// Directory of the mapping files: `mappings_dir_path/app_name/provider_id`
+    path: PathBuf,
+    /// Mappings read from the mapping files of the directory
+    mappings: OnceCell<RwLock<HashMap<KeyTriple, KeyInfo>>>,
+    /// Held while the directory is modified, so that its mapping files are written one at a time
+    /// and in the same order as the mappings are updated in memory.
+    write_lock: Mutex<()>,
+}
+
+impl ProviderDirectory {
+    /// Creates a directory whose mapping files are not read yet.
+    fn unread(path: PathBuf) -> ProviderDirectory {
+        ProviderDirectory {
+            path,
+            mappings: OnceCell::new(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Creates a directory without mapping files yet.
+    fn empty(path: PathBuf) -> ProviderDirectory {
+        let mappings = OnceCell::new();
+        let _ = mappings.set(RwLock::new(HashMap::new()));
+        ProviderDirectory {
+            path,
+            mappings,
+            write_lock: Mutex::new(()),
+        }
+    }
+}
+
+/// Provider directories indexed by provider and by application directory name
+type KeyStore = HashMap<ProviderId, HashMap<String, Arc<ProviderDirectory>>>;
+
 /// A key info manager storing key triple to key info mapping on files on disk
 #[derive(Debug)]
 pub struct OnDiskKeyInfoManager {
-    /// Internal mapping, used for non-modifying operations.
-    key_store: HashMap<KeyTriple, KeyInfo>,
+    /// Internal mapping, used for non-modifying operations. The index of the directories is only
+    /// locked to find or add a directory, each directory then has its own locks.
+    key_store: RwLock<KeyStore>,
     /// Folder where all the key triple to key info mappings are saved. This folder will be created
     /// if it does already exist.
     mappings_dir_path: PathBuf,
+    /// Cipher used to encrypt the mapping files, if encryption is configured.
+    cipher: Option<MappingCipher>,
+    /// Whether the mappings directory is shared with other instances of the service.
+    shared: bool,
+    /// Generation of the shared mappings directory when it was indexed or last modified by this
+    /// manager.
+    generation: AtomicU64,
+    /// Number of times the mappings were indexed again or changed by the watcher.
+    external_changes: AtomicU64,
+    /// Whether the mappings directory is watched for mapping files modified outside of the
+    /// service.
+    watched: AtomicBool,
+    /// Mapping files changed by the manager whose events were not received yet by the watcher,
+    /// with the number of changes.
+    own_changes: Mutex<HashMap<PathBuf, usize>>,
+    /// Lock on the mappings directory, held for the lifetime of the manager.
+    _instance_lock: FileLock,
+}
+
+/// Converts an application or key name to a filename: its base64 encoding or, if that is longer
+/// than `max_len`, its SHA-256 hash.
+fn name_to_filename(name: &str, max_len: usize) -> String {
+    let base64_name = base64::encode_config(name.as_bytes(), base64::URL_SAFE);
+    if base64_name.len() <= max_len {
+        base64_name
+    } else {
+        format!(
+            "{}{:x}",
+            HASHED_NAME_PREFIX,
+            Sha256::digest(name.as_bytes())
+        )
+    }
+}
+
+/// Converts an application name into the name of its directory, prefixed with the type of its
+/// authenticator.
+fn app_name_to_dir_name(app_name: &ApplicationName) -> String {
+    format!(
+        "{}{}{}",
+        u8::from(app_name.authenticator_type()),
+        AUTH_TYPE_SEPARATOR,
+        name_to_filename(app_name, MAX_FILE_NAME_LEN - AUTH_TYPE_PREFIX_MAX_LEN)
+    )
+}
+
+/// Splits the name of an application directory into the authenticator type and the filename of
+/// the application name. Returns `None` if the name is not prefixed with a valid authenticator
+/// type, as the directories written before the authenticator type was recorded.
+fn split_app_dir_name(app_name_file_name: &str) -> Option<(AuthenticatorType, &str)> {
+    let mut parts = app_name_file_name.splitn(2, AUTH_TYPE_SEPARATOR);
+    let id = parts.next()?.parse::<u8>().ok()?;
+    let authenticator_type = AuthenticatorType::try_from(id).ok()?;
+
+    Some((authenticator_type, parts.next()?))
 }
 
-/// Encodes a KeyTriple's data into base64 strings that can be used as filenames.
-/// The ProviderId will not be converted as a base64 as it can always be represented as a String
-/// being a number from 0 and 255.
-fn key_triple_to_base64_filenames(key_triple: &KeyTriple) -> (String, String, String) {
+/// Checks if an application directory name is one written before the authenticator type was
+/// recorded: a base64 encoded or hashed name without any prefix.
+fn is_legacy_app_dir_name(app_name_file_name: &str) -> bool {
+    split_app_dir_name(app_name_file_name).is_none()
+        && (app_name_file_name.starts_with(HASHED_NAME_PREFIX)
+            || base64_data_to_string(app_name_file_name.as_bytes()).is_ok())
+}
+
+/// Converts a KeyTriple's data into the filenames of its mapping file.
+fn key_triple_to_filenames(key_triple: &KeyTriple) -> (String, String, String) {
     (
-        base64::encode_config(key_triple.app_name.as_bytes(), base64::URL_SAFE),
+        app_name_to_dir_name(&key_triple.app_name),
         (key_triple.provider_id as u8).to_string(),
-        base64::encode_config(key_triple.key_name.as_bytes(), base64::URL_SAFE),
+        name_to_filename(&key_triple.key_name, MAX_FILE_NAME_LEN),
     )
 }
 
+/// Checks if the application directory or the key name file of the mapping file located at
+/// `mappings_dir_path/app_name/provider_id/key_name` is named after a hash.
+fn has_hashed_name(key_name_file_path: &Path) -> bool {
+    let is_hashed = |path: Option<&Path>| {
+        path.and_then(Path::file_name)
+            .and_then(OsStr::to_str)
+            .map(|name| {
+                let name = split_app_dir_name(name).map_or(name, |(_, name)| name);
+                name.starts_with(HASHED_NAME_PREFIX)
+            })
+            .unwrap_or(false)
+    };
+
+    is_hashed(Some(key_name_file_path))
+        || is_hashed(key_name_file_path.parent().and_then(Path::parent))
+}
+
+/// Deserialises the content of a mapping file whose path contains a hashed name.
+///
+/// # Errors
+///
+/// Returns an error as a String if the deserialisation failed.
+fn deserialize_named_mapping(data: &[u8]) -> Result<NamedMapping, String> {
+    bincode::deserialize(data).map_err(|e| {
+        format!(
+            "Error deserializing the names stored in the mapping file ({})",
+            e
+        )
+    })
+}
+
 /// Decodes base64 bytes to its original String value.
 ///
 /// # Errors
@@ -65,17 +288,18 @@ fn base64_data_to_string(base64_bytes: &[u8]) -> Result<String, String> {
 }
 
 /// Decodes key triple's data to the original path.
-/// The Provider ID data is not converted as base64.
+/// The authenticator type and Provider ID data are not converted as base64.
 ///
 /// # Errors
 ///
 /// Returns an error as a string if either the decoding or the bytes conversion to UTF-8 failed.
 fn base64_data_triple_to_key_triple(
+    authenticator_type: AuthenticatorType,
     app_name: &[u8],
     provider_id: ProviderId,
     key_name: &[u8],
 ) -> Result<KeyTriple, String> {
-    let app_name = ApplicationName::from_name(base64_data_to_string(app_name)?);
+    let app_name = ApplicationName::new(base64_data_to_string(app_name)?, authenticator_type);
     let key_name = base64_data_to_string(key_name)?;
 
     Ok(KeyTriple {
@@ -148,12 +372,278 @@ fn list_files(path: &Path) -> std::io::Result<Vec<PathBuf>> {
         .collect())
 }
 
+/// Checks if a file of a provider directory is a temporary file used to write a mapping file.
+fn is_temp_file_name(name: &OsStr) -> bool {
+    name.to_str()
+        .map(|name| name.starts_with(TEMP_FILE_PREFIX))
+        .unwrap_or(false)
+}
+
+/// Checks if the given directory contains mapping files, without reading them.
+fn has_mapping_files(path: &Path) -> bool {
+    list_files(path)
+        .map(|file_paths| {
+            file_paths
+                .iter()
+                .any(|file_path| !file_path.file_name().map_or(true, is_temp_file_name))
+        })
+        .unwrap_or(false)
+}
+
+/// Returns the final component of a path, as it is expected to be found in the mappings directory.
+fn file_name(path: &Path) -> std::io::Result<&OsStr> {
+    path.file_name()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Path does not contain a final component."))
+}
+
+/// Flushes the directory entries of the given directory to disk so that files created, renamed
+/// or removed in it are persisted.
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Atomically replaces the content of the file at `path` with `data`.
+///
+/// The data is written to a uniquely named temporary file in the same directory and flushed to
+/// disk before being renamed over the destination file. The directory is then flushed so that the
+/// rename itself is persisted. At any point in time, the destination file contains either its old
+/// or its new content.
+fn write_file_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let dir_path = path.parent().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "Path does not contain a parent directory.",
+        )
+    })?;
+
+    // The temporary file is removed when dropped if it could not be renamed.
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(TEMP_FILE_PREFIX)
+        .tempfile_in(dir_path)?;
+    temp_file.write_all(data)?;
+    temp_file.as_file().sync_all()?;
+    let _ = temp_file.persist(path).map_err(|e| e.error)?;
+
+    sync_dir(dir_path)
+}
+
+/// Locks the mappings directory for the lifetime of a manager: exclusively or, if it is shared
+/// with other instances of the service, shared.
+///
+/// # Errors
+///
+/// Returns an error if the directory is already used by another instance in a conflicting mode.
+fn lock_mappings_dir(mappings_dir_path: &Path, shared: bool) -> Result<FileLock> {
+    let lock_path = mappings_dir_path.join(INSTANCE_LOCK_FILE_NAME);
+    let mode = if shared {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    match FileLock::try_lock(&lock_path, mode)
+        .with_context(|| format!("Failed to lock the mappings directory at {:?}", lock_path))?
+    {
+        Some(lock) => Ok(lock),
+        None if shared => Err(Error::new(
+            ErrorKind::WouldBlock,
+            format!(
+                "the mappings directory at {:?} is used by another Parsec instance which does \
+                 not share it",
+                mappings_dir_path
+            ),
+        )
+        .into()),
+        None => Err(Error::new(
+            ErrorKind::WouldBlock,
+            format!(
+                "the mappings directory at {:?} is already used by another Parsec instance; \
+                 stop it or configure all the instances to share the directory",
+                mappings_dir_path
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Reads the generation of a shared mappings directory, which is 0 until it is first modified.
+fn read_generation(mappings_dir_path: &Path) -> std::io::Result<u64> {
+    match fs::read(mappings_dir_path.join(GENERATION_FILE_NAME)) {
+        Ok(data) => <[u8; 8]>::try_from(data.as_slice())
+            .map(u64::from_le_bytes)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid generation file")),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads and parses the mapping file at the given path, located at
+/// `mappings_dir_path/app_name/provider_id/key_name`. Also returns whether the key info was stored
+/// encrypted.
+///
+/// # Errors
+///
+/// Returns an error as a string if the file could not be read, if its path is not a valid key
+/// triple or if its content is not a valid key info.
+fn read_mapping_file(
+    key_name_file_path: &Path,
+    cipher: Option<&MappingCipher>,
+) -> Result<(KeyTriple, KeyInfo, bool), String> {
+    let provider_dir_path = key_name_file_path
+        .parent()
+        .ok_or("The key name file path should contain a parent directory.")?;
+    let app_name_dir_path = provider_dir_path
+        .parent()
+        .ok_or("The provider directory path should contain a parent directory.")?;
+
+    let mut data = Vec::new();
+    let _ = File::open(&key_name_file_path)
+        .and_then(|mut key_info_file| key_info_file.read_to_end(&mut data))
+        .map_err(|e| format!("Failed to read the mapping file ({})", e))?;
+
+    let app_name_file_name = file_name(app_name_dir_path).map_err(|e| e.to_string())?;
+    let (authenticator_type, app_name_file_name) = app_name_file_name
+        .to_str()
+        .and_then(split_app_dir_name)
+        .ok_or("The application directory is not prefixed with an authenticator type")?;
+    let provider_id =
+        os_str_to_provider_id(file_name(provider_dir_path).map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+    let key_name_file_name = file_name(key_name_file_path).map_err(|e| e.to_string())?;
+
+    let (key_triple, key_info) = if has_hashed_name(key_name_file_path) {
+        let mapping = deserialize_named_mapping(&data)?;
+        let key_triple = KeyTriple {
+            app_name: ApplicationName::new(mapping.app_name, authenticator_type),
+            provider_id,
+            key_name: mapping.key_name,
+        };
+        // The stored names must be the ones the mapping file was named after.
+        let (app_name, _, key_name) = key_triple_to_filenames(&key_triple);
+        if split_app_dir_name(&app_name) != Some((authenticator_type, app_name_file_name))
+            || key_name_file_name != key_name.as_str()
+        {
+            return Err(String::from(
+                "The names stored in the mapping file do not match its path",
+            ));
+        }
+        (key_triple, mapping.key_info)
+    } else {
+        let key_triple = base64_data_triple_to_key_triple(
+            authenticator_type,
+            app_name_file_name.as_bytes(),
+            provider_id,
+            os_str_to_u8_ref(key_name_file_name).map_err(|e| e.to_string())?,
+        )
+        .map_err(|e| format!("Failed to convert the mapping path to a key triple ({})", e))?;
+        (key_triple, data)
+    };
+    let is_encrypted = format::is_encrypted(&key_info);
+    let key_info = deserialize_key_info(&key_triple, &key_info, cipher)?;
+
+    Ok((key_triple, key_info, is_encrypted))
+}
+
+/// Returns the reason why the mapping file at the given path can not be read while not being
+/// corrupted. See `format::unreadable_reason`.
+fn unreadable_mapping_file_reason(
+    key_name_file_path: &Path,
+    cipher: Option<&MappingCipher>,
+) -> Option<String> {
+    let data = fs::read(key_name_file_path).ok()?;
+    if has_hashed_name(key_name_file_path) {
+        let mapping = deserialize_named_mapping(&data).ok()?;
+        format::unreadable_reason(&mapping.key_info, cipher)
+    } else {
+        format::unreadable_reason(&data, cipher)
+    }
+}
+
+/// Upgrades the content of the mapping file at the given path to the current format version.
+/// Returns `None` if it is already in the current version. See `format::upgrade_key_info`.
+fn upgrade_mapping_file(key_name_file_path: &Path, data: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    if !has_hashed_name(key_name_file_path) {
+        return upgrade_key_info(data);
+    }
+
+    let mut mapping = deserialize_named_mapping(data)?;
+    match upgrade_key_info(&mapping.key_info)? {
+        Some(key_info) => {
+            mapping.key_info = key_info;
+            bincode::serialize(&mapping)
+                .map(Some)
+                .map_err(|e| format!("Error serializing the mapping file ({})", e))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Moves a mapping file to the quarantine directory, keeping its path relative to the mappings
+/// directory. If a file was already quarantined under the same path, the current time is appended
+/// to the file name. Returns the new path of the file.
+fn quarantine_mapping_file(
+    mappings_dir_path: &Path,
+    key_name_file_path: &Path,
+) -> std::io::Result<PathBuf> {
+    let relative_path = key_name_file_path
+        .strip_prefix(mappings_dir_path)
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    let mut quarantine_path = mappings_dir_path
+        .join(QUARANTINE_DIR_NAME)
+        .join(relative_path);
+    if quarantine_path.exists() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let mut quarantine_file_name = file_name(&quarantine_path)?.to_os_string();
+        quarantine_file_name.push(format!(".{}", timestamp));
+        quarantine_path.set_file_name(quarantine_file_name);
+    }
+    let quarantine_parent = quarantine_path.parent().ok_or_else(|| {
+        Error::new(
+            ErrorKind::Other,
+            "Quarantine path does not contain a parent directory.",
+        )
+    })?;
+
+    fs::create_dir_all(quarantine_parent)?;
+    fs::rename(key_name_file_path, &quarantine_path)?;
+    sync_dir(quarantine_parent)?;
+    if let Some(key_name_file_parent) = key_name_file_path.parent() {
+        sync_dir(key_name_file_parent)?;
+    }
+
+    Ok(quarantine_path)
+}
+
+/// Moves a mapping file which could not be read to the quarantine directory and logs it.
+fn quarantine_unreadable_mapping_file(
+    mappings_dir_path: &Path,
+    key_name_file_path: &Path,
+    error: &str,
+) -> Result<()> {
+    format_error!("Failed to read a mapping file from disk", error);
+    let quarantine_path = quarantine_mapping_file(mappings_dir_path, key_name_file_path)
+        .with_context(|| {
+            format!(
+                "Failed to quarantine the mapping file at {:?}",
+                key_name_file_path
+            )
+        })?;
+    error!(
+        "The mapping file at {:?} could not be read and was moved to {:?}.",
+        key_name_file_path, quarantine_path
+    );
+
+    Ok(())
+}
+
 /// Filesystem-based `KeyInfoManager`
 ///
 /// The `OnDiskKeyInfoManager` relies on access control mechanisms provided by the OS for
 /// the filesystem to ensure security of the mappings.
 impl OnDiskKeyInfoManager {
-    /// Creates an instance of the on-disk manager from the mapping files. This function will
+    /// Creates an instance of the on-disk manager from the mappings directory. This function will
     /// create the mappings directory if it does not already exist.
     /// The mappings folder is composed of three levels: two levels of directory and one level
     /// of files. The key triple to key info mappings are represented on disk as the following:
@@ -174,16 +664,27 @@ impl OnDiskKeyInfoManager {
     ///
     /// where the path of a key name from the mappings directory is the key triple (application,
     /// provider, key) and the data inside the key name file is the key info serialised in binary
-    /// format.
+    /// format. If the application or key name is too long to be a filename, the full names are
+    /// stored in the key name file with the key info.
     /// Each mapping is contained in its own file to prevent the modification of one mapping
     /// impacting the other ones.
     ///
+    /// Only the application and provider directories are indexed here, the mapping files are read
+    /// when they are first needed. Mapping files found in the directory of an unknown provider
+    /// can not be indexed and are quarantined.
+    ///
+    /// If `shared` is false, the mappings directory is locked exclusively for the lifetime of the
+    /// manager. Otherwise, it can be shared with other instances of the service also sharing it.
+    ///
     /// # Errors
     ///
-    /// Returns an std::io error if the function failed reading the mapping files.
-    fn new(mappings_dir_path: PathBuf) -> Result<OnDiskKeyInfoManager> {
-        let mut key_store = HashMap::new();
-
+    /// Returns an std::io error if the function failed reading the directories or if the mappings
+    /// directory is used by another instance of the service in a conflicting mode.
+    fn new(
+        mappings_dir_path: PathBuf,
+        cipher: Option<MappingCipher>,
+        shared: bool,
+    ) -> Result<OnDiskKeyInfoManager> {
         // Will ignore if the mappings directory already exists.
         fs::create_dir_all(&mappings_dir_path).with_context(|| {
             format!(
@@ -191,66 +692,380 @@ impl OnDiskKeyInfoManager {
                 mappings_dir_path
             )
         })?;
+        let instance_lock = lock_mappings_dir(&mappings_dir_path, shared)?;
 
-        for app_name_dir_path in list_dirs(&mappings_dir_path)?.iter() {
+        let manager = OnDiskKeyInfoManager {
+            key_store: RwLock::new(HashMap::new()),
+            mappings_dir_path,
+            cipher,
+            shared,
+            generation: AtomicU64::new(0),
+            external_changes: AtomicU64::new(0),
+            watched: AtomicBool::new(false),
+            own_changes: Mutex::new(HashMap::new()),
+            _instance_lock: instance_lock,
+        };
+        manager.index()?;
+
+        Ok(manager)
+    }
+
+    /// Indexes the application and provider directories of the mappings directory, discarding
+    /// the mappings read so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns an std::io error if the function failed reading the directories.
+    fn index(&self) -> Result<()> {
+        let mut key_store = KeyStore::new();
+        let mut directories = 0;
+        let mappings_dir_path = &self.mappings_dir_path;
+
+        // Modifications made by other instances while the directories are listed will be
+        // noticed later on.
+        let generation = if self.shared {
+            read_generation(mappings_dir_path)?
+        } else {
+            0
+        };
+
+        for app_name_dir_path in list_dirs(mappings_dir_path)?.iter() {
+            let app_name_file_name = file_name(app_name_dir_path)?;
+            // The quarantine directory is not part of the mappings.
+            if app_name_file_name == QUARANTINE_DIR_NAME {
+                continue;
+            }
+            // Invalid application names are detected when the mapping files are read.
+            let app_name_file_name = app_name_file_name.to_string_lossy().into_owned();
+            if is_legacy_app_dir_name(&app_name_file_name) {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "the application directory at {:?} does not record the type of its \
+                         authenticator, run `parsec migrate-mappings` to upgrade the mappings \
+                         directory",
+                        app_name_dir_path
+                    ),
+                )
+                .into());
+            }
             for provider_dir_path in list_dirs(&app_name_dir_path)?.iter() {
-                for key_name_file_path in list_files(&provider_dir_path)?.iter() {
-                    let mut key_info = Vec::new();
-                    let mut key_info_file = File::open(&key_name_file_path).with_context(|| {
-                        format!(
-                            "Failed to open Key Info Mappings file at {:?}",
-                            key_name_file_path
-                        )
-                    })?;
-                    let _ = key_info_file.read_to_end(&mut key_info)?;
-                    let key_info = bincode::deserialize(&key_info[..]).map_err(|e| {
-                        format_error!("Error deserializing key info", e);
-                        Error::new(ErrorKind::Other, "error deserializing key info")
-                    })?;
-                    match base64_data_triple_to_key_triple(
-                        os_str_to_u8_ref(app_name_dir_path.file_name().expect(
-                            "The application name directory path should contain a final component.",
-                        ))?,
-                        os_str_to_provider_id(provider_dir_path.file_name().expect(
-                            "The provider directory path should contain a final component.",
-                        ))?,
-                        os_str_to_u8_ref(key_name_file_path.file_name().expect(
-                            "The key name directory path should contain a final component.",
-                        ))?,
-                    ) {
-                        Ok(key_triple) => {
-                            if crate::utils::GlobalConfig::log_error_details() {
-                                warn!(
-                                    "Inserting Key Triple ({}) mapping read from disk.",
-                                    key_triple.clone()
-                                );
-                            }
-                            let _ = key_store.insert(key_triple, key_info);
-                        }
-                        Err(string) => {
-                            format_error!(
-                                "Failed to convert the mapping path found to an UTF-8 string",
-                                string
-                            );
-                            return Err(
-                                Error::new(ErrorKind::Other, "error parsing mapping path").into()
-                            );
+                match os_str_to_provider_id(file_name(provider_dir_path)?) {
+                    Ok(provider_id) => {
+                        let _ = key_store.entry(provider_id).or_default().insert(
+                            app_name_file_name.clone(),
+                            Arc::new(ProviderDirectory::unread(provider_dir_path.clone())),
+                        );
+                        directories += 1;
+                    }
+                    Err(e) if self.shared => warn!(
+                        "The invalid provider directory at {:?} is ignored ({}).",
+                        provider_dir_path, e
+                    ),
+                    Err(e) => {
+                        for key_name_file_path in list_files(&provider_dir_path)?.iter() {
+                            quarantine_unreadable_mapping_file(
+                                mappings_dir_path,
+                                key_name_file_path,
+                                &e.to_string(),
+                            )?;
                         }
                     }
                 }
             }
         }
 
-        if !crate::utils::GlobalConfig::log_error_details() {
-            info!("Found {} mapping files", key_store.len());
+        info!(
+            "Found {} application directories in providers, their mapping files are read when first needed",
+            directories
+        );
+        *self.key_store.write().expect("Key store lock poisoned") = key_store;
+        self.generation.store(generation, Ordering::SeqCst);
+        let _ = self.external_changes.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Returns the provider directory of an application, if it exists.
+    fn directory(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Option<Arc<ProviderDirectory>> {
+        self.key_store
+            .read()
+            .expect("Key store lock poisoned")
+            .get(&provider_id)?
+            .get(&app_name_to_dir_name(app_name))
+            .cloned()
+    }
+
+    /// Returns the provider directories of a provider.
+    fn directories(&self, provider_id: ProviderId) -> Vec<(String, Arc<ProviderDirectory>)> {
+        self.key_store
+            .read()
+            .expect("Key store lock poisoned")
+            .get(&provider_id)
+            .map(|directories| {
+                directories
+                    .iter()
+                    .map(|(app_name_file_name, directory)| {
+                        (app_name_file_name.clone(), directory.clone())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the provider directory of an application, adding it if it does not exist yet.
+    fn directory_or_insert(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Arc<ProviderDirectory> {
+        if let Some(directory) = self.directory(provider_id, app_name) {
+            return directory;
         }
 
-        Ok(OnDiskKeyInfoManager {
-            key_store,
-            mappings_dir_path,
+        let app_name_file_name = app_name_to_dir_name(app_name);
+        let provider_dir_path = self
+            .mappings_dir_path
+            .join(&app_name_file_name)
+            .join((provider_id as u8).to_string());
+        self.key_store
+            .write()
+            .expect("Key store lock poisoned")
+            .entry(provider_id)
+            .or_default()
+            .entry(app_name_file_name)
+            .or_insert_with(|| Arc::new(ProviderDirectory::empty(provider_dir_path)))
+            .clone()
+    }
+
+    /// Runs a modification of the mappings of the directory of a key triple, holding its write
+    /// lock. The other directories can be read and modified meanwhile.
+    fn modify<T>(
+        &self,
+        key_triple: &KeyTriple,
+        modification: impl FnOnce(&RwLock<HashMap<KeyTriple, KeyInfo>>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.modify_store(|| {
+            let directory = self.directory_or_insert(key_triple.provider_id, &key_triple.app_name);
+            let _write_lock = directory
+                .write_lock
+                .lock()
+                .expect("Mappings directory lock poisoned");
+            // The existing mappings of the directory are read before it is modified.
+            self.load(&directory).and_then(modification)
         })
     }
 
+    /// Runs a modification of the mappings directory.
+    ///
+    /// If the mappings directory is shared, it is locked during the modification, the mappings
+    /// are reloaded first if another instance modified them and the generation of the directory
+    /// is increased afterwards.
+    fn modify_store<T>(
+        &self,
+        modification: impl FnOnce() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let _store_lock = if self.shared {
+            let store_lock = FileLock::lock(
+                &self.mappings_dir_path.join(WRITE_LOCK_FILE_NAME),
+                LockMode::Exclusive,
+            )
+            .map_err(|e| format!("Failed to lock the mappings directory ({})", e))?;
+            if self.generation_changed()? {
+                self.index().map_err(|e| e.to_string())?;
+            }
+            Some(store_lock)
+        } else {
+            None
+        };
+
+        let result = modification()?;
+
+        if self.shared {
+            // The other instances read the mappings again once the modification is done.
+            let generation = read_generation(&self.mappings_dir_path)
+                .map_err(|e| format!("Failed to read the mappings generation ({})", e))?
+                .wrapping_add(1);
+            write_file_atomically(
+                &self.mappings_dir_path.join(GENERATION_FILE_NAME),
+                &generation.to_le_bytes(),
+            )
+            .map_err(|e| format!("Failed to write the mappings generation ({})", e))?;
+            self.generation.store(generation, Ordering::SeqCst);
+        }
+
+        Ok(result)
+    }
+
+    /// Makes a change to the file at the given path, recording it first so that the watcher
+    /// skips its event instead of reading the file again.
+    fn own_change<T, E>(&self, path: &Path, change: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        if !self.watched.load(Ordering::SeqCst) {
+            return change();
+        }
+
+        *self
+            .own_changes
+            .lock()
+            .expect("Own changes lock poisoned")
+            .entry(path.to_path_buf())
+            .or_default() += 1;
+        let result = change();
+        if result.is_err() {
+            // Failed changes are not seen by the watcher.
+            let _ = self.take_own_change(path);
+        }
+
+        result
+    }
+
+    /// Checks if the file at the given path was changed by the manager, forgetting that change.
+    fn take_own_change(&self, path: &Path) -> bool {
+        let mut own_changes = self.own_changes.lock().expect("Own changes lock poisoned");
+        match own_changes.get_mut(path) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    let _ = own_changes.remove(path);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks if another instance sharing the mappings directory modified it since this manager
+    /// indexed or modified it.
+    fn generation_changed(&self) -> Result<bool, String> {
+        if !self.shared {
+            return Ok(false);
+        }
+
+        read_generation(&self.mappings_dir_path)
+            .map(|generation| generation != self.generation.load(Ordering::SeqCst))
+            .map_err(|e| format!("Failed to read the mappings generation ({})", e))
+    }
+
+    /// Reads the mapping files of a provider directory. Mapping files which can not be read are
+    /// quarantined and mapping files stored in plaintext are encrypted if encryption is
+    /// configured.
+    ///
+    /// If the mappings directory is shared, the mapping files are only read, as other instances
+    /// could be writing them: mapping files which can not be read are ignored and mapping files
+    /// stored in plaintext are kept as they are, until an instance uses the directory exclusively.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the directory could not be read or if it contains mapping
+    /// files which can not be read without being corrupted: encrypted mapping files while no
+    /// cipher was given or mapping files written in a newer format version. They are not
+    /// quarantined as that would make all the keys inaccessible when the encryption is missing
+    /// from the configuration or when the service was downgraded.
+    fn read_provider_dir(
+        &self,
+        provider_dir_path: &Path,
+    ) -> Result<HashMap<KeyTriple, KeyInfo>, String> {
+        let mut mappings = HashMap::new();
+        let mut quarantined = 0;
+
+        let key_name_file_paths = match list_files(provider_dir_path) {
+            Ok(key_name_file_paths) => key_name_file_paths,
+            // The directory was removed since the manager started.
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(mappings),
+            Err(e) => return Err(format!("Failed to list the mapping files ({})", e)),
+        };
+        for key_name_file_path in key_name_file_paths.iter() {
+            if key_name_file_path
+                .file_name()
+                .map_or(false, is_temp_file_name)
+            {
+                // A temporary file left behind means that the service stopped while writing
+                // it: the mapping file it was meant to replace is still intact.
+                if !self.shared {
+                    warn!(
+                        "Removing the incomplete mapping file left at {:?}.",
+                        key_name_file_path
+                    );
+                    fs::remove_file(&key_name_file_path).map_err(|e| e.to_string())?;
+                }
+                continue;
+            }
+
+            match read_mapping_file(key_name_file_path, self.cipher.as_ref()) {
+                Ok((key_triple, key_info, is_encrypted)) => {
+                    if crate::utils::GlobalConfig::log_error_details() {
+                        warn!(
+                            "Inserting Key Triple ({}) mapping read from disk.",
+                            key_triple.clone()
+                        );
+                    }
+                    if self.cipher.is_some() && !is_encrypted && !self.shared {
+                        info!(
+                            "Encrypting the mapping file at {:?} stored in plaintext.",
+                            key_name_file_path
+                        );
+                        self.save_mapping(&key_triple, &key_info)
+                            .map_err(|e| e.to_string())?;
+                    }
+                    let _ = mappings.insert(key_triple, key_info);
+                }
+                Err(string) => {
+                    if let Some(reason) =
+                        unreadable_mapping_file_reason(key_name_file_path, self.cipher.as_ref())
+                    {
+                        return Err(format!(
+                            "the mapping file at {:?} can not be read ({})",
+                            key_name_file_path, reason
+                        ));
+                    }
+                    if self.shared {
+                        format_error!("Failed to read a mapping file from disk", string);
+                        warn!(
+                            "The mapping file at {:?} can not be read and is ignored.",
+                            key_name_file_path
+                        );
+                        continue;
+                    }
+                    self.own_change(key_name_file_path, || {
+                        quarantine_unreadable_mapping_file(
+                            &self.mappings_dir_path,
+                            key_name_file_path,
+                            &string,
+                        )
+                    })
+                    .map_err(|e| e.to_string())?;
+                    quarantined += 1;
+                }
+            }
+        }
+
+        if quarantined > 0 {
+            warn!(
+                "{} mapping files could not be read and were moved to {:?}. The keys they \
+                 reference are not accessible until they are restored.",
+                quarantined,
+                self.mappings_dir_path.join(QUARANTINE_DIR_NAME)
+            );
+        }
+
+        Ok(mappings)
+    }
+
+    /// Returns the mappings of a provider directory, reading them if it is the first time they
+    /// are needed. Only the readers of this directory wait for it to be read.
+    fn load<'a>(
+        &self,
+        directory: &'a ProviderDirectory,
+    ) -> Result<&'a RwLock<HashMap<KeyTriple, KeyInfo>>, String> {
+        directory
+            .mappings
+            .get_or_try_init(|| self.read_provider_dir(&directory.path).map(RwLock::new))
+    }
+
     /// Saves the key triple to key info mapping in its own file.
     /// The filename will be `mappings/[APP_NAME]/[PROVIDER_NAME]/[KEY_NAME]` under the same path as the
     /// on-disk manager. It will contain the Key info data.
@@ -261,97 +1076,485 @@ impl OnDiskKeyInfoManager {
                 key_triple.clone()
             );
         }
-        // Create the directories with base64 names.
-        let (app_name, prov, key_name) = key_triple_to_base64_filenames(key_triple);
-        let provider_dir_path = self.mappings_dir_path.join(app_name).join(prov);
+        // Create the directories with base64 or hashed names.
+        let (app_name, prov, key_name) = key_triple_to_filenames(key_triple);
+        let app_name_dir_path = self.mappings_dir_path.join(app_name);
+        let provider_dir_path = app_name_dir_path.join(prov);
         let key_name_file_path = provider_dir_path.join(key_name);
-        // Will ignore if they already exist.
-        fs::create_dir_all(&provider_dir_path)?;
-
-        if key_name_file_path.exists() {
-            fs::remove_file(&key_name_file_path)?;
+        if !provider_dir_path.exists() {
+            fs::create_dir_all(&provider_dir_path)?;
+            // Persist the new directory entries.
+            sync_dir(&app_name_dir_path)?;
+            sync_dir(&self.mappings_dir_path)?;
         }
 
-        let mut mapping_file = fs::File::create(&key_name_file_path).map_err(|e| {
+        let key_info =
+            serialize_key_info(key_triple, key_info, self.cipher.as_ref()).map_err(|e| {
+                format_error!("Error serializing key info", e);
+                Error::new(ErrorKind::Other, "error serializing key info")
+            })?;
+        let data = if has_hashed_name(&key_name_file_path) {
+            bincode::serialize(&NamedMapping {
+                app_name: key_triple.app_name.to_string(),
+                key_name: key_triple.key_name.clone(),
+                key_info,
+            })
+            .map_err(|e| {
+                format_error!("Error serializing the mapping file", e);
+                Error::new(ErrorKind::Other, "error serializing key info")
+            })?
+        } else {
+            key_info
+        };
+        self.own_change(&key_name_file_path, || {
+            write_file_atomically(&key_name_file_path, &data)
+        })
+        .map_err(|e| {
             error!(
-                "Failed to create Key Info Mapping file at {:?}",
+                "Failed to write Key Info Mapping file at {:?}",
                 key_name_file_path
             );
             e
-        })?;
-        mapping_file.write_all(&bincode::serialize(key_info).map_err(|e| {
-            format_error!("Error serializing key info", e);
-            Error::new(ErrorKind::Other, "error serializing key info")
-        })?)
+        })
+    }
+
+    /// Returns the path of the mapping file of a key triple.
+    fn mapping_file_path(&self, key_triple: &KeyTriple) -> PathBuf {
+        let (app_name, prov, key_name) = key_triple_to_filenames(key_triple);
+        self.mappings_dir_path
+            .join(app_name)
+            .join(prov)
+            .join(key_name)
+    }
+
+    /// Moves the mapping file to the quarantine directory.
+    /// Will do nothing if the mapping file does not exist.
+    fn quarantine_mapping(&self, key_triple: &KeyTriple) -> std::io::Result<()> {
+        let key_name_file_path = self.mapping_file_path(key_triple);
+        if key_name_file_path.exists() {
+            let quarantine_path = self.own_change(&key_name_file_path, || {
+                quarantine_mapping_file(&self.mappings_dir_path, &key_name_file_path)
+            })?;
+            info!(
+                "The mapping file at {:?} was moved to {:?}.",
+                key_name_file_path, quarantine_path
+            );
+        }
+        Ok(())
     }
 
     /// Removes the mapping file.
     /// Will do nothing if the mapping file does not exist.
     fn delete_mapping(&self, key_triple: &KeyTriple) -> std::io::Result<()> {
-        let (app_name, prov, key_name) = key_triple_to_base64_filenames(key_triple);
-        let key_name_file_path = self
-            .mappings_dir_path
-            .join(app_name)
-            .join(prov)
-            .join(key_name);
+        let key_name_file_path = self.mapping_file_path(key_triple);
         if key_name_file_path.exists() {
-            fs::remove_file(key_name_file_path)
-        } else {
-            Ok(())
+            self.own_change(&key_name_file_path, || fs::remove_file(&key_name_file_path))?;
+            if let Some(provider_dir_path) = key_name_file_path.parent() {
+                sync_dir(provider_dir_path)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Writes the mapping files of the mappings under the application `to`. If one of them can
+    /// not be written, the ones already written are removed.
+    fn save_transferred_mappings(
+        &self,
+        mappings: &HashMap<KeyTriple, KeyInfo>,
+        to: &ApplicationName,
+    ) -> std::io::Result<()> {
+        let mut written = Vec::new();
+        for (key_triple, key_info) in mappings.iter() {
+            let new_key_triple = key_triple.with_app_name(to.clone());
+            if let Err(e) = self.save_mapping(&new_key_triple, key_info) {
+                for key_triple in written.iter() {
+                    let _ = self.delete_mapping(key_triple);
+                }
+                return Err(e);
+            }
+            written.push(new_key_triple);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the mapping files of the mappings, once they were written under the application
+    /// `to`. If one of them can not be removed, the ones already removed are written again and the
+    /// ones written under `to` are removed, as before the transfer.
+    fn delete_transferred_mappings(
+        &self,
+        mappings: &HashMap<KeyTriple, KeyInfo>,
+        to: &ApplicationName,
+    ) -> std::io::Result<()> {
+        let mut deleted = Vec::new();
+        for (key_triple, key_info) in mappings.iter() {
+            if let Err(e) = self.delete_mapping(key_triple) {
+                for (key_triple, key_info) in deleted.iter() {
+                    if self.save_mapping(key_triple, key_info).is_err() {
+                        error!(
+                            "The mapping of {} could not be restored after a failed transfer.",
+                            key_triple
+                        );
+                    }
+                }
+                for key_triple in mappings.keys() {
+                    let _ = self.delete_mapping(&key_triple.with_app_name(to.clone()));
+                }
+                return Err(e);
+            }
+            deleted.push((key_triple, key_info));
+        }
+
+        Ok(())
     }
 }
 
 impl ManageKeyInfo for OnDiskKeyInfoManager {
-    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String> {
-        // An Option<&Vec<u8>> can not automatically coerce to an Option<&[u8]>, it needs to be
-        // done by hand.
-        if let Some(key_info) = self.key_store.get(key_triple) {
-            Ok(Some(key_info))
-        } else {
-            Ok(None)
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        match self.directory(key_triple.provider_id, &key_triple.app_name) {
+            Some(directory) => Ok(self
+                .load(&directory)?
+                .read()
+                .expect("Mappings lock poisoned")
+                .get(key_triple)
+                .cloned()),
+            None => Ok(None),
         }
     }
 
-    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<&KeyTriple>, String> {
-        Ok(self
-            .key_store
-            .keys()
-            .filter(|key_triple| key_triple.belongs_to_provider(provider_id))
-            .collect())
+    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<KeyTriple>, String> {
+        let mut key_triples = Vec::new();
+        for (_, directory) in self.directories(provider_id).iter() {
+            key_triples.extend(
+                self.load(directory)?
+                    .read()
+                    .expect("Mappings lock poisoned")
+                    .keys()
+                    .cloned(),
+            );
+        }
+
+        Ok(key_triples)
     }
 
-    fn insert(
-        &mut self,
-        key_triple: KeyTriple,
-        key_info: KeyInfo,
-    ) -> Result<Option<KeyInfo>, String> {
-        if let Err(err) = self.save_mapping(&key_triple, &key_info) {
-            Err(err.to_string())
-        } else {
-            Ok(self.key_store.insert(key_triple, key_info))
+    fn get_all_for_app(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Result<Vec<KeyTriple>, String> {
+        match self.directory(provider_id, app_name) {
+            Some(directory) => Ok(self
+                .load(&directory)?
+                .read()
+                .expect("Mappings lock poisoned")
+                .keys()
+                .cloned()
+                .collect()),
+            None => Ok(Vec::new()),
         }
     }
 
-    fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
-        if let Err(err) = self.delete_mapping(key_triple) {
-            Err(err.to_string())
-        } else if let Some(key_info) = self.key_store.remove(key_triple) {
-            Ok(Some(key_info))
-        } else {
-            Ok(None)
+    fn get_clients(&self, provider_id: ProviderId) -> Result<Vec<ApplicationName>, String> {
+        let first_app_name = |mappings: &RwLock<HashMap<KeyTriple, KeyInfo>>| {
+            mappings
+                .read()
+                .expect("Mappings lock poisoned")
+                .keys()
+                .next()
+                .map(|key_triple| key_triple.app_name.clone())
+        };
+        let mut clients = Vec::new();
+        for (app_name_file_name, directory) in self.directories(provider_id).iter() {
+            let split_name = split_app_dir_name(app_name_file_name);
+            let app_name = match (directory.mappings.get(), split_name) {
+                (Some(mappings), _) => first_app_name(mappings),
+                // Hashed application names can only be recovered from the mapping files.
+                (None, Some((_, name))) if name.starts_with(HASHED_NAME_PREFIX) => {
+                    first_app_name(self.load(directory)?)
+                }
+                // The mapping files are not read only to list the applications.
+                (None, Some((authenticator_type, name))) if has_mapping_files(&directory.path) => {
+                    base64_data_to_string(name.as_bytes())
+                        .ok()
+                        .map(|name| ApplicationName::new(name, authenticator_type))
+                }
+                _ => None,
+            };
+            if let Some(app_name) = app_name {
+                clients.push(app_name);
+            }
         }
+
+        Ok(clients)
+    }
+
+    fn insert(&self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<Option<KeyInfo>, String> {
+        self.modify(&key_triple, |mappings| {
+            self.save_mapping(&key_triple, &key_info)
+                .map_err(|e| e.to_string())?;
+            Ok(mappings
+                .write()
+                .expect("Mappings lock poisoned")
+                .insert(key_triple.clone(), key_info))
+        })
+    }
+
+    fn update(
+        &self,
+        key_triple: &KeyTriple,
+        update: &mut dyn FnMut(&mut KeyInfo),
+    ) -> Result<bool, String> {
+        self.modify(key_triple, |mappings| {
+            let mut key_info = match mappings
+                .read()
+                .expect("Mappings lock poisoned")
+                .get(key_triple)
+            {
+                Some(key_info) => key_info.clone(),
+                None => return Ok(false),
+            };
+            update(&mut key_info);
+            self.save_mapping(key_triple, &key_info)
+                .map_err(|e| e.to_string())?;
+            let _ = mappings
+                .write()
+                .expect("Mappings lock poisoned")
+                .insert(key_triple.clone(), key_info);
+
+            Ok(true)
+        })
+    }
+
+    fn remove(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.modify(key_triple, |mappings| {
+            self.delete_mapping(key_triple).map_err(|e| e.to_string())?;
+            Ok(mappings
+                .write()
+                .expect("Mappings lock poisoned")
+                .remove(key_triple))
+        })
     }
 
     fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
-        Ok(self.key_store.contains_key(key_triple))
+        match self.directory(key_triple.provider_id, &key_triple.app_name) {
+            Some(directory) => Ok(self
+                .load(&directory)?
+                .read()
+                .expect("Mappings lock poisoned")
+                .contains_key(key_triple)),
+            None => Ok(false),
+        }
+    }
+
+    fn quarantine(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.modify(key_triple, |mappings| {
+            self.quarantine_mapping(key_triple)
+                .map_err(|e| e.to_string())?;
+            Ok(mappings
+                .write()
+                .expect("Mappings lock poisoned")
+                .remove(key_triple))
+        })
+    }
+
+    fn transfer(
+        &self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> Result<Option<usize>, String> {
+        if from == to {
+            return Err(String::from(
+                "The mappings can not be moved to the application they belong to",
+            ));
+        }
+
+        self.modify_store(|| {
+            let source = match self.directory(provider_id, from) {
+                Some(source) => source,
+                None => return Ok(Some(0)),
+            };
+            let target = self.directory_or_insert(provider_id, to);
+            // The directories are always locked in the same order so that two opposite transfers
+            // can not block each other.
+            let (first, second) = if source.path < target.path {
+                (&source, &target)
+            } else {
+                (&target, &source)
+            };
+            let _first_lock = first
+                .write_lock
+                .lock()
+                .expect("Mappings directory lock poisoned");
+            let _second_lock = second
+                .write_lock
+                .lock()
+                .expect("Mappings directory lock poisoned");
+            let mut source_mappings = self.load(&source)?.write().expect("Mappings lock poisoned");
+            let mut target_mappings = self.load(&target)?.write().expect("Mappings lock poisoned");
+            if source_mappings.keys().any(|key_triple| {
+                target_mappings.contains_key(&key_triple.with_app_name(to.clone()))
+            }) {
+                return Ok(None);
+            }
+
+            // The new mapping files are all written before the previous ones are removed: if the
+            // service stops in between, the keys are mapped by both applications, never by none.
+            // The mappings in memory are only moved once the mapping files are, which are
+            // restored if they can not all be moved.
+            self.save_transferred_mappings(&source_mappings, to)
+                .map_err(|e| e.to_string())?;
+            self.delete_transferred_mappings(&source_mappings, to)
+                .map_err(|e| e.to_string())?;
+            let moved = source_mappings.len();
+            target_mappings.extend(
+                source_mappings
+                    .drain()
+                    .map(|(key_triple, key_info)| (key_triple.with_app_name(to.clone()), key_info)),
+            );
+
+            Ok(Some(moved))
+        })
+    }
+
+    fn is_stale(&self) -> Result<bool, String> {
+        self.generation_changed()
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        if self.generation_changed()? {
+            info!("The mappings were modified by another instance, reading them again.");
+            self.index().map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn external_changes(&self) -> u64 {
+        self.external_changes.load(Ordering::SeqCst)
+    }
+}
+
+/// Upgrades all the mapping files of the mappings directory to the current format version. In
+/// dry-run mode, the mapping files are checked but not modified.
+///
+/// Application directories written before the authenticator type was recorded are moved to the
+/// namespace of the given authenticator type, their mapping files are counted as upgraded.
+///
+/// The service must not be running while the mappings are upgraded.
+///
+/// # Errors
+///
+/// Returns an std::io error if the mappings directory could not be walked through, if it is used
+/// by an instance of the service, if an upgraded mapping file could not be written or if an
+/// application directory could not be moved. Mapping files which can not be read are reported in
+/// the `MigrationReport`.
+pub fn migrate_mappings(
+    mappings_dir_path: &Path,
+    authenticator_type: AuthenticatorType,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    let _instance_lock = lock_mappings_dir(mappings_dir_path, false)?;
+
+    migrate_app_dirs(mappings_dir_path, authenticator_type, dry_run)
+}
+
+/// Upgrades the mapping files of the mappings directory, which must be locked, to the current
+/// format version, as `migrate_mappings`.
+fn migrate_app_dirs(
+    mappings_dir_path: &Path,
+    authenticator_type: AuthenticatorType,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+
+    for app_name_dir_path in list_dirs(mappings_dir_path)?.iter() {
+        let app_name_file_name = file_name(app_name_dir_path)?;
+        if app_name_file_name == QUARANTINE_DIR_NAME {
+            continue;
+        }
+        let app_name_file_name = app_name_file_name.to_string_lossy().into_owned();
+        let is_legacy = is_legacy_app_dir_name(&app_name_file_name);
+        if is_legacy && app_name_file_name.len() > MAX_FILE_NAME_LEN - AUTH_TYPE_PREFIX_MAX_LEN {
+            report.failed.push((
+                format!("{:?}", app_name_dir_path),
+                String::from(
+                    "The application name is too long to be prefixed with an authenticator type",
+                ),
+            ));
+            continue;
+        }
+
+        for provider_dir_path in list_dirs(&app_name_dir_path)?.iter() {
+            for key_name_file_path in list_files(&provider_dir_path)?.iter() {
+                if is_temp_file_name(file_name(key_name_file_path)?) {
+                    continue;
+                }
+
+                let upgraded = fs::read(key_name_file_path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| upgrade_mapping_file(key_name_file_path, &data));
+                match upgraded {
+                    Ok(None) if !is_legacy => report.up_to_date += 1,
+                    Ok(None) => report.upgraded += 1,
+                    Ok(Some(data)) => {
+                        if !dry_run {
+                            write_file_atomically(key_name_file_path, &data).with_context(
+                                || {
+                                    format!(
+                                        "Failed to write the upgraded mapping file at {:?}",
+                                        key_name_file_path
+                                    )
+                                },
+                            )?;
+                        }
+                        report.upgraded += 1;
+                    }
+                    Err(string) => report
+                        .failed
+                        .push((format!("{:?}", key_name_file_path), string)),
+                }
+            }
+        }
+
+        if is_legacy && !dry_run {
+            let new_app_name_dir_path = mappings_dir_path.join(format!(
+                "{}{}{}",
+                u8::from(authenticator_type),
+                AUTH_TYPE_SEPARATOR,
+                app_name_file_name
+            ));
+            if new_app_name_dir_path.exists() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "the application directory at {:?} can not be moved to {:?} which already \
+                         exists",
+                        app_name_dir_path, new_app_name_dir_path
+                    ),
+                )
+                .into());
+            }
+            fs::rename(app_name_dir_path, &new_app_name_dir_path).with_context(|| {
+                format!(
+                    "Failed to move the application directory at {:?}",
+                    app_name_dir_path
+                )
+            })?;
+            sync_dir(mappings_dir_path)?;
+        }
     }
+
+    Ok(report)
 }
 
 /// OnDiskKeyInfoManager builder
 #[derive(Debug, Default)]
 pub struct OnDiskKeyInfoManagerBuilder {
     mappings_dir_path: Option<PathBuf>,
+    cipher: Option<MappingCipher>,
+    shared: bool,
+    watch: bool,
 }
 
 impl OnDiskKeyInfoManagerBuilder {
@@ -359,6 +1562,9 @@ impl OnDiskKeyInfoManagerBuilder {
     pub fn new() -> OnDiskKeyInfoManagerBuilder {
         OnDiskKeyInfoManagerBuilder {
             mappings_dir_path: None,
+            cipher: None,
+            shared: false,
+            watch: false,
         }
     }
 
@@ -369,19 +1575,67 @@ impl OnDiskKeyInfoManagerBuilder {
         self
     }
 
-    /// Build into a OnDiskKeyInfoManager
-    pub fn build(self) -> Result<OnDiskKeyInfoManager> {
-        OnDiskKeyInfoManager::new(
+    /// Add a cipher to encrypt the mapping files to the builder
+    pub(super) fn with_cipher(mut self, cipher: MappingCipher) -> OnDiskKeyInfoManagerBuilder {
+        self.cipher = Some(cipher);
+
+        self
+    }
+
+    /// Share the mappings directory with other instances of the service
+    pub fn with_shared(mut self, shared: bool) -> OnDiskKeyInfoManagerBuilder {
+        self.shared = shared;
+
+        self
+    }
+
+    /// Watch the mappings directory for mapping files modified outside of the service, merging
+    /// them into the mappings. Requires the `mappings-watcher` feature.
+    pub fn with_watch(mut self, watch: bool) -> OnDiskKeyInfoManagerBuilder {
+        self.watch = watch;
+
+        self
+    }
+
+    /// Build into a OnDiskKeyInfoManager, shared with the thread watching the mappings directory
+    /// if watching is configured
+    pub fn build(self) -> Result<Arc<OnDiskKeyInfoManager>> {
+        let manager = Arc::new(OnDiskKeyInfoManager::new(
             self.mappings_dir_path
                 .unwrap_or_else(|| PathBuf::from(DEFAULT_MAPPINGS_PATH)),
-        )
+            self.cipher,
+            self.shared,
+        )?);
+        if self.watch {
+            #[cfg(feature = "mappings-watcher")]
+            OnDiskKeyInfoManager::watch(&manager)?;
+            #[cfg(not(feature = "mappings-watcher"))]
+            {
+                error!(
+                    "The mappings directory at {:?} can not be watched as the mappings watcher was \
+                     not compiled in Parsec binary.",
+                    manager.mappings_dir_path
+                );
+                return Err(
+                    Error::new(ErrorKind::InvalidData, "mappings watcher not compiled").into(),
+                );
+            }
+        }
+
+        Ok(manager)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::format::KEY_INFO_FORMAT_VERSION;
+    use super::super::metadata::KeyMetadata;
     use super::super::{KeyInfo, KeyTriple, ManageKeyInfo};
-    use super::OnDiskKeyInfoManager;
+    use super::{
+        key_triple_to_filenames, migrate_mappings, name_to_filename, OnDiskKeyInfoManager,
+        OnDiskKeyInfoManagerBuilder, HASHED_NAME_PREFIX, MAX_FILE_NAME_LEN, QUARANTINE_DIR_NAME,
+        TEMP_FILE_PREFIX,
+    };
     use crate::authenticators::ApplicationName;
     use parsec_interface::operations::psa_algorithm::{
         Algorithm, AsymmetricSignature, Hash, SignHash,
@@ -389,9 +1643,13 @@ mod test {
     use parsec_interface::operations::psa_key_attributes::{
         Attributes, Lifetime, Policy, Type, UsageFlags,
     };
-    use parsec_interface::requests::ProviderId;
+    use parsec_interface::requests::{AuthType, ProviderId};
     use std::fs;
     use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    #[cfg(feature = "mappings-watcher")]
+    use std::time::Duration;
 
     fn test_key_attributes() -> Attributes {
         Attributes {
@@ -424,13 +1682,16 @@ mod test {
         KeyInfo {
             id: vec![0x11, 0x22, 0x33],
             attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
         }
     }
 
     #[test]
     fn insert_get_key_info() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_get_key_info_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
         let key_triple = new_key_triple("insert_get_key_info".to_string());
         let key_info = test_key_info();
@@ -445,8 +1706,7 @@ mod test {
         let stored_key_info = manager
             .get(&key_triple)
             .unwrap()
-            .expect("Failed to get key info")
-            .clone();
+            .expect("Failed to get key info");
 
         assert_eq!(stored_key_info, key_info);
         assert!(manager.remove(&key_triple).unwrap().is_some());
@@ -456,7 +1716,7 @@ mod test {
     #[test]
     fn insert_remove_key() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_remove_key_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
         let key_triple = new_key_triple("insert_remove_key".to_string());
         let key_info = test_key_info();
@@ -470,7 +1730,7 @@ mod test {
     #[test]
     fn remove_unexisting_key() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/remove_unexisting_key_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
         let key_triple = new_key_triple("remove_unexisting_key".to_string());
         assert_eq!(manager.remove(&key_triple).unwrap(), None);
@@ -480,7 +1740,7 @@ mod test {
     #[test]
     fn exists() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/exists_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
         let key_triple = new_key_triple("exists".to_string());
         let key_info = test_key_info();
@@ -498,13 +1758,16 @@ mod test {
     #[test]
     fn insert_overwrites() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_overwrites_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
         let key_triple = new_key_triple("insert_overwrites".to_string());
         let key_info_1 = test_key_info();
         let key_info_2 = KeyInfo {
             id: vec![0xaa, 0xbb, 0xcc],
             attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
         };
 
         let _ = manager.insert(key_triple.clone(), key_info_1).unwrap();
@@ -515,8 +1778,7 @@ mod test {
         let stored_key_info = manager
             .get(&key_triple)
             .unwrap()
-            .expect("Failed to get key info")
-            .clone();
+            .expect("Failed to get key info");
 
         assert_eq!(stored_key_info, key_info_2);
         assert!(manager.remove(&key_triple).unwrap().is_some());
@@ -526,9 +1788,9 @@ mod test {
     #[test]
     fn big_names_ascii() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/big_names_ascii_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
-        let big_app_name_ascii = ApplicationName::from_name("  Lorem ipsum dolor sit amet, ei suas viris sea, deleniti repudiare te qui. Natum paulo decore ut nec, ne propriae offendit adipisci has. Eius clita legere mel at, ei vis minimum tincidunt.".to_string());
+        let big_app_name_ascii = ApplicationName::new("  Lorem ipsum dolor sit amet, ei suas viris sea, deleniti repudiare te qui. Natum paulo decore ut nec, ne propriae offendit adipisci has. Eius clita legere mel at, ei vis minimum tincidunt.".to_string(), AuthType::Direct);
         let big_key_name_ascii = "  Lorem ipsum dolor sit amet, ei suas viris sea, deleniti repudiare te qui. Natum paulo decore ut nec, ne propriae offendit adipisci has. Eius clita legere mel at, ei vis minimum tincidunt.".to_string();
 
         let key_triple = KeyTriple::new(big_app_name_ascii, ProviderId::Core, big_key_name_ascii);
@@ -544,9 +1806,9 @@ mod test {
     #[test]
     fn big_names_emoticons() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/big_names_emoticons_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
-        let big_app_name_emoticons = ApplicationName::from_name("😀😁😂😃😄😅😆😇😈😉😊😋😌😍😎😏😐😑😒😓😔😕😖😗😘😙😚😛😜😝😞😟😠😡😢😣😤😥😦😧😨😩😪😫😬😭😮".to_string());
+        let big_app_name_emoticons = ApplicationName::new("😀😁😂😃😄😅😆😇😈😉😊😋😌😍😎😏😐😑😒😓😔😕😖😗😘😙😚😛😜😝😞😟😠😡😢😣😤😥😦😧😨😩😪😫😬😭😮".to_string(), AuthType::Direct);
         let big_key_name_emoticons = "😀😁😂😃😄😅😆😇😈😉😊😋😌😍😎😏😐😑😒😓😔😕😖😗😘😙😚😛😜😝😞😟😠😡😢😣😤😥😦😧😨😩😪😫😬😭😮".to_string();
 
         let key_triple = KeyTriple::new(
@@ -567,28 +1829,35 @@ mod test {
     fn create_and_load() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/create_and_load_mappings");
 
-        let app_name1 = ApplicationName::from_name("😀 Application One 😀".to_string());
+        let app_name1 = ApplicationName::new("😀 Application One 😀".to_string(), AuthType::Direct);
         let key_name1 = "😀 Key One 😀".to_string();
         let key_triple1 = KeyTriple::new(app_name1, ProviderId::Core, key_name1);
         let key_info1 = test_key_info();
 
-        let app_name2 = ApplicationName::from_name("😇 Application Two 😇".to_string());
+        let app_name2 = ApplicationName::new("😇 Application Two 😇".to_string(), AuthType::Direct);
         let key_name2 = "😇 Key Two 😇".to_string();
         let key_triple2 = KeyTriple::new(app_name2, ProviderId::MbedCrypto, key_name2);
         let key_info2 = KeyInfo {
             id: vec![0x12, 0x22, 0x32],
             attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
         };
 
-        let app_name3 = ApplicationName::from_name("😈 Application Three 😈".to_string());
+        let app_name3 =
+            ApplicationName::new("😈 Application Three 😈".to_string(), AuthType::Direct);
         let key_name3 = "😈 Key Three 😈".to_string();
         let key_triple3 = KeyTriple::new(app_name3, ProviderId::Core, key_name3);
         let key_info3 = KeyInfo {
             id: vec![0x13, 0x23, 0x33],
             attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
         };
         {
-            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
             let _ = manager
                 .insert(key_triple1.clone(), key_info1.clone())
@@ -602,7 +1871,7 @@ mod test {
         }
         // The local hashmap is dropped when leaving the inner scope.
         {
-            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
 
             assert_eq!(manager.remove(&key_triple1).unwrap().unwrap(), key_info1);
             assert_eq!(manager.remove(&key_triple2).unwrap().unwrap(), key_info2);
@@ -612,9 +1881,585 @@ mod test {
         fs::remove_dir_all(path).unwrap();
     }
 
+    #[test]
+    fn metadata_is_persisted() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/metadata_is_persisted");
+        let key_triple = new_key_triple("metadata key".to_string());
+        let mut key_info = test_key_info();
+        key_info.metadata = KeyMetadata::new(Some(AuthType::UnixPeerCredentials.into()));
+        key_info.metadata.record_uses(3, 42);
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+        }
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        let stored_key_info = manager.remove(&key_triple).unwrap().unwrap();
+        assert_eq!(stored_key_info, key_info);
+        assert_eq!(
+            stored_key_info.metadata.creator_authenticator_type(),
+            Some(AuthType::UnixPeerCredentials.into())
+        );
+        assert_eq!(stored_key_info.metadata.use_count(), 3);
+        assert_eq!(stored_key_info.metadata.last_used(), Some(42));
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_mapping_is_quarantined() {
+        let path = PathBuf::from(
+            env!("OUT_DIR").to_owned() + "/corrupted_mapping_is_quarantined_mappings",
+        );
+
+        let key_triple_ok = new_key_triple("valid key".to_string());
+        let key_info_ok = test_key_info();
+        let key_triple_corrupted = new_key_triple("corrupted key".to_string());
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager
+                .insert(key_triple_ok.clone(), key_info_ok.clone())
+                .unwrap();
+            let _ = manager
+                .insert(key_triple_corrupted.clone(), test_key_info())
+                .unwrap();
+        }
+
+        // Simulate a mapping file truncated by a crash and a temporary file left behind.
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple_corrupted);
+        let provider_dir_path = path.join(&app_name).join(&prov);
+        fs::write(provider_dir_path.join(&key_name), &[0x11, 0x22]).unwrap();
+        let temp_file_name = format!("{}1a2b3c", TEMP_FILE_PREFIX);
+        fs::write(provider_dir_path.join(&temp_file_name), &[0x11, 0x22]).unwrap();
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(!manager.exists(&key_triple_corrupted).unwrap());
+        assert!(!provider_dir_path.join(&key_name).exists());
+        assert!(!provider_dir_path.join(&temp_file_name).exists());
+        assert!(path
+            .join(QUARANTINE_DIR_NAME)
+            .join(&app_name)
+            .join(&prov)
+            .join(&key_name)
+            .exists());
+        assert_eq!(
+            manager.remove(&key_triple_ok).unwrap().unwrap(),
+            key_info_ok
+        );
+
+        // The quarantine directory is not read as mappings.
+        drop(manager);
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(manager.get_all(ProviderId::MbedCrypto).unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn quarantine_moves_mapping_file() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/quarantine_moves_mapping_file");
+        let key_triple = new_key_triple("quarantined key".to_string());
+        let key_info = test_key_info();
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+
+            assert_eq!(manager.quarantine(&key_triple).unwrap().unwrap(), key_info);
+            assert!(!manager.exists(&key_triple).unwrap());
+            assert_eq!(manager.quarantine(&key_triple).unwrap(), None);
+        }
+
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple);
+        assert!(path
+            .join(QUARANTINE_DIR_NAME)
+            .join(&app_name)
+            .join(&prov)
+            .join(&key_name)
+            .is_file());
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(!manager.exists(&key_triple).unwrap());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn migrate_legacy_mappings() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/migrate_legacy_mappings");
+        let key_triple = new_key_triple("legacy key".to_string());
+        let key_info = test_key_info();
+
+        // Mapping file written before the versioned format, in an application directory which
+        // does not record the authenticator type.
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple);
+        let legacy_app_name = name_to_filename(&key_triple.app_name, MAX_FILE_NAME_LEN);
+        let legacy_file_path = path.join(&legacy_app_name).join(&prov).join(&key_name);
+        fs::create_dir_all(legacy_file_path.parent().unwrap()).unwrap();
+        let legacy_data = bincode::serialize(&key_info).unwrap();
+        fs::write(&legacy_file_path, &legacy_data).unwrap();
+        let _ = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap_err();
+
+        let report = migrate_mappings(&path, AuthType::Direct.into(), true).unwrap();
+        assert_eq!(report.upgraded, 1);
+        assert_eq!(fs::read(&legacy_file_path).unwrap(), legacy_data);
+
+        let report = migrate_mappings(&path, AuthType::Direct.into(), false).unwrap();
+        assert_eq!(report.upgraded, 1);
+        assert!(report.failed.is_empty());
+        assert!(!path.join(&legacy_app_name).exists());
+        assert!(path.join(&app_name).join(&prov).join(&key_name).is_file());
+        let report = migrate_mappings(&path, AuthType::Direct.into(), false).unwrap();
+        assert_eq!(report.upgraded, 0);
+        assert_eq!(report.up_to_date, 1);
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert_eq!(manager.remove(&key_triple).unwrap().unwrap(), key_info);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn legacy_mappings_are_refused_on_start() {
+        let path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/legacy_mappings_are_refused_on_start");
+        let key_triple = new_key_triple("legacy key".to_string());
+        let key_info = test_key_info();
+
+        let (_, prov, key_name) = key_triple_to_filenames(&key_triple);
+        let legacy_app_name = name_to_filename(&key_triple.app_name, MAX_FILE_NAME_LEN);
+        let legacy_file_path = path.join(&legacy_app_name).join(&prov).join(&key_name);
+        let legacy_data = bincode::serialize(&key_info).unwrap();
+        fs::create_dir_all(legacy_file_path.parent().unwrap()).unwrap();
+        fs::write(&legacy_file_path, &legacy_data).unwrap();
+
+        for shared in [false, true].iter() {
+            let error = OnDiskKeyInfoManagerBuilder::new()
+                .with_mappings_dir_path(path.clone())
+                .with_shared(*shared)
+                .build()
+                .unwrap_err();
+            assert!(error.to_string().contains("parsec migrate-mappings"));
+            // The mappings directory is left as it is until it is migrated.
+            assert_eq!(fs::read(&legacy_file_path).unwrap(), legacy_data);
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn newer_format_is_not_quarantined() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/newer_format_is_not_quarantined");
+        let key_triple = new_key_triple("newer key".to_string());
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager.insert(key_triple.clone(), test_key_info()).unwrap();
+        }
+
+        // Simulate a mapping file written by a newer version of the service.
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple);
+        let key_name_file_path = path.join(&app_name).join(&prov).join(&key_name);
+        let mut data = fs::read(&key_name_file_path).unwrap();
+        // The format version follows the four magic bytes.
+        data[4] = KEY_INFO_FORMAT_VERSION + 1;
+        fs::write(&key_name_file_path, &data).unwrap();
+
+        // The mapping file is only read when the mappings of its application are needed.
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        let _ = manager.get_all(ProviderId::MbedCrypto).unwrap_err();
+        let _ = manager.exists(&key_triple).unwrap_err();
+        assert!(key_name_file_path.exists());
+        assert!(!path.join(QUARANTINE_DIR_NAME).exists());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn mappings_are_read_on_demand() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/mappings_are_read_on_demand");
+        let app_name1 = ApplicationName::new("application one".to_string(), AuthType::Direct);
+        let app_name2 = ApplicationName::new("application two".to_string(), AuthType::Direct);
+        let key_triple1 = KeyTriple::new(
+            app_name1.clone(),
+            ProviderId::MbedCrypto,
+            "key one".to_string(),
+        );
+        let key_triple2 = KeyTriple::new(
+            app_name2.clone(),
+            ProviderId::MbedCrypto,
+            "key two".to_string(),
+        );
+        let key_info = test_key_info();
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager
+                .insert(key_triple1.clone(), key_info.clone())
+                .unwrap();
+            let _ = manager
+                .insert(key_triple2.clone(), key_info.clone())
+                .unwrap();
+        }
+
+        // Corrupt the mapping file of the second application.
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple2);
+        let key_name_file_path = path.join(&app_name).join(&prov).join(&key_name);
+        fs::write(&key_name_file_path, &[0x11, 0x22]).unwrap();
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        let mut clients = manager.get_clients(ProviderId::MbedCrypto).unwrap();
+        clients.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(clients, vec![app_name1.clone(), app_name2.clone()]);
+        assert_eq!(
+            manager
+                .get_all_for_app(ProviderId::MbedCrypto, &app_name1)
+                .unwrap(),
+            vec![key_triple1.clone()]
+        );
+        assert_eq!(manager.get(&key_triple1).unwrap().unwrap(), key_info);
+        // Nothing read the mappings of the second application yet.
+        assert!(key_name_file_path.exists());
+
+        assert!(manager
+            .get_all_for_app(ProviderId::MbedCrypto, &app_name2)
+            .unwrap()
+            .is_empty());
+        assert!(!key_name_file_path.exists());
+        assert_eq!(
+            manager.get_clients(ProviderId::MbedCrypto).unwrap(),
+            vec![app_name1]
+        );
+        assert!(manager.get_clients(ProviderId::Pkcs11).unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn long_names_are_hashed() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/long_names_are_hashed_mappings");
+        let long_app_name = ApplicationName::new("😀 Application ".repeat(100), AuthType::Direct);
+        let long_key_name = "😀 Key ".repeat(200);
+        let key_triple1 = KeyTriple::new(
+            long_app_name.clone(),
+            ProviderId::MbedCrypto,
+            long_key_name.clone(),
+        );
+        let key_triple2 = KeyTriple::new(long_app_name, ProviderId::Core, "short key".to_string());
+        let key_triple3 = new_key_triple(long_key_name);
+        let key_info = test_key_info();
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            for key_triple in [&key_triple1, &key_triple2, &key_triple3].iter() {
+                let _ = manager
+                    .insert((*key_triple).clone(), key_info.clone())
+                    .unwrap();
+            }
+        }
+
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple1);
+        assert!(app_name.starts_with(&format!("1.{}", HASHED_NAME_PREFIX)));
+        assert!(key_name.starts_with(HASHED_NAME_PREFIX));
+        assert!(path.join(app_name).join(prov).join(key_name).is_file());
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(!path.join(QUARANTINE_DIR_NAME).exists());
+        for key_triple in [&key_triple1, &key_triple2, &key_triple3].iter() {
+            assert_eq!(manager.remove(key_triple).unwrap().unwrap(), key_info);
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn mismatched_stored_names_are_quarantined() {
+        let path = PathBuf::from(
+            env!("OUT_DIR").to_owned() + "/mismatched_stored_names_are_quarantined_mappings",
+        );
+        let key_triple = new_key_triple("😀 Key ".repeat(200));
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            let _ = manager.insert(key_triple.clone(), test_key_info()).unwrap();
+        }
+
+        // Move the mapping file to the hashed name of another key.
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple);
+        let (_, _, other_key_name) =
+            key_triple_to_filenames(&new_key_triple("😇 Key ".repeat(200)));
+        let provider_dir_path = path.join(app_name).join(prov);
+        fs::rename(
+            provider_dir_path.join(key_name),
+            provider_dir_path.join(other_key_name),
+        )
+        .unwrap();
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(manager.get_all(ProviderId::MbedCrypto).unwrap().is_empty());
+        assert!(path.join(QUARANTINE_DIR_NAME).exists());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn transfer_moves_mapping_files() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/transfer_moves_mapping_files");
+        let to = ApplicationName::new("😇 Application ".repeat(100), AuthType::JwtSvid);
+        let key_triple1 = new_key_triple("key one".to_string());
+        let key_triple2 = new_key_triple("key two".to_string());
+        let key_info = test_key_info();
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+            for key_triple in [&key_triple1, &key_triple2].iter() {
+                let _ = manager
+                    .insert((*key_triple).clone(), key_info.clone())
+                    .unwrap();
+            }
+            let _ = manager
+                .insert(key_triple2.with_app_name(to.clone()), key_info.clone())
+                .unwrap();
+            assert_eq!(
+                manager
+                    .transfer(ProviderId::MbedCrypto, key_triple1.app_name(), &to)
+                    .unwrap(),
+                None
+            );
+
+            let _ = manager
+                .remove(&key_triple2.with_app_name(to.clone()))
+                .unwrap();
+            assert_eq!(
+                manager
+                    .transfer(ProviderId::MbedCrypto, key_triple1.app_name(), &to)
+                    .unwrap(),
+                Some(2)
+            );
+        }
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        assert!(!path.join(QUARANTINE_DIR_NAME).exists());
+        for key_triple in [&key_triple1, &key_triple2].iter() {
+            assert!(!manager.exists(key_triple).unwrap());
+            assert_eq!(
+                manager
+                    .remove(&key_triple.with_app_name(to.clone()))
+                    .unwrap()
+                    .unwrap(),
+                key_info
+            );
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn failed_transfer_restores_mapping_files() {
+        let path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/failed_transfer_restores_mapping_files");
+        let to = ApplicationName::new("other application".to_string(), AuthType::JwtSvid);
+        let key_triple1 = new_key_triple("key one".to_string());
+        let key_triple2 = new_key_triple("key two".to_string());
+        let key_info = test_key_info();
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        for key_triple in [&key_triple1, &key_triple2].iter() {
+            let _ = manager
+                .insert((*key_triple).clone(), key_info.clone())
+                .unwrap();
+        }
+
+        // A directory in place of a mapping file can not be removed as one.
+        let blocked_file_path = manager.mapping_file_path(&key_triple2);
+        fs::remove_file(&blocked_file_path).unwrap();
+        fs::create_dir_all(blocked_file_path.join("blocker")).unwrap();
+        let _ = manager
+            .transfer(ProviderId::MbedCrypto, key_triple1.app_name(), &to)
+            .unwrap_err();
+
+        assert!(manager.mapping_file_path(&key_triple1).is_file());
+        for key_triple in [&key_triple1, &key_triple2].iter() {
+            assert_eq!(manager.get(key_triple).unwrap().unwrap(), key_info);
+            let target_key_triple = key_triple.with_app_name(to.clone());
+            assert!(manager.get(&target_key_triple).unwrap().is_none());
+            assert!(!manager.mapping_file_path(&target_key_triple).exists());
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_applications_do_not_lose_mappings() {
+        let path = PathBuf::from(
+            env!("OUT_DIR").to_owned() + "/concurrent_applications_do_not_lose_mappings",
+        );
+        let manager = Arc::new(OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap());
+        let app_names: Vec<ApplicationName> = (0..4)
+            .map(|i| ApplicationName::new(format!("application {}", i), AuthType::Direct))
+            .collect();
+
+        let threads: Vec<_> = app_names
+            .iter()
+            .map(|app_name| {
+                let manager = manager.clone();
+                let app_name = app_name.clone();
+                thread::spawn(move || {
+                    for i in 0..20 {
+                        let key_triple = KeyTriple::new(
+                            app_name.clone(),
+                            ProviderId::MbedCrypto,
+                            format!("key {}", i),
+                        );
+                        let _ = manager.insert(key_triple, test_key_info()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(manager.get_all(ProviderId::MbedCrypto).unwrap().len(), 80);
+        drop(manager);
+
+        // All the mapping files were written.
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+        for app_name in app_names.iter() {
+            assert_eq!(
+                manager
+                    .get_all_for_app(ProviderId::MbedCrypto, app_name)
+                    .unwrap()
+                    .len(),
+                20
+            );
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn second_instance_is_refused() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/second_instance_is_refused");
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+
+        let _ = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap_err();
+        let _ = OnDiskKeyInfoManager::new(path.clone(), None, true).unwrap_err();
+        let _ = migrate_mappings(&path, AuthType::Direct.into(), true).unwrap_err();
+
+        // The lock is released with the manager.
+        drop(manager);
+        let _ = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap();
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn shared_instances_see_modifications() {
+        let path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/shared_instances_see_modifications");
+        let key_triple = new_key_triple("shared key".to_string());
+        let key_info = test_key_info();
+        let manager1 = OnDiskKeyInfoManager::new(path.clone(), None, true).unwrap();
+        let manager2 = OnDiskKeyInfoManager::new(path.clone(), None, true).unwrap();
+        let _ = OnDiskKeyInfoManager::new(path.clone(), None, false).unwrap_err();
+        assert!(!manager2.is_stale().unwrap());
+
+        let _ = manager1
+            .insert(key_triple.clone(), key_info.clone())
+            .unwrap();
+        assert!(!manager1.is_stale().unwrap());
+        assert!(manager2.is_stale().unwrap());
+        manager2.reload().unwrap();
+        assert!(!manager2.is_stale().unwrap());
+        assert_eq!(manager2.get(&key_triple).unwrap().unwrap(), key_info);
+
+        // Modifications are made on top of the ones of the other instances.
+        assert_eq!(manager2.remove(&key_triple).unwrap().unwrap(), key_info);
+        assert!(manager1.is_stale().unwrap());
+        manager1.reload().unwrap();
+        assert!(!manager1.exists(&key_triple).unwrap());
+
+        // A failed modification does not make the other instances read the mappings again.
+        let failing_key_triple = KeyTriple::new(
+            ApplicationName::new("failing app".to_string(), AuthType::Direct),
+            ProviderId::MbedCrypto,
+            "failing key".to_string(),
+        );
+        let (app_name, _, _) = key_triple_to_filenames(&failing_key_triple);
+        fs::write(path.join(app_name), &[0x11, 0x22]).unwrap();
+        let _ = manager1.insert(failing_key_triple, key_info).unwrap_err();
+        assert!(!manager2.is_stale().unwrap());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[cfg(feature = "mappings-watcher")]
+    #[test]
+    fn watched_mapping_files_are_applied() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/watched_mapping_files_are_applied");
+        let source_path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/watched_mapping_files_are_applied_source");
+        let key_triple = new_key_triple("watched key".to_string());
+        let key_info = test_key_info();
+        {
+            let manager = OnDiskKeyInfoManager::new(source_path.clone(), None, false).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+        }
+        let (app_name, prov, key_name) = key_triple_to_filenames(&key_triple);
+        let source_file_path = source_path.join(&app_name).join(&prov).join(&key_name);
+        let provider_dir_path = path.join(&app_name).join(&prov);
+        let key_name_file_path = provider_dir_path.join(&key_name);
+
+        let manager = OnDiskKeyInfoManagerBuilder::new()
+            .with_mappings_dir_path(path.clone())
+            .with_watch(true)
+            .build()
+            .unwrap();
+        assert!(!manager.exists(&key_triple).unwrap());
+
+        // Restore the mapping file in a new directory.
+        fs::create_dir_all(&provider_dir_path).unwrap();
+        let _ = fs::copy(&source_file_path, &key_name_file_path).unwrap();
+        wait_until(|| manager.exists(&key_triple).unwrap());
+        assert_eq!(manager.get(&key_triple).unwrap().unwrap(), key_info);
+
+        // Mapping files which can not be read are ignored but kept.
+        fs::write(&key_name_file_path, &[0x11, 0x22]).unwrap();
+        wait_until(|| !manager.exists(&key_triple).unwrap());
+        assert!(key_name_file_path.exists());
+        assert!(!path.join(QUARANTINE_DIR_NAME).exists());
+
+        fs::rename(&source_file_path, &key_name_file_path).unwrap();
+        wait_until(|| manager.exists(&key_triple).unwrap());
+        assert_eq!(manager.get(&key_triple).unwrap().unwrap(), key_info);
+
+        fs::remove_file(&key_name_file_path).unwrap();
+        wait_until(|| !manager.exists(&key_triple).unwrap());
+
+        // The mapping files written by the manager are not read again.
+        let _ = manager
+            .insert(key_triple.clone(), key_info.clone())
+            .unwrap();
+        wait_until(|| manager.own_changes.lock().unwrap().is_empty());
+        assert_eq!(manager.get(&key_triple).unwrap().unwrap(), key_info);
+        assert!(!manager.is_stale().unwrap());
+
+        fs::remove_dir_all(path).unwrap();
+        fs::remove_dir_all(source_path).unwrap();
+    }
+
+    #[cfg(feature = "mappings-watcher")]
+    fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The changes of the mappings directory were not applied");
+    }
+
     fn new_key_triple(key_name: String) -> KeyTriple {
         KeyTriple::new(
-            ApplicationName::from_name("Testing Application 😎".to_string()),
+            ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct),
             ProviderId::MbedCrypto,
             key_name,
         )
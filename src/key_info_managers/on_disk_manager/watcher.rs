@@ -0,0 +1,488 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Watcher of the mapping files modified outside of the service
+//!
+//! The watcher uses inotify to be notified of the mapping files added, replaced or removed in the
+//! mappings directory, for example by an operator restoring a backup. Its thread merges the
+//! changes into the mappings of the manager as soon as they are noticed, the manager validating
+//! the mapping files as it reads them.
+//!
+//! The mapping files written, removed or quarantined by the manager itself are not read again:
+//! the manager records them before modifying them and the watcher skips their events.
+//!
+//! If the events can not be read, the watcher starts over with a new inotify instance and all the
+//! mappings are read again, as some events might have been lost. If that keeps failing, the
+//! mappings directory is not watched anymore until the service restarts.
+use super::{
+    file_name, is_legacy_app_dir_name, is_temp_file_name, key_triple_to_filenames, list_dirs,
+    os_str_to_provider_id, read_mapping_file, OnDiskKeyInfoManager, ProviderDirectory,
+    QUARANTINE_DIR_NAME,
+};
+use anyhow::{Context, Result};
+use inotify::{Event, EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::thread;
+
+/// Depth, from the mappings directory, of the provider directories containing the mapping files
+const PROVIDER_DIR_DEPTH: usize = 2;
+
+/// Number of times in a row the watcher starts over after failing to read the events, before
+/// giving up
+const MAX_RESTARTS: usize = 3;
+
+/// Change of the mappings directory to merge into the mappings of the manager
+#[derive(Debug)]
+pub(super) enum Change {
+    /// Events were lost, all the mappings need to be read again
+    Lost,
+    /// An application or provider directory was created or removed
+    Directory(PathBuf),
+    /// An application or provider directory was moved into the mappings directory, replacing
+    /// the mappings it might have had
+    MovedDirectory(PathBuf),
+    /// A mapping file was added, replaced or removed
+    MappingFile(PathBuf),
+}
+
+impl OnDiskKeyInfoManager {
+    /// Starts watching the mappings directory for mapping files modified outside of the service,
+    /// which are merged into the mappings of the manager as soon as they are noticed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an std::io error if the directories could not be watched.
+    pub(super) fn watch(manager: &Arc<OnDiskKeyInfoManager>) -> Result<()> {
+        manager.watched.store(true, Ordering::SeqCst);
+        watch(manager).with_context(|| {
+            format!(
+                "Failed to watch the mappings directory at {:?}",
+                manager.mappings_dir_path
+            )
+        })
+    }
+
+    /// Stops considering the mappings directory as watched, once the watcher gave up.
+    fn stop_watching(&self) {
+        self.watched.store(false, Ordering::SeqCst);
+        self.own_changes
+            .lock()
+            .expect("Own changes lock poisoned")
+            .clear();
+        error!(
+            "The mappings directory at {:?} is not watched anymore: the mapping files modified \
+             outside of the service are not noticed until it restarts.",
+            self.mappings_dir_path
+        );
+    }
+
+    /// Merges a change of the mappings directory found by the watcher, as `merge_change`, and
+    /// counts it as an external change.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the directories could not be read.
+    fn apply_change(&self, change: Change) -> Result<(), String> {
+        let result = self.merge_change(change);
+        // Counted even if the change was only partly merged.
+        let _ = self.external_changes.fetch_add(1, Ordering::SeqCst);
+
+        result
+    }
+
+    /// Merges a change of the mappings directory found by the watcher: a changed mapping file is
+    /// read again if the mappings of its directory were read, a created or removed directory is
+    /// indexed or forgotten and a directory moved into the mappings directory is indexed again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the directories could not be read.
+    fn merge_change(&self, change: Change) -> Result<(), String> {
+        let (path, replace) = match change {
+            Change::Lost => {
+                info!("Reading all the mappings again.");
+                // The events of the changes made by the manager might have been lost as well.
+                self.own_changes
+                    .lock()
+                    .expect("Own changes lock poisoned")
+                    .clear();
+                return self.index().map_err(|e| e.to_string());
+            }
+            Change::Directory(path) => (path, false),
+            Change::MovedDirectory(path) => (path, true),
+            Change::MappingFile(path) => {
+                self.reread_mapping_file(&path);
+                return Ok(());
+            }
+        };
+        let relative_path = match path.strip_prefix(&self.mappings_dir_path) {
+            Ok(relative_path) => relative_path,
+            Err(_) => return Ok(()),
+        };
+        match relative_path.iter().collect::<Vec<&OsStr>>().as_slice() {
+            [app_name_file_name] => self
+                .reindex_app_dir(&app_name_file_name.to_string_lossy(), replace)
+                .map_err(|e| e.to_string()),
+            [app_name_file_name, provider_file_name] => {
+                self.reindex_provider_dir(
+                    &app_name_file_name.to_string_lossy(),
+                    provider_file_name,
+                    replace,
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Indexes again the provider directories of an application directory. The directories
+    /// already indexed are kept, unless `replace` is true.
+    fn reindex_app_dir(&self, app_name_file_name: &str, replace: bool) -> std::io::Result<()> {
+        let app_name_dir_path = self.mappings_dir_path.join(app_name_file_name);
+        let is_dir = app_name_dir_path.is_dir();
+        let provider_dir_paths = if is_dir {
+            list_dirs(&app_name_dir_path)?
+        } else {
+            Vec::new()
+        };
+
+        if replace || !is_dir {
+            for directories in self
+                .key_store
+                .write()
+                .expect("Key store lock poisoned")
+                .values_mut()
+            {
+                let _ = directories.remove(app_name_file_name);
+            }
+        }
+        for provider_dir_path in provider_dir_paths.iter() {
+            self.reindex_provider_dir(app_name_file_name, file_name(provider_dir_path)?, replace);
+        }
+
+        Ok(())
+    }
+
+    /// Indexes again a provider directory, its mapping files being read when first needed. An
+    /// indexed directory is kept, unless `replace` is true, as its mapping files changes are
+    /// merged one by one. Directories of unknown providers and of applications not prefixed with
+    /// an authenticator type are ignored.
+    fn reindex_provider_dir(
+        &self,
+        app_name_file_name: &str,
+        provider_file_name: &OsStr,
+        replace: bool,
+    ) {
+        let provider_id = match os_str_to_provider_id(provider_file_name) {
+            Ok(provider_id) => provider_id,
+            Err(_) => return,
+        };
+        if is_legacy_app_dir_name(app_name_file_name) {
+            warn!(
+                "The application directory {:?} does not record the type of its authenticator \
+                 and is ignored until the mappings directory is migrated.",
+                app_name_file_name
+            );
+            return;
+        }
+        let provider_dir_path = self
+            .mappings_dir_path
+            .join(app_name_file_name)
+            .join(provider_file_name);
+        let mut key_store = self.key_store.write().expect("Key store lock poisoned");
+        let directories = key_store.entry(provider_id).or_default();
+        if !replace && directories.contains_key(app_name_file_name) && provider_dir_path.is_dir() {
+            return;
+        }
+        if provider_dir_path.is_dir() {
+            info!(
+                "The provider directory at {:?} changed, its mapping files are read again.",
+                provider_dir_path
+            );
+            let _ = directories.insert(
+                app_name_file_name.to_string(),
+                Arc::new(ProviderDirectory::unread(provider_dir_path)),
+            );
+        } else {
+            let _ = directories.remove(app_name_file_name);
+        }
+    }
+
+    /// Reads again a mapping file which was added, replaced or removed. A mapping file which can
+    /// not be read is ignored, with the mapping it contained before. The directory of the mapping
+    /// file is locked as when the manager modifies it.
+    fn reread_mapping_file(&self, key_name_file_path: &Path) {
+        let (provider_dir_path, key_name_file_name) =
+            match (key_name_file_path.parent(), key_name_file_path.file_name()) {
+                (Some(provider_dir_path), Some(key_name_file_name)) => {
+                    (provider_dir_path, key_name_file_name)
+                }
+                _ => return,
+            };
+        let directory = self
+            .key_store
+            .read()
+            .expect("Key store lock poisoned")
+            .values()
+            .find_map(|directories| {
+                directories
+                    .values()
+                    .find(|directory| directory.path == provider_dir_path)
+                    .cloned()
+            });
+        let directory = match directory {
+            Some(directory) => directory,
+            None => {
+                if let Some(app_name_dir_path) = provider_dir_path.parent() {
+                    if let (Ok(app_name_file_name), Ok(provider_file_name)) =
+                        (file_name(app_name_dir_path), file_name(provider_dir_path))
+                    {
+                        self.reindex_provider_dir(
+                            &app_name_file_name.to_string_lossy(),
+                            provider_file_name,
+                            false,
+                        );
+                    }
+                }
+                return;
+            }
+        };
+        let _write_lock = directory
+            .write_lock
+            .lock()
+            .expect("Mappings directory lock poisoned");
+        // The mapping file is read with the others when they are first needed.
+        let mut mappings = match directory.mappings.get() {
+            Some(mappings) => mappings.write().expect("Mappings lock poisoned"),
+            None => return,
+        };
+
+        let mapping = if key_name_file_path.is_file() {
+            match read_mapping_file(key_name_file_path, self.cipher.as_ref()) {
+                Ok((key_triple, key_info, _)) => Some((key_triple, key_info)),
+                Err(e) => {
+                    format_error!("Failed to read a changed mapping file", e);
+                    warn!(
+                        "The mapping file at {:?} can not be read and is ignored.",
+                        key_name_file_path
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        mappings.retain(|key_triple, _| {
+            let (_, _, key_name) = key_triple_to_filenames(key_triple);
+            key_name_file_name != key_name.as_str()
+        });
+        if let Some((key_triple, key_info)) = mapping {
+            info!(
+                "The mapping file at {:?} changed and was read again.",
+                key_name_file_path
+            );
+            let _ = mappings.insert(key_triple, key_info);
+        } else {
+            info!(
+                "The mapping file at {:?} was removed or can not be read.",
+                key_name_file_path
+            );
+        }
+    }
+}
+
+/// Events watched in the directories of the mappings directory
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MOVED_TO
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+}
+
+/// Starts watching the mappings directory of the manager, its application and provider
+/// directories, from a new thread which stops at the first event following the drop of the
+/// manager.
+///
+/// # Errors
+///
+/// Returns an std::io error if the directories could not be watched or if the thread could not be
+/// spawned.
+fn watch(manager: &Arc<OnDiskKeyInfoManager>) -> std::io::Result<()> {
+    let watcher = MappingsWatcher::new(&manager.mappings_dir_path)?;
+    let manager = Arc::downgrade(manager);
+    let _ = thread::Builder::new()
+        .name(String::from("mappings-watcher"))
+        .spawn(move || watcher.run(manager))?;
+
+    Ok(())
+}
+
+/// inotify instance watching the mappings directory
+#[derive(Debug)]
+struct MappingsWatcher {
+    inotify: Inotify,
+    /// Watched directories and their depth from the mappings directory, by watch descriptor
+    watches: HashMap<WatchDescriptor, (PathBuf, usize)>,
+}
+
+impl MappingsWatcher {
+    /// Creates an inotify instance watching the mappings directory at the given path.
+    fn new(mappings_dir_path: &Path) -> std::io::Result<MappingsWatcher> {
+        let mut watcher = MappingsWatcher {
+            inotify: Inotify::init()?,
+            watches: HashMap::new(),
+        };
+        watcher.watch_dir(mappings_dir_path, 0)?;
+
+        Ok(watcher)
+    }
+
+    /// Watches a directory, located at `depth` from the mappings directory, and its
+    /// sub-directories down to the provider directories.
+    fn watch_dir(&mut self, path: &Path, depth: usize) -> std::io::Result<()> {
+        let wd = self.inotify.add_watch(path, watch_mask())?;
+        let _ = self.watches.insert(wd, (path.to_path_buf(), depth));
+
+        if depth < PROVIDER_DIR_DEPTH {
+            for dir_path in list_dirs(path)?.iter() {
+                if depth == 0 && file_name(dir_path)? == QUARANTINE_DIR_NAME {
+                    continue;
+                }
+                self.watch_dir(dir_path, depth + 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops watching a directory and its sub-directories.
+    fn unwatch_dir(&mut self, path: &Path) {
+        let removed: Vec<WatchDescriptor> = self
+            .watches
+            .iter()
+            .filter(|(_, (dir_path, _))| dir_path.starts_with(path))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in removed {
+            let _ = self.watches.remove(&wd);
+            // The watch might already have been removed by the kernel, if the directory was
+            // deleted.
+            let _ = self.inotify.rm_watch(wd);
+        }
+    }
+
+    /// Merges the changes into the mappings of the manager until it is dropped.
+    fn run(mut self, manager: Weak<OnDiskKeyInfoManager>) {
+        let mut buffer = [0u8; 4096];
+        let mut restarts = 0;
+        loop {
+            // Waits for the next events: the manager is only found dropped once they arrive.
+            let events = self.inotify.read_events_blocking(&mut buffer);
+            let manager = match manager.upgrade() {
+                Some(manager) => manager,
+                None => return,
+            };
+
+            let changes: Vec<Change> = match events {
+                Ok(events) => {
+                    restarts = 0;
+                    events
+                        .filter_map(|event| self.handle_event(event, &manager))
+                        .collect()
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    format_error!("Failed to read the mappings directory events", e);
+                    match MappingsWatcher::restart(&manager, restarts) {
+                        Some(watcher) => self = watcher,
+                        None => {
+                            manager.stop_watching();
+                            return;
+                        }
+                    }
+                    restarts += 1;
+                    vec![Change::Lost]
+                }
+            };
+            for change in changes {
+                if let Err(e) = manager.apply_change(change) {
+                    format_error!("Failed to apply a change of the mappings directory", e);
+                }
+            }
+        }
+    }
+
+    /// Returns a new watcher of the mappings directory of the manager, unless it already started
+    /// over too many times in a row or could not be created.
+    fn restart(manager: &OnDiskKeyInfoManager, restarts: usize) -> Option<MappingsWatcher> {
+        if restarts >= MAX_RESTARTS {
+            return None;
+        }
+        match MappingsWatcher::new(&manager.mappings_dir_path) {
+            Ok(watcher) => {
+                warn!("Watching the mappings directory again, all the mappings are read again.");
+                Some(watcher)
+            }
+            Err(e) => {
+                format_error!("Failed to watch the mappings directory again", e);
+                None
+            }
+        }
+    }
+
+    /// Returns the change made by an event, watching the new directories. The changes made by
+    /// the manager itself are skipped.
+    fn handle_event(
+        &mut self,
+        event: Event<&OsStr>,
+        manager: &OnDiskKeyInfoManager,
+    ) -> Option<Change> {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            warn!("Events of the mappings directory were lost, all the mappings are read again.");
+            return Some(Change::Lost);
+        }
+        if event.mask.contains(EventMask::IGNORED) {
+            let _ = self.watches.remove(&event.wd);
+            return None;
+        }
+        let (dir_path, depth) = self.watches.get(&event.wd)?.clone();
+        let name = event.name?;
+        let path = dir_path.join(name);
+
+        if event.mask.contains(EventMask::ISDIR) {
+            if depth >= PROVIDER_DIR_DEPTH || (depth == 0 && name == QUARANTINE_DIR_NAME) {
+                return None;
+            }
+            if event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                if let Err(e) = self.watch_dir(&path, depth + 1) {
+                    error!("Failed to watch the directory at {:?} ({}).", path, e);
+                }
+            } else {
+                self.unwatch_dir(&path);
+            }
+            if event.mask.contains(EventMask::MOVED_TO) {
+                Some(Change::MovedDirectory(path))
+            } else {
+                Some(Change::Directory(path))
+            }
+        } else if depth == PROVIDER_DIR_DEPTH
+            && !is_temp_file_name(name)
+            // Mapping files are read once they are written, not when they are created.
+            && !event.mask.contains(EventMask::CREATE)
+            && !manager.take_own_change(&path)
+        {
+            Some(Change::MappingFile(path))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Reconciliation of the mappings with the keys stored by the providers
+//!
+//! When they are created, providers check the mappings of their Key Info Manager against their
+//! key store in both directions:
+//! * mappings whose key can not be found, or whose key info is invalid, are moved to the
+//!   quarantine area of the Key Info Manager. They are not used anymore but are not deleted
+//!   either, so that they can be restored if the key store was only temporarily unavailable.
+//! * keys found in the key store but not referenced by any mapping are reported. They are never
+//!   modified.
+//!
+//! The result of the reconciliation is a `ReconciliationReport` per provider, logged when the
+//! service starts and saved to a file, which the `reconciliation-report` command of the service
+//! prints. The `reconcile` command reconciles the mappings again without starting the service.
+//!
+//! The TPM provider does not reconcile its mappings, see its module for the reasons.
+use super::{metadata, KeyTriple};
+use log::{info, warn};
+use parsec_interface::requests::ProviderId;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Default path of the file the reports of the reconciliation are saved to when the service starts
+pub const DEFAULT_REPORTS_PATH: &str = "/var/lib/parsec/reconciliation_reports";
+
+/// Mapping moved to the quarantine area of the Key Info Manager
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedMapping {
+    /// Key triple of the mapping
+    pub key_triple: KeyTriple,
+    /// Reason why the mapping was quarantined
+    pub reason: String,
+}
+
+/// Result of the reconciliation of the mappings of a provider with its key store
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconciliationReport {
+    /// Provider which was reconciled
+    pub provider_id: ProviderId,
+    /// Number of mappings found consistent with the key store
+    pub consistent: usize,
+    /// Mappings moved to the quarantine area
+    pub quarantined: Vec<QuarantinedMapping>,
+    /// Keys of the key store without mapping, identified in the provider's terms
+    pub orphaned_keys: Vec<String>,
+}
+
+impl ReconciliationReport {
+    /// Creates an empty report for a provider
+    pub fn new(provider_id: ProviderId) -> ReconciliationReport {
+        ReconciliationReport {
+            provider_id,
+            consistent: 0,
+            quarantined: Vec::new(),
+            orphaned_keys: Vec::new(),
+        }
+    }
+
+    /// Checks if the mappings and the key store were found consistent.
+    pub fn is_consistent(&self) -> bool {
+        self.quarantined.is_empty() && self.orphaned_keys.is_empty()
+    }
+
+    /// Logs a summary of the report.
+    pub(super) fn log(&self) {
+        if self.is_consistent() {
+            info!(
+                "The {} mappings of provider {} are consistent with its key store.",
+                self.consistent, self.provider_id
+            );
+            return;
+        }
+        for quarantined in self.quarantined.iter() {
+            if crate::utils::GlobalConfig::log_error_details() {
+                warn!(
+                    "Mapping of key triple ({}) quarantined: {}",
+                    quarantined.key_triple, quarantined.reason
+                );
+            } else {
+                warn!("Mapping quarantined: {}", quarantined.reason);
+            }
+        }
+        for orphaned_key in self.orphaned_keys.iter() {
+            warn!(
+                "Key without mapping found in provider {}: {}",
+                self.provider_id, orphaned_key
+            );
+        }
+        warn!(
+            "Reconciliation of provider {}: {} consistent mappings, {} mappings quarantined, {} keys without mapping.",
+            self.provider_id,
+            self.consistent,
+            self.quarantined.len(),
+            self.orphaned_keys.len()
+        );
+    }
+}
+
+impl fmt::Display for ReconciliationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Provider {}: {} consistent mappings",
+            self.provider_id, self.consistent
+        )?;
+        for quarantined in self.quarantined.iter() {
+            writeln!(
+                f,
+                "  quarantined mapping ({}): {}",
+                quarantined.key_triple, quarantined.reason
+            )?;
+        }
+        for orphaned_key in self.orphaned_keys.iter() {
+            writeln!(f, "  key without mapping: {}", orphaned_key)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Saves the reports of the reconciliation made when the service started to a file, replacing the
+/// reports saved before. The file is only readable by the user of the service as the reports name
+/// the keys of the applications.
+///
+/// # Errors
+///
+/// Returns an error if the file could not be written.
+pub fn save_reports(path: &Path, reports: &[ReconciliationReport]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(
+        file,
+        "Reconciliation made when the service started, at {} seconds since the Unix epoch",
+        metadata::now()
+    )?;
+    for report in reports.iter() {
+        write!(file, "{}", report)?;
+    }
+
+    file.sync_all()
+}
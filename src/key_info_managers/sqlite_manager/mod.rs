@@ -0,0 +1,1030 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! A key info manager storing key triple to key info mapping in a SQLite database
+//!
+//! All the mappings are stored in a single database file, in one table indexed by the key triple.
+//! The application name is stored along with the type of the authenticator of the application.
+//! Databases written before the authenticator type was recorded are upgraded, their mappings being
+//! moved to the namespace of the default authenticator, when the manager starts or with
+//! `parsec migrate-mappings`.
+//! Each modification of the mapping is a single SQL statement which SQLite executes atomically:
+//! if the service stops in the middle of it, the database will contain either the old or the new
+//! mapping but never a partially written one.
+//! An in-memory copy of the mappings is kept to serve the non-modifying operations. It is filled
+//! at startup with one query, without having to walk through the filesystem. The database
+//! executes the modifications one at a time but they do not block the non-modifying operations,
+//! and the in-memory copy only locks the mappings of the modified provider while it is updated.
+//! Because the in-memory copy would get out of date, there can not be two instances of this
+//! manager pointing to the same database file at a time: a lock file next to the database is
+//! locked for the lifetime of the manager, so that a second instance of the service refuses to
+//! start.
+//! For security reasons, only the PARSEC service should have the ability to modify this file.
+//! If encryption of the mappings is configured, the key info column is encrypted; rows previously
+//! written in plaintext are encrypted when the manager starts.
+//! Quarantined mappings are moved to a separate table, along with the time they were quarantined.
+use super::encryption::MappingCipher;
+use super::format::{self, deserialize_key_info, serialize_key_info, upgrade_key_info};
+use super::index::KeyInfoIndex;
+use super::store_lock::{FileLock, LockMode};
+use super::{KeyInfo, KeyTriple, ManageKeyInfo, MigrationReport};
+use crate::authenticators::{ApplicationName, AuthenticatorType};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use parsec_interface::requests::ProviderId;
+use rusqlite::{params, Connection};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Default path of the SQLite database file storing the mappings
+pub const DEFAULT_DB_PATH: &str =
+    "/var/lib/parsec/kim-mappings/sqlite/sqlite-key-info-manager.sqlite3";
+
+/// Version of the database schema, stored in the `user_version` field of the database header.
+const SCHEMA_VERSION: u32 = 2;
+
+/// First version of the schema storing the authenticator type of the applications
+const AUTH_TYPE_SCHEMA_VERSION: u32 = 2;
+
+/// Locks the database exclusively, through a lock file next to it with the `.lock` suffix.
+///
+/// # Errors
+///
+/// Returns an error if the database is already used by another instance of the service.
+fn lock_database(database_path: &Path) -> Result<FileLock> {
+    let mut lock_path = database_path.as_os_str().to_os_string();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    FileLock::try_lock(&lock_path, LockMode::Exclusive)
+        .with_context(|| format!("Failed to lock the database at {:?}", database_path))?
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::WouldBlock,
+                format!(
+                    "the SQLite Key Info Manager database at {:?} is already used by another \
+                     Parsec instance",
+                    database_path
+                ),
+            )
+            .into()
+        })
+}
+
+/// Checks that the schema of the database is not newer than the one supported and returns its
+/// version.
+fn check_schema_version(connection: &Connection) -> Result<u32> {
+    let user_version: u32 =
+        connection.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    if user_version > SCHEMA_VERSION {
+        format_error!(
+            "The SQLite Key Info Manager database has an unsupported schema version",
+            user_version
+        );
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unsupported SQLite Key Info Manager schema version",
+        )
+        .into());
+    }
+
+    Ok(user_version)
+}
+
+/// Checks if a table exists in the database.
+fn table_exists(connection: &Connection, table: &str) -> Result<bool> {
+    let count: u32 = connection.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |row| row.get(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+/// Creates the tables of the mappings, if they do not exist yet.
+fn create_tables(connection: &Connection) -> Result<()> {
+    let _ = connection.execute(
+        "CREATE TABLE IF NOT EXISTS kim_key_info (
+            application_name TEXT NOT NULL,
+            auth_type INTEGER NOT NULL,
+            provider_id INTEGER NOT NULL,
+            key_name TEXT NOT NULL,
+            key_info BLOB NOT NULL,
+            PRIMARY KEY (application_name, auth_type, provider_id, key_name)
+        )",
+        params![],
+    )?;
+    // Mappings which are inconsistent with the key store of their provider are moved to this
+    // table instead of being deleted.
+    let _ = connection.execute(
+        "CREATE TABLE IF NOT EXISTS kim_quarantined_key_info (
+            application_name TEXT NOT NULL,
+            auth_type INTEGER NOT NULL,
+            provider_id INTEGER NOT NULL,
+            key_name TEXT NOT NULL,
+            key_info BLOB NOT NULL,
+            quarantined_at INTEGER NOT NULL,
+            PRIMARY KEY (application_name, auth_type, provider_id, key_name)
+        )",
+        params![],
+    )?;
+
+    Ok(())
+}
+
+/// A key info manager storing key triple to key info mapping in a SQLite database
+#[derive(Debug)]
+pub struct SQLiteKeyInfoManager {
+    /// Internal mapping, used for non-modifying operations.
+    key_store: KeyInfoIndex,
+    /// Connection to the database. `rusqlite::Connection` is `Send` but not `Sync`: the mutex is
+    /// held by the modifying operations while they update the database and then the in-memory
+    /// copy, so that both are modified in the same order.
+    connection: Mutex<Connection>,
+    /// Cipher used to encrypt the key info column, if encryption is configured.
+    cipher: Option<MappingCipher>,
+    /// Lock on the database, held for the lifetime of the manager.
+    _instance_lock: FileLock,
+}
+
+impl SQLiteKeyInfoManager {
+    /// Creates an instance of the SQLite manager from the database file. The database file and its
+    /// parent directories will be created if they do not already exist.
+    ///
+    /// The mappings are stored in the `kim_key_info` table, where each row contains the elements
+    /// of the key triple, including the authenticator type of the application, and the key info
+    /// serialised in binary format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database could not be opened, if it is used by another instance of
+    /// the service, if its schema does not record the authenticator types yet, in which case
+    /// `parsec migrate-mappings` must be run first, or if one of the mappings stored in it could
+    /// not be read, which includes encrypted mappings when no cipher is given.
+    fn new(database_path: PathBuf, cipher: Option<MappingCipher>) -> Result<SQLiteKeyInfoManager> {
+        if let Some(parent) = database_path.parent() {
+            // Will ignore if the directory already exists.
+            fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Failed to create the SQLite Key Info Manager directory at {:?}",
+                    parent
+                )
+            })?;
+        }
+
+        let instance_lock = lock_database(&database_path)?;
+        let mut connection = Connection::open(&database_path).with_context(|| {
+            format!(
+                "Failed to open the SQLite Key Info Manager database at {:?}",
+                database_path
+            )
+        })?;
+
+        let schema_version = check_schema_version(&connection)?;
+        let is_legacy =
+            schema_version < AUTH_TYPE_SCHEMA_VERSION && table_exists(&connection, "kim_key_info")?;
+        if is_legacy {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the SQLite Key Info Manager database at {:?} does not record the \
+                     authenticator types, run `parsec migrate-mappings` to upgrade it",
+                    database_path
+                ),
+            )
+            .into());
+        }
+
+        create_tables(&connection)?;
+        let _ = connection.execute(
+            &format!("PRAGMA user_version = {}", SCHEMA_VERSION),
+            params![],
+        )?;
+
+        let mut key_store = KeyInfoIndex::default();
+        let mut plaintext_mappings = Vec::new();
+        {
+            let mut statement = connection.prepare(
+                "SELECT application_name, auth_type, provider_id, key_name, key_info
+                    FROM kim_key_info",
+            )?;
+            let mut rows = statement.query(params![])?;
+            while let Some(row) = rows.next()? {
+                let app_name: String = row.get(0)?;
+                let auth_type: u8 = row.get(1)?;
+                let provider_id: u8 = row.get(2)?;
+                let key_name: String = row.get(3)?;
+                let key_info: Vec<u8> = row.get(4)?;
+
+                let authenticator_type = AuthenticatorType::try_from(auth_type).map_err(|e| {
+                    format_error!("Invalid authenticator type stored in the database", e);
+                    Error::new(ErrorKind::InvalidData, "invalid authenticator type")
+                })?;
+                let provider_id = ProviderId::try_from(provider_id).map_err(|e| {
+                    format_error!("Invalid Provider ID stored in the database", e);
+                    Error::new(ErrorKind::InvalidData, "invalid provider ID")
+                })?;
+                let key_triple = KeyTriple::new(
+                    ApplicationName::new(app_name, authenticator_type),
+                    provider_id,
+                    key_name,
+                );
+                if cipher.is_some() && !format::is_encrypted(&key_info) {
+                    plaintext_mappings.push(key_triple.clone());
+                }
+                let key_info = deserialize_key_info(&key_triple, &key_info, cipher.as_ref())
+                    .map_err(|e| {
+                        format_error!("Error deserializing key info", e);
+                        Error::new(ErrorKind::Other, "error deserializing key info")
+                    })?;
+
+                if crate::utils::GlobalConfig::log_error_details() {
+                    warn!(
+                        "Inserting Key Triple ({}) mapping read from the database.",
+                        key_triple
+                    );
+                }
+                let _ = key_store.insert(key_triple, key_info);
+            }
+        }
+
+        if !crate::utils::GlobalConfig::log_error_details() {
+            info!("Found {} mappings in the database", key_store.count());
+        }
+
+        let manager = SQLiteKeyInfoManager {
+            key_store,
+            connection: Mutex::new(connection),
+            cipher,
+            _instance_lock: instance_lock,
+        };
+
+        if !plaintext_mappings.is_empty() {
+            info!(
+                "Encrypting {} mappings stored in plaintext.",
+                plaintext_mappings.len()
+            );
+            let connection = manager.connection();
+            for key_triple in plaintext_mappings.iter() {
+                if let Some(key_info) = manager.key_store.get(key_triple) {
+                    manager.save_mapping(&connection, key_triple, &key_info)?;
+                }
+            }
+        }
+
+        Ok(manager)
+    }
+
+    /// Locks the connection to the database.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .expect("SQLite connection lock poisoned")
+    }
+
+    /// Saves the key triple to key info mapping in the database, replacing the existing one if
+    /// any.
+    fn save_mapping(
+        &self,
+        connection: &Connection,
+        key_triple: &KeyTriple,
+        key_info: &KeyInfo,
+    ) -> Result<()> {
+        if crate::utils::GlobalConfig::log_error_details() {
+            warn!(
+                "Saving Key Triple ({}) mapping to the database.",
+                key_triple
+            );
+        }
+        let key_info =
+            serialize_key_info(key_triple, key_info, self.cipher.as_ref()).map_err(|e| {
+                format_error!("Error serializing key info", e);
+                Error::new(ErrorKind::Other, "error serializing key info")
+            })?;
+        let _ = connection.execute(
+            "INSERT OR REPLACE INTO kim_key_info
+                (application_name, auth_type, provider_id, key_name, key_info)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                key_triple.app_name().as_str(),
+                u8::from(key_triple.authenticator_type()),
+                key_triple.provider_id as u8,
+                key_triple.key_name(),
+                key_info
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Removes the mapping from the database.
+    /// Will do nothing if the mapping does not exist.
+    fn delete_mapping(&self, connection: &Connection, key_triple: &KeyTriple) -> Result<()> {
+        let _ = connection.execute(
+            "DELETE FROM kim_key_info
+                WHERE application_name = ?1 AND auth_type = ?2 AND provider_id = ?3
+                AND key_name = ?4",
+            params![
+                key_triple.app_name().as_str(),
+                u8::from(key_triple.authenticator_type()),
+                key_triple.provider_id as u8,
+                key_triple.key_name()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Moves the mapping from the main table to the quarantine table, in a single transaction.
+    /// Will do nothing if the mapping does not exist.
+    fn quarantine_mapping(
+        &self,
+        connection: &mut Connection,
+        key_triple: &KeyTriple,
+    ) -> Result<()> {
+        let transaction = connection.transaction()?;
+        let _ = transaction.execute(
+            "INSERT OR REPLACE INTO kim_quarantined_key_info
+                (application_name, auth_type, provider_id, key_name, key_info, quarantined_at)
+                SELECT application_name, auth_type, provider_id, key_name, key_info,
+                    strftime('%s', 'now')
+                FROM kim_key_info
+                WHERE application_name = ?1 AND auth_type = ?2 AND provider_id = ?3
+                AND key_name = ?4",
+            params![
+                key_triple.app_name().as_str(),
+                u8::from(key_triple.authenticator_type()),
+                key_triple.provider_id as u8,
+                key_triple.key_name()
+            ],
+        )?;
+        let _ = transaction.execute(
+            "DELETE FROM kim_key_info
+                WHERE application_name = ?1 AND auth_type = ?2 AND provider_id = ?3
+                AND key_name = ?4",
+            params![
+                key_triple.app_name().as_str(),
+                u8::from(key_triple.authenticator_type()),
+                key_triple.provider_id as u8,
+                key_triple.key_name()
+            ],
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Moves the mappings of the key triples to the application `to`, in a single transaction.
+    /// The key info are serialised again as encrypted key info are bound to their key triple.
+    fn transfer_mappings(
+        &self,
+        connection: &mut Connection,
+        key_triples: &[KeyTriple],
+        to: &ApplicationName,
+    ) -> Result<()> {
+        let transaction = connection.transaction()?;
+        for key_triple in key_triples.iter() {
+            if let Some(key_info) = self.key_store.get(key_triple) {
+                self.delete_mapping(&transaction, key_triple)?;
+                self.save_mapping(
+                    &transaction,
+                    &key_triple.with_app_name(to.clone()),
+                    &key_info,
+                )?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+impl ManageKeyInfo for SQLiteKeyInfoManager {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<KeyTriple>, String> {
+        Ok(self.key_store.get_all(provider_id))
+    }
+
+    fn get_all_for_app(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Result<Vec<KeyTriple>, String> {
+        Ok(self.key_store.get_all_for_app(provider_id, app_name))
+    }
+
+    fn get_clients(&self, provider_id: ProviderId) -> Result<Vec<ApplicationName>, String> {
+        Ok(self.key_store.get_clients(provider_id))
+    }
+
+    fn insert(&self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<Option<KeyInfo>, String> {
+        let connection = self.connection();
+        if let Err(err) = self.save_mapping(&connection, &key_triple, &key_info) {
+            Err(err.to_string())
+        } else {
+            Ok(self.key_store.insert(key_triple, key_info))
+        }
+    }
+
+    fn update(
+        &self,
+        key_triple: &KeyTriple,
+        update: &mut dyn FnMut(&mut KeyInfo),
+    ) -> Result<bool, String> {
+        let connection = self.connection();
+        let mut key_info = match self.key_store.get(key_triple) {
+            Some(key_info) => key_info,
+            None => return Ok(false),
+        };
+        update(&mut key_info);
+        if let Err(err) = self.save_mapping(&connection, key_triple, &key_info) {
+            Err(err.to_string())
+        } else {
+            let _ = self.key_store.insert(key_triple.clone(), key_info);
+            Ok(true)
+        }
+    }
+
+    fn remove(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        let connection = self.connection();
+        if let Err(err) = self.delete_mapping(&connection, key_triple) {
+            Err(err.to_string())
+        } else {
+            Ok(self.key_store.remove(key_triple))
+        }
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.key_store.contains(key_triple))
+    }
+
+    fn quarantine(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        let mut connection = self.connection();
+        if let Err(err) = self.quarantine_mapping(&mut connection, key_triple) {
+            Err(err.to_string())
+        } else {
+            Ok(self.key_store.remove(key_triple))
+        }
+    }
+
+    fn transfer(
+        &self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> Result<Option<usize>, String> {
+        let mut connection = self.connection();
+        let key_triples = self.key_store.get_all_for_app(provider_id, from);
+        if key_triples.iter().any(|key_triple| {
+            self.key_store
+                .contains(&key_triple.with_app_name(to.clone()))
+        }) {
+            return Ok(None);
+        }
+        if let Err(err) = self.transfer_mappings(&mut connection, &key_triples, to) {
+            Err(err.to_string())
+        } else {
+            Ok(self.key_store.transfer(provider_id, from, to))
+        }
+    }
+}
+
+/// Upgrades all the mappings of the database to the current format version, in a single
+/// transaction. In dry-run mode, the transaction is rolled back.
+///
+/// Databases written before the authenticator type was recorded are upgraded to the current
+/// schema, their mappings being moved to the namespace of the given authenticator type and
+/// counted as upgraded.
+///
+/// The service must not be running while the mappings are upgraded.
+///
+/// # Errors
+///
+/// Returns an error if the database does not exist or could not be accessed. Mappings which can
+/// not be read are reported in the `MigrationReport`.
+pub fn migrate_mappings(
+    database_path: &Path,
+    authenticator_type: AuthenticatorType,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    // Opening a connection would create a missing database.
+    if !database_path.exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no SQLite Key Info Manager database at {:?}", database_path),
+        )
+        .into());
+    }
+    let _instance_lock = lock_database(database_path)?;
+    let mut connection = Connection::open(database_path)?;
+
+    migrate_database(&mut connection, authenticator_type, dry_run)
+}
+
+/// Upgrades all the mappings of the database opened by the connection, which must be locked, to
+/// the current format version, as `migrate_mappings`.
+fn migrate_database(
+    connection: &mut Connection,
+    authenticator_type: AuthenticatorType,
+    dry_run: bool,
+) -> Result<MigrationReport> {
+    let schema_version = check_schema_version(connection)?;
+
+    let mut report = MigrationReport::default();
+    let transaction = connection.transaction()?;
+    let is_legacy = schema_version < AUTH_TYPE_SCHEMA_VERSION;
+    if is_legacy {
+        for table in ["kim_key_info", "kim_quarantined_key_info"].iter() {
+            if table_exists(&transaction, table)? {
+                let _ = transaction.execute(
+                    &format!("ALTER TABLE {} RENAME TO {}_v1", table, table),
+                    params![],
+                )?;
+            }
+        }
+        create_tables(&transaction)?;
+        if table_exists(&transaction, "kim_key_info_v1")? {
+            let _ = transaction.execute(
+                "INSERT INTO kim_key_info
+                    SELECT application_name, ?1, provider_id, key_name, key_info
+                    FROM kim_key_info_v1",
+                params![u8::from(authenticator_type)],
+            )?;
+            let _ = transaction.execute("DROP TABLE kim_key_info_v1", params![])?;
+        }
+        if table_exists(&transaction, "kim_quarantined_key_info_v1")? {
+            let _ = transaction.execute(
+                "INSERT INTO kim_quarantined_key_info
+                    SELECT application_name, ?1, provider_id, key_name, key_info, quarantined_at
+                    FROM kim_quarantined_key_info_v1",
+                params![u8::from(authenticator_type)],
+            )?;
+            let _ = transaction.execute("DROP TABLE kim_quarantined_key_info_v1", params![])?;
+        }
+        let _ = transaction.execute(
+            &format!("PRAGMA user_version = {}", SCHEMA_VERSION),
+            params![],
+        )?;
+    }
+
+    let mut mappings: Vec<(String, u8, u8, String, Vec<u8>)> = Vec::new();
+    {
+        let mut statement = transaction.prepare(
+            "SELECT application_name, auth_type, provider_id, key_name, key_info
+                FROM kim_key_info",
+        )?;
+        let mut rows = statement.query(params![])?;
+        while let Some(row) = rows.next()? {
+            mappings.push((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ));
+        }
+    }
+
+    for (app_name, auth_type, provider_id, key_name, key_info) in mappings.iter() {
+        match upgrade_key_info(key_info) {
+            Ok(None) if !is_legacy => report.up_to_date += 1,
+            Ok(None) => report.upgraded += 1,
+            Ok(Some(key_info)) => {
+                let _ = transaction.execute(
+                    "UPDATE kim_key_info SET key_info = ?5
+                        WHERE application_name = ?1 AND auth_type = ?2 AND provider_id = ?3
+                        AND key_name = ?4",
+                    params![app_name, auth_type, provider_id, key_name, key_info],
+                )?;
+                report.upgraded += 1;
+            }
+            Err(string) => report.failed.push((
+                format!(
+                    "Application Name: \"{}\", Authenticator: {}, Provider ID: {}, \
+                     Key Name: \"{}\"",
+                    app_name, auth_type, provider_id, key_name
+                ),
+                string,
+            )),
+        }
+    }
+
+    if dry_run {
+        transaction.rollback()?;
+    } else {
+        transaction.commit()?;
+    }
+
+    Ok(report)
+}
+
+/// SQLiteKeyInfoManager builder
+#[derive(Debug, Default)]
+pub struct SQLiteKeyInfoManagerBuilder {
+    database_path: Option<PathBuf>,
+    cipher: Option<MappingCipher>,
+}
+
+impl SQLiteKeyInfoManagerBuilder {
+    /// Create a new SQLiteKeyInfoManagerBuilder
+    pub fn new() -> SQLiteKeyInfoManagerBuilder {
+        SQLiteKeyInfoManagerBuilder {
+            database_path: None,
+            cipher: None,
+        }
+    }
+
+    /// Add a database file path to the builder
+    pub fn with_database_path(mut self, path: PathBuf) -> SQLiteKeyInfoManagerBuilder {
+        self.database_path = Some(path);
+
+        self
+    }
+
+    /// Add a cipher to encrypt the key info column to the builder
+    pub(super) fn with_cipher(mut self, cipher: MappingCipher) -> SQLiteKeyInfoManagerBuilder {
+        self.cipher = Some(cipher);
+
+        self
+    }
+
+    /// Build into a SQLiteKeyInfoManager
+    pub fn build(self) -> Result<SQLiteKeyInfoManager> {
+        SQLiteKeyInfoManager::new(
+            self.database_path
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH)),
+            self.cipher,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::format::serialize_key_info;
+    use super::super::{KeyInfo, KeyTriple, ManageKeyInfo};
+    use super::{migrate_mappings, SQLiteKeyInfoManager, SQLiteKeyInfoManagerBuilder};
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{AuthType, ProviderId};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn test_key_attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::Derive,
+            bits: 1024,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: true,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::AsymmetricSignature(
+                    AsymmetricSignature::RsaPkcs1v15Sign {
+                        hash_alg: SignHash::Specific(Hash::Sha256),
+                    },
+                ),
+            },
+        }
+    }
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        }
+    }
+
+    fn test_db_path(name: &str) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/{}_sqlite/sqlite-key-info-manager.sqlite3",
+            env!("OUT_DIR"),
+            name
+        ))
+    }
+
+    fn new_key_triple(key_name: String) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct),
+            ProviderId::MbedCrypto,
+            key_name,
+        )
+    }
+
+    #[test]
+    fn insert_get_key_info() {
+        let path = test_db_path("insert_get_key_info");
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+        let key_triple = new_key_triple("insert_get_key_info".to_string());
+        let key_info = test_key_info();
+
+        assert!(manager.get(&key_triple).unwrap().is_none());
+
+        assert!(manager
+            .insert(key_triple.clone(), key_info.clone())
+            .unwrap()
+            .is_none());
+
+        let stored_key_info = manager
+            .get(&key_triple)
+            .unwrap()
+            .expect("Failed to get key info");
+
+        assert_eq!(stored_key_info, key_info);
+        assert!(manager.remove(&key_triple).unwrap().is_some());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn second_instance_is_refused() {
+        let path = test_db_path("second_instance_is_refused");
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+        let _ = SQLiteKeyInfoManager::new(path.clone(), None).unwrap_err();
+        let _ = migrate_mappings(&path, AuthType::Direct.into(), true).unwrap_err();
+
+        drop(manager);
+        let _ = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn legacy_database_is_migrated() {
+        let path = test_db_path("legacy_database_is_migrated");
+        let key_triple = new_key_triple("legacy key".to_string());
+        let key_info = test_key_info();
+
+        write_legacy_database(&path, &key_triple, &key_info);
+        let _ = SQLiteKeyInfoManager::new(path.clone(), None).unwrap_err();
+
+        let report = migrate_mappings(&path, AuthType::Direct.into(), true).unwrap();
+        assert_eq!(report.upgraded, 1);
+        let _ = SQLiteKeyInfoManager::new(path.clone(), None).unwrap_err();
+
+        let report = migrate_mappings(&path, AuthType::Direct.into(), false).unwrap();
+        assert_eq!(report.upgraded, 1);
+        assert!(report.failed.is_empty());
+        let report = migrate_mappings(&path, AuthType::Direct.into(), false).unwrap();
+        assert_eq!(report.upgraded, 0);
+        assert_eq!(report.up_to_date, 1);
+
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+        assert_eq!(manager.remove(&key_triple).unwrap().unwrap(), key_info);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn legacy_database_is_refused_on_start() {
+        let path = test_db_path("legacy_database_is_refused_on_start");
+        let key_triple = new_key_triple("legacy key".to_string());
+        let key_info = test_key_info();
+
+        write_legacy_database(&path, &key_triple, &key_info);
+        let error = SQLiteKeyInfoManagerBuilder::new()
+            .with_database_path(path.clone())
+            .build()
+            .unwrap_err();
+        assert!(error.to_string().contains("parsec migrate-mappings"));
+
+        // The database is left as it is until it is migrated.
+        let report = migrate_mappings(&path, AuthType::Direct.into(), true).unwrap();
+        assert_eq!(report.upgraded, 1);
+        assert_eq!(report.up_to_date, 0);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Writes a database as before the authenticator type was recorded.
+    fn write_legacy_database(path: &Path, key_triple: &KeyTriple, key_info: &KeyInfo) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let connection = rusqlite::Connection::open(path).unwrap();
+        let _ = connection
+            .execute(
+                "CREATE TABLE kim_key_info (
+                    application_name TEXT NOT NULL,
+                    provider_id INTEGER NOT NULL,
+                    key_name TEXT NOT NULL,
+                    key_info BLOB NOT NULL,
+                    PRIMARY KEY (application_name, provider_id, key_name)
+                )",
+                rusqlite::params![],
+            )
+            .unwrap();
+        let _ = connection
+            .execute(
+                "INSERT INTO kim_key_info VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    key_triple.app_name().as_str(),
+                    key_triple.provider_id as u8,
+                    key_triple.key_name(),
+                    serialize_key_info(key_triple, key_info, None).unwrap()
+                ],
+            )
+            .unwrap();
+        let _ = connection
+            .execute("PRAGMA user_version = 1", rusqlite::params![])
+            .unwrap();
+    }
+
+    #[test]
+    fn remove_unexisting_key() {
+        let path = test_db_path("remove_unexisting_key");
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+        let key_triple = new_key_triple("remove_unexisting_key".to_string());
+        assert_eq!(manager.remove(&key_triple).unwrap(), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn quarantine_moves_mapping() {
+        let path = test_db_path("quarantine_moves_mapping");
+        let key_triple = new_key_triple("quarantine_moves_mapping".to_string());
+        let key_info = test_key_info();
+        {
+            let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+
+            assert_eq!(manager.quarantine(&key_triple).unwrap().unwrap(), key_info);
+            assert!(!manager.exists(&key_triple).unwrap());
+            assert_eq!(manager.quarantine(&key_triple).unwrap(), None);
+        }
+
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+        assert!(!manager.exists(&key_triple).unwrap());
+        let quarantined: u32 = rusqlite::Connection::open(&path)
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM kim_quarantined_key_info",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(quarantined, 1);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn transfer_is_persisted() {
+        let path = test_db_path("transfer_is_persisted");
+        let to = ApplicationName::new("1000".to_string(), AuthType::UnixPeerCredentials);
+        let key_triple = new_key_triple("transfer_is_persisted".to_string());
+        let key_info = test_key_info();
+        {
+            let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+            assert_eq!(
+                manager
+                    .transfer(ProviderId::MbedCrypto, key_triple.app_name(), &to)
+                    .unwrap(),
+                Some(1)
+            );
+        }
+
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+        assert!(!manager.exists(&key_triple).unwrap());
+        assert_eq!(
+            manager
+                .remove(&key_triple.with_app_name(to))
+                .unwrap()
+                .unwrap(),
+            key_info
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn insert_overwrites() {
+        let path = test_db_path("insert_overwrites");
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+        let key_triple = new_key_triple("insert_overwrites".to_string());
+        let key_info_1 = test_key_info();
+        let key_info_2 = KeyInfo {
+            id: vec![0xaa, 0xbb, 0xcc],
+            attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        };
+
+        let _ = manager.insert(key_triple.clone(), key_info_1).unwrap();
+        let _ = manager
+            .insert(key_triple.clone(), key_info_2.clone())
+            .unwrap();
+
+        // The overwritten mapping should also have been replaced in the database.
+        drop(manager);
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+        assert_eq!(manager.remove(&key_triple).unwrap().unwrap(), key_info_2);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn big_names() {
+        let path = test_db_path("big_names");
+        let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+        let big_app_name = ApplicationName::new("😀".repeat(1000), AuthType::Direct);
+        let big_key_name = "  Lorem ipsum dolor sit amet".repeat(100);
+
+        let key_triple = KeyTriple::new(big_app_name, ProviderId::Pkcs11, big_key_name);
+        let key_info = test_key_info();
+
+        let _ = manager
+            .insert(key_triple.clone(), key_info.clone())
+            .unwrap();
+        assert_eq!(manager.remove(&key_triple).unwrap().unwrap(), key_info);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn create_and_load() {
+        let path = test_db_path("create_and_load");
+
+        let app_name1 = ApplicationName::new("😀 Application One 😀".to_string(), AuthType::Direct);
+        let key_name1 = "😀 Key One 😀".to_string();
+        let key_triple1 = KeyTriple::new(app_name1, ProviderId::Core, key_name1);
+        let key_info1 = test_key_info();
+
+        let app_name2 = ApplicationName::new("😇 Application Two 😇".to_string(), AuthType::Direct);
+        let key_name2 = "😇 Key Two 😇".to_string();
+        let key_triple2 = KeyTriple::new(app_name2, ProviderId::MbedCrypto, key_name2);
+        let key_info2 = KeyInfo {
+            id: vec![0x12, 0x22, 0x32],
+            attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        };
+
+        let app_name3 =
+            ApplicationName::new("😈 Application Three 😈".to_string(), AuthType::Direct);
+        let key_name3 = "😈 Key Three 😈".to_string();
+        let key_triple3 = KeyTriple::new(app_name3, ProviderId::Core, key_name3);
+        let key_info3 = KeyInfo {
+            id: vec![0x13, 0x23, 0x33],
+            attributes: test_key_attributes(),
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        };
+        {
+            let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+            let _ = manager
+                .insert(key_triple1.clone(), key_info1.clone())
+                .unwrap();
+            let _ = manager
+                .insert(key_triple2.clone(), key_info2.clone())
+                .unwrap();
+            let _ = manager
+                .insert(key_triple3.clone(), key_info3.clone())
+                .unwrap();
+        }
+        // The local hashmap is dropped when leaving the inner scope.
+        {
+            let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+            assert_eq!(manager.get_all(ProviderId::Core).unwrap().len(), 2);
+            assert_eq!(manager.remove(&key_triple1).unwrap().unwrap(), key_info1);
+            assert_eq!(manager.remove(&key_triple2).unwrap().unwrap(), key_info2);
+            assert_eq!(manager.remove(&key_triple3).unwrap().unwrap(), key_info3);
+        }
+        // Removals are persisted as well.
+        {
+            let manager = SQLiteKeyInfoManager::new(path.clone(), None).unwrap();
+
+            assert!(manager.get_all(ProviderId::Core).unwrap().is_empty());
+            assert!(manager.get_all(ProviderId::MbedCrypto).unwrap().is_empty());
+        }
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Advisory locking of the mapping stores between instances of the service
+//!
+//! The locks are taken with `flock`, on files next to the mappings. They are advisory: they only
+//! protect the stores from other Parsec instances, which all take them. They are released when
+//! the file is closed, which includes the process exiting or crashing, so that a stale lock can
+//! never prevent the service from starting.
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Kind of lock taken on a file
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum LockMode {
+    /// Several instances can hold the lock at the same time.
+    Shared,
+    /// Only one instance can hold the lock.
+    Exclusive,
+}
+
+/// Advisory lock on a file, released when dropped
+#[derive(Debug)]
+pub(super) struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Takes a lock on the file at `path`, which is created if needed. Returns `None` without
+    /// blocking if a conflicting lock is held by another instance.
+    ///
+    /// # Errors
+    ///
+    /// Returns an std::io error if the file could not be opened or locked.
+    pub fn try_lock(path: &Path, mode: LockMode) -> std::io::Result<Option<FileLock>> {
+        let file = open_lock_file(path)?;
+        match flock(&file, mode, false) {
+            Ok(()) => Ok(Some(FileLock { file })),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes a lock on the file at `path`, which is created if needed, waiting for conflicting
+    /// locks held by other instances to be released.
+    ///
+    /// # Errors
+    ///
+    /// Returns an std::io error if the file could not be opened or locked.
+    pub fn lock(path: &Path, mode: LockMode) -> std::io::Result<FileLock> {
+        let file = open_lock_file(path)?;
+        flock(&file, mode, true)?;
+
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock anyway, unlocking explicitly only makes it visible
+        // earlier to the other instances.
+        let _ = unlock(&self.file);
+    }
+}
+
+/// Opens the file used for locking, creating it if needed.
+fn open_lock_file(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+}
+
+/// Takes a lock on an open file.
+fn flock(file: &File, mode: LockMode, blocking: bool) -> std::io::Result<()> {
+    let mut operation = match mode {
+        LockMode::Shared => libc::LOCK_SH,
+        LockMode::Exclusive => libc::LOCK_EX,
+    };
+    if !blocking {
+        operation |= libc::LOCK_NB;
+    }
+
+    loop {
+        // Safe as the file descriptor is valid for the lifetime of `file`.
+        let ret = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let error = Error::last_os_error();
+        // A blocking call can be interrupted by a signal, such as SIGHUP.
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Releases the lock held on an open file.
+fn unlock(file: &File) -> std::io::Result<()> {
+    // Safe as the file descriptor is valid for the lifetime of `file`.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
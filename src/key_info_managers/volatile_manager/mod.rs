@@ -0,0 +1,483 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! A key info manager keeping key triple to key info mapping in memory only
+//!
+//! The mappings are lost when the service stops: nothing is ever written to disk. This manager is
+//! meant for ephemeral deployments, such as containers or CI jobs, where keys must not outlive the
+//! service. It should be paired with providers whose keys do not outlive the service either,
+//! otherwise the keys they store are left behind without any mapping referencing them.
+use super::index::KeyInfoIndex;
+use super::{KeyInfo, KeyTriple, ManageKeyInfo};
+use crate::authenticators::ApplicationName;
+use anyhow::Result;
+use parsec_interface::requests::ProviderId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A key info manager keeping key triple to key info mapping in memory only
+#[derive(Debug, Default)]
+pub struct VolatileKeyInfoManager {
+    /// Mappings, only stored in memory.
+    key_store: KeyInfoIndex,
+    /// Quarantined mappings, only stored in memory.
+    quarantined: Mutex<HashMap<KeyTriple, KeyInfo>>,
+}
+
+impl ManageKeyInfo for VolatileKeyInfoManager {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderId) -> Result<Vec<KeyTriple>, String> {
+        Ok(self.key_store.get_all(provider_id))
+    }
+
+    fn get_all_for_app(
+        &self,
+        provider_id: ProviderId,
+        app_name: &ApplicationName,
+    ) -> Result<Vec<KeyTriple>, String> {
+        Ok(self.key_store.get_all_for_app(provider_id, app_name))
+    }
+
+    fn get_clients(&self, provider_id: ProviderId) -> Result<Vec<ApplicationName>, String> {
+        Ok(self.key_store.get_clients(provider_id))
+    }
+
+    fn insert(&self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.insert(key_triple, key_info))
+    }
+
+    fn update(
+        &self,
+        key_triple: &KeyTriple,
+        update: &mut dyn FnMut(&mut KeyInfo),
+    ) -> Result<bool, String> {
+        Ok(self.key_store.update(key_triple, update))
+    }
+
+    fn remove(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.remove(key_triple))
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.key_store.contains(key_triple))
+    }
+
+    fn quarantine(&self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        match self.key_store.remove(key_triple) {
+            Some(key_info) => {
+                let _ = self
+                    .quarantined
+                    .lock()
+                    .expect("Quarantined mappings lock poisoned")
+                    .insert(key_triple.clone(), key_info.clone());
+                Ok(Some(key_info))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn transfer(
+        &self,
+        provider_id: ProviderId,
+        from: &ApplicationName,
+        to: &ApplicationName,
+    ) -> Result<Option<usize>, String> {
+        Ok(self.key_store.transfer(provider_id, from, to))
+    }
+}
+
+/// VolatileKeyInfoManager builder
+#[derive(Debug, Default, Copy, Clone)]
+pub struct VolatileKeyInfoManagerBuilder {}
+
+impl VolatileKeyInfoManagerBuilder {
+    /// Create a new VolatileKeyInfoManagerBuilder
+    pub fn new() -> VolatileKeyInfoManagerBuilder {
+        VolatileKeyInfoManagerBuilder {}
+    }
+
+    /// Build into a VolatileKeyInfoManager
+    pub fn build(self) -> Result<VolatileKeyInfoManager> {
+        Ok(VolatileKeyInfoManager::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::acl::KeyPermission;
+    use super::super::expiry::KeyLifetimes;
+    use super::super::{KeyInfo, KeyInfoManagerFactory, KeyTriple, ManageKeyInfo};
+    use super::VolatileKeyInfoManagerBuilder;
+    use crate::authenticators::{ApplicationName, AuthenticatorType};
+    use crate::utils::config::{KeyInfoManagerConfig, KeyInfoManagerType};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{AuthType, ProviderId, ResponseStatus};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Volatile,
+                key_type: Type::Derive,
+                bits: 1024,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::RsaPkcs1v15Sign {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            metadata: Default::default(),
+            expires_at: None,
+            grants: Vec::new(),
+        }
+    }
+
+    fn new_key_triple(provider_id: ProviderId, key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct),
+            provider_id,
+            key_name.to_string(),
+        )
+    }
+
+    #[test]
+    fn insert_get_remove_key_info() {
+        let manager = VolatileKeyInfoManagerBuilder::new().build().unwrap();
+        let key_triple = new_key_triple(ProviderId::MbedCrypto, "insert_get_remove_key_info");
+        let key_info = test_key_info();
+
+        assert!(!manager.exists(&key_triple).unwrap());
+        assert!(manager
+            .insert(key_triple.clone(), key_info.clone())
+            .unwrap()
+            .is_none());
+        assert_eq!(manager.get(&key_triple).unwrap().unwrap(), key_info);
+        assert_eq!(manager.remove(&key_triple).unwrap().unwrap(), key_info);
+        assert!(manager.remove(&key_triple).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_all_filters_provider() {
+        let manager = VolatileKeyInfoManagerBuilder::new().build().unwrap();
+        let key_triple1 = new_key_triple(ProviderId::MbedCrypto, "key one");
+        let key_triple2 = new_key_triple(ProviderId::Core, "key two");
+
+        let _ = manager
+            .insert(key_triple1.clone(), test_key_info())
+            .unwrap();
+        let _ = manager.insert(key_triple2, test_key_info()).unwrap();
+
+        assert_eq!(
+            manager.get_all(ProviderId::MbedCrypto).unwrap(),
+            vec![key_triple1]
+        );
+        assert!(manager.get_all(ProviderId::Pkcs11).unwrap().is_empty());
+    }
+
+    #[test]
+    fn transfer_moves_all_mappings_or_none() {
+        let manager = VolatileKeyInfoManagerBuilder::new().build().unwrap();
+        let from = ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct);
+        let to = ApplicationName::new("1000".to_string(), AuthType::UnixPeerCredentials);
+        let key_triple1 = new_key_triple(ProviderId::MbedCrypto, "key one");
+        let key_triple2 = new_key_triple(ProviderId::MbedCrypto, "key two");
+        let _ = manager
+            .insert(key_triple1.clone(), test_key_info())
+            .unwrap();
+        let _ = manager
+            .insert(key_triple2.clone(), test_key_info())
+            .unwrap();
+
+        // "key two" already exists for the other application.
+        let _ = manager
+            .insert(key_triple2.with_app_name(to.clone()), test_key_info())
+            .unwrap();
+        assert_eq!(
+            manager
+                .transfer(ProviderId::MbedCrypto, &from, &to)
+                .unwrap(),
+            None
+        );
+        assert!(manager.exists(&key_triple1).unwrap());
+
+        let _ = manager
+            .remove(&key_triple2.with_app_name(to.clone()))
+            .unwrap();
+        assert_eq!(
+            manager
+                .transfer(ProviderId::MbedCrypto, &from, &to)
+                .unwrap(),
+            Some(2)
+        );
+        assert!(manager
+            .get_all_for_app(ProviderId::MbedCrypto, &from)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            manager
+                .get(&key_triple1.with_app_name(to.clone()))
+                .unwrap()
+                .unwrap(),
+            test_key_info()
+        );
+        assert_eq!(
+            manager.get_clients(ProviderId::MbedCrypto).unwrap(),
+            vec![to]
+        );
+    }
+
+    #[test]
+    fn concurrent_providers_do_not_lose_mappings() {
+        let manager = Arc::new(VolatileKeyInfoManagerBuilder::new().build().unwrap());
+        let providers = [ProviderId::MbedCrypto, ProviderId::Pkcs11, ProviderId::Tpm];
+
+        let threads: Vec<_> = providers
+            .iter()
+            .map(|provider_id| {
+                let manager = manager.clone();
+                let provider_id = *provider_id;
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key_triple = new_key_triple(provider_id, &format!("key {}", i));
+                        let _ = manager.insert(key_triple.clone(), test_key_info()).unwrap();
+                        let mut add_id_byte = |key_info: &mut KeyInfo| key_info.id.push(i);
+                        assert!(manager.update(&key_triple, &mut add_id_byte).unwrap());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for provider_id in providers.iter() {
+            assert_eq!(manager.get_all(*provider_id).unwrap().len(), 100);
+            let key_triple = new_key_triple(*provider_id, "key 42");
+            let key_info = manager.get(&key_triple).unwrap().unwrap();
+            assert_eq!(key_info.id, vec![0x11, 0x22, 0x33, 42]);
+        }
+    }
+
+    #[test]
+    fn client_records_key_metadata() {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        let factory = KeyInfoManagerFactory::new(&config, None).unwrap();
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let key_triple = client.get_key_triple(
+            ApplicationName::new("Testing Application 😎".to_string(), AuthType::Direct),
+            "client_records_key_metadata".to_string(),
+        );
+
+        client
+            .insert_key_info(key_triple.clone(), &1u32, test_key_info().attributes)
+            .unwrap();
+        let metadata = client.get_key_metadata(&key_triple).unwrap();
+        assert!(metadata.created_at().is_some());
+        assert_eq!(
+            metadata.creator_authenticator_type(),
+            Some(AuthType::Direct.into())
+        );
+        assert_eq!(metadata.provider_version(), Some(version::version!()));
+        assert_eq!(metadata.use_count(), 0);
+        assert!(metadata.last_used().is_none());
+
+        let _: u32 = client.peek_key_id(&key_triple).unwrap();
+        assert_eq!(client.get_key_metadata(&key_triple).unwrap().use_count(), 0);
+
+        for _ in 0..3 {
+            let _: u32 = client.get_key_id(&key_triple).unwrap();
+        }
+        let metadata = client.get_key_metadata(&key_triple).unwrap();
+        assert_eq!(metadata.use_count(), 3);
+        assert!(metadata.last_used().is_some());
+
+        client.remove_key_info(&key_triple).unwrap();
+        assert!(client.get_key_metadata(&key_triple).is_err());
+    }
+
+    #[test]
+    fn client_refuses_expired_keys() {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        let mut factory = KeyInfoManagerFactory::new(&config, None).unwrap();
+        let long_lived = ApplicationName::new("long lived".to_string(), AuthType::Direct);
+        let mut application_lifetimes = HashMap::new();
+        let _ = application_lifetimes.insert(long_lived.clone(), None);
+        factory.set_key_lifetimes(KeyLifetimes::new(Some(0), application_lifetimes));
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let expired = client.get_key_triple(
+            ApplicationName::new("short lived".to_string(), AuthType::Direct),
+            "client_refuses_expired_keys".to_string(),
+        );
+        // Application with the same name as the long lived one, from another authenticator.
+        let other_expired = client.get_key_triple(
+            ApplicationName::new(
+                "long lived".to_string(),
+                AuthenticatorType::ClientCertificate,
+            ),
+            "client_refuses_expired_keys".to_string(),
+        );
+        let not_expired =
+            client.get_key_triple(long_lived, "client_refuses_expired_keys".to_string());
+        for key_triple in [&expired, &other_expired, &not_expired].iter() {
+            client
+                .insert_key_info((*key_triple).clone(), &1u32, test_key_info().attributes)
+                .unwrap();
+        }
+
+        assert_eq!(
+            client.check_not_expired(&expired).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            client.check_not_expired(&other_expired).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        client.check_not_expired(&not_expired).unwrap();
+        let mut expired_keys = client.get_expired().unwrap();
+        expired_keys.sort_by_key(|key_triple| key_triple.app_name().to_string());
+        assert_eq!(expired_keys, vec![other_expired.clone(), expired.clone()]);
+
+        client.set_key_expiry(&expired, None).unwrap();
+        client.check_not_expired(&expired).unwrap();
+        assert_eq!(client.get_expired().unwrap(), vec![other_expired]);
+    }
+
+    #[test]
+    fn client_indexes_expired_keys() {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        let factory = KeyInfoManagerFactory::new(&config, None).unwrap();
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let from = ApplicationName::new("from".to_string(), AuthType::Direct);
+        let to = ApplicationName::new("to".to_string(), AuthType::Direct);
+        let key_triple = client.get_key_triple(from.clone(), "expiring".to_string());
+        let other_key_triple = client.get_key_triple(from.clone(), "other".to_string());
+        for key_triple in [&key_triple, &other_key_triple].iter() {
+            client
+                .insert_key_info((*key_triple).clone(), &1u32, test_key_info().attributes)
+                .unwrap();
+        }
+        assert!(client.get_expired().unwrap().is_empty());
+
+        // The expiry times changed after the provider was indexed are found.
+        client.set_key_expiry(&key_triple, Some(0)).unwrap();
+        assert_eq!(client.get_expired().unwrap(), vec![key_triple.clone()]);
+        assert_eq!(client.transfer_keys(&from, &to).unwrap(), 2);
+        let key_triple = key_triple.with_app_name(to);
+        assert_eq!(client.get_expired().unwrap(), vec![key_triple.clone()]);
+        client.remove_key_info(&key_triple).unwrap();
+        assert!(client.get_expired().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_shares_granted_keys() {
+        let config = KeyInfoManagerConfig {
+            name: String::from("volatile"),
+            manager_type: KeyInfoManagerType::Volatile,
+            store_path: None,
+            encryption: None,
+            shared: None,
+            watch: None,
+        };
+        let factory = KeyInfoManagerFactory::new(&config, None).unwrap();
+        let client = factory.build_client(ProviderId::MbedCrypto);
+        let owner = ApplicationName::new("owner".to_string(), AuthType::Direct);
+        let grantee = ApplicationName::new("grantee".to_string(), AuthType::Direct);
+        let key_name = "client_shares_granted_keys".to_string();
+        let key_triple = client.get_key_triple(owner.clone(), key_name.clone());
+        client
+            .insert_key_info(key_triple.clone(), &1u32, test_key_info().attributes)
+            .unwrap();
+
+        assert_eq!(
+            client
+                .grant_key_access(&key_triple, &owner, &[KeyPermission::Sign])
+                .unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+        assert!(client
+            .find_shared_key(&grantee, &key_name, KeyPermission::Sign)
+            .unwrap()
+            .is_none());
+
+        client
+            .grant_key_access(&key_triple, &grantee, &[KeyPermission::Sign])
+            .unwrap();
+        let grants = client.get_key_grants(&key_triple).unwrap();
+        assert_eq!(grants.len(), 1);
+        assert_eq!(grants[0].app_name(), Some(grantee.clone()));
+        assert_eq!(grants[0].permissions(), vec![KeyPermission::Sign]);
+        assert_eq!(
+            client
+                .find_shared_key(&grantee, &key_name, KeyPermission::Sign)
+                .unwrap(),
+            Some(key_triple.clone())
+        );
+        assert!(client
+            .find_shared_key(&grantee, &key_name, KeyPermission::Verify)
+            .unwrap()
+            .is_none());
+
+        // The key keeps its grants when it is transferred.
+        let new_owner = ApplicationName::new("new owner".to_string(), AuthType::Direct);
+        assert_eq!(client.transfer_keys(&owner, &new_owner).unwrap(), 1);
+        let key_triple = key_triple.with_app_name(new_owner);
+        assert_eq!(
+            client
+                .find_shared_key(&grantee, &key_name, KeyPermission::Sign)
+                .unwrap(),
+            Some(key_triple.clone())
+        );
+
+        client.grant_key_access(&key_triple, &grantee, &[]).unwrap();
+        assert!(client.get_key_grants(&key_triple).unwrap().is_empty());
+        assert!(client
+            .find_shared_key(&grantee, &key_name, KeyPermission::Sign)
+            .unwrap()
+            .is_none());
+    }
+}
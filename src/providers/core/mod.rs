@@ -6,7 +6,10 @@
 //! aiding clients in discovering the capabilities offered by their underlying
 //! platform.
 use super::Provide;
-use crate::authenticators::ApplicationName;
+use crate::authenticators::{ApplicationName, AuthenticatorType};
+use crate::key_info_managers::acl::{KeyGrant, KeyPermission};
+use crate::key_info_managers::metadata::KeyMetadata;
+use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
 use derivative::Derivative;
 use log::{error, trace};
 use parsec_interface::operations::{
@@ -44,9 +47,217 @@ pub struct Provider {
     wire_protocol_version_maj: u8,
     provider_info: Vec<ProviderInfo>,
     provider_opcodes: HashMap<ProviderId, HashSet<Opcode>>,
-    authenticator_info: Vec<AuthenticatorInfo>,
+    authenticator_info: Vec<(AuthenticatorType, AuthenticatorInfo)>,
     #[derivative(Debug = "ignore")]
     prov_list: Vec<Arc<dyn Provide + Send + Sync>>,
+    key_info_manager_clients: HashMap<ProviderId, KeyInfoManagerClient>,
+}
+
+impl Provider {
+    /// Returns the metadata of the key `key_name` of the application `app_name` in the provider
+    /// `provider_id`, for the `key-metadata` admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider does not exist and `PsaErrorDoesNotExist`
+    /// if the key does not exist.
+    pub fn key_metadata(
+        &self,
+        provider_id: ProviderId,
+        app_name: ApplicationName,
+        key_name: String,
+    ) -> Result<KeyMetadata> {
+        let client = self
+            .key_info_manager_clients
+            .get(&provider_id)
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+
+        client.get_key_metadata(&client.get_key_triple(app_name, key_name))
+    }
+
+    /// Returns the metadata of all the keys of the provider `provider_id`, to find the stale ones,
+    /// for the `list-key-metadata` admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider does not exist.
+    pub fn list_key_metadata(
+        &self,
+        provider_id: ProviderId,
+    ) -> Result<Vec<(KeyTriple, KeyMetadata)>> {
+        let client = self
+            .key_info_manager_clients
+            .get(&provider_id)
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+        let mut key_metadata = Vec::new();
+        for key_triple in client.get_all()? {
+            match client.get_key_metadata(&key_triple) {
+                Ok(metadata) => key_metadata.push((key_triple, metadata)),
+                // The key was removed concurrently.
+                Err(ResponseStatus::PsaErrorDoesNotExist) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(key_metadata)
+    }
+
+    /// Sets the expiry time of the key `key_name` of the application `app_name` in the provider
+    /// `provider_id`, in seconds since the Unix epoch. The key does not expire anymore if
+    /// `expires_at` is `None`. Expired keys can not be used anymore and are destroyed by the key
+    /// reaper, when the expiry of the keys is configured.
+    ///
+    /// This is the `set-key-expiry` admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider does not exist and `PsaErrorDoesNotExist`
+    /// if the key does not exist.
+    pub fn set_key_expiry(
+        &self,
+        provider_id: ProviderId,
+        app_name: ApplicationName,
+        key_name: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let client = self
+            .key_info_manager_clients
+            .get(&provider_id)
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+
+        client.set_key_expiry(&client.get_key_triple(app_name, key_name), expires_at)
+    }
+
+    /// Grants the application `grantee` the permissions on the key `key_name` of the application
+    /// `owner` in the provider `provider_id`, replacing the permissions granted to it before. The
+    /// permissions of `grantee` are revoked if `permissions` is empty.
+    ///
+    /// This is the `grant-key-access` admin command, run on behalf of `owner`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider does not exist, `PsaErrorDoesNotExist` if
+    /// the key does not exist and `PsaErrorInvalidArgument` if `grantee` is `owner`.
+    pub fn grant_key_access(
+        &self,
+        provider_id: ProviderId,
+        owner: ApplicationName,
+        key_name: String,
+        grantee: ApplicationName,
+        permissions: &[KeyPermission],
+    ) -> Result<()> {
+        let client = self
+            .key_info_manager_clients
+            .get(&provider_id)
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+
+        client.grant_key_access(
+            &client.get_key_triple(owner, key_name),
+            &grantee,
+            permissions,
+        )
+    }
+
+    /// Returns the permissions on the key `key_name` of the application `owner` in the provider
+    /// `provider_id` granted to other applications.
+    ///
+    /// This is the `list-key-grants` admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider does not exist and `PsaErrorDoesNotExist`
+    /// if the key does not exist.
+    pub fn key_grants(
+        &self,
+        provider_id: ProviderId,
+        owner: ApplicationName,
+        key_name: String,
+    ) -> Result<Vec<KeyGrant>> {
+        let client = self
+            .key_info_manager_clients
+            .get(&provider_id)
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+
+        client.get_key_grants(&client.get_key_triple(owner, key_name))
+    }
+
+    /// Moves all the keys of the application `from` to the application `to`, in all the
+    /// providers, and returns the number of keys moved. This keeps the keys of a client reachable
+    /// when its application name changes, for example when it switches to another authenticator.
+    ///
+    /// The keys are moved atomically per provider: either all the keys of `from` in a provider
+    /// are moved or none of them.
+    ///
+    /// This is the `transfer-client` admin command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if `from` and `to` are the same application. Returns
+    /// `PsaErrorAlreadyExists`, without moving any key, if `to` already has a key with the same
+    /// name as one of the keys of `from` in a provider. If moving the keys fails in a provider,
+    /// the keys of the providers already processed stay moved.
+    pub fn transfer_client(&self, from: ApplicationName, to: ApplicationName) -> Result<usize> {
+        if from == to {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        // The key names are checked in all the providers first so that a conflict does not leave
+        // the keys of the client split between the two applications.
+        for client in self.key_info_manager_clients.values() {
+            let to_keys: HashSet<String> = client
+                .list_keys(&to)?
+                .into_iter()
+                .map(|key| key.name)
+                .collect();
+            if client
+                .list_keys(&from)?
+                .iter()
+                .any(|key| to_keys.contains(&key.name))
+            {
+                return Err(ResponseStatus::PsaErrorAlreadyExists);
+            }
+        }
+
+        let mut transferred = 0;
+        for (provider_id, client) in &self.key_info_manager_clients {
+            transferred += client.transfer_keys(&from, &to).map_err(|e| {
+                error!(
+                    "transfer_keys failed on provider {} with {}",
+                    provider_id, e
+                );
+                e
+            })?;
+        }
+
+        Ok(transferred)
+    }
+
+    /// Destroys all the keys of the client in all the providers.
+    fn delete_client_keys(&self, client: ApplicationName) {
+        for provider in &self.prov_list {
+            let id = if let Ok((provider_info, _)) = provider.describe() {
+                provider_info.id.to_string()
+            } else {
+                "unknown".to_string()
+            };
+            // Currently Parsec only stores keys, we delete all of them.
+            let keys = provider
+                .list_keys(client.clone(), list_keys::Operation {})
+                .unwrap_or_else(|e| {
+                    error!("list_keys failed on provider {} with {}", id, e);
+                    list_keys::Result { keys: Vec::new() }
+                })
+                .keys;
+            for key in keys {
+                let key_name = key.name;
+                let _ = provider
+                    .psa_destroy_key(client.clone(), psa_destroy_key::Operation { key_name })
+                    .unwrap_or_else(|e| {
+                        error!("psa_destroy_key failed on provider {} with {}", id, e);
+                        psa_destroy_key::Result {}
+                    });
+            }
+        }
+    }
 }
 
 impl Provide for Provider {
@@ -70,11 +281,19 @@ impl Provide for Provider {
 
     fn list_authenticators(
         &self,
+        authenticator_types: &[AuthenticatorType],
         _op: list_authenticators::Operation,
     ) -> Result<list_authenticators::Result> {
         trace!("list_authenticators ingress");
+        // Each listener uses some of the authenticators, which the clients select with distinct
+        // authentication types.
         Ok(list_authenticators::Result {
-            authenticators: self.authenticator_info.clone(),
+            authenticators: self
+                .authenticator_info
+                .iter()
+                .filter(|(authenticator_type, _)| authenticator_types.contains(authenticator_type))
+                .map(|(_, authenticator_info)| authenticator_info.clone())
+                .collect(),
         })
     }
 
@@ -128,40 +347,27 @@ impl Provide for Provider {
         Ok(list_clients::Result { clients })
     }
 
-    fn delete_client(&self, op: delete_client::Operation) -> Result<delete_client::Result> {
+    fn delete_client(
+        &self,
+        app_name: ApplicationName,
+        op: delete_client::Operation,
+    ) -> Result<delete_client::Result> {
         trace!("delete_client ingress");
 
-        let client = op.client;
+        // Client names are only unique within the applications of an authenticator. As ListClients
+        // gives the names of the clients of all the authenticators, the clients with that name
+        // are deleted in all of them.
+        let mut authenticator_types: Vec<AuthenticatorType> = self
+            .authenticator_info
+            .iter()
+            .map(|(authenticator_type, _)| *authenticator_type)
+            .collect();
+        if !authenticator_types.contains(&app_name.authenticator_type()) {
+            authenticator_types.push(app_name.authenticator_type());
+        }
 
-        for provider in &self.prov_list {
-            let id = if let Ok((provider_info, _)) = provider.describe() {
-                provider_info.id.to_string()
-            } else {
-                "unknown".to_string()
-            };
-            // Currently Parsec only stores keys, we delete all of them.
-            let keys = provider
-                .list_keys(
-                    ApplicationName::from_name(client.clone()),
-                    list_keys::Operation {},
-                )
-                .unwrap_or_else(|e| {
-                    error!("list_keys failed on provider {} with {}", id, e);
-                    list_keys::Result { keys: Vec::new() }
-                })
-                .keys;
-            for key in keys {
-                let key_name = key.name;
-                let _ = provider
-                    .psa_destroy_key(
-                        ApplicationName::from_name(client.clone()),
-                        psa_destroy_key::Operation { key_name },
-                    )
-                    .unwrap_or_else(|e| {
-                        error!("psa_destroy_key failed on provider {} with {}", id, e);
-                        psa_destroy_key::Result {}
-                    });
-            }
+        for authenticator_type in authenticator_types {
+            self.delete_client_keys(ApplicationName::new(op.client.clone(), authenticator_type));
         }
 
         Ok(delete_client::Result {})
@@ -192,7 +398,8 @@ pub struct ProviderBuilder {
     #[derivative(Debug = "ignore")]
     prov_list: Vec<Arc<dyn Provide + Send + Sync>>,
     #[derivative(Debug = "ignore")]
-    authenticator_info: Vec<AuthenticatorInfo>,
+    authenticator_info: Vec<(AuthenticatorType, AuthenticatorInfo)>,
+    key_info_manager_clients: HashMap<ProviderId, KeyInfoManagerClient>,
 }
 
 impl ProviderBuilder {
@@ -203,6 +410,7 @@ impl ProviderBuilder {
             version_min: None,
             prov_list: Vec::new(),
             authenticator_info: Vec::new(),
+            key_info_manager_clients: HashMap::new(),
         }
     }
 
@@ -221,9 +429,23 @@ impl ProviderBuilder {
         self
     }
 
-    /// Add the authenticator information
-    pub fn with_authenticator_info(mut self, authenticator_info: AuthenticatorInfo) -> Self {
-        self.authenticator_info.push(authenticator_info);
+    /// Add the information of an authenticator of the given type
+    pub fn with_authenticator_info(
+        mut self,
+        authenticator_type: AuthenticatorType,
+        authenticator_info: AuthenticatorInfo,
+    ) -> Self {
+        self.authenticator_info
+            .push((authenticator_type, authenticator_info));
+
+        self
+    }
+
+    /// Add the Key Info Manager client of a provider, to read the metadata of its keys
+    pub fn with_key_info_manager_client(mut self, client: KeyInfoManagerClient) -> Self {
+        let _ = self
+            .key_info_manager_clients
+            .insert(client.provider_id(), client);
 
         self
     }
@@ -277,6 +499,7 @@ impl ProviderBuilder {
             provider_info: provider_info_vec,
             authenticator_info: self.authenticator_info,
             prov_list: self.prov_list,
+            key_info_manager_clients: self.key_info_manager_clients,
         };
 
         Ok(core_provider)
@@ -286,6 +509,85 @@ impl ProviderBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::AuthType;
+    use std::sync::Mutex;
+
+    /// Provider storing the names of the keys of each application
+    #[derive(Default)]
+    struct KeyNamesProvider {
+        keys: Mutex<HashMap<ApplicationName, Vec<String>>>,
+    }
+
+    impl Provide for KeyNamesProvider {
+        fn describe(&self) -> Result<(ProviderInfo, HashSet<Opcode>)> {
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+
+        fn list_keys(
+            &self,
+            app_name: ApplicationName,
+            _op: list_keys::Operation,
+        ) -> Result<list_keys::Result> {
+            let keys = self.keys.lock().unwrap();
+            let key_names = keys.get(&app_name).cloned().unwrap_or_default();
+            Ok(list_keys::Result {
+                keys: key_names
+                    .into_iter()
+                    .map(|name| KeyInfo {
+                        provider_id: ProviderId::MbedCrypto,
+                        name,
+                        attributes: Attributes {
+                            lifetime: Lifetime::Persistent,
+                            key_type: Type::Aes,
+                            bits: 128,
+                            policy: Policy {
+                                usage_flags: UsageFlags {
+                                    sign_hash: false,
+                                    verify_hash: false,
+                                    sign_message: false,
+                                    verify_message: false,
+                                    export: false,
+                                    encrypt: true,
+                                    decrypt: true,
+                                    cache: false,
+                                    copy: false,
+                                    derive: false,
+                                },
+                                permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+                            },
+                        },
+                    })
+                    .collect(),
+            })
+        }
+
+        fn list_clients(&self, _op: list_clients::Operation) -> Result<list_clients::Result> {
+            let keys = self.keys.lock().unwrap();
+            Ok(list_clients::Result {
+                clients: keys.keys().map(|app_name| app_name.to_string()).collect(),
+            })
+        }
+
+        fn psa_destroy_key(
+            &self,
+            app_name: ApplicationName,
+            op: psa_destroy_key::Operation,
+        ) -> Result<psa_destroy_key::Result> {
+            let mut keys = self.keys.lock().unwrap();
+            let key_names = keys
+                .get_mut(&app_name)
+                .ok_or(ResponseStatus::PsaErrorDoesNotExist)?;
+            key_names.retain(|name| name != &op.key_name);
+            if key_names.is_empty() {
+                let _ = keys.remove(&app_name);
+            }
+            Ok(psa_destroy_key::Result {})
+        }
+    }
 
     #[test]
     fn test_ping() {
@@ -296,6 +598,7 @@ mod tests {
             authenticator_info: Vec::new(),
             provider_opcodes: HashMap::new(),
             prov_list: Vec::new(),
+            key_info_manager_clients: HashMap::new(),
         };
         let op = ping::Operation {};
         let result = provider.ping(op).unwrap();
@@ -308,4 +611,94 @@ mod tests {
             provider.wire_protocol_version_min
         );
     }
+
+    #[test]
+    fn list_authenticators_of_the_listener() {
+        let info = |description: &str, id| list_authenticators::AuthenticatorInfo {
+            description: String::from(description),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id,
+        };
+        let provider = Provider {
+            wire_protocol_version_min: 8,
+            wire_protocol_version_maj: 10,
+            provider_info: Vec::new(),
+            authenticator_info: vec![
+                (AuthType::Direct.into(), info("direct", AuthType::Direct)),
+                (
+                    AuthenticatorType::ClientCertificate,
+                    info("client certificate", AuthType::Direct),
+                ),
+            ],
+            provider_opcodes: HashMap::new(),
+            prov_list: Vec::new(),
+            key_info_manager_clients: HashMap::new(),
+        };
+
+        let result = provider
+            .list_authenticators(
+                &[AuthenticatorType::ClientCertificate],
+                list_authenticators::Operation {},
+            )
+            .unwrap();
+        assert_eq!(result.authenticators.len(), 1);
+        assert_eq!(result.authenticators[0].description, "client certificate");
+    }
+
+    #[test]
+    fn delete_client_of_all_authenticators() {
+        let info = || list_authenticators::AuthenticatorInfo {
+            description: String::new(),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::Direct,
+        };
+        let key_names_provider = Arc::new(KeyNamesProvider::default());
+        let direct_client = ApplicationName::new(String::from("client"), AuthType::Direct);
+        let cert_client =
+            ApplicationName::new(String::from("client"), AuthenticatorType::ClientCertificate);
+        let other_client = ApplicationName::new(String::from("other"), AuthType::Direct);
+        {
+            let mut keys = key_names_provider.keys.lock().unwrap();
+            for app_name in &[&direct_client, &cert_client, &other_client] {
+                let _ = keys.insert(
+                    (*app_name).clone(),
+                    vec![String::from("key 1"), String::from("key 2")],
+                );
+            }
+        }
+        let provider = Provider {
+            wire_protocol_version_min: 8,
+            wire_protocol_version_maj: 10,
+            provider_info: Vec::new(),
+            authenticator_info: vec![
+                (AuthType::Direct.into(), info()),
+                (AuthenticatorType::ClientCertificate, info()),
+            ],
+            provider_opcodes: HashMap::new(),
+            prov_list: vec![key_names_provider.clone()],
+            key_info_manager_clients: HashMap::new(),
+        };
+
+        // The admin is authenticated by the client certificate authenticator but the client of
+        // the direct authenticator is deleted as well.
+        let admin =
+            ApplicationName::new(String::from("admin"), AuthenticatorType::ClientCertificate);
+        let _ = provider
+            .delete_client(
+                admin,
+                delete_client::Operation {
+                    client: String::from("client"),
+                },
+            )
+            .unwrap();
+
+        let keys = key_names_provider.keys.lock().unwrap();
+        assert!(!keys.contains_key(&direct_client));
+        assert!(!keys.contains_key(&cert_client));
+        assert_eq!(keys[&other_client].len(), 2);
+    }
 }
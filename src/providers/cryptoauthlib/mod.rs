@@ -4,8 +4,10 @@
 //!
 //! This provider implements Parsec operations using CryptoAuthentication
 //! Library backed by the ATECCx08 cryptochip.
+use super::opcode_filter::OpcodeFilter;
 use super::Provide;
 use crate::authenticators::ApplicationName;
+use crate::key_info_managers::reconciliation::ReconciliationReport;
 use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
 use crate::providers::cryptoauthlib::key_slot_storage::KeySlotStorage;
 use derivative::Derivative;
@@ -42,6 +44,7 @@ pub struct Provider {
     key_info_store: KeyInfoManagerClient,
     key_slots: KeySlotStorage,
     supported_opcodes: HashSet<Opcode>,
+    opcode_filter: OpcodeFilter,
 }
 
 impl Provider {
@@ -50,6 +53,7 @@ impl Provider {
         key_info_store: KeyInfoManagerClient,
         atca_iface: rust_cryptoauthlib::AtcaIfaceCfg,
         access_key_file_name: Option<String>,
+        opcode_filter: OpcodeFilter,
     ) -> Option<Provider> {
         // This will be returned when everything succeedes
         let mut cryptoauthlib_provider: Provider;
@@ -75,6 +79,7 @@ impl Provider {
             key_info_store,
             key_slots: KeySlotStorage::new(),
             supported_opcodes: HashSet::new(),
+            opcode_filter,
         };
 
         // Get the configuration from ATECC...
@@ -97,29 +102,17 @@ impl Provider {
         }
 
         // Validate key info store against hardware configuration.
-        // Delete invalid entries or invalid mappings.
+        // Quarantine invalid entries or invalid mappings.
         // Mark the slots free/busy appropriately.
-        let mut to_remove: Vec<KeyTriple> = Vec::new();
+        // ATECC slots always hold data, slots without mapping can not be told to contain a key.
+        let mut report = ReconciliationReport::new(ProviderId::CryptoAuthLib);
+        let mut to_quarantine: Vec<(KeyTriple, String)> = Vec::new();
         match cryptoauthlib_provider.key_info_store.get_all() {
             Ok(key_triples) => {
                 for key_triple in key_triples.iter().cloned() {
-                    match cryptoauthlib_provider
-                        .key_info_store
-                        .does_not_exist(&key_triple)
-                    {
-                        Ok(x) => x,
-                        Err(err) => {
-                            warn!("Error getting the Key ID for triple:\n{}\n(error: {}), continuing...",
-                                key_triple,
-                                err
-                            );
-                            to_remove.push(key_triple.clone());
-                            continue;
-                        }
-                    };
                     let key_info_id = match cryptoauthlib_provider
                         .key_info_store
-                        .get_key_id::<u8>(&key_triple)
+                        .peek_key_id::<u8>(&key_triple)
                     {
                         Ok(x) => x,
                         Err(err) => {
@@ -127,7 +120,8 @@ impl Provider {
                                 "Could not get key info id for key triple {:?} because {}",
                                 key_triple, err
                             );
-                            to_remove.push(key_triple.clone());
+                            to_quarantine
+                                .push((key_triple.clone(), format!("invalid key ID ({})", err)));
                             continue;
                         }
                     };
@@ -141,7 +135,10 @@ impl Provider {
                                 "Could not get key attributes for key triple {:?} because {}",
                                 key_triple, err
                             );
-                            to_remove.push(key_triple.clone());
+                            to_quarantine.push((
+                                key_triple.clone(),
+                                format!("invalid key attributes ({})", err),
+                            ));
                             continue;
                         }
                     };
@@ -149,11 +146,17 @@ impl Provider {
                         .key_slots
                         .key_validate_and_mark_busy(key_info_id, &key_info_attributes)
                     {
-                        Ok(None) => (),
-                        Ok(Some(warning)) => warn!("{} for key triple {:?}", warning, key_triple),
+                        Ok(None) => report.consistent += 1,
+                        Ok(Some(warning)) => {
+                            warn!("{} for key triple {:?}", warning, key_triple);
+                            report.consistent += 1;
+                        }
                         Err(err) => {
                             warn!("{} for key triple {:?}", err, key_triple);
-                            to_remove.push(key_triple.clone());
+                            to_quarantine.push((
+                                key_triple.clone(),
+                                format!("slot {} does not match the key ({})", key_info_id, err),
+                            ));
                             continue;
                         }
                     }
@@ -164,15 +167,19 @@ impl Provider {
                 return None;
             }
         };
-        for key_triple in to_remove.iter() {
-            if let Err(err) = cryptoauthlib_provider
-                .key_info_store
-                .remove_key_info(key_triple)
-            {
+        for (key_triple, reason) in to_quarantine.iter() {
+            if let Err(err) = cryptoauthlib_provider.key_info_store.quarantine_key_info(
+                key_triple,
+                reason,
+                &mut report,
+            ) {
                 error!("Key Info Manager error: {}", err);
                 return None;
             }
         }
+        cryptoauthlib_provider
+            .key_info_store
+            .set_reconciliation_report(report);
 
         if None == cryptoauthlib_provider.set_opcodes() {
             warn!("Failed to setup opcodes for cryptoauthlib_provider");
@@ -235,7 +242,7 @@ impl Provide for Provider {
             version_min: 1,
             version_rev: 0,
             id: ProviderId::CryptoAuthLib,
-        }, self.supported_opcodes.iter().copied().collect()))
+        }, self.opcode_filter.filter(self.supported_opcodes.iter().copied())))
     }
 
     fn list_keys(
@@ -427,6 +434,7 @@ pub struct ProviderBuilder {
     bus: Option<u8>,
     baud: Option<u32>,
     access_key_file_name: Option<String>,
+    opcode_filter: Option<OpcodeFilter>,
 }
 
 impl ProviderBuilder {
@@ -442,6 +450,7 @@ impl ProviderBuilder {
             bus: None,
             baud: None,
             access_key_file_name: None,
+            opcode_filter: None,
         }
     }
 
@@ -508,6 +517,13 @@ impl ProviderBuilder {
         self
     }
 
+    /// Restrict the operations of the provider
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> ProviderBuilder {
+        self.opcode_filter = Some(opcode_filter);
+
+        self
+    }
+
     /// Attempt to build CryptoAuthLib Provider
     pub fn build(self) -> std::io::Result<Provider> {
         let iface_cfg = match self.iface_type {
@@ -560,6 +576,7 @@ impl ProviderBuilder {
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info store"))?,
             iface_cfg,
             self.access_key_file_name,
+            self.opcode_filter.unwrap_or_default(),
         )
         .ok_or_else(|| {
             Error::new(
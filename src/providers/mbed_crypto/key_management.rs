@@ -1,6 +1,6 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
-use super::Provider;
+use super::{Provider, STORAGE_KEY_SEALING_KEY_ID};
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
 use log::error;
@@ -14,16 +14,18 @@ use psa_crypto::types::key;
 use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
 
 /// Creates a new PSA Key ID
+///
+/// The highest user key ID is reserved for the key sealing the Key Info Manager storage key.
 pub fn create_key_id(max_current_id: &AtomicU32) -> Result<key::psa_key_id_t> {
     // fetch_add adds 1 to the old value and returns the old value, so add 1 to local value for new ID
     let new_key_id = max_current_id.fetch_add(1, Relaxed) + 1;
-    if new_key_id > key::PSA_KEY_ID_USER_MAX {
+    if new_key_id >= STORAGE_KEY_SEALING_KEY_ID {
         // If storing key failed and no other keys were created in the mean time, it is safe to
         // decrement the key counter.
-        let _ = max_current_id.store(key::PSA_KEY_ID_USER_MAX, Relaxed);
+        let _ = max_current_id.store(STORAGE_KEY_SEALING_KEY_ID - 1, Relaxed);
         error!(
             "PSA max key ID limit of {} reached",
-            key::PSA_KEY_ID_USER_MAX
+            STORAGE_KEY_SEALING_KEY_ID - 1
         );
         return Err(ResponseStatus::PsaErrorInsufficientMemory);
     }
@@ -3,8 +3,10 @@
 //! Mbed Crypto provider
 //!
 //! This provider is a software based implementation of PSA Crypto, Mbed Crypto.
+use super::opcode_filter::OpcodeFilter;
 use super::Provide;
 use crate::authenticators::ApplicationName;
+use crate::key_info_managers::reconciliation::ReconciliationReport;
 use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
 use derivative::Derivative;
 use log::{error, trace};
@@ -32,6 +34,9 @@ mod generate_random;
 mod hash;
 mod key_agreement;
 pub(super) mod key_management;
+mod storage_key;
+
+pub use storage_key::{StorageKeySealer, STORAGE_KEY_SEALING_KEY_ID};
 
 const SUPPORTED_OPCODES: [Opcode; 15] = [
     Opcode::PsaGenerateKey,
@@ -71,14 +76,15 @@ pub struct Provider {
     // Holds the highest ID of all keys (including destroyed keys). New keys will receive an ID of
     // id_counter + 1. Once id_counter reaches the highest allowed ID, no more keys can be created.
     id_counter: AtomicU32,
+    opcode_filter: OpcodeFilter,
 }
 
 impl Provider {
     /// Creates and initialise a new instance of MbedCryptoProvider.
     /// Checks if there are not more keys stored in the Key Info Manager than in the MbedCryptoProvider and
-    /// if there, delete them. Adds Key IDs currently in use in the local IDs store.
+    /// if there, quarantine them. Reports the persistent keys which are not in the Key Info Manager.
     /// Returns `None` if the initialisation failed.
-    fn new(key_info_store: KeyInfoManagerClient) -> Option<Provider> {
+    fn new(key_info_store: KeyInfoManagerClient, opcode_filter: OpcodeFilter) -> Option<Provider> {
         // Safety: this function should be called before any of the other Mbed Crypto functions
         // are.
         if let Err(error) = psa_crypto::init() {
@@ -89,24 +95,30 @@ impl Provider {
             key_info_store,
             key_handle_mutex: Mutex::new(()),
             id_counter: AtomicU32::new(key::PSA_KEY_ID_USER_MIN),
+            opcode_filter,
         };
         let mut max_key_id: key::psa_key_id_t = key::PSA_KEY_ID_USER_MIN;
         {
-            let mut to_remove: Vec<KeyTriple> = Vec::new();
+            let mut report = ReconciliationReport::new(ProviderId::MbedCrypto);
+            let mut to_quarantine: Vec<(KeyTriple, String)> = Vec::new();
+            let mut mapped_key_ids = HashSet::new();
             // Go through all MbedCryptoProvider key triple to key info mappings and check if they are still
             // present.
-            // Delete those who are not present and add to the local_store the ones present.
+            // Quarantine those who are not present and add to the local_store the ones present.
             match mbed_crypto_provider.key_info_store.get_all() {
                 Ok(key_triples) => {
                     for key_triple in key_triples.iter().cloned() {
                         let key_id = match mbed_crypto_provider
                             .key_info_store
-                            .get_key_id(&key_triple)
+                            .peek_key_id(&key_triple)
                         {
                             Ok(key_id) => key_id,
                             Err(response_status) => {
                                 error!("Error getting the Key ID for triple:\n{}\n(error: {}), continuing...", key_triple, response_status);
-                                to_remove.push(key_triple.clone());
+                                to_quarantine.push((
+                                    key_triple.clone(),
+                                    format!("invalid key ID ({})", response_status),
+                                ));
                                 continue;
                             }
                         };
@@ -116,8 +128,13 @@ impl Provider {
                                 if key_id > max_key_id {
                                     max_key_id = key_id;
                                 }
+                                let _ = mapped_key_ids.insert(key_id);
+                                report.consistent += 1;
                             }
-                            Err(status::Error::DoesNotExist) => to_remove.push(key_triple.clone()),
+                            Err(status::Error::DoesNotExist) => to_quarantine.push((
+                                key_triple.clone(),
+                                format!("persistent key ID {} not found in Mbed Crypto", key_id),
+                            )),
                             Err(e) => {
                                 format_error!("Failed to open persistent Mbed Crypto key", e);
                                 return None;
@@ -129,15 +146,29 @@ impl Provider {
                     return None;
                 }
             };
-            for key_triple in to_remove.iter() {
+            for (key_triple, reason) in to_quarantine.iter() {
                 if mbed_crypto_provider
                     .key_info_store
-                    .remove_key_info(key_triple)
+                    .quarantine_key_info(key_triple, reason, &mut report)
                     .is_err()
                 {
                     return None;
                 }
             }
+            // Mbed Crypto can not list its keys: the IDs which could have been given to keys are
+            // probed instead.
+            for key_id in key::PSA_KEY_ID_USER_MIN..=max_key_id {
+                if !mapped_key_ids.contains(&key_id)
+                    && key::Id::from_persistent_key_id(key_id).is_ok()
+                {
+                    report
+                        .orphaned_keys
+                        .push(format!("persistent key ID {}", key_id));
+                }
+            }
+            mbed_crypto_provider
+                .key_info_store
+                .set_reconciliation_report(report);
         }
         mbed_crypto_provider.id_counter.store(max_key_id, Relaxed);
         Some(mbed_crypto_provider)
@@ -156,7 +187,7 @@ impl Provide for Provider {
             version_min: 1,
             version_rev: 0,
             id: ProviderId::MbedCrypto,
-        }, SUPPORTED_OPCODES.iter().copied().collect()))
+        }, self.opcode_filter.filter(SUPPORTED_OPCODES.iter().copied())))
     }
 
     fn list_keys(
@@ -321,6 +352,7 @@ impl Provide for Provider {
 pub struct ProviderBuilder {
     #[derivative(Debug = "ignore")]
     key_info_store: Option<KeyInfoManagerClient>,
+    opcode_filter: Option<OpcodeFilter>,
 }
 
 impl ProviderBuilder {
@@ -328,6 +360,7 @@ impl ProviderBuilder {
     pub fn new() -> ProviderBuilder {
         ProviderBuilder {
             key_info_store: None,
+            opcode_filter: None,
         }
     }
 
@@ -338,11 +371,19 @@ impl ProviderBuilder {
         self
     }
 
+    /// Restrict the operations of the provider
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> ProviderBuilder {
+        self.opcode_filter = Some(opcode_filter);
+
+        self
+    }
+
     /// Build into a MbedProvider
     pub fn build(self) -> std::io::Result<Provider> {
         Provider::new(
             self.key_info_store
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info store"))?,
+            self.opcode_filter.unwrap_or_default(),
         )
         .ok_or_else(|| {
             Error::new(
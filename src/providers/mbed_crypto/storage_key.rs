@@ -0,0 +1,147 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Sealing of the Key Info Manager storage key with Mbed Crypto
+use crate::key_info_managers::encryption::SealStorageKey;
+use anyhow::Result;
+use log::info;
+use psa_crypto::operations::other::generate_random;
+use psa_crypto::operations::{aead, key_management as psa_crypto_key_management};
+use psa_crypto::types::algorithm::{Aead, AeadWithDefaultLengthTag, Algorithm};
+use psa_crypto::types::key::{self, Attributes, Lifetime, Policy, Type, UsageFlags};
+use psa_crypto::types::status;
+use std::io::{Error, ErrorKind};
+use zeroize::Zeroizing;
+
+/// ID of the persistent key sealing the storage key. The highest user key ID is reserved for it
+/// and never given to the keys of the clients.
+pub const STORAGE_KEY_SEALING_KEY_ID: key::psa_key_id_t = key::PSA_KEY_ID_USER_MAX;
+
+const NONCE_SIZE: usize = 12;
+const SEALING_ALGORITHM: Aead = Aead::AeadWithDefaultLengthTag(AeadWithDefaultLengthTag::Gcm);
+
+/// Seals the Key Info Manager storage key with Mbed Crypto
+///
+/// The storage key is encrypted with AES-256-GCM using a persistent key stored by Mbed Crypto,
+/// which is created the first time. The sealed storage key is the nonce followed by the
+/// ciphertext.
+#[derive(Debug)]
+pub struct StorageKeySealer {
+    key_id: key::Id,
+}
+
+impl StorageKeySealer {
+    /// Opens the key sealing the storage key, creating it if it does not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if Mbed Crypto could not be initialised or if the key could not be
+    /// opened or created.
+    pub fn new() -> Result<StorageKeySealer> {
+        // Safety: this function should be called before any of the other Mbed Crypto functions
+        // are.
+        psa_crypto::init().map_err(|e| {
+            format_error!("Error when initialising Mbed Crypto", e);
+            Error::new(ErrorKind::Other, "failed initialising Mbed Crypto")
+        })?;
+
+        let key_id = match key::Id::from_persistent_key_id(STORAGE_KEY_SEALING_KEY_ID) {
+            Ok(key_id) => key_id,
+            Err(status::Error::DoesNotExist) => {
+                info!("Creating the Mbed Crypto key sealing the storage key.");
+                psa_crypto_key_management::generate(
+                    Attributes {
+                        lifetime: Lifetime::Persistent,
+                        key_type: Type::Aes,
+                        bits: 256,
+                        policy: Policy {
+                            usage_flags: UsageFlags {
+                                sign_hash: false,
+                                verify_hash: false,
+                                sign_message: false,
+                                verify_message: false,
+                                export: false,
+                                encrypt: true,
+                                decrypt: true,
+                                cache: false,
+                                copy: false,
+                                derive: false,
+                            },
+                            permitted_algorithms: Algorithm::Aead(SEALING_ALGORITHM),
+                        },
+                    },
+                    Some(STORAGE_KEY_SEALING_KEY_ID),
+                )
+                .map_err(|e| {
+                    format_error!("Failed to create the storage key sealing key", e);
+                    Error::new(ErrorKind::Other, "failed to create the sealing key")
+                })?
+            }
+            Err(e) => {
+                format_error!("Failed to open the storage key sealing key", e);
+                return Err(Error::new(ErrorKind::Other, "failed to open the sealing key").into());
+            }
+        };
+
+        Ok(StorageKeySealer { key_id })
+    }
+}
+
+impl SealStorageKey for StorageKeySealer {
+    fn seal(&self, storage_key: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_SIZE];
+        generate_random(&mut nonce).map_err(|e| {
+            format_error!("Failed to generate the sealing nonce", e);
+            Error::new(ErrorKind::Other, "failed to generate the sealing nonce")
+        })?;
+
+        let buffer_size = key::Attributes::from_key_id(self.key_id)
+            .and_then(|attributes| {
+                attributes.aead_encrypt_output_size(SEALING_ALGORITHM, storage_key.len())
+            })
+            .map_err(|e| {
+                format_error!("Failed to get the sealed storage key size", e);
+                Error::new(ErrorKind::Other, "failed to seal the storage key")
+            })?;
+        let mut sealed_storage_key = vec![0; NONCE_SIZE + buffer_size];
+        sealed_storage_key[..NONCE_SIZE].copy_from_slice(&nonce);
+        let output_size = aead::encrypt(
+            self.key_id,
+            SEALING_ALGORITHM,
+            &nonce,
+            &[],
+            storage_key,
+            &mut sealed_storage_key[NONCE_SIZE..],
+        )
+        .map_err(|e| {
+            format_error!("Failed to seal the storage key", e);
+            Error::new(ErrorKind::Other, "failed to seal the storage key")
+        })?;
+        sealed_storage_key.truncate(NONCE_SIZE + output_size);
+
+        Ok(sealed_storage_key)
+    }
+
+    fn unseal(&self, sealed_storage_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        if sealed_storage_key.len() < NONCE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "sealed storage key too short").into());
+        }
+        let (nonce, ciphertext) = sealed_storage_key.split_at(NONCE_SIZE);
+
+        let mut storage_key = Zeroizing::new(vec![0; ciphertext.len()]);
+        let output_size = aead::decrypt(
+            self.key_id,
+            SEALING_ALGORITHM,
+            nonce,
+            &[],
+            ciphertext,
+            &mut storage_key,
+        )
+        .map_err(|e| {
+            format_error!("Failed to unseal the storage key", e);
+            Error::new(ErrorKind::Other, "failed to unseal the storage key")
+        })?;
+        storage_key.truncate(output_size);
+
+        Ok(storage_key)
+    }
+}
@@ -11,6 +11,7 @@ use parsec_interface::requests::Opcode;
 use std::collections::HashSet;
 
 pub mod core;
+pub mod opcode_filter;
 
 #[cfg(feature = "pkcs11-provider")]
 //TODO: To remove when #301 is merged
@@ -29,7 +30,7 @@ pub mod cryptoauthlib;
 #[cfg(feature = "trusted-service-provider")]
 pub mod trusted_service;
 
-use crate::authenticators::ApplicationName;
+use crate::authenticators::{ApplicationName, AuthenticatorType};
 use parsec_interface::operations::{
     delete_client, list_authenticators, list_clients, list_keys, list_opcodes, list_providers,
     ping, psa_aead_decrypt, psa_aead_encrypt, psa_asymmetric_decrypt, psa_asymmetric_encrypt,
@@ -64,9 +65,11 @@ pub trait Provide {
         Err(ResponseStatus::PsaErrorNotSupported)
     }
 
-    /// List the authenticators supported by the given provider.
+    /// List the authenticators supported by the given provider, among the
+    /// `authenticator_types` of the listener which received the request.
     fn list_authenticators(
         &self,
+        _authenticator_types: &[AuthenticatorType],
         _op: list_authenticators::Operation,
     ) -> Result<list_authenticators::Result> {
         trace!("list_authenticators ingress");
@@ -83,8 +86,13 @@ pub trait Provide {
     /// Lists all clients currently having data in the service.
     fn list_clients(&self, _op: list_clients::Operation) -> Result<list_clients::Result>;
 
-    /// Delete all data a client has in the service..
-    fn delete_client(&self, _op: delete_client::Operation) -> Result<delete_client::Result> {
+    /// Delete all data a client has in the service. The client is looked for among the
+    /// applications of all the authenticators, as for ListClients.
+    fn delete_client(
+        &self,
+        _app_name: ApplicationName,
+        _op: delete_client::Operation,
+    ) -> Result<delete_client::Result> {
         trace!("delete_client ingress");
         Err(ResponseStatus::PsaErrorNotSupported)
     }
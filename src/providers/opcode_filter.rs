@@ -0,0 +1,101 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Restriction of the operations of a provider
+//!
+//! Operators can turn off some of the operations supported by a provider, from its
+//! configuration, either by listing the only ones allowed or by listing the ones denied. The
+//! operations turned off are not described by the provider and are refused by its backend
+//! handler.
+use crate::utils::config::opcode_from_name;
+use log::error;
+use parsec_interface::requests::Opcode;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+
+/// Operations allowed on a provider
+#[derive(Debug, Clone, Default)]
+pub struct OpcodeFilter {
+    // All the opcodes are allowed if absent.
+    allowed: Option<HashSet<Opcode>>,
+    denied: HashSet<Opcode>,
+}
+
+impl OpcodeFilter {
+    /// Create the filter allowing the opcodes named in `allowed`, or all of them if absent, except
+    /// the ones named in `denied`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if an opcode name is unknown.
+    pub fn new(allowed: Option<&[String]>, denied: Option<&[String]>) -> std::io::Result<Self> {
+        Ok(OpcodeFilter {
+            allowed: allowed.map(opcodes_from_names).transpose()?,
+            denied: opcodes_from_names(denied.unwrap_or_default())?,
+        })
+    }
+
+    /// Checks if the opcode is allowed.
+    pub fn allows(&self, opcode: Opcode) -> bool {
+        self.allowed
+            .as_ref()
+            .map_or(true, |allowed| allowed.contains(&opcode))
+            && !self.denied.contains(&opcode)
+    }
+
+    /// Returns the opcodes allowed among the ones supported by the provider.
+    pub fn filter(&self, opcodes: impl IntoIterator<Item = Opcode>) -> HashSet<Opcode> {
+        opcodes
+            .into_iter()
+            .filter(|opcode| self.allows(*opcode))
+            .collect()
+    }
+}
+
+fn opcodes_from_names(names: &[String]) -> std::io::Result<HashSet<Opcode>> {
+    names
+        .iter()
+        .map(|name| {
+            opcode_from_name(name).ok_or_else(|| {
+                error!(
+                    "Unknown opcode \"{}\" in the configuration of a provider.",
+                    name
+                );
+                Error::new(ErrorKind::InvalidData, "unknown opcode")
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::OpcodeFilter;
+    use parsec_interface::requests::Opcode;
+
+    #[test]
+    fn allowed_and_denied_opcodes() {
+        let supported = vec![
+            Opcode::PsaGenerateKey,
+            Opcode::PsaExportKey,
+            Opcode::PsaSignHash,
+        ];
+
+        let filter = OpcodeFilter::default();
+        assert_eq!(filter.filter(supported.clone()).len(), 3);
+
+        let filter = OpcodeFilter::new(None, Some(&[String::from("PsaExportKey")])).unwrap();
+        assert!(!filter.allows(Opcode::PsaExportKey));
+        assert!(filter.allows(Opcode::PsaSignHash));
+
+        let filter = OpcodeFilter::new(
+            Some(&[String::from("PsaGenerateKey"), String::from("PsaSignHash")]),
+            Some(&[String::from("PsaGenerateKey")]),
+        )
+        .unwrap();
+        assert_eq!(
+            filter.filter(supported),
+            vec![Opcode::PsaSignHash].into_iter().collect()
+        );
+
+        let _ = OpcodeFilter::new(Some(&[String::from("PsaExportEverything")]), None).unwrap_err();
+    }
+}
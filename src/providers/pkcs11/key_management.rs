@@ -16,6 +16,7 @@ use parsec_interface::requests::{ProviderId, ResponseStatus, Result};
 use parsec_interface::secrecy::ExposeSecret;
 use picky_asn1::wrapper::{IntegerAsn1, OctetStringAsn1};
 use picky_asn1_x509::RSAPublicKey;
+use std::collections::HashSet;
 use std::convert::{TryFrom, TryInto};
 
 impl Provider {
@@ -47,6 +48,39 @@ impl Provider {
         }
     }
 
+    /// Find the IDs of all the key objects of the slot which have an ID in the format given by
+    /// this provider: a 32 bits big-endian integer.
+    pub(super) fn find_key_ids(&self, session: &Session) -> Result<HashSet<u32>> {
+        let mut key_ids = HashSet::new();
+
+        for class in [
+            ObjectClass::PRIVATE_KEY,
+            ObjectClass::PUBLIC_KEY,
+            ObjectClass::SECRET_KEY,
+        ]
+        .iter()
+        {
+            trace!("FindObjects commands");
+            let objects = session
+                .find_objects(&[Attribute::Class(*class)])
+                .map_err(to_response_status)?;
+            for object in objects {
+                let attributes = session
+                    .get_attributes(object, &[AttributeType::Id])
+                    .map_err(to_response_status)?;
+                for attribute in attributes {
+                    if let Attribute::Id(id) = attribute {
+                        if let Ok(id) = <[u8; 4]>::try_from(id.as_slice()) {
+                            let _ = key_ids.insert(u32::from_be_bytes(id));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(key_ids)
+    }
+
     pub(super) fn move_pub_key_to_psa_crypto(&self, key_triple: &KeyTriple) -> Result<Id> {
         info!("Attempting to export public key");
         let export_operation = psa_export_public_key::Operation {
@@ -4,8 +4,10 @@
 //!
 //! This provider allows clients to access any PKCS 11 compliant device
 //! through the Parsec interface.
+use super::opcode_filter::OpcodeFilter;
 use super::Provide;
 use crate::authenticators::ApplicationName;
+use crate::key_info_managers::reconciliation::ReconciliationReport;
 use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
 use cryptoki::types::locking::CInitializeArgs;
 use cryptoki::types::session::{Session, UserType};
@@ -65,12 +67,14 @@ pub struct Provider {
     slot_number: Slot,
     software_public_operations: bool,
     allow_export: bool,
+    opcode_filter: OpcodeFilter,
 }
 
 impl Provider {
     /// Creates and initialise a new instance of Pkcs11Provider.
     /// Checks if there are not more keys stored in the Key Info Manager than in the PKCS 11 library
-    /// and if there are, delete them. Adds Key IDs currently in use in the local IDs store.
+    /// and if there are, quarantine them. Adds Key IDs currently in use in the local IDs store.
+    /// Reports the key objects of the slot which are not in the Key Info Manager.
     /// Returns `None` if the initialisation failed.
     fn new(
         key_info_store: KeyInfoManagerClient,
@@ -79,6 +83,7 @@ impl Provider {
         user_pin: Option<SecretString>,
         software_public_operations: bool,
         allow_export: bool,
+        opcode_filter: OpcodeFilter,
     ) -> Option<Provider> {
         if let Some(pin) = user_pin {
             backend.set_pin(slot_number, pin.expose_secret()).ok()?;
@@ -92,22 +97,23 @@ impl Provider {
             slot_number,
             software_public_operations,
             allow_export,
+            opcode_filter,
         };
         {
             let mut local_ids_handle = pkcs11_provider
                 .local_ids
                 .write()
                 .expect("Local ID lock poisoned");
-            let mut to_remove: Vec<KeyTriple> = Vec::new();
+            let mut report = ReconciliationReport::new(ProviderId::Pkcs11);
+            let mut to_quarantine: Vec<(KeyTriple, String)> = Vec::new();
             // Go through all PKCS 11 key triple to key info mappings and check if they are still
             // present.
-            // Delete those who are not present and add to the local_store the ones present.
+            // Quarantine those who are not present and add to the local_store the ones present.
+            let session = pkcs11_provider.new_session().ok()?;
             match pkcs11_provider.key_info_store.get_all() {
                 Ok(key_triples) => {
-                    let session = pkcs11_provider.new_session().ok()?;
-
                     for key_triple in key_triples.iter().cloned() {
-                        let key_id = match pkcs11_provider.key_info_store.get_key_id(&key_triple) {
+                        let key_id = match pkcs11_provider.key_info_store.peek_key_id(&key_triple) {
                             Ok(id) => id,
                             Err(ResponseStatus::PsaErrorDoesNotExist) => {
                                 error!("Stored key info missing for key triple {}.", key_triple);
@@ -122,7 +128,8 @@ impl Provider {
                                     e
                                 );
 
-                                to_remove.push(key_triple.clone());
+                                to_quarantine
+                                    .push((key_triple.clone(), format!("invalid key ID ({})", e)));
                                 continue;
                             }
                         };
@@ -138,17 +145,21 @@ impl Provider {
                                     warn!("Key found in the PKCS 11 library, adding it.");
                                 }
                                 let _ = local_ids_handle.insert(key_id);
+                                report.consistent += 1;
                             }
                             Err(ResponseStatus::PsaErrorDoesNotExist) => {
                                 if crate::utils::GlobalConfig::log_error_details() {
                                     warn!(
-                                        "Key {} not found in the PKCS 11 library, deleting it.",
+                                        "Key {} not found in the PKCS 11 library, quarantining it.",
                                         key_triple
                                     );
                                 } else {
-                                    warn!("Key not found in the PKCS 11 library, deleting it.");
+                                    warn!("Key not found in the PKCS 11 library, quarantining it.");
                                 }
-                                to_remove.push(key_triple.clone());
+                                to_quarantine.push((
+                                    key_triple.clone(),
+                                    format!("object ID {} not found in the PKCS 11 slot", key_id),
+                                ));
                             }
                             Err(e) => {
                                 format_error!("Error finding key objects", e);
@@ -162,15 +173,31 @@ impl Provider {
                     return None;
                 }
             };
-            for key_triple in to_remove.iter() {
+            for (key_triple, reason) in to_quarantine.iter() {
                 if pkcs11_provider
                     .key_info_store
-                    .remove_key_info(&key_triple)
+                    .quarantine_key_info(key_triple, reason, &mut report)
                     .is_err()
                 {
                     return None;
                 }
             }
+            match pkcs11_provider.find_key_ids(&session) {
+                Ok(key_ids) => {
+                    let mut orphaned_key_ids: Vec<u32> =
+                        key_ids.difference(&local_ids_handle).copied().collect();
+                    orphaned_key_ids.sort_unstable();
+                    report.orphaned_keys.extend(
+                        orphaned_key_ids
+                            .iter()
+                            .map(|key_id| format!("object ID {}", key_id)),
+                    );
+                }
+                Err(e) => format_error!("Error listing the key objects of the slot", e),
+            }
+            pkcs11_provider
+                .key_info_store
+                .set_reconciliation_report(report);
         }
 
         if pkcs11_provider.software_public_operations {
@@ -220,7 +247,7 @@ impl Provide for Provider {
                 version_rev: 0,
                 id: ProviderId::Pkcs11,
             },
-            SUPPORTED_OPCODES.iter().copied().collect(),
+            self.opcode_filter.filter(SUPPORTED_OPCODES.iter().copied()),
         ))
     }
 
@@ -345,6 +372,7 @@ pub struct ProviderBuilder {
     user_pin: Option<SecretString>,
     software_public_operations: Option<bool>,
     allow_export: Option<bool>,
+    opcode_filter: Option<OpcodeFilter>,
 }
 
 impl ProviderBuilder {
@@ -357,6 +385,7 @@ impl ProviderBuilder {
             user_pin: None,
             software_public_operations: None,
             allow_export: None,
+            opcode_filter: None,
         }
     }
 
@@ -410,6 +439,13 @@ impl ProviderBuilder {
         self
     }
 
+    /// Restrict the operations of the provider
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> ProviderBuilder {
+        self.opcode_filter = Some(opcode_filter);
+
+        self
+    }
+
     /// Attempt to build a PKCS11 provider
     pub fn build(self) -> std::io::Result<Provider> {
         let library_path = self
@@ -449,6 +485,7 @@ impl ProviderBuilder {
             self.user_pin,
             self.software_public_operations.unwrap_or(false),
             self.allow_export.unwrap_or(true),
+            self.opcode_filter.unwrap_or_default(),
         )
         .ok_or_else(|| Error::new(ErrorKind::InvalidData, "PKCS 11 initialization failed"))?)
     }
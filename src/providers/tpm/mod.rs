@@ -4,6 +4,13 @@
 //!
 //! Provider allowing clients to use hardware or software TPM 2.0 implementations
 //! for their Parsec operations.
+//!
+//! Unlike the other providers, the TPM provider does not reconcile its mappings with a key store
+//! when it is created. Its keys are not stored in the TPM: each mapping holds the context of its
+//! key, wrapped by the TPM. There are no keys without mapping to find, and checking the contexts
+//! would mean loading each of them in the TPM at start-up, while the requests using a key with an
+//! invalid context fail anyway.
+use super::opcode_filter::OpcodeFilter;
 use super::Provide;
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyInfoManagerClient;
@@ -20,6 +27,7 @@ use std::io::ErrorKind;
 use std::str::FromStr;
 use std::sync::Mutex;
 use tss_esapi::abstraction::cipher::Cipher;
+use tss_esapi::abstraction::transient::{TransientKeyContext, TransientKeyContextBuilder};
 use tss_esapi::interface_types::algorithm::HashingAlgorithm;
 use tss_esapi::interface_types::resource_handles::Hierarchy;
 use tss_esapi::Tcti;
@@ -29,8 +37,11 @@ use zeroize::Zeroize;
 mod asym_encryption;
 mod asym_sign;
 mod key_management;
+mod storage_key;
 mod utils;
 
+pub use storage_key::StorageKeySealer;
+
 const SUPPORTED_OPCODES: [Opcode; 8] = [
     Opcode::PsaGenerateKey,
     Opcode::PsaDestroyKey,
@@ -64,6 +75,7 @@ pub struct Provider {
     // structure).
     #[derivative(Debug = "ignore")]
     key_info_store: KeyInfoManagerClient,
+    opcode_filter: OpcodeFilter,
 }
 
 impl Provider {
@@ -71,10 +83,12 @@ impl Provider {
     fn new(
         key_info_store: KeyInfoManagerClient,
         esapi_context: tss_esapi::TransientKeyContext,
+        opcode_filter: OpcodeFilter,
     ) -> Provider {
         Provider {
             esapi_context: Mutex::new(esapi_context),
             key_info_store,
+            opcode_filter,
         }
     }
 }
@@ -91,7 +105,7 @@ impl Provide for Provider {
             version_min: 1,
             version_rev: 0,
             id: ProviderId::Tpm,
-        }, SUPPORTED_OPCODES.iter().copied().collect()))
+        }, self.opcode_filter.filter(SUPPORTED_OPCODES.iter().copied())))
     }
 
     fn list_keys(
@@ -208,6 +222,7 @@ pub struct ProviderBuilder {
     key_info_store: Option<KeyInfoManagerClient>,
     tcti: Option<String>,
     owner_hierarchy_auth: Option<String>,
+    opcode_filter: Option<OpcodeFilter>,
 }
 
 impl ProviderBuilder {
@@ -217,6 +232,7 @@ impl ProviderBuilder {
             key_info_store: None,
             tcti: None,
             owner_hierarchy_auth: None,
+            opcode_filter: None,
         }
     }
 
@@ -241,6 +257,13 @@ impl ProviderBuilder {
         self
     }
 
+    /// Restrict the operations of the provider
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> ProviderBuilder {
+        self.opcode_filter = Some(opcode_filter);
+
+        self
+    }
+
     fn get_hierarchy_auth(&mut self) -> std::io::Result<Vec<u8>> {
         match self.owner_hierarchy_auth.take() {
             None => Err(std::io::Error::new(
@@ -297,13 +320,11 @@ impl ProviderBuilder {
         ))
     }
 
-    /// Create an instance of TpmProvider
+    /// Create the TSS context used to interact with the TPM.
     ///
-    /// # Safety
-    ///
-    /// Undefined behaviour might appear if two instances of TransientObjectContext are created
-    /// using a same TCTI that does not handle multiple applications concurrently.
-    pub unsafe fn build(mut self) -> std::io::Result<Provider> {
+    /// The method is unsafe because it relies on creating a TSS Context which could cause
+    /// undefined behaviour if multiple such contexts are opened concurrently.
+    unsafe fn build_esapi_context(&mut self) -> std::io::Result<TransientKeyContext> {
         let hierarchy_auth = self.get_hierarchy_auth()?;
         let default_cipher = self.find_default_context_cipher()?;
         let tcti = Tcti::from_str(self.tcti.as_ref().ok_or_else(|| {
@@ -314,23 +335,47 @@ impl ProviderBuilder {
         })?;
         self.tcti.zeroize();
         self.owner_hierarchy_auth.zeroize();
+        TransientKeyContextBuilder::new()
+            .with_tcti(tcti)
+            .with_root_key_size(ROOT_KEY_SIZE)
+            .with_root_key_auth_size(ROOT_KEY_AUTH_SIZE)
+            .with_hierarchy_auth(hierarchy_auth)
+            .with_hierarchy(Hierarchy::Owner)
+            .with_session_hash_alg(HashingAlgorithm::Sha256)
+            .with_default_context_cipher(default_cipher)
+            .build()
+            .map_err(|e| {
+                format_error!("Error creating TSS Transient Object Context", e);
+                std::io::Error::new(ErrorKind::InvalidData, "failed initializing TSS context")
+            })
+    }
+
+    /// Create an instance of TpmProvider
+    ///
+    /// # Safety
+    ///
+    /// Undefined behaviour might appear if two instances of TransientObjectContext are created
+    /// using a same TCTI that does not handle multiple applications concurrently.
+    pub unsafe fn build(mut self) -> std::io::Result<Provider> {
+        let esapi_context = self.build_esapi_context()?;
         Ok(Provider::new(
             self.key_info_store.ok_or_else(|| {
                 std::io::Error::new(ErrorKind::InvalidData, "missing key info store")
             })?,
-            tss_esapi::abstraction::transient::TransientKeyContextBuilder::new()
-                .with_tcti(tcti)
-                .with_root_key_size(ROOT_KEY_SIZE)
-                .with_root_key_auth_size(ROOT_KEY_AUTH_SIZE)
-                .with_hierarchy_auth(hierarchy_auth)
-                .with_hierarchy(Hierarchy::Owner)
-                .with_session_hash_alg(HashingAlgorithm::Sha256)
-                .with_default_context_cipher(default_cipher)
-                .build()
-                .map_err(|e| {
-                    format_error!("Error creating TSS Transient Object Context", e);
-                    std::io::Error::new(ErrorKind::InvalidData, "failed initializing TSS context")
-                })?,
+            esapi_context,
+            self.opcode_filter.unwrap_or_default(),
         ))
     }
+
+    /// Create a sealer of the Key Info Manager storage key, using the TPM. No key info store is
+    /// needed.
+    ///
+    /// # Safety
+    ///
+    /// Undefined behaviour might appear if two instances of TransientObjectContext are created
+    /// using a same TCTI that does not handle multiple applications concurrently. The sealer must
+    /// be dropped before the TPM provider is built.
+    pub unsafe fn build_storage_key_sealer(mut self) -> std::io::Result<StorageKeySealer> {
+        Ok(StorageKeySealer::new(self.build_esapi_context()?))
+    }
 }
@@ -0,0 +1,120 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Sealing of the Key Info Manager storage key with the TPM
+use super::utils::PasswordContext;
+use crate::key_info_managers::encryption::SealStorageKey;
+use anyhow::Result;
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use tss_esapi::abstraction::transient::{KeyParams, TransientKeyContext};
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+use tss_esapi::utils::AsymSchemeUnion;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Size of the RSA key sealing the storage key
+const SEALING_KEY_SIZE: u16 = 2048;
+const AUTH_VAL_LEN: usize = 32;
+
+/// Storage key sealed by the TPM, as written on disk
+#[derive(Serialize, Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct SealedStorageKey {
+    /// Context of the RSA key, wrapped by the TPM, and its authentication value
+    password_context: PasswordContext,
+    /// Storage key encrypted with RSA-OAEP
+    ciphertext: Vec<u8>,
+}
+
+/// Seals the Key Info Manager storage key with the TPM
+///
+/// A new RSA encryption key is created under the Owner hierarchy and the storage key is
+/// encrypted with it. The sealed storage key contains the context of that RSA key, which can only
+/// be loaded back by the same TPM, with the same Owner hierarchy.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct StorageKeySealer {
+    #[derivative(Debug = "ignore")]
+    esapi_context: Mutex<TransientKeyContext>,
+}
+
+impl StorageKeySealer {
+    pub(super) fn new(esapi_context: TransientKeyContext) -> StorageKeySealer {
+        StorageKeySealer {
+            esapi_context: Mutex::new(esapi_context),
+        }
+    }
+}
+
+impl SealStorageKey for StorageKeySealer {
+    fn seal(&self, storage_key: &[u8]) -> Result<Vec<u8>> {
+        let mut esapi_context = self
+            .esapi_context
+            .lock()
+            .expect("ESAPI Context lock poisoned");
+
+        let (key_context, auth_value) = esapi_context
+            .create_key(
+                KeyParams::RsaEncrypt {
+                    size: SEALING_KEY_SIZE,
+                    pub_exponent: 0,
+                },
+                AUTH_VAL_LEN,
+            )
+            .map_err(|e| {
+                format_error!("Error creating the storage key sealing key", e);
+                Error::new(ErrorKind::Other, "failed to create the sealing key")
+            })?;
+        // We hardcode the AUTH_VAL_LEN, so we can assume there is an auth_value
+        let auth_value = auth_value.unwrap().value().to_vec();
+
+        let ciphertext = esapi_context
+            .rsa_encrypt(
+                key_context.clone(),
+                Some(auth_value.clone().try_into()?),
+                storage_key.to_vec().try_into()?,
+                AsymSchemeUnion::RSAOAEP(HashingAlgorithm::Sha256),
+                None,
+            )
+            .map_err(|e| {
+                format_error!("Error sealing the storage key", e);
+                Error::new(ErrorKind::Other, "failed to seal the storage key")
+            })?;
+
+        Ok(bincode::serialize(&SealedStorageKey {
+            password_context: PasswordContext {
+                context: key_context,
+                auth_value,
+            },
+            ciphertext: ciphertext.value().to_vec(),
+        })?)
+    }
+
+    fn unseal(&self, sealed_storage_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let mut esapi_context = self
+            .esapi_context
+            .lock()
+            .expect("ESAPI Context lock poisoned");
+
+        let mut sealed_storage_key: SealedStorageKey = bincode::deserialize(sealed_storage_key)?;
+        let ciphertext = std::mem::take(&mut sealed_storage_key.ciphertext);
+        let auth_value = std::mem::take(&mut sealed_storage_key.password_context.auth_value);
+
+        let storage_key = esapi_context
+            .rsa_decrypt(
+                sealed_storage_key.password_context.context.clone(),
+                Some(auth_value.try_into()?),
+                ciphertext.try_into()?,
+                AsymSchemeUnion::RSAOAEP(HashingAlgorithm::Sha256),
+                None,
+            )
+            .map_err(|e| {
+                format_error!("Error unsealing the storage key", e);
+                Error::new(ErrorKind::Other, "failed to unseal the storage key")
+            })?;
+
+        Ok(Zeroizing::new(storage_key.value().to_vec()))
+    }
+}
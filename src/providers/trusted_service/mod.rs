@@ -4,7 +4,9 @@
 //!
 //! This provider is backed by a crypto Trusted Service deployed in TrustZone
 use crate::authenticators::ApplicationName;
+use crate::key_info_managers::reconciliation::ReconciliationReport;
 use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
+use crate::providers::opcode_filter::OpcodeFilter;
 use crate::providers::Provide;
 use context::Context;
 use derivative::Derivative;
@@ -51,30 +53,40 @@ pub struct Provider {
     // Holds the highest ID of all keys (including destroyed keys). New keys will receive an ID of
     // id_counter + 1. Once id_counter reaches the highest allowed ID, no more keys can be created.
     id_counter: AtomicU32,
+    opcode_filter: OpcodeFilter,
 }
 
 impl Provider {
     /// Creates and initialises a new instance of Provider.
-    fn new(key_info_store: KeyInfoManagerClient) -> anyhow::Result<Provider> {
+    fn new(
+        key_info_store: KeyInfoManagerClient,
+        opcode_filter: OpcodeFilter,
+    ) -> anyhow::Result<Provider> {
         let ts_provider = Provider {
             key_info_store,
             context: Context::connect()?,
             id_counter: AtomicU32::new(key::PSA_KEY_ID_USER_MIN),
+            opcode_filter,
         };
         let mut max_key_id: key::psa_key_id_t = key::PSA_KEY_ID_USER_MIN;
         {
-            let mut to_remove: Vec<KeyTriple> = Vec::new();
-            // Go through all TrustedServiceProvider key triples to key info mappings and check if they are still
-            // present.
-            // Delete those who are not present and add to the local_store the ones present.
+            let mut report = ReconciliationReport::new(ProviderId::TrustedService);
+            let mut to_quarantine: Vec<(KeyTriple, String)> = Vec::new();
+            // Go through all TrustedServiceProvider key triples to key info mappings and check if
+            // their key ID is valid. The Crypto Trusted Service offers no way to check that a key
+            // exists, or to list its keys, so the key store itself is not checked.
+            // Quarantine the invalid ones.
             match ts_provider.key_info_store.get_all() {
                 Ok(key_triples) => {
                     for key_triple in key_triples.iter().cloned() {
-                        let key_id = match ts_provider.key_info_store.get_key_id(&key_triple) {
+                        let key_id = match ts_provider.key_info_store.peek_key_id(&key_triple) {
                             Ok(key_id) => key_id,
                             Err(response_status) => {
                                 error!("Error getting the Key ID for triple:\n{}\n(error: {}), continuing...", key_triple, response_status);
-                                to_remove.push(key_triple.clone());
+                                to_quarantine.push((
+                                    key_triple.clone(),
+                                    format!("invalid key ID ({})", response_status),
+                                ));
                                 continue;
                             }
                         };
@@ -82,6 +94,7 @@ impl Provider {
                         if key_id > max_key_id {
                             max_key_id = key_id;
                         }
+                        report.consistent += 1;
                     }
                 }
                 Err(string) => {
@@ -89,11 +102,20 @@ impl Provider {
                     return Err(std::io::Error::new(std::io::ErrorKind::Other, string).into());
                 }
             };
-            for key_triple in to_remove.iter() {
-                if let Err(string) = ts_provider.key_info_store.remove_key_info(key_triple) {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, string).into());
+            for (key_triple, reason) in to_quarantine.iter() {
+                if let Err(response_status) =
+                    ts_provider
+                        .key_info_store
+                        .quarantine_key_info(key_triple, reason, &mut report)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        response_status.to_string(),
+                    )
+                    .into());
                 }
             }
+            ts_provider.key_info_store.set_reconciliation_report(report);
         }
         ts_provider.id_counter.store(max_key_id, Ordering::Relaxed);
         Ok(ts_provider)
@@ -112,7 +134,7 @@ impl Provide for Provider {
             version_min: 1,
             version_rev: 0,
             id: ProviderId::TrustedService,
-        }, SUPPORTED_OPCODES.iter().copied().collect()))
+        }, self.opcode_filter.filter(SUPPORTED_OPCODES.iter().copied())))
     }
 
     fn list_keys(
@@ -199,6 +221,7 @@ impl Provide for Provider {
 pub struct ProviderBuilder {
     #[derivative(Debug = "ignore")]
     key_info_store: Option<KeyInfoManagerClient>,
+    opcode_filter: Option<OpcodeFilter>,
 }
 
 impl ProviderBuilder {
@@ -206,6 +229,7 @@ impl ProviderBuilder {
     pub fn new() -> ProviderBuilder {
         ProviderBuilder {
             key_info_store: None,
+            opcode_filter: None,
         }
     }
 
@@ -216,10 +240,20 @@ impl ProviderBuilder {
         self
     }
 
+    /// Restrict the operations of the provider
+    pub fn with_opcode_filter(mut self, opcode_filter: OpcodeFilter) -> ProviderBuilder {
+        self.opcode_filter = Some(opcode_filter);
+
+        self
+    }
+
     /// Build into a TrustedService
     pub fn build(self) -> anyhow::Result<Provider> {
-        Provider::new(self.key_info_store.ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "missing key info store")
-        })?)
+        Provider::new(
+            self.key_info_store.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "missing key info store")
+            })?,
+            self.opcode_filter.unwrap_or_default(),
+        )
     }
 }
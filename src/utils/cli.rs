@@ -6,6 +6,7 @@
 // removed, new flags should be tested.
 // See https://github.com/parallaxsecond/parsec/issues/392 for details.
 
+use crate::key_info_managers::acl::KeyPermission;
 use structopt::StructOpt;
 
 /// Parsec is the Platform AbstRaction for SECurity, a new open-source initiative to provide a
@@ -22,4 +23,155 @@ pub struct Opts {
     /// Sets the configuration file path
     #[structopt(short, long, default_value = "config.toml")]
     pub config: String,
+
+    /// Maintenance command to run instead of starting the service
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance commands, run while the service is stopped
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Upgrades the key info mappings of all the key managers of the configuration file to the
+    /// current format version. The service must be stopped. The mappings written before the
+    /// authenticator type was recorded are moved to the namespace of the first authenticator of
+    /// the configuration file: the service refuses to start until they are.
+    MigrateMappings {
+        /// Only reports the mappings which would be upgraded, without modifying them
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Saves the key info mappings of a key manager of the configuration file to an archive. The
+    /// service must be stopped.
+    BackupMappings {
+        /// Path of the archive to create
+        #[structopt(short, long)]
+        output: String,
+        /// Name of the key manager, defaults to the first one of the configuration file
+        #[structopt(long)]
+        key_manager: Option<String>,
+    },
+    /// Restores the key info mappings of an archive in a key manager of the configuration file.
+    /// The providers then check that the restored keys exist. The service must be stopped.
+    RestoreMappings {
+        /// Path of the archive to restore
+        #[structopt(short, long)]
+        input: String,
+        /// Name of the key manager, defaults to the first one of the configuration file
+        #[structopt(long)]
+        key_manager: Option<String>,
+    },
+    /// Checks the key info mappings against the keys stored by the providers, as when the service
+    /// starts, and prints the report. Inconsistent mappings are quarantined. The service must be
+    /// stopped.
+    Reconcile,
+    /// Prints the reports of the reconciliation of the key info mappings made when the service
+    /// last started, without modifying the mappings. The service can be running.
+    ReconciliationReport,
+    /// Prints the creation and usage metadata of a key. The service must be stopped.
+    KeyMetadata {
+        /// ID of the provider storing the key
+        #[structopt(long)]
+        provider: u8,
+        /// Name of the application owning the key
+        #[structopt(long)]
+        application: String,
+        /// Name of the authenticator of the application, defaults to the first one of the
+        /// configuration file
+        #[structopt(long)]
+        authenticator: Option<String>,
+        /// Name of the key
+        #[structopt(long)]
+        key_name: String,
+    },
+    /// Prints the creation and usage metadata of all the keys of a provider, to find the stale
+    /// ones. The service must be stopped.
+    ListKeyMetadata {
+        /// ID of the provider storing the keys
+        #[structopt(long)]
+        provider: u8,
+    },
+    /// Moves all the keys of an application to another one, in all the providers, for example
+    /// when the application switches to another authenticator. The service must be stopped.
+    TransferClient {
+        /// Name of the application owning the keys
+        #[structopt(long)]
+        from: String,
+        /// Name of the authenticator of the application owning the keys, defaults to the first
+        /// one of the configuration file
+        #[structopt(long)]
+        from_authenticator: Option<String>,
+        /// Name of the application to move the keys to
+        #[structopt(long)]
+        to: String,
+        /// Name of the authenticator of the application to move the keys to, defaults to the
+        /// first one of the configuration file
+        #[structopt(long)]
+        to_authenticator: Option<String>,
+    },
+    /// Sets the expiry time of a key. The service must be stopped.
+    SetKeyExpiry {
+        /// ID of the provider storing the key
+        #[structopt(long)]
+        provider: u8,
+        /// Name of the application owning the key
+        #[structopt(long)]
+        application: String,
+        /// Name of the authenticator of the application, defaults to the first one of the
+        /// configuration file
+        #[structopt(long)]
+        authenticator: Option<String>,
+        /// Name of the key
+        #[structopt(long)]
+        key_name: String,
+        /// Expiry time of the key, in seconds since the Unix epoch. The key does not expire
+        /// anymore if absent.
+        #[structopt(long)]
+        expires_at: Option<u64>,
+    },
+    /// Grants another application permissions on a key, on behalf of the application owning the
+    /// key. The service must be stopped.
+    GrantKeyAccess {
+        /// ID of the provider storing the key
+        #[structopt(long)]
+        provider: u8,
+        /// Name of the application owning the key
+        #[structopt(long)]
+        application: String,
+        /// Name of the authenticator of the application owning the key, defaults to the first one
+        /// of the configuration file
+        #[structopt(long)]
+        authenticator: Option<String>,
+        /// Name of the key
+        #[structopt(long)]
+        key_name: String,
+        /// Name of the application the permissions are granted to
+        #[structopt(long)]
+        grantee: String,
+        /// Name of the authenticator of the application the permissions are granted to, defaults
+        /// to the first one of the configuration file
+        #[structopt(long)]
+        grantee_authenticator: Option<String>,
+        /// Permission granted, among Sign, Verify, Encrypt and ExportPublic. Can be repeated. The
+        /// permissions granted before are replaced, they are all revoked if absent.
+        #[structopt(long = "permission")]
+        permissions: Vec<KeyPermission>,
+    },
+    /// Prints the permissions on a key granted to other applications. The service must be
+    /// stopped.
+    ListKeyGrants {
+        /// ID of the provider storing the key
+        #[structopt(long)]
+        provider: u8,
+        /// Name of the application owning the key
+        #[structopt(long)]
+        application: String,
+        /// Name of the authenticator of the application owning the key, defaults to the first one
+        /// of the configuration file
+        #[structopt(long)]
+        authenticator: Option<String>,
+        /// Name of the key
+        #[structopt(long)]
+        key_name: String,
+    },
 }
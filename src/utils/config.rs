@@ -2,15 +2,18 @@
 // SPDX-License-Identifier: Apache-2.0
 //! Structures for the Parsec configuration file
 
+use crate::authenticators::AuthenticatorType;
+use crate::providers::opcode_filter::OpcodeFilter;
 use log::LevelFilter;
-use parsec_interface::requests::ProviderId;
-use serde::Deserialize;
+use parsec_interface::requests::{AuthType, Opcode, ProviderId};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
 use zeroize::Zeroize;
 
 /// Core settings
 ///
 /// See the config.toml file for a description of each field.
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 #[allow(missing_docs)]
 pub struct CoreSettings {
     pub thread_pool_size: Option<usize>,
@@ -21,6 +24,7 @@ pub struct CoreSettings {
     pub log_error_details: Option<bool>,
     pub allow_root: Option<bool>,
     pub buffer_size_limit: Option<usize>,
+    pub reconciliation_reports_path: Option<String>,
 }
 
 /// Type of the Listener used
@@ -28,6 +32,10 @@ pub struct CoreSettings {
 pub enum ListenerType {
     /// Listener using Unix Domain Socket
     DomainSocket,
+    /// Listener using TCP, with mutual TLS authentication
+    Tcp,
+    /// Listener using vsock
+    Vsock,
 }
 
 /// Configuration of the Listener
@@ -39,6 +47,25 @@ pub struct ListenerConfig {
     pub timeout: u64,
     /// Path of the Unix Domain socket
     pub socket_path: Option<String>,
+    /// Permissions of the Unix Domain socket file
+    pub socket_permissions: Option<u32>,
+    /// Address the TCP listener binds to
+    pub address: Option<String>,
+    /// Path of the PEM file containing the certificate chain of the TCP listener
+    pub tls_certificate_path: Option<String>,
+    /// Path of the PEM file containing the private key of the TCP listener
+    pub tls_private_key_path: Option<String>,
+    /// Path of the PEM file containing the CA certificates trusted to issue client certificates
+    pub tls_client_ca_path: Option<String>,
+    /// Context Identifier the vsock listener binds to
+    pub cid: Option<u32>,
+    /// Port the vsock listener binds to
+    pub port: Option<u32>,
+    /// Authenticators allowed on the connections of the Listener, all of them if absent
+    pub authenticators: Option<Vec<String>>,
+    /// Limit on the size of the request bodies received by the Listener, instead of the one of
+    /// the core settings
+    pub body_len_limit: Option<usize>,
 }
 
 /// Authenticator configuration structure
@@ -63,6 +90,44 @@ pub enum AuthenticatorConfig {
         /// List of service admins
         admins: Option<Vec<Admin>>,
     },
+    /// Client certificate authentication, for listeners using TLS
+    ClientCertificate {
+        /// Field of the client certificate giving the application name
+        identity: Option<String>,
+        /// List of service admins
+        admins: Option<Vec<Admin>>,
+    },
+    /// vsock CID authentication, for vsock listeners
+    VsockCid {
+        /// Application names of the virtual machines
+        applications: Vec<VsockApplication>,
+        /// List of service admins
+        admins: Option<Vec<Admin>>,
+    },
+}
+
+impl AuthenticatorConfig {
+    /// Give the type of the authenticator
+    pub fn authenticator_type(&self) -> AuthenticatorType {
+        match self {
+            AuthenticatorConfig::Direct { .. } => AuthType::Direct.into(),
+            AuthenticatorConfig::UnixPeerCredentials { .. } => AuthType::UnixPeerCredentials.into(),
+            AuthenticatorConfig::JwtSvid { .. } => AuthType::JwtSvid.into(),
+            AuthenticatorConfig::ClientCertificate { .. } => AuthenticatorType::ClientCertificate,
+            AuthenticatorConfig::VsockCid { .. } => AuthenticatorType::VsockCid,
+        }
+    }
+
+    /// Give the name of the authenticator in the configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthenticatorConfig::Direct { .. } => "Direct",
+            AuthenticatorConfig::UnixPeerCredentials { .. } => "UnixPeerCredentials",
+            AuthenticatorConfig::JwtSvid { .. } => "JwtSvid",
+            AuthenticatorConfig::ClientCertificate { .. } => "ClientCertificate",
+            AuthenticatorConfig::VsockCid { .. } => "VsockCid",
+        }
+    }
 }
 
 /// Structure defining the properties of a service admin
@@ -79,11 +144,35 @@ impl Admin {
     }
 }
 
+/// Structure giving the application name of a virtual machine connecting through vsock
+#[derive(Deserialize, Debug, Zeroize, Clone)]
+#[zeroize(drop)]
+pub struct VsockApplication {
+    cid: u32,
+    name: String,
+}
+
+impl VsockApplication {
+    /// Give the Context Identifier of the virtual machine
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// Give the application name of the virtual machine
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 /// Type of the KeyInfoManager
 #[derive(Copy, Clone, Deserialize, Debug)]
 pub enum KeyInfoManagerType {
     /// KeyInfoManager storing the mappings on disk
     OnDisk,
+    /// KeyInfoManager storing the mappings in a SQLite database
+    SQLite,
+    /// KeyInfoManager keeping the mappings in memory only, they are lost when the service stops
+    Volatile,
 }
 
 /// KeyInfoManager configuration
@@ -93,8 +182,50 @@ pub struct KeyInfoManagerConfig {
     pub name: String,
     /// Type of the KeyInfoManager
     pub manager_type: KeyInfoManagerType,
-    /// Path used to store the mappings
+    /// Path used to store the mappings. This is a directory for the `OnDisk` manager and a
+    /// database file for the `SQLite` manager. It is not used by the `Volatile` manager.
     pub store_path: Option<String>,
+    /// Encryption of the mappings at rest
+    pub encryption: Option<KeyInfoEncryptionConfig>,
+    /// Share the store with other instances of the service configured to share it as well. Only
+    /// supported by the `OnDisk` manager.
+    pub shared: Option<bool>,
+    /// Watch the store for mappings modified outside of the service. Only supported by the
+    /// `OnDisk` manager.
+    pub watch: Option<bool>,
+}
+
+/// Provider sealing the storage key of encrypted mappings
+#[derive(Copy, Clone, Deserialize, Debug)]
+pub enum StorageKeySealerType {
+    /// The storage key is sealed by a persistent key of the Mbed Crypto provider
+    MbedCrypto,
+    /// The storage key is sealed by a key of the TPM provider
+    Tpm,
+}
+
+/// Configuration of the encryption of the mappings
+#[derive(Deserialize, Debug)]
+pub struct KeyInfoEncryptionConfig {
+    /// Provider sealing the storage key
+    pub sealing_provider: StorageKeySealerType,
+    /// Path of the file containing the sealed storage key. Defaults to a file next to the
+    /// mappings.
+    pub sealed_key_path: Option<String>,
+}
+
+/// Limits on the keys and operations of a provider, set alongside its other options
+#[derive(Deserialize, Debug, Zeroize)]
+pub struct ProviderLimitsConfig {
+    /// Maximum number of keys each application can have in the provider
+    pub max_keys_per_application: Option<usize>,
+    /// Maximum number of keys all the applications together can have in the provider
+    pub max_keys: Option<usize>,
+    /// Opcodes allowed on the provider, among the ones it supports. All of them are allowed if
+    /// absent.
+    pub allowed_opcodes: Option<Vec<String>>,
+    /// Opcodes denied on the provider
+    pub denied_opcodes: Option<Vec<String>>,
 }
 
 /// Provider configuration structure
@@ -110,11 +241,17 @@ pub enum ProviderConfig {
     MbedCrypto {
         /// Name of the Key Info Manager to use
         key_info_manager: String,
+        /// Limits on the keys and operations of this provider
+        #[serde(flatten)]
+        limits: ProviderLimitsConfig,
     },
     /// PKCS 11 provider configuration
     Pkcs11 {
         /// Name of the Key Info Manager to use
         key_info_manager: String,
+        /// Limits on the keys and operations of this provider
+        #[serde(flatten)]
+        limits: ProviderLimitsConfig,
         /// Path of the PKCS 11 library
         library_path: String,
         /// Slot number to use
@@ -130,6 +267,9 @@ pub enum ProviderConfig {
     Tpm {
         /// Name of the Key Info Manager to use
         key_info_manager: String,
+        /// Limits on the keys and operations of this provider
+        #[serde(flatten)]
+        limits: ProviderLimitsConfig,
         /// TCTI to use with the provider
         tcti: String,
         /// Owner Hierarchy Authentication
@@ -142,6 +282,9 @@ pub enum ProviderConfig {
     CryptoAuthLib {
         /// Name of the Key Info Manager to use
         key_info_manager: String,
+        /// Limits on the keys and operations of this provider
+        #[serde(flatten)]
+        limits: ProviderLimitsConfig,
         /// ATECC Device type
         device_type: String,
         /// Interface type
@@ -163,6 +306,9 @@ pub enum ProviderConfig {
     TrustedService {
         /// Name of Key Info Manager to use
         key_info_manager: String,
+        /// Limits on the keys and operations of this provider
+        #[serde(flatten)]
+        limits: ProviderLimitsConfig,
     },
 }
 
@@ -192,6 +338,39 @@ impl ProviderConfig {
             } => key_info_manager,
         }
     }
+    /// Get the limits on the keys and operations of the provider
+    pub fn limits(&self) -> &ProviderLimitsConfig {
+        match *self {
+            ProviderConfig::MbedCrypto { ref limits, .. } => limits,
+            ProviderConfig::Pkcs11 { ref limits, .. } => limits,
+            ProviderConfig::Tpm { ref limits, .. } => limits,
+            ProviderConfig::CryptoAuthLib { ref limits, .. } => limits,
+            ProviderConfig::TrustedService { ref limits, .. } => limits,
+        }
+    }
+
+    /// Get the limits on the number of keys stored in the provider
+    pub fn key_quota(&self) -> KeyQuotaConfig {
+        let limits = self.limits();
+        KeyQuotaConfig {
+            max_keys_per_application: limits.max_keys_per_application,
+            max_keys: limits.max_keys,
+        }
+    }
+
+    /// Get the filter of the opcodes allowed on the provider
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if an opcode name is unknown.
+    pub fn opcode_filter(&self) -> std::io::Result<OpcodeFilter> {
+        let limits = self.limits();
+        OpcodeFilter::new(
+            limits.allowed_opcodes.as_deref(),
+            limits.denied_opcodes.as_deref(),
+        )
+    }
+
     /// Get the Provider ID of the provider
     pub fn provider_id(&self) -> ProviderId {
         match *self {
@@ -204,6 +383,120 @@ impl ProviderConfig {
     }
 }
 
+/// Limits on the number of keys stored in a provider
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KeyQuotaConfig {
+    /// Maximum number of keys each application can have in the provider
+    pub max_keys_per_application: Option<usize>,
+    /// Maximum number of keys all the applications together can have in the provider
+    pub max_keys: Option<usize>,
+}
+
+/// Application with its own limit on the number of keys it can have in each provider
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeyQuotaOverride {
+    /// Name of the application
+    pub name: String,
+    /// Authenticators of the application, all the configured ones if absent
+    pub authenticators: Option<Vec<String>>,
+    /// Maximum number of keys the application can have in each provider. The number of keys of
+    /// the application is not limited if absent.
+    pub max_keys_per_application: Option<usize>,
+}
+
+/// Configuration of the expiry of the keys
+#[derive(Deserialize, Debug, Clone)]
+pub struct KeyExpiryConfig {
+    /// Lifetime of the keys created, in seconds. The keys do not expire if absent.
+    pub default_lifetime: Option<u64>,
+    /// Applications whose keys have another lifetime
+    pub application_lifetimes: Option<Vec<ApplicationKeyLifetime>>,
+    /// Interval between two destructions of the expired keys, in seconds
+    pub reaper_interval: Option<u64>,
+}
+
+/// Lifetime of the keys created by an application
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApplicationKeyLifetime {
+    /// Name of the application
+    pub name: String,
+    /// Authenticators of the application, all the configured ones if absent
+    pub authenticators: Option<Vec<String>>,
+    /// Lifetime of the keys of the application, in seconds. The keys of the application do not
+    /// expire if absent.
+    pub lifetime: Option<u64>,
+}
+
+/// Configuration of the access control policy
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccessPolicyConfig {
+    /// Path of the TOML file defining the access control policy
+    pub policy_path: String,
+}
+
+/// Configuration of the cryptographic policy
+///
+/// Algorithms, hashes and elliptic curve families are named as the variants of the PSA Crypto
+/// types of the Parsec interface, for example "RsaPkcs1v15Crypt", "Sha1" or "SecpR1".
+#[derive(Deserialize, Debug, Clone)]
+pub struct CryptoPolicyConfig {
+    /// Hash algorithms which can not be used, alone or within another algorithm
+    pub forbidden_hashes: Option<Vec<String>>,
+    /// Asymmetric signature, asymmetric encryption and key agreement algorithms which can not be
+    /// used
+    pub forbidden_algorithms: Option<Vec<String>>,
+    /// Minimum size of the RSA keys, in bits
+    pub min_rsa_key_bits: Option<usize>,
+    /// Elliptic curve families of the ECC keys. All the families are allowed if absent.
+    pub allowed_ecc_families: Option<Vec<String>>,
+    /// Minimum size of the ECC keys, in bits
+    pub min_ecc_key_bits: Option<usize>,
+}
+
+/// Get the opcode named `name` in a configuration file, for example "PsaSignHash"
+pub fn opcode_from_name(name: &str) -> Option<Opcode> {
+    Some(match name {
+        "Ping" => Opcode::Ping,
+        "PsaGenerateKey" => Opcode::PsaGenerateKey,
+        "PsaDestroyKey" => Opcode::PsaDestroyKey,
+        "PsaSignHash" => Opcode::PsaSignHash,
+        "PsaVerifyHash" => Opcode::PsaVerifyHash,
+        "PsaImportKey" => Opcode::PsaImportKey,
+        "PsaExportPublicKey" => Opcode::PsaExportPublicKey,
+        "ListProviders" => Opcode::ListProviders,
+        "ListOpcodes" => Opcode::ListOpcodes,
+        "PsaAsymmetricEncrypt" => Opcode::PsaAsymmetricEncrypt,
+        "PsaAsymmetricDecrypt" => Opcode::PsaAsymmetricDecrypt,
+        "PsaExportKey" => Opcode::PsaExportKey,
+        "PsaGenerateRandom" => Opcode::PsaGenerateRandom,
+        "ListAuthenticators" => Opcode::ListAuthenticators,
+        "PsaHashCompute" => Opcode::PsaHashCompute,
+        "PsaHashCompare" => Opcode::PsaHashCompare,
+        "PsaAeadEncrypt" => Opcode::PsaAeadEncrypt,
+        "PsaAeadDecrypt" => Opcode::PsaAeadDecrypt,
+        "PsaRawKeyAgreement" => Opcode::PsaRawKeyAgreement,
+        "PsaSignMessage" => Opcode::PsaSignMessage,
+        "PsaVerifyMessage" => Opcode::PsaVerifyMessage,
+        "ListKeys" => Opcode::ListKeys,
+        "ListClients" => Opcode::ListClients,
+        "DeleteClient" => Opcode::DeleteClient,
+        _ => return None,
+    })
+}
+
+/// Get the provider named `name` in a configuration file, for example "MbedCrypto"
+pub fn provider_id_from_name(name: &str) -> Option<ProviderId> {
+    Some(match name {
+        "Core" => ProviderId::Core,
+        "MbedCrypto" => ProviderId::MbedCrypto,
+        "Pkcs11" => ProviderId::Pkcs11,
+        "Tpm" => ProviderId::Tpm,
+        "CryptoAuthLib" => ProviderId::CryptoAuthLib,
+        "TrustedService" => ProviderId::TrustedService,
+        _ => return None,
+    })
+}
+
 /// Configuration of Parsec
 ///
 /// See the config.toml file for a description of each field.
@@ -211,8 +504,135 @@ impl ProviderConfig {
 #[allow(missing_docs)]
 pub struct ServiceConfig {
     pub core_settings: CoreSettings,
-    pub listener: ListenerConfig,
-    pub authenticator: AuthenticatorConfig,
+    #[serde(deserialize_with = "one_or_many")]
+    pub listener: Vec<ListenerConfig>,
+    /// The first authenticator is the default one.
+    #[serde(deserialize_with = "one_or_many")]
+    pub authenticator: Vec<AuthenticatorConfig>,
     pub key_manager: Option<Vec<KeyInfoManagerConfig>>,
     pub provider: Option<Vec<ProviderConfig>>,
+    pub key_quota_override: Option<Vec<KeyQuotaOverride>>,
+    pub key_expiry: Option<KeyExpiryConfig>,
+    pub access_policy: Option<AccessPolicyConfig>,
+    pub crypto_policy: Option<CryptoPolicyConfig>,
+}
+
+/// Deserializes a non-empty list of tables from either one table or an array of tables, so that
+/// the configurations written for a single listener or authenticator stay valid.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let many = match toml::Value::deserialize(deserializer)? {
+        toml::Value::Array(array) => array
+            .into_iter()
+            .map(toml::Value::try_into)
+            .collect::<Result<Vec<T>, _>>()
+            .map_err(D::Error::custom)?,
+        value => vec![value.try_into().map_err(D::Error::custom)?],
+    };
+    if many.is_empty() {
+        return Err(D::Error::custom("expected at least one table"));
+    }
+
+    Ok(many)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{opcode_from_name, provider_id_from_name, ServiceConfig};
+    use parsec_interface::requests::{Opcode, ProviderId};
+
+    const CONFIG: &str = r#"
+        [core_settings]
+
+        [authenticator]
+        auth_type = "UnixPeerCredentials"
+    "#;
+
+    #[test]
+    fn one_or_many_listeners() {
+        let config: ServiceConfig = toml::from_str(&format!(
+            r#"
+            {}
+            [listener]
+            listener_type = "DomainSocket"
+            timeout = 200
+            "#,
+            CONFIG
+        ))
+        .unwrap();
+        assert_eq!(config.listener.len(), 1);
+        assert_eq!(config.authenticator.len(), 1);
+
+        let config: ServiceConfig = toml::from_str(&format!(
+            r#"
+            {}
+            [[listener]]
+            listener_type = "DomainSocket"
+            timeout = 200
+            authenticators = ["UnixPeerCredentials"]
+
+            [[listener]]
+            listener_type = "DomainSocket"
+            timeout = 200
+            socket_path = "/run/parsec/admin.sock"
+            socket_permissions = 0o600
+            "#,
+            CONFIG
+        ))
+        .unwrap();
+        assert_eq!(config.listener.len(), 2);
+        assert_eq!(config.listener[1].socket_permissions, Some(0o600));
+
+        let _ = toml::from_str::<ServiceConfig>(&format!("listener = []\n{}", CONFIG)).unwrap_err();
+        let _ = toml::from_str::<ServiceConfig>(&format!(
+            r#"
+            {}
+            [listener]
+            listener_type = "DomainSocket"
+            "#,
+            CONFIG
+        ))
+        .unwrap_err();
+    }
+
+    #[test]
+    fn provider_limits() {
+        let config: ServiceConfig = toml::from_str(&format!(
+            r#"
+            {}
+            [listener]
+            listener_type = "DomainSocket"
+            timeout = 200
+
+            [[provider]]
+            provider_type = "MbedCrypto"
+            key_info_manager = "on-disk-manager"
+            max_keys = 10
+            denied_opcodes = ["PsaExportKey"]
+            "#,
+            CONFIG
+        ))
+        .unwrap();
+        let provider = &config.provider.unwrap()[0];
+        assert_eq!(provider.key_info_manager(), "on-disk-manager");
+        assert_eq!(provider.key_quota().max_keys, Some(10));
+        assert_eq!(provider.key_quota().max_keys_per_application, None);
+        assert_eq!(
+            provider.limits().denied_opcodes,
+            Some(vec!["PsaExportKey".to_string()])
+        );
+        assert!(provider.limits().allowed_opcodes.is_none());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(opcode_from_name("PsaSignHash"), Some(Opcode::PsaSignHash));
+        assert_eq!(opcode_from_name("DeleteClient"), Some(Opcode::DeleteClient));
+        assert_eq!(opcode_from_name("psa_sign_hash"), None);
+        assert_eq!(provider_id_from_name("Tpm"), Some(ProviderId::Tpm));
+        assert_eq!(provider_id_from_name("Unknown"), None);
+    }
 }
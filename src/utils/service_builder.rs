@@ -5,43 +5,66 @@
 //! The service builder is required to bootstrap all the components based on a
 //! provided configuration.
 use super::global_config::GlobalConfigBuilder;
-use crate::authenticators::Authenticate;
+use crate::authenticators::{ApplicationName, Authenticate, AuthenticatorType};
 use crate::back::{
+    access_policy::AccessPolicy,
     backend_handler::{BackEndHandler, BackEndHandlerBuilder},
+    crypto_policy::CryptoPolicy,
     dispatcher::DispatcherBuilder,
+    key_quota::KeyQuota,
+    key_reaper::KeyReaper,
 };
 use crate::front::{
     domain_socket::DomainSocketListenerBuilder, front_end::FrontEndHandler,
     front_end::FrontEndHandlerBuilder, listener::Listen,
 };
-use crate::key_info_managers::KeyInfoManagerFactory;
-use crate::providers::{core::ProviderBuilder as CoreProviderBuilder, Provide};
+use crate::key_info_managers::archive::{KeyInfoArchive, RestoreReport};
+use crate::key_info_managers::expiry::KeyLifetimes;
+use crate::key_info_managers::reconciliation::{self, ReconciliationReport};
+use crate::key_info_managers::{
+    encryption::SealStorageKey, KeyInfoManagerClient, KeyInfoManagerFactory,
+};
+use crate::providers::{
+    core::Provider as CoreProvider, core::ProviderBuilder as CoreProviderBuilder,
+    opcode_filter::OpcodeFilter, Provide,
+};
 use crate::utils::config::{
-    AuthenticatorConfig, KeyInfoManagerConfig, ListenerConfig, ListenerType, ProviderConfig,
-    ServiceConfig,
+    AuthenticatorConfig, KeyInfoEncryptionConfig, KeyInfoManagerType, ListenerConfig, ListenerType,
+    ProviderConfig, ServiceConfig, StorageKeySealerType,
 };
 use anyhow::Result;
 use log::{error, warn};
 use parsec_interface::operations_protobuf::ProtobufConverter;
-use parsec_interface::requests::AuthType;
 use parsec_interface::requests::{BodyType, ProviderId};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
 
+#[cfg(feature = "client-certificate-authenticator")]
+use crate::authenticators::client_certificate_authenticator::ClientCertificateAuthenticator;
 #[cfg(feature = "direct-authenticator")]
 use crate::authenticators::direct_authenticator::DirectAuthenticator;
 #[cfg(feature = "jwt-svid-authenticator")]
 use crate::authenticators::jwt_svid_authenticator::JwtSvidAuthenticator;
 #[cfg(feature = "unix-peer-credentials-authenticator")]
 use crate::authenticators::unix_peer_credentials_authenticator::UnixPeerCredentialsAuthenticator;
+#[cfg(feature = "vsock-cid-authenticator")]
+use crate::authenticators::vsock_cid_authenticator::VsockCidAuthenticator;
+
+#[cfg(feature = "tcp-listener")]
+use crate::front::tcp::TcpTlsListenerBuilder;
+#[cfg(feature = "vsock-listener")]
+use crate::front::vsock::VsockListenerBuilder;
 
 #[cfg(feature = "cryptoauthlib-provider")]
 use crate::providers::cryptoauthlib::ProviderBuilder as CryptoAuthLibProviderBuilder;
 #[cfg(feature = "mbed-crypto-provider")]
-use crate::providers::mbed_crypto::ProviderBuilder as MbedCryptoProviderBuilder;
+use crate::providers::mbed_crypto::{
+    ProviderBuilder as MbedCryptoProviderBuilder, StorageKeySealer as MbedCryptoStorageKeySealer,
+};
 #[cfg(feature = "pkcs11-provider")]
 use crate::providers::pkcs11::ProviderBuilder as Pkcs11ProviderBuilder;
 #[cfg(feature = "tpm-provider")]
@@ -67,9 +90,40 @@ const DEFAULT_BODY_LEN_LIMIT: usize = 1 << 20;
 /// Default value for the limit on the buffer size for response (in bytes) - equal to 1MB
 pub const DEFAULT_BUFFER_SIZE_LIMIT: usize = 1 << 20;
 
+/// Default value for the interval between two destructions of the expired keys (in seconds)
+const DEFAULT_KEY_REAPER_INTERVAL: u64 = 60;
+
 type Provider = Arc<dyn Provide + Send + Sync>;
 type Authenticator = Box<dyn Authenticate + Send + Sync>;
 
+/// Policies checked by the backend handlers before dispatching the requests
+#[derive(Debug)]
+struct Policies {
+    access_policy: Option<Arc<AccessPolicy>>,
+    crypto_policy: Option<Arc<CryptoPolicy>>,
+    opcode_filters: HashMap<ProviderId, OpcodeFilter>,
+}
+
+impl Policies {
+    /// Add the policies to the backend handler of a provider
+    fn apply(
+        &self,
+        provider_id: ProviderId,
+        mut builder: BackEndHandlerBuilder,
+    ) -> BackEndHandlerBuilder {
+        if let Some(opcode_filter) = self.opcode_filters.get(&provider_id) {
+            builder = builder.with_opcode_filter(opcode_filter.clone());
+        }
+        if let Some(access_policy) = &self.access_policy {
+            builder = builder.with_access_policy(access_policy.clone());
+        }
+        if let Some(crypto_policy) = &self.crypto_policy {
+            builder = builder.with_crypto_policy(crypto_policy.clone());
+        }
+        builder
+    }
+}
+
 /// Service component builder and assembler
 ///
 /// Entity responsible for converting a Parsec service configuration into a fully formed service.
@@ -98,35 +152,181 @@ impl ServiceBuilder {
             )
             .build();
 
-        let key_info_manager_builders =
-            gey_key_info_manager_builders(config.key_manager.as_ref().unwrap_or(&Vec::new()))?;
+        // Checked before anything is built, as the listeners are only started once the service is.
+        check_listener_authenticators(config)?;
+        let authenticators = build_authenticators(&config.authenticator)?;
 
-        let providers = build_providers(
-            config.provider.as_ref().unwrap_or(&Vec::new()),
-            key_info_manager_builders,
-        )?;
+        if config
+            .authenticator
+            .iter()
+            .any(|authenticator| matches!(authenticator, AuthenticatorConfig::Direct { .. }))
+        {
+            warn!("Direct authenticator has been configured. It is only secure under specific requirements. Please make sure to read the Recommendations on a Secure Parsec Deployment at https://parallaxsecond.github.io/parsec-book/parsec_security/secure_deployment.html");
+        }
+
+        let provider_configs = config.provider.as_ref().map(Vec::as_slice).unwrap_or(&[]);
+        let mut key_info_manager_builders = gey_key_info_manager_builders(config)?;
+        let key_lifetimes = match &config.key_expiry {
+            Some(key_expiry) => {
+                let mut application_lifetimes = HashMap::new();
+                for application in key_expiry.application_lifetimes.iter().flatten() {
+                    for authenticator_type in
+                        authenticator_types(config, application.authenticators.as_deref())?
+                    {
+                        let _ = application_lifetimes.insert(
+                            ApplicationName::new(application.name.clone(), authenticator_type),
+                            application.lifetime,
+                        );
+                    }
+                }
+                KeyLifetimes::new(key_expiry.default_lifetime, application_lifetimes)
+            }
+            None => KeyLifetimes::default(),
+        };
+        for key_info_manager_builder in key_info_manager_builders.values_mut() {
+            key_info_manager_builder.set_key_lifetimes(key_lifetimes.clone());
+        }
+
+        let providers = build_providers(provider_configs, &key_info_manager_builders)?;
+
+        // The reports are kept for the admins, failing to save them does not stop the service.
+        let reconciliation_reports_path = reconciliation_reports_path(config);
+        if let Err(e) = reconciliation::save_reports(
+            &reconciliation_reports_path,
+            &reconciliation_reports(&key_info_manager_builders),
+        ) {
+            warn!(
+                "Failed to save the reconciliation reports to {}: {}.",
+                reconciliation_reports_path.display(),
+                e
+            );
+        }
 
         if providers.is_empty() {
             error!("Parsec needs at least one provider to start. No valid provider could be created from the configuration.");
             return Err(Error::new(ErrorKind::InvalidData, "need one provider").into());
         }
 
-        let authenticators = build_authenticators(&config.authenticator)?;
+        // The core provider reads the metadata of the keys of the providers created.
+        let key_info_manager_clients = provider_configs
+            .iter()
+            .filter(|provider_config| {
+                providers
+                    .iter()
+                    .any(|(provider_id, _)| *provider_id == provider_config.provider_id())
+            })
+            .filter_map(|provider_config| {
+                key_info_manager_builders
+                    .get(provider_config.key_info_manager())
+                    .map(|builder| builder.build_client(provider_config.provider_id()))
+            })
+            .collect();
 
-        if authenticators[0].0 == AuthType::Direct {
-            warn!("Direct authenticator has been set as the default one. It is only secure under specific requirements. Please make sure to read the Recommendations on a Secure Parsec Deployment at https://parallaxsecond.github.io/parsec-book/parsec_security/secure_deployment.html");
+        let mut key_quota_overrides = HashMap::new();
+        for key_quota_override in config.key_quota_override.iter().flatten() {
+            for authenticator_type in
+                authenticator_types(config, key_quota_override.authenticators.as_deref())?
+            {
+                let _ = key_quota_overrides.insert(
+                    ApplicationName::new(key_quota_override.name.clone(), authenticator_type),
+                    key_quota_override.max_keys_per_application,
+                );
+            }
         }
+        let key_quotas = provider_configs
+            .iter()
+            .filter_map(|provider_config| {
+                let key_info_manager_builder =
+                    key_info_manager_builders.get(provider_config.key_info_manager())?;
+                let provider_id = provider_config.provider_id();
+                KeyQuota::new(
+                    key_info_manager_builder.build_client(provider_id),
+                    provider_config.key_quota(),
+                    key_quota_overrides.clone(),
+                )
+                .map(|key_quota| (provider_id, key_quota))
+            })
+            .collect();
+
+        let backend_key_info_manager_clients = providers
+            .iter()
+            .filter_map(|(provider_id, _)| {
+                build_key_info_manager_client(
+                    provider_configs,
+                    &key_info_manager_builders,
+                    *provider_id,
+                )
+                .map(|client| (*provider_id, client))
+            })
+            .collect();
+
+        // The reaper runs even without `[key_expiry]`, as an expiry can also be set by the
+        // `set-key-expiry` admin command. The expiry times are indexed, its passes do not read
+        // all the mappings.
+        let key_reaper = KeyReaper::start(
+            providers
+                .iter()
+                .filter_map(|(provider_id, provider)| {
+                    build_key_info_manager_client(
+                        provider_configs,
+                        &key_info_manager_builders,
+                        *provider_id,
+                    )
+                    .map(|client| (provider.clone(), client))
+                })
+                .collect(),
+            Duration::from_secs(
+                config
+                    .key_expiry
+                    .as_ref()
+                    .and_then(|key_expiry_config| key_expiry_config.reaper_interval)
+                    .unwrap_or(DEFAULT_KEY_REAPER_INTERVAL),
+            ),
+        )?;
 
-        let backend_handlers = build_backend_handlers(providers, &authenticators)?;
+        let policies = Policies {
+            access_policy: match &config.access_policy {
+                Some(access_policy_config) => Some(Arc::new(AccessPolicy::from_file(
+                    Path::new(&access_policy_config.policy_path),
+                    &config.authenticator,
+                )?)),
+                None => None,
+            },
+            crypto_policy: match &config.crypto_policy {
+                Some(crypto_policy_config) => {
+                    Some(Arc::new(CryptoPolicy::new(crypto_policy_config)?))
+                }
+                None => None,
+            },
+            opcode_filters: provider_configs
+                .iter()
+                .map(|provider_config| {
+                    Ok((
+                        provider_config.provider_id(),
+                        provider_config.opcode_filter()?,
+                    ))
+                })
+                .collect::<std::io::Result<_>>()?,
+        };
+
+        let backend_handlers = build_backend_handlers(
+            providers,
+            &authenticators,
+            key_info_manager_clients,
+            backend_key_info_manager_clients,
+            key_quotas,
+            &policies,
+        )?;
 
         let dispatcher = DispatcherBuilder::new()
             .with_backends(backend_handlers)
+            .with_key_reaper(key_reaper)
             .build()?;
 
         let mut front_end_handler_builder = FrontEndHandlerBuilder::new();
-        for (auth_type, authenticator) in authenticators {
+        for (authenticator_type, authenticator) in authenticators {
             front_end_handler_builder =
-                front_end_handler_builder.with_authenticator(auth_type, authenticator);
+                front_end_handler_builder.with_authenticator(authenticator_type, authenticator);
         }
         front_end_handler_builder = front_end_handler_builder
             .with_dispatcher(dispatcher)
@@ -140,16 +340,245 @@ impl ServiceBuilder {
         Ok(front_end_handler_builder.build()?)
     }
 
+    /// Snapshot the mappings of the key info manager named `key_manager` into an archive. The
+    /// service must not be running.
+    ///
+    /// # Errors
+    /// * if the key info manager does not exist in the configuration, if it does not store its
+    /// mappings or if its mappings could not be read.
+    pub fn backup_mappings(config: &ServiceConfig, key_manager: &str) -> Result<KeyInfoArchive> {
+        check_key_info_manager_is_persistent(config, key_manager)?;
+        let key_info_manager_builders = gey_key_info_manager_builders(config)?;
+
+        get_key_info_manager_builder(&key_info_manager_builders, key_manager)?.backup()
+    }
+
+    /// Restore the mappings of an archive in the key info manager named `key_manager`. The
+    /// service must not be running.
+    ///
+    /// The providers of the configuration are then created, as when the service starts, so that
+    /// they check that the keys of the restored mappings exist. The restored mappings quarantined
+    /// by them are listed in the report. Providers which do not check their keys when they are created
+    /// keep all the restored mappings.
+    ///
+    /// # Errors
+    /// * if the key info manager does not exist in the configuration, if it does not store its
+    /// mappings, if the mappings could not be restored or if the providers could not be created.
+    pub fn restore_mappings(
+        config: &ServiceConfig,
+        key_manager: &str,
+        archive: &KeyInfoArchive,
+    ) -> Result<RestoreReport> {
+        check_key_info_manager_is_persistent(config, key_manager)?;
+        let provider_configs = config.provider.as_ref().map(Vec::as_slice).unwrap_or(&[]);
+        let key_info_manager_builders = gey_key_info_manager_builders(config)?;
+        let key_info_manager_builder =
+            get_key_info_manager_builder(&key_info_manager_builders, key_manager)?;
+
+        let mut report = RestoreReport {
+            restored: key_info_manager_builder.restore(archive)?,
+            ..Default::default()
+        };
+
+        let key_triples = archive.key_triples()?;
+        let mut clients = HashMap::new();
+        for key_triple in key_triples.iter() {
+            let provider_id = key_triple.provider_id();
+            let _ = clients
+                .entry(provider_id)
+                .or_insert_with(|| key_info_manager_builder.build_client(provider_id));
+        }
+
+        // Providers quarantine the mappings of the keys they do not have when they are created.
+        let providers = build_providers(provider_configs, &key_info_manager_builders)?;
+        let checked_providers: Vec<ProviderId> = providers
+            .iter()
+            .map(|(provider_id, _)| *provider_id)
+            .filter(|provider_id| {
+                provider_configs.iter().any(|provider_config| {
+                    provider_config.provider_id() == *provider_id
+                        && provider_config.key_info_manager() == key_manager
+                })
+            })
+            .collect();
+        drop(providers);
+
+        for (provider_id, client) in clients.iter() {
+            if !checked_providers.contains(provider_id) {
+                report.unchecked_providers.push(*provider_id);
+                continue;
+            }
+            let remaining = client.get_all()?;
+            report.missing_keys.extend(
+                key_triples
+                    .iter()
+                    .filter(|key_triple| {
+                        key_triple.provider_id() == *provider_id && !remaining.contains(key_triple)
+                    })
+                    .cloned(),
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Reconcile the mappings of all the key info managers with the key stores of the providers
+    /// using them, as when the service starts, and return the reports. The service must not be
+    /// running.
+    ///
+    /// # Errors
+    /// * if the key info managers or the providers could not be created.
+    pub fn reconcile(config: &ServiceConfig) -> Result<Vec<ReconciliationReport>> {
+        let provider_configs = config.provider.as_ref().map(Vec::as_slice).unwrap_or(&[]);
+        let key_info_manager_builders = gey_key_info_manager_builders(config)?;
+
+        // Providers reconcile their mappings when they are created.
+        drop(build_providers(
+            provider_configs,
+            &key_info_manager_builders,
+        )?);
+
+        Ok(reconciliation_reports(&key_info_manager_builders))
+    }
+
+    /// Read the reports of the reconciliation made when the service last started, as saved by it.
+    /// The mappings are not modified and the service can be running.
+    ///
+    /// # Errors
+    /// * if the reports could not be read, for example if the service never started.
+    pub fn saved_reconciliation_reports(config: &ServiceConfig) -> Result<String> {
+        let path = reconciliation_reports_path(config);
+        std::fs::read_to_string(&path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "Failed to read the reconciliation reports from {} ({})",
+                    path.display(),
+                    e
+                ),
+            )
+            .into()
+        })
+    }
+
+    /// Construct the core provider used by the admin command `command`, which reads or updates the
+    /// mappings of the keys of the providers of the configuration. The service must not be
+    /// running.
+    ///
+    /// The providers themselves are not created. The providers using a volatile key info manager
+    /// are left out as their mappings only exist while the service is running.
+    ///
+    /// # Errors
+    /// * if an access policy is configured and does not allow the command.
+    /// * if the key info managers or the core provider could not be created.
+    pub fn build_admin_provider(config: &ServiceConfig, command: &str) -> Result<CoreProvider> {
+        if let Some(access_policy_config) = &config.access_policy {
+            AccessPolicy::from_file(
+                Path::new(&access_policy_config.policy_path),
+                &config.authenticator,
+            )?
+            .check_admin_command(command)?;
+        }
+
+        let provider_configs = config.provider.as_ref().map(Vec::as_slice).unwrap_or(&[]);
+        let key_info_manager_builders = gey_key_info_manager_builders(config)?;
+
+        let mut core_provider_builder = CoreProviderBuilder::new()
+            .with_wire_protocol_version(WIRE_PROTOCOL_VERSION_MINOR, WIRE_PROTOCOL_VERSION_MAJOR);
+        for provider_config in provider_configs {
+            let is_volatile = config.key_manager.iter().flatten().any(|key_manager| {
+                key_manager.name == *provider_config.key_info_manager()
+                    && matches!(key_manager.manager_type, KeyInfoManagerType::Volatile)
+            });
+            if is_volatile {
+                continue;
+            }
+            if let Some(key_info_manager_builder) =
+                key_info_manager_builders.get(provider_config.key_info_manager())
+            {
+                core_provider_builder = core_provider_builder.with_key_info_manager_client(
+                    key_info_manager_builder.build_client(provider_config.provider_id()),
+                );
+            }
+        }
+
+        Ok(core_provider_builder.build()?)
+    }
+
+    /// Construct all the listeners of the service, each with the front end handler of the service
+    /// restricted to the authenticators and the request body size limit of the listener.
+    ///
+    /// # Errors
+    /// * if a listener allows an authenticator which is not configured, an error of kind
+    /// `InvalidData` is returned.
+    /// * if a listener uses two authenticators selected with the same authentication type, an
+    /// error of kind `InvalidInput` is returned.
+    pub fn start_listeners(
+        config: &ServiceConfig,
+        front_end_handler: &FrontEndHandler,
+    ) -> Result<Vec<(Box<dyn Listen>, Arc<FrontEndHandler>)>> {
+        let mut listeners = Vec::new();
+        for listener_config in &config.listener {
+            let authenticator_types =
+                authenticator_types(config, listener_config.authenticators.as_deref())?;
+            let front_end_handler = front_end_handler
+                .restrict(Some(&authenticator_types), listener_config.body_len_limit)?;
+            listeners.push((
+                ServiceBuilder::start_listener(listener_config.clone())?,
+                Arc::new(front_end_handler),
+            ));
+        }
+
+        Ok(listeners)
+    }
+
     /// Construct the service IPC front component and return ownership to it.
     pub fn start_listener(config: ListenerConfig) -> Result<Box<dyn Listen>> {
-        let listener = match config.listener_type {
-            ListenerType::DomainSocket => DomainSocketListenerBuilder::new()
-                .with_timeout(Duration::from_millis(config.timeout))
-                .with_socket_path(config.socket_path.map(|s| s.into()))
-                .build(),
-        }?;
-
-        Ok(Box::new(listener))
+        let listener: Box<dyn Listen> = match config.listener_type {
+            ListenerType::DomainSocket => Box::new(
+                DomainSocketListenerBuilder::new()
+                    .with_timeout(Duration::from_millis(config.timeout))
+                    .with_socket_path(config.socket_path.map(|s| s.into()))
+                    .with_socket_permissions(config.socket_permissions)
+                    .build()?,
+            ),
+            #[cfg(feature = "tcp-listener")]
+            ListenerType::Tcp => Box::new(
+                TcpTlsListenerBuilder::new()
+                    .with_timeout(Duration::from_millis(config.timeout))
+                    .with_address(config.address)
+                    .with_certificate_path(config.tls_certificate_path.map(|s| s.into()))
+                    .with_private_key_path(config.tls_private_key_path.map(|s| s.into()))
+                    .with_client_ca_path(config.tls_client_ca_path.map(|s| s.into()))
+                    .build()?,
+            ),
+            #[cfg(not(feature = "tcp-listener"))]
+            ListenerType::Tcp => {
+                error!(
+                    "The TCP listener chosen in the configuration was not compiled in Parsec \
+                    binary."
+                );
+                return Err(Error::new(ErrorKind::InvalidData, "listener not compiled").into());
+            }
+            #[cfg(feature = "vsock-listener")]
+            ListenerType::Vsock => Box::new(
+                VsockListenerBuilder::new()
+                    .with_timeout(Duration::from_millis(config.timeout))
+                    .with_cid(config.cid)
+                    .with_port(config.port)
+                    .build()?,
+            ),
+            #[cfg(not(feature = "vsock-listener"))]
+            ListenerType::Vsock => {
+                error!(
+                    "The vsock listener chosen in the configuration was not compiled in Parsec \
+                    binary."
+                );
+                return Err(Error::new(ErrorKind::InvalidData, "listener not compiled").into());
+            }
+        };
+
+        Ok(listener)
     }
 
     /// Construct the thread pool that will be used to process all service requests.
@@ -164,39 +593,61 @@ impl ServiceBuilder {
 
 fn build_backend_handlers(
     mut providers: Vec<(ProviderId, Provider)>,
-    authenticators: &[(AuthType, Authenticator)],
+    authenticators: &[(AuthenticatorType, Authenticator)],
+    key_info_manager_clients: Vec<KeyInfoManagerClient>,
+    mut backend_key_info_manager_clients: HashMap<ProviderId, KeyInfoManagerClient>,
+    mut key_quotas: HashMap<ProviderId, KeyQuota>,
+    policies: &Policies,
 ) -> Result<HashMap<ProviderId, BackEndHandler>> {
     let mut map = HashMap::new();
 
     let mut core_provider_builder = CoreProviderBuilder::new()
         .with_wire_protocol_version(WIRE_PROTOCOL_VERSION_MINOR, WIRE_PROTOCOL_VERSION_MAJOR);
 
-    for (_auth_type, authenticator) in authenticators {
+    for (authenticator_type, authenticator) in authenticators {
         let authenticator_info = authenticator
             .describe()
             .map_err(|_| Error::new(ErrorKind::Other, "Failed to describe authenticator"))?;
-        core_provider_builder = core_provider_builder.with_authenticator_info(authenticator_info);
+        core_provider_builder =
+            core_provider_builder.with_authenticator_info(*authenticator_type, authenticator_info);
+    }
+
+    for key_info_manager_client in key_info_manager_clients {
+        core_provider_builder =
+            core_provider_builder.with_key_info_manager_client(key_info_manager_client);
     }
 
     for (provider_id, provider) in providers.drain(..) {
         core_provider_builder = core_provider_builder.with_provider(provider.clone());
 
-        let backend_handler = BackEndHandlerBuilder::new()
+        let mut backend_handler_builder = BackEndHandlerBuilder::new()
             .with_provider(provider)
             .with_converter(Box::from(ProtobufConverter {}))
             .with_provider_id(provider_id)
             .with_content_type(BodyType::Protobuf)
-            .with_accept_type(BodyType::Protobuf)
+            .with_accept_type(BodyType::Protobuf);
+        if let Some(client) = backend_key_info_manager_clients.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_key_info_manager_client(client);
+        }
+        if let Some(key_quota) = key_quotas.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_key_quota(key_quota);
+        }
+        let backend_handler = policies
+            .apply(provider_id, backend_handler_builder)
             .build()?;
         let _ = map.insert(provider_id, backend_handler);
     }
 
-    let core_provider_backend = BackEndHandlerBuilder::new()
-        .with_provider(Arc::new(core_provider_builder.build()?))
-        .with_converter(Box::from(ProtobufConverter {}))
-        .with_provider_id(ProviderId::Core)
-        .with_content_type(BodyType::Protobuf)
-        .with_accept_type(BodyType::Protobuf)
+    let core_provider_backend = policies
+        .apply(
+            ProviderId::Core,
+            BackEndHandlerBuilder::new()
+                .with_provider(Arc::new(core_provider_builder.build()?))
+                .with_converter(Box::from(ProtobufConverter {}))
+                .with_provider_id(ProviderId::Core)
+                .with_content_type(BodyType::Protobuf)
+                .with_accept_type(BodyType::Protobuf),
+        )
         .build()?;
 
     let _ = map.insert(ProviderId::Core, core_provider_backend);
@@ -204,9 +655,48 @@ fn build_backend_handlers(
     Ok(map)
 }
 
+/// Returns the reports of the reconciliations made by the providers with all the key info
+/// managers, ordered by provider ID.
+fn reconciliation_reports(
+    key_info_manager_builders: &HashMap<String, KeyInfoManagerFactory>,
+) -> Vec<ReconciliationReport> {
+    let mut reports: Vec<ReconciliationReport> = key_info_manager_builders
+        .values()
+        .flat_map(KeyInfoManagerFactory::reconciliation_reports)
+        .collect();
+    reports.sort_by_key(|report| report.provider_id as u8);
+
+    reports
+}
+
+fn reconciliation_reports_path(config: &ServiceConfig) -> PathBuf {
+    config
+        .core_settings
+        .reconciliation_reports_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(reconciliation::DEFAULT_REPORTS_PATH))
+}
+
+/// Builds a Key Info Manager client for the provider, using the Key Info Manager of its
+/// configuration.
+fn build_key_info_manager_client(
+    provider_configs: &[ProviderConfig],
+    key_info_manager_builders: &HashMap<String, KeyInfoManagerFactory>,
+    provider_id: ProviderId,
+) -> Option<KeyInfoManagerClient> {
+    let provider_config = provider_configs
+        .iter()
+        .find(|provider_config| provider_config.provider_id() == provider_id)?;
+
+    key_info_manager_builders
+        .get(provider_config.key_info_manager())
+        .map(|builder| builder.build_client(provider_id))
+}
+
 fn build_providers(
     configs: &[ProviderConfig],
-    kim_factorys: HashMap<String, KeyInfoManagerFactory>,
+    kim_factorys: &HashMap<String, KeyInfoManagerFactory>,
 ) -> Result<Vec<(ProviderId, Provider)>> {
     let mut list = Vec::new();
     for config in configs {
@@ -280,6 +770,7 @@ unsafe fn get_provider(
             Ok(Some(Arc::new(
                 MbedCryptoProviderBuilder::new()
                     .with_key_info_store(kim_factory.build_client(ProviderId::MbedCrypto))
+                    .with_opcode_filter(config.opcode_filter()?)
                     .build()?,
             )))
         }
@@ -303,6 +794,7 @@ unsafe fn get_provider(
                     .with_user_pin(user_pin.clone())
                     .with_software_public_operations(*software_public_operations)
                     .with_allow_export(*allow_export)
+                    .with_opcode_filter(config.opcode_filter()?)
                     .build()?,
             )))
         }
@@ -340,6 +832,7 @@ unsafe fn get_provider(
                     .with_key_info_store(kim_factory.build_client(ProviderId::Tpm))
                     .with_tcti(tcti)
                     .with_owner_hierarchy_auth(owner_hierarchy_auth.clone())
+                    .with_opcode_filter(config.opcode_filter()?)
                     .build()?,
             )))
         }
@@ -367,6 +860,7 @@ unsafe fn get_provider(
                     .with_bus(*bus)
                     .with_baud(*baud)
                     .with_access_key_file(access_key_file_name.clone())
+                    .with_opcode_filter(config.opcode_filter()?)
                     .build()?,
             )))
         }
@@ -376,6 +870,7 @@ unsafe fn get_provider(
             Ok(Some(Arc::new(
                 TrustedServiceProviderBuilder::new()
                     .with_key_info_store(kim_factory.build_client(ProviderId::TrustedService))
+                    .with_opcode_filter(config.opcode_filter()?)
                     .build()?,
             )))
         }
@@ -396,41 +891,221 @@ unsafe fn get_provider(
     }
 }
 
+/// Returns the types of the authenticators named, or of all the configured authenticators if
+/// `names` is `None`.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if one of the authenticators named is not configured.
+fn authenticator_types(
+    config: &ServiceConfig,
+    names: Option<&[String]>,
+) -> std::io::Result<Vec<AuthenticatorType>> {
+    let names = match names {
+        Some(names) => names,
+        None => {
+            return Ok(config
+                .authenticator
+                .iter()
+                .map(AuthenticatorConfig::authenticator_type)
+                .collect())
+        }
+    };
+
+    names
+        .iter()
+        .map(|name| {
+            config
+                .authenticator
+                .iter()
+                .find(|authenticator| authenticator.name() == name.as_str())
+                .map(AuthenticatorConfig::authenticator_type)
+                .ok_or_else(|| {
+                    error!("The authenticator \"{}\" is not configured.", name);
+                    Error::new(ErrorKind::InvalidData, "authenticator not configured")
+                })
+        })
+        .collect()
+}
+
+/// Checks that the authenticators allowed on each listener are selected with different
+/// authentication types. The "ClientCertificate" and "VsockCid" authenticators are selected with
+/// the `Direct` authentication type, as the wire protocol does not define one for them.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if a listener allows an authenticator which is not
+/// configured or two authenticators selected with the same authentication type.
+fn check_listener_authenticators(config: &ServiceConfig) -> std::io::Result<()> {
+    for listener_config in &config.listener {
+        let authenticator_types =
+            authenticator_types(config, listener_config.authenticators.as_deref())?;
+        for (index, authenticator_type) in authenticator_types.iter().enumerate() {
+            if let Some(other_authenticator_type) = authenticator_types[..index]
+                .iter()
+                .find(|other| other.auth_type() == authenticator_type.auth_type())
+            {
+                error!(
+                    "The {:?} listener allows the authenticators {:?} and {:?}, both selected \
+                     with the {:?} authentication type. Only allow one of them with the \
+                     `authenticators` of the listener.",
+                    listener_config.listener_type,
+                    other_authenticator_type,
+                    authenticator_type,
+                    authenticator_type.auth_type()
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "authentication type used twice on a listener",
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn gey_key_info_manager_builders(
-    configs: &[KeyInfoManagerConfig],
+    service_config: &ServiceConfig,
 ) -> Result<HashMap<String, KeyInfoManagerFactory>> {
+    let provider_configs = service_config
+        .provider
+        .as_ref()
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
     let mut map = HashMap::new();
-    for config in configs {
-        let _ = map.insert(config.name.clone(), KeyInfoManagerFactory::new(config)?);
+    for config in service_config.key_manager.iter().flatten() {
+        // The sealer is only needed while the storage key is unsealed and is dropped before the
+        // providers are created.
+        let sealer = match &config.encryption {
+            // The volatile manager has nothing to encrypt.
+            Some(_) if matches!(config.manager_type, KeyInfoManagerType::Volatile) => None,
+            Some(encryption_config) => Some(build_storage_key_sealer(
+                encryption_config,
+                provider_configs,
+            )?),
+            None => None,
+        };
+        let _ = map.insert(
+            config.name.clone(),
+            KeyInfoManagerFactory::new(config, sealer.as_deref())?,
+        );
     }
 
     Ok(map)
 }
 
-// Allowed to simplify the cfg blocks
-#[allow(clippy::unnecessary_wraps)]
-fn build_authenticators(config: &AuthenticatorConfig) -> Result<Vec<(AuthType, Authenticator)>> {
+#[cfg_attr(not(feature = "tpm-provider"), allow(unused_variables))]
+fn build_storage_key_sealer(
+    config: &KeyInfoEncryptionConfig,
+    provider_configs: &[ProviderConfig],
+) -> Result<Box<dyn SealStorageKey>> {
+    match config.sealing_provider {
+        #[cfg(feature = "mbed-crypto-provider")]
+        StorageKeySealerType::MbedCrypto => Ok(Box::new(MbedCryptoStorageKeySealer::new()?)),
+        #[cfg(feature = "tpm-provider")]
+        StorageKeySealerType::Tpm => {
+            let (tcti, owner_hierarchy_auth) = provider_configs
+                .iter()
+                .find_map(|provider_config| match provider_config {
+                    ProviderConfig::Tpm {
+                        tcti,
+                        owner_hierarchy_auth,
+                        ..
+                    } => Some((tcti, owner_hierarchy_auth)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    error!("The TPM sealing the storage key of the mappings needs a TPM provider to be configured.");
+                    Error::new(ErrorKind::InvalidData, "missing TPM provider configuration")
+                })?;
+            // Safety: the TSS context of the sealer is dropped before the one of the TPM provider
+            // is created.
+            let sealer = unsafe {
+                TpmProviderBuilder::new()
+                    .with_tcti(tcti)
+                    .with_owner_hierarchy_auth(owner_hierarchy_auth.clone())
+                    .build_storage_key_sealer()?
+            };
+            Ok(Box::new(sealer))
+        }
+        #[cfg(not(all(feature = "mbed-crypto-provider", feature = "tpm-provider")))]
+        _ => {
+            error!(
+                "The {:?} provider can not seal the storage key as it was not compiled in.",
+                config.sealing_provider
+            );
+            Err(Error::new(ErrorKind::InvalidData, "sealing provider not compiled").into())
+        }
+    }
+}
+
+fn get_key_info_manager_builder<'a>(
+    key_info_manager_builders: &'a HashMap<String, KeyInfoManagerFactory>,
+    name: &str,
+) -> Result<&'a KeyInfoManagerFactory> {
+    key_info_manager_builders.get(name).ok_or_else(|| {
+        format_error!("Key info manager with specified name was not found", name);
+        Error::new(ErrorKind::InvalidData, "key info manager not found").into()
+    })
+}
+
+/// Checks that the key info manager named `name` stores its mappings: the mappings of a volatile
+/// manager only exist while the service is running.
+fn check_key_info_manager_is_persistent(config: &ServiceConfig, name: &str) -> Result<()> {
+    let is_volatile = config
+        .key_manager
+        .as_ref()
+        .and_then(|configs| configs.iter().find(|config| config.name == name))
+        .map(|config| matches!(config.manager_type, KeyInfoManagerType::Volatile))
+        .unwrap_or(false);
+    if is_volatile {
+        format_error!("The key info manager does not store its mappings", name);
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "volatile key info managers can not be backed up or restored",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn build_authenticators(
+    configs: &[AuthenticatorConfig],
+) -> Result<Vec<(AuthenticatorType, Authenticator)>> {
     // The authenticators supported by the Parsec service.
     // NOTE: order here is important. The order in which the elements are added here is the
-    // order in which they will be returned to any client requesting them!
-    // Currently only one authenticator is allowed by the Parsec service
-    // See parallaxsecond/parsec#271
-    let mut authenticators: Vec<(AuthType, Authenticator)> = Vec::new();
+    // order in which they will be returned to any client requesting them! The first one is the
+    // default authenticator.
+    let mut authenticators: Vec<(AuthenticatorType, Authenticator)> = Vec::new();
 
+    for config in configs {
+        let authenticator_type = config.authenticator_type();
+        if authenticators
+            .iter()
+            .any(|(other_authenticator_type, _)| *other_authenticator_type == authenticator_type)
+        {
+            error!("Authenticator \"{}\" is configured twice.", config.name());
+            return Err(Error::new(ErrorKind::InvalidData, "authenticator type used twice").into());
+        }
+        authenticators.push((authenticator_type, build_authenticator(config)?));
+    }
+
+    Ok(authenticators)
+}
+
+// Allowed to simplify the cfg blocks
+#[allow(clippy::unnecessary_wraps)]
+fn build_authenticator(config: &AuthenticatorConfig) -> Result<Authenticator> {
     match config {
         #[cfg(feature = "direct-authenticator")]
-        AuthenticatorConfig::Direct { admins } => authenticators.push((
-            AuthType::Direct,
-            Box::from(DirectAuthenticator::new(
-                admins.as_ref().cloned().unwrap_or_default(),
-            )),
-        )),
+        AuthenticatorConfig::Direct { admins } => Ok(Box::from(DirectAuthenticator::new(
+            admins.as_ref().cloned().unwrap_or_default(),
+        ))),
         #[cfg(feature = "unix-peer-credentials-authenticator")]
-        AuthenticatorConfig::UnixPeerCredentials { admins } => authenticators.push((
-            AuthType::UnixPeerCredentials,
-            Box::from(UnixPeerCredentialsAuthenticator::new(
-                admins.as_ref().cloned().unwrap_or_default(),
-            )),
+        AuthenticatorConfig::UnixPeerCredentials { admins } => Ok(Box::from(
+            UnixPeerCredentialsAuthenticator::new(admins.as_ref().cloned().unwrap_or_default()),
         )),
         #[cfg(feature = "jwt-svid-authenticator")]
         AuthenticatorConfig::JwtSvid {
@@ -450,21 +1125,381 @@ fn build_authenticators(config: &AuthenticatorConfig) -> Result<Vec<(AuthType, A
                     .into())
                 }
             };
-            authenticators.push((AuthType::JwtSvid, Box::from(jwt_svid_authenticator)))
+            Ok(Box::from(jwt_svid_authenticator))
+        }
+        #[cfg(feature = "client-certificate-authenticator")]
+        AuthenticatorConfig::ClientCertificate { identity, admins } => {
+            Ok(Box::from(ClientCertificateAuthenticator::new(
+                identity.as_deref(),
+                admins.as_ref().cloned().unwrap_or_default(),
+            )?))
         }
+        #[cfg(feature = "vsock-cid-authenticator")]
+        AuthenticatorConfig::VsockCid {
+            applications,
+            admins,
+        } => Ok(Box::from(VsockCidAuthenticator::new(
+            applications,
+            admins.as_ref().cloned().unwrap_or_default(),
+        )?)),
         #[cfg(not(all(
             feature = "direct-authenticator",
             feature = "unix-peer-credentials-authenticator",
             feature = "jwt-svid-authenticator",
+            feature = "client-certificate-authenticator",
+            feature = "vsock-cid-authenticator",
         )))]
         _ => {
             error!(
                 "Authenticator \"{:?}\" chosen in the configuration was not compiled in Parsec binary.",
                 config
             );
-            return Err(Error::new(ErrorKind::InvalidData, "authenticator not compiled").into());
+            Err(Error::new(ErrorKind::InvalidData, "authenticator not compiled").into())
         }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServiceBuilder;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::acl::KeyPermission;
+    use crate::key_info_managers::reconciliation::{self, ReconciliationReport};
+    use crate::utils::config::{AccessPolicyConfig, AuthenticatorConfig, ServiceConfig};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
     };
+    use parsec_interface::requests::{AuthType, ProviderId, ResponseStatus};
+    use std::path::Path;
 
-    Ok(authenticators)
+    /// Returns a configuration with an Mbed Crypto provider using an on-disk key info manager
+    /// storing its mappings in `store_path`, and a PKCS 11 provider using a volatile one.
+    fn config(store_path: &Path) -> ServiceConfig {
+        toml::from_str(&format!(
+            r#"
+            [core_settings]
+
+            [listener]
+            listener_type = "DomainSocket"
+            timeout = 200
+
+            [[authenticator]]
+            auth_type = "UnixPeerCredentials"
+
+            [[authenticator]]
+            auth_type = "Direct"
+
+            [[key_manager]]
+            name = "on-disk-manager"
+            manager_type = "OnDisk"
+            store_path = "{}"
+
+            [[key_manager]]
+            name = "volatile-manager"
+            manager_type = "Volatile"
+
+            [[provider]]
+            provider_type = "MbedCrypto"
+            key_info_manager = "on-disk-manager"
+
+            [[provider]]
+            provider_type = "Pkcs11"
+            key_info_manager = "volatile-manager"
+            library_path = "/usr/local/lib/softhsm/libsofthsm2.so"
+            slot_number = 0
+            "#,
+            store_path.display()
+        ))
+        .unwrap()
+    }
+
+    fn attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::Aes,
+            bits: 128,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: true,
+                    decrypt: true,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+            },
+        }
+    }
+
+    #[test]
+    fn admin_provider_reads_stored_key_metadata() {
+        let store = tempfile::tempdir().unwrap();
+        let config = config(store.path());
+        let app_name = ApplicationName::new(String::from("app"), AuthType::Direct);
+        {
+            let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+            let client =
+                key_info_manager_builders["on-disk-manager"].build_client(ProviderId::MbedCrypto);
+            let key_triple = client.get_key_triple(app_name.clone(), String::from("key"));
+            client
+                .insert_key_info(key_triple.clone(), &0_u32, attributes())
+                .unwrap();
+            let _: u32 = client.get_key_id(&key_triple).unwrap();
+        }
+
+        let admin_provider = ServiceBuilder::build_admin_provider(&config, "key-metadata").unwrap();
+        let metadata = admin_provider
+            .key_metadata(
+                ProviderId::MbedCrypto,
+                app_name.clone(),
+                String::from("key"),
+            )
+            .unwrap();
+        assert_eq!(
+            metadata.creator_authenticator_type(),
+            Some(AuthType::Direct.into())
+        );
+        assert_eq!(metadata.use_count(), 1);
+
+        let key_metadata = admin_provider
+            .list_key_metadata(ProviderId::MbedCrypto)
+            .unwrap();
+        assert_eq!(key_metadata.len(), 1);
+        assert_eq!(key_metadata[0].0.app_name(), &app_name);
+        assert_eq!(key_metadata[0].0.key_name(), "key");
+        assert_eq!(key_metadata[0].1, metadata);
+
+        // The keys of the same name in the namespace of another authenticator are different.
+        assert_eq!(
+            admin_provider
+                .key_metadata(
+                    ProviderId::MbedCrypto,
+                    ApplicationName::new(String::from("app"), AuthType::UnixPeerCredentials),
+                    String::from("key"),
+                )
+                .unwrap_err(),
+            ResponseStatus::PsaErrorDoesNotExist
+        );
+        // The mappings of the volatile key info manager do not outlive the service.
+        assert_eq!(
+            admin_provider
+                .list_key_metadata(ProviderId::Pkcs11)
+                .unwrap_err(),
+            ResponseStatus::ProviderNotRegistered
+        );
+    }
+
+    #[test]
+    fn admin_provider_transfers_clients() {
+        let store = tempfile::tempdir().unwrap();
+        let config = config(store.path());
+        let app_name = ApplicationName::new(String::from("app"), AuthType::Direct);
+        let new_app_name = ApplicationName::new(String::from("app"), AuthType::UnixPeerCredentials);
+        {
+            let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+            let client =
+                key_info_manager_builders["on-disk-manager"].build_client(ProviderId::MbedCrypto);
+            client
+                .insert_key_info(
+                    client.get_key_triple(app_name.clone(), String::from("key")),
+                    &0_u32,
+                    attributes(),
+                )
+                .unwrap();
+        }
+
+        let admin_provider =
+            ServiceBuilder::build_admin_provider(&config, "transfer-client").unwrap();
+        assert_eq!(
+            admin_provider
+                .transfer_client(app_name.clone(), new_app_name.clone())
+                .unwrap(),
+            1
+        );
+        drop(admin_provider);
+
+        // The keys are moved in the stored mappings.
+        let admin_provider = ServiceBuilder::build_admin_provider(&config, "key-metadata").unwrap();
+        let _ = admin_provider
+            .key_metadata(ProviderId::MbedCrypto, new_app_name, String::from("key"))
+            .unwrap();
+        assert_eq!(
+            admin_provider
+                .key_metadata(ProviderId::MbedCrypto, app_name, String::from("key"))
+                .unwrap_err(),
+            ResponseStatus::PsaErrorDoesNotExist
+        );
+    }
+
+    #[test]
+    fn admin_provider_sets_key_expiry() {
+        let store = tempfile::tempdir().unwrap();
+        let config = config(store.path());
+        let app_name = ApplicationName::new(String::from("app"), AuthType::Direct);
+        let key_triple = {
+            let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+            let client =
+                key_info_manager_builders["on-disk-manager"].build_client(ProviderId::MbedCrypto);
+            let key_triple = client.get_key_triple(app_name.clone(), String::from("key"));
+            client
+                .insert_key_info(key_triple.clone(), &0_u32, attributes())
+                .unwrap();
+            key_triple
+        };
+
+        let admin_provider =
+            ServiceBuilder::build_admin_provider(&config, "set-key-expiry").unwrap();
+        admin_provider
+            .set_key_expiry(
+                ProviderId::MbedCrypto,
+                app_name.clone(),
+                String::from("key"),
+                Some(0),
+            )
+            .unwrap();
+        drop(admin_provider);
+
+        // The expiry is stored with the mapping.
+        let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+        let client =
+            key_info_manager_builders["on-disk-manager"].build_client(ProviderId::MbedCrypto);
+        assert_eq!(
+            client.check_not_expired(&key_triple).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        drop(client);
+        drop(key_info_manager_builders);
+
+        let admin_provider =
+            ServiceBuilder::build_admin_provider(&config, "set-key-expiry").unwrap();
+        admin_provider
+            .set_key_expiry(ProviderId::MbedCrypto, app_name, String::from("key"), None)
+            .unwrap();
+        drop(admin_provider);
+        let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+        key_info_manager_builders["on-disk-manager"]
+            .build_client(ProviderId::MbedCrypto)
+            .check_not_expired(&key_triple)
+            .unwrap();
+    }
+
+    #[test]
+    fn admin_provider_grants_key_access() {
+        let store = tempfile::tempdir().unwrap();
+        let config = config(store.path());
+        let owner = ApplicationName::new(String::from("owner"), AuthType::Direct);
+        let signer = ApplicationName::new(String::from("signer"), AuthType::UnixPeerCredentials);
+        {
+            let key_info_manager_builders = super::gey_key_info_manager_builders(&config).unwrap();
+            let client =
+                key_info_manager_builders["on-disk-manager"].build_client(ProviderId::MbedCrypto);
+            client
+                .insert_key_info(
+                    client.get_key_triple(owner.clone(), String::from("key")),
+                    &0_u32,
+                    attributes(),
+                )
+                .unwrap();
+        }
+
+        let admin_provider =
+            ServiceBuilder::build_admin_provider(&config, "grant-key-access").unwrap();
+        // The keys are looked for among the ones of the owner.
+        assert_eq!(
+            admin_provider
+                .grant_key_access(
+                    ProviderId::MbedCrypto,
+                    signer.clone(),
+                    String::from("key"),
+                    owner.clone(),
+                    &[KeyPermission::Sign],
+                )
+                .unwrap_err(),
+            ResponseStatus::PsaErrorDoesNotExist
+        );
+        admin_provider
+            .grant_key_access(
+                ProviderId::MbedCrypto,
+                owner.clone(),
+                String::from("key"),
+                signer.clone(),
+                &[KeyPermission::Sign],
+            )
+            .unwrap();
+        drop(admin_provider);
+
+        // The grants are stored with the mapping.
+        let admin_provider =
+            ServiceBuilder::build_admin_provider(&config, "list-key-grants").unwrap();
+        let grants = admin_provider
+            .key_grants(ProviderId::MbedCrypto, owner, String::from("key"))
+            .unwrap();
+        assert_eq!(grants.len(), 1);
+        assert_eq!(grants[0].app_name(), Some(signer));
+        assert_eq!(grants[0].permissions(), vec![KeyPermission::Sign]);
+    }
+
+    #[test]
+    fn access_policy_refuses_admin_commands_by_default() {
+        let store = tempfile::tempdir().unwrap();
+        let policy_path = store.path().join("access_policy.toml");
+        let mut config = config(store.path());
+        config.access_policy = Some(AccessPolicyConfig {
+            policy_path: policy_path.display().to_string(),
+        });
+
+        std::fs::write(&policy_path, "").unwrap();
+        let _ = ServiceBuilder::build_admin_provider(&config, "key-metadata").unwrap_err();
+
+        std::fs::write(&policy_path, "admin_commands = [\"key-metadata\"]").unwrap();
+        let _ = ServiceBuilder::build_admin_provider(&config, "key-metadata").unwrap();
+        let _ = ServiceBuilder::build_admin_provider(&config, "set-key-expiry").unwrap_err();
+    }
+
+    #[test]
+    fn reconciliation_reports_are_saved() {
+        let store = tempfile::tempdir().unwrap();
+        let mut config = config(store.path());
+        config.core_settings.reconciliation_reports_path =
+            Some(store.path().join("reports").display().to_string());
+        let _ = ServiceBuilder::saved_reconciliation_reports(&config).unwrap_err();
+
+        let mut report = ReconciliationReport::new(ProviderId::MbedCrypto);
+        report.consistent = 2;
+        report
+            .orphaned_keys
+            .push(String::from("persistent key ID 1"));
+        reconciliation::save_reports(&super::reconciliation_reports_path(&config), &[report])
+            .unwrap();
+
+        let reports = ServiceBuilder::saved_reconciliation_reports(&config).unwrap();
+        assert!(reports.contains("2 consistent mappings"));
+        assert!(reports.contains("key without mapping: persistent key ID 1"));
+    }
+
+    #[test]
+    fn listeners_refuse_authenticators_selected_alike() {
+        let store = tempfile::tempdir().unwrap();
+        let mut config = config(store.path());
+        config
+            .authenticator
+            .push(AuthenticatorConfig::ClientCertificate {
+                identity: None,
+                admins: None,
+            });
+        // The listener allows all the authenticators, "Direct" and "ClientCertificate" included.
+        let _ = super::check_listener_authenticators(&config).unwrap_err();
+
+        config.listener[0].authenticators = Some(vec![
+            String::from("UnixPeerCredentials"),
+            String::from("ClientCertificate"),
+        ]);
+        super::check_listener_authenticators(&config).unwrap();
+    }
 }